@@ -0,0 +1,143 @@
+//! A buffer that asks the user for login credentials.
+//!
+//! The prompt is opened if the server rejects our password or asks for
+//! additional authentication stages, this way the user can answer right away
+//! instead of editing the config and reconnecting. Whatever is entered in the
+//! buffer is handed to the waiting task and never printed, the buffer closes
+//! once the user answered.
+//!
+//! The input of the prompt is masked while it's typed and kept out of the
+//! input history, since it usually is a password.
+
+use std::{borrow::Cow, cell::RefCell, rc::Rc};
+
+use tokio::sync::oneshot;
+use weechat::{
+    buffer::{Buffer, BufferBuilder, BufferHandle},
+    hooks::{ModifierData, ModifierHook},
+    Weechat,
+};
+
+pub struct AuthPrompt {
+    buffer: BufferHandle,
+    receiver: oneshot::Receiver<String>,
+    _mask_hook: ModifierHook,
+    _history_hook: ModifierHook,
+}
+
+impl AuthPrompt {
+    /// Create a prompt buffer for the server with the given name.
+    ///
+    /// # Arguments
+    ///
+    /// * `server_name` - The name of the server the prompt belongs to.
+    ///
+    /// * `title` - The title of the buffer, telling the user what to enter.
+    pub fn new(server_name: &str, title: &str) -> Option<Self> {
+        let (sender, receiver) = oneshot::channel();
+        let sender = Rc::new(RefCell::new(Some(sender)));
+
+        let buffer =
+            BufferBuilder::new(&format!("server.{}.auth", server_name))
+                .input_callback({
+                    let sender = sender.clone();
+
+                    move |_: &Weechat, _: &Buffer, input: Cow<str>| {
+                        if let Some(sender) = sender.borrow_mut().take() {
+                            let _ = sender.send(input.to_string());
+                        }
+                        Ok(())
+                    }
+                })
+                .close_callback(move |_: &Weechat, _: &Buffer| {
+                    // Dropping the sender tells the waiting task that the user
+                    // won't answer.
+                    sender.borrow_mut().take();
+                    Ok(())
+                })
+                .build()
+                .ok()?;
+
+        let prompt_buffer = buffer.upgrade().ok()?;
+
+        prompt_buffer.set_short_name(&format!("{}.auth", server_name));
+        prompt_buffer.set_title(title);
+        prompt_buffer.set_localvar("type", "private");
+        prompt_buffer.set_localvar("server", server_name);
+        prompt_buffer.switch_to();
+
+        let full_name = prompt_buffer.full_name().to_string();
+        let is_prompt = move |data: &Option<ModifierData>| {
+            if let Some(ModifierData::Buffer(buffer)) = data {
+                buffer.full_name() == full_name
+            } else {
+                false
+            }
+        };
+
+        let mask_hook = ModifierHook::new("input_text_display", {
+            let is_prompt = is_prompt.clone();
+
+            move |_: &Weechat,
+                  _: &str,
+                  data: Option<ModifierData>,
+                  input: Cow<str>| {
+                if is_prompt(&data) {
+                    Some("*".repeat(input.chars().count()))
+                } else {
+                    None
+                }
+            }
+        })
+        .ok()?;
+
+        // An empty string tells WeeChat to not add the input to the history.
+        let history_hook = ModifierHook::new(
+            "history_add",
+            move |_: &Weechat,
+                  _: &str,
+                  data: Option<ModifierData>,
+                  _: Cow<str>| {
+                if is_prompt(&data) {
+                    Some(String::new())
+                } else {
+                    None
+                }
+            },
+        )
+        .ok()?;
+
+        Some(Self {
+            buffer,
+            receiver,
+            _mask_hook: mask_hook,
+            _history_hook: history_hook,
+        })
+    }
+
+    /// Get the buffer handle of the prompt.
+    pub fn buffer_handle(&self) -> BufferHandle {
+        self.buffer.clone()
+    }
+
+    /// Print a message to the prompt buffer.
+    pub fn print(&self, message: &str) {
+        if let Ok(buffer) = self.buffer.upgrade() {
+            buffer.print(message);
+        }
+    }
+
+    /// Wait for the user to answer the prompt, the buffer is closed
+    /// afterwards.
+    ///
+    /// Returns `None` if the buffer was closed without an answer.
+    pub async fn answer(self) -> Option<String> {
+        let answer = self.receiver.await.ok();
+
+        if let Ok(buffer) = self.buffer.upgrade() {
+            buffer.close();
+        }
+
+        answer
+    }
+}
@@ -0,0 +1,58 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{BarItem, BarItemCallback},
+    Weechat,
+};
+
+use crate::Servers;
+
+pub(super) struct Activity {
+    servers: Servers,
+}
+
+impl Activity {
+    pub(super) fn create(servers: Servers) -> Result<BarItem, ()> {
+        let activity = Activity { servers };
+        BarItem::new("matrix_activity", activity)
+    }
+
+    pub(super) fn format_counts(unread: u64, highlights: u64) -> String {
+        format!(
+            "{}{}{}/{}{}",
+            Weechat::color("status_count_msg"),
+            unread,
+            Weechat::color("bar_delim"),
+            Weechat::color("status_count_highlight"),
+            highlights,
+        )
+    }
+}
+
+impl BarItemCallback for Activity {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer) -> String {
+        let (total_unread, total_highlights) = self
+            .servers
+            .borrow()
+            .values()
+            .flat_map(|s| s.rooms())
+            .map(|r| r.activity())
+            .fold((0, 0), |(u, h), (unread, highlights)| {
+                (u + unread, h + highlights)
+            });
+
+        let total = format!(
+            "{}[{}{}]",
+            Weechat::color("bar_delim"),
+            Self::format_counts(total_unread, total_highlights),
+            Weechat::color("bar_delim"),
+        );
+
+        if let Some(room) = self.servers.find_room(buffer) {
+            let (unread, highlights) = room.activity();
+
+            format!("{} {}", Self::format_counts(unread, highlights), total)
+        } else {
+            total
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Bar item listing the most recently active rooms.
+//!
+//! The item complements buflist for users that hide it, every room is shown
+//! with its unread messages and highlights. The rooms can be clicked with the
+//! mouse, or selected in cursor mode, to switch to their buffer.
+
+use unicode_segmentation::UnicodeSegmentation;
+use weechat::{
+    buffer::Buffer,
+    hooks::{BarItem, BarItemCallback},
+    Weechat,
+};
+
+use super::activity::Activity;
+use crate::{config::ConfigHandle, room::RoomHandle, Servers};
+
+pub struct HotRooms {
+    servers: Servers,
+    config: ConfigHandle,
+}
+
+impl HotRooms {
+    pub(super) fn create(
+        servers: Servers,
+        config: ConfigHandle,
+    ) -> Result<BarItem, ()> {
+        Self::bind_keys();
+
+        let hot_rooms = HotRooms { servers, config };
+        BarItem::new("matrix_hotrooms", hot_rooms)
+    }
+
+    /// Bind the cursor mode and mouse keys that switch to the room under the
+    /// cursor.
+    fn bind_keys() {
+        let command =
+            "/matrix hotrooms click ${_bar_item_line} ${_bar_item_col}";
+
+        let buffer = Weechat::current_buffer();
+
+        for (context, key, command) in &[
+            (
+                "cursor",
+                "@item(matrix_hotrooms):Return",
+                format!("{};/cursor stop", command),
+            ),
+            (
+                "mouse",
+                "@item(matrix_hotrooms):button1",
+                command.to_owned(),
+            ),
+        ] {
+            let _ = buffer.run_command(&format!(
+                "/mute /key bindctxt {} {} {}",
+                context, key, command
+            ));
+        }
+    }
+
+    /// Get the rooms the bar item lists, most recently active first.
+    pub fn rooms(servers: &Servers, config: &ConfigHandle) -> Vec<RoomHandle> {
+        let count = config.borrow().look().hotrooms_count();
+        servers.hot_rooms(count as usize)
+    }
+
+    fn format_room(room: &RoomHandle) -> String {
+        let name = room
+            .buffer_handle()
+            .upgrade()
+            .map(|b| b.short_name().to_string())
+            .unwrap_or_else(|_| room.room_id().to_string());
+
+        let (unread, highlights) = room.activity();
+
+        if unread == 0 && highlights == 0 {
+            format!("{}{}", Weechat::color("bar_fg"), name)
+        } else {
+            format!(
+                "{}{}{}({}{})",
+                Weechat::color("bar_fg"),
+                name,
+                Weechat::color("bar_delim"),
+                Activity::format_counts(unread, highlights),
+                Weechat::color("bar_delim"),
+            )
+        }
+    }
+
+    /// Find the room that is shown at the given position of the bar item.
+    ///
+    /// Every room is shown on its own line, bars with a horizontal filling
+    /// show the lines on a single line separated by spaces instead.
+    pub fn room_at(
+        servers: &Servers,
+        config: &ConfigHandle,
+        line: usize,
+        col: usize,
+    ) -> Option<RoomHandle> {
+        let rooms = Self::rooms(servers, config);
+
+        if line > 0 {
+            return rooms.into_iter().nth(line);
+        }
+
+        let mut start = 0;
+
+        rooms.into_iter().find(|room| {
+            let width = Weechat::remove_color(&Self::format_room(room))
+                .graphemes(true)
+                .count();
+            let end = start + width;
+            start = end + 1;
+
+            col <= end
+        })
+    }
+}
+
+impl BarItemCallback for HotRooms {
+    fn callback(&mut self, _: &Weechat, _: &Buffer) -> String {
+        Self::rooms(&self.servers, &self.config)
+            .iter()
+            .map(Self::format_room)
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
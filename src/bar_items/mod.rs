@@ -1,13 +1,21 @@
+mod activity;
 mod buffer_name;
 mod buffer_plugin;
+mod hotrooms;
+mod notify;
 mod status;
+mod typing;
 
 use weechat::hooks::BarItem;
 
-use crate::Servers;
+use crate::{config::ConfigHandle, Servers};
+use activity::Activity;
 use buffer_name::BufferName;
 use buffer_plugin::BufferPlugin;
+pub use hotrooms::HotRooms;
+use notify::Notify;
 use status::Status;
+use typing::Typing;
 
 pub struct BarItems {
     #[allow(dead_code)]
@@ -16,14 +24,29 @@ pub struct BarItems {
     buffer_name: BarItem,
     #[allow(dead_code)]
     buffer_plugin: BarItem,
+    #[allow(dead_code)]
+    activity: BarItem,
+    #[allow(dead_code)]
+    hot_rooms: BarItem,
+    #[allow(dead_code)]
+    notify: BarItem,
+    #[allow(dead_code)]
+    typing: BarItem,
 }
 
 impl BarItems {
-    pub fn hook_all(servers: Servers) -> Result<Self, ()> {
+    pub fn hook_all(
+        servers: Servers,
+        config: &ConfigHandle,
+    ) -> Result<Self, ()> {
         Ok(Self {
             status: Status::create(servers.clone())?,
             buffer_name: BufferName::create(servers.clone())?,
-            buffer_plugin: BufferPlugin::create(servers)?,
+            buffer_plugin: BufferPlugin::create(servers.clone())?,
+            activity: Activity::create(servers.clone())?,
+            hot_rooms: HotRooms::create(servers.clone(), config.clone())?,
+            notify: Notify::create(servers.clone())?,
+            typing: Typing::create(servers)?,
         })
     }
 }
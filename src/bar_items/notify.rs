@@ -0,0 +1,33 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{BarItem, BarItemCallback},
+    Weechat,
+};
+
+use crate::{push_rules::RoomNotificationMode, Servers};
+
+pub(super) struct Notify {
+    servers: Servers,
+}
+
+impl Notify {
+    pub(super) fn create(servers: Servers) -> Result<BarItem, ()> {
+        let notify = Notify { servers };
+        BarItem::new("matrix_notify", notify)
+    }
+}
+
+impl BarItemCallback for Notify {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer) -> String {
+        // Rooms that notify about every message are the default, only the
+        // restricted modes are shown.
+        match self
+            .servers
+            .find_room(buffer)
+            .and_then(|r| r.notification_mode())
+        {
+            Some(RoomNotificationMode::All) | None => String::new(),
+            Some(mode) => mode.as_str().to_owned(),
+        }
+    }
+}
@@ -20,23 +20,26 @@ impl Status {
 impl BarItemCallback for Status {
     fn callback(&mut self, _: &Weechat, buffer: &Buffer) -> String {
         let mut signs = Vec::new();
+        let owner = self.servers.buffer_owner(buffer);
 
-        if let BufferOwner::Room(server, room) =
-            self.servers.buffer_owner(buffer)
-        {
+        if let BufferOwner::Room(server, room) = &owner {
             if room.is_encrypted() {
                 signs.push(
                     server.config().borrow().look().encrypted_room_sign(),
                 );
 
-                if !room.contains_only_verified_devices() {
-                    signs.push(
+                let unverified = room.unverified_device_count();
+
+                if unverified > 0 {
+                    signs.push(format!(
+                        "{}{}",
                         server
                             .config()
                             .borrow()
                             .look()
                             .encryption_warning_sign(),
-                    );
+                        unverified
+                    ));
                 }
             }
 
@@ -44,11 +47,29 @@ impl BarItemCallback for Status {
                 signs.push(server.config().borrow().look().public_room_sign());
             }
 
+            if room.has_short_retention() {
+                signs.push(server.config().borrow().look().retention_sign());
+            }
+
+            if server.is_room_muted(room.room_id()) {
+                signs.push(server.config().borrow().look().muted_sign());
+            }
+
             if room.is_busy() {
                 signs.push(server.config().borrow().look().busy_sign());
             }
         }
 
+        if let BufferOwner::Server(server) | BufferOwner::Room(server, _) =
+            &owner
+        {
+            if server.key_backup_problem().is_some() {
+                signs.push(
+                    server.config().borrow().look().key_backup_warning_sign(),
+                );
+            }
+        }
+
         signs.join("")
     }
 }
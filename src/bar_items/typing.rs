@@ -0,0 +1,27 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{BarItem, BarItemCallback},
+    Weechat,
+};
+
+use crate::Servers;
+
+pub(super) struct Typing {
+    servers: Servers,
+}
+
+impl Typing {
+    pub(super) fn create(servers: Servers) -> Result<BarItem, ()> {
+        let typing = Typing { servers };
+        BarItem::new("matrix_typing", typing)
+    }
+}
+
+impl BarItemCallback for Typing {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer) -> String {
+        self.servers
+            .find_room(buffer)
+            .and_then(|r| r.typing_summary())
+            .unwrap_or_default()
+    }
+}
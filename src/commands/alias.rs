@@ -0,0 +1,43 @@
+use std::borrow::Cow;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{CommandRun, CommandRunCallback},
+    ReturnCode, Weechat,
+};
+
+use crate::Servers;
+
+/// Expands the commands configured in the `input.command_aliases` option
+/// into messages for the room they're run in.
+pub struct CommandAliasHook {
+    servers: Servers,
+}
+
+impl CommandAliasHook {
+    pub fn create(servers: &Servers) -> Result<CommandRun, ()> {
+        CommandRun::new(
+            "/*",
+            CommandAliasHook {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandRunCallback for CommandAliasHook {
+    fn callback(
+        &mut self,
+        _: &Weechat,
+        buffer: &Buffer,
+        command: Cow<str>,
+    ) -> ReturnCode {
+        if let Some(room) = self.servers.find_room(buffer) {
+            if room.run_command_alias(&command) {
+                return ReturnCode::OkEat;
+            }
+        }
+
+        ReturnCode::Ok
+    }
+}
@@ -0,0 +1,109 @@
+use std::borrow::Cow;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{
+        Command, CommandCallback, CommandRun, CommandRunCallback,
+        CommandSettings,
+    },
+    Args, ReturnCode, Weechat,
+};
+
+use crate::Servers;
+
+pub struct CodeCommand;
+
+impl CodeCommand {
+    pub const DESCRIPTION: &'static str =
+        "Send a code block to the current room.";
+
+    pub fn create() -> Result<Command, ()> {
+        let settings = CommandSettings::new("code")
+            .description(Self::DESCRIPTION)
+            .add_argument("[-l <language>] <code>")
+            .arguments_description(
+                "language: The language of the code, clients use it to \
+                 highlight the code.
+    code: The code that should be sent, the input isn't spell checked \
+                 unless code is added to spell.check.commands.",
+            )
+            .add_completion("-l");
+
+        Command::new(settings, Self)
+    }
+
+    /// Split the raw command line into the language and the code, the code
+    /// is returned as it was typed so its whitespace is preserved.
+    fn parse(command: &str) -> (Option<&str>, &str) {
+        let arguments = command.splitn(2, ' ').nth(1).unwrap_or_default();
+
+        if let Some(rest) = arguments.strip_prefix("-l ") {
+            let mut rest = rest.trim_start_matches(' ').splitn(2, ' ');
+            let language = rest.next().filter(|l| !l.is_empty());
+
+            (language, rest.next().unwrap_or_default())
+        } else {
+            (None, arguments)
+        }
+    }
+}
+
+impl CommandCallback for CodeCommand {
+    fn callback(&mut self, _: &Weechat, _: &Buffer, _: Args) {
+        // Room buffers are handled by the CodeInputHook, which gets to see
+        // the raw command line.
+        Weechat::print("Must be executed on a Matrix room buffer");
+    }
+}
+
+/// Runs the `/code` command in room buffers.
+///
+/// The arguments of a command are split on whitespace, the command run hook
+/// gets the command line as it was typed instead, this way the indentation
+/// and the spacing of the code is kept.
+pub struct CodeInputHook {
+    servers: Servers,
+}
+
+impl CodeInputHook {
+    pub fn create(servers: &Servers) -> Result<CommandRun, ()> {
+        CommandRun::new(
+            "/code",
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandRunCallback for CodeInputHook {
+    fn callback(
+        &mut self,
+        _: &Weechat,
+        buffer: &Buffer,
+        command: Cow<str>,
+    ) -> ReturnCode {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            return ReturnCode::Ok;
+        };
+
+        let (language, code) = CodeCommand::parse(&command);
+
+        if code.trim().is_empty() {
+            room.print_error("No code was given");
+            return ReturnCode::OkEat;
+        }
+
+        let language = language.map(ToOwned::to_owned);
+        let code = code.to_owned();
+
+        Weechat::spawn(async move {
+            room.send_code(language.as_deref(), &code).await
+        })
+        .detach();
+
+        ReturnCode::OkEat
+    }
+}
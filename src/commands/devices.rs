@@ -20,7 +20,7 @@ pub struct DevicesCommand {
 
 impl DevicesCommand {
     pub const DESCRIPTION: &'static str =
-        "List, delete or rename Matrix devices";
+        "List, delete, rename or verify Matrix devices";
 
     pub const SETTINGS: &'static [ArgParseSettings] = &[
         ArgParseSettings::DisableHelpFlags,
@@ -35,14 +35,18 @@ impl DevicesCommand {
             .add_argument("list")
             .add_argument("delete <device-id>")
             .add_argument("set-name <device-id> <name>")
+            .add_argument("verify <device-id> [confirm|cancel]")
             .arguments_description(
                 "device-id: The unique id of the device that should be deleted.
-     name: The name that the device name should be set to.",
+     name: The name that the device name should be set to.
+  confirm: Confirm that the emojis of the verification match.
+   cancel: Cancel the verification because the emojis don't match.",
             )
             .add_completion("list %(matrix-users)")
             .add_completion("delete %(matrix-own-devices)")
             .add_completion("set-name %(matrix-own-devices)")
-            .add_completion("help list|delete|set-name");
+            .add_completion("verify %(matrix-own-devices) confirm|cancel")
+            .add_completion("help list|delete|set-name|verify");
 
         Command::new(
             settings,
@@ -65,6 +69,46 @@ impl DevicesCommand {
         }
     }
 
+    fn set_name(
+        servers: &Servers,
+        buffer: &Buffer,
+        device_id: OwnedDeviceId,
+        name: String,
+    ) {
+        let server = servers.find_server(buffer);
+
+        if let Some(s) = server {
+            Weechat::spawn(async move {
+                s.set_device_name(device_id, name).await;
+            })
+            .detach();
+        } else {
+            Weechat::print("Must be executed on Matrix buffer")
+        }
+    }
+
+    fn verify(
+        servers: &Servers,
+        buffer: &Buffer,
+        device_id: OwnedDeviceId,
+        answer: Option<bool>,
+    ) {
+        let server = servers.find_server(buffer);
+
+        if let Some(s) = server {
+            Weechat::spawn(async move {
+                if let Some(matches) = answer {
+                    s.confirm_device_verification(device_id, matches).await;
+                } else {
+                    s.verify_device(device_id).await;
+                }
+            })
+            .detach();
+        } else {
+            Weechat::print("Must be executed on Matrix buffer")
+        }
+    }
+
     fn list(servers: &Servers, buffer: &Buffer, user_id: Option<OwnedUserId>) {
         let server = servers.find_server(buffer);
 
@@ -103,6 +147,26 @@ impl DevicesCommand {
                     .collect();
                 Self::delete(servers, buffer, devices);
             }
+            ("set-name", Some(args)) => {
+                let device_id = args
+                    .value_of("device-id")
+                    .expect("Args didn't contain a device id");
+                let name = args
+                    .values_of("name")
+                    .expect("Args didn't contain a name")
+                    .collect::<Vec<&str>>()
+                    .join(" ");
+
+                Self::set_name(servers, buffer, device_id.into(), name);
+            }
+            ("verify", Some(args)) => {
+                let device_id = args
+                    .value_of("device-id")
+                    .expect("Args didn't contain a device id");
+                let answer = args.value_of("answer").map(|a| a == "confirm");
+
+                Self::verify(servers, buffer, device_id.into(), answer);
+            }
             _ => Weechat::print(&format!(
                 "{}Subcommand isn't implemented",
                 Weechat::prefix(Prefix::Error)
@@ -132,7 +196,18 @@ impl DevicesCommand {
             SubCommand::with_name("set-name")
                 .about("Set the human readable name of the given device")
                 .arg(Arg::with_name("device-id").required(true))
-                .arg(Arg::with_name("name").required(true)),
+                .arg(Arg::with_name("name").required(true).multiple(true)),
+            SubCommand::with_name("verify")
+                .about(
+                    "Verify one of your own devices by comparing emojis, run \
+                     it again with confirm or cancel once the emojis are shown",
+                )
+                .arg(Arg::with_name("device-id").required(true))
+                .arg(
+                    Arg::with_name("answer")
+                        .required(false)
+                        .possible_values(&["confirm", "cancel"]),
+                ),
         ]
     }
 }
@@ -0,0 +1,78 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct EditCommand {
+    servers: Servers,
+}
+
+impl EditCommand {
+    pub const DESCRIPTION: &'static str =
+        "Edit one of your messages in the current room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("edit")
+            .description(Self::DESCRIPTION)
+            .add_argument("<event-id>|last <text>")
+            .arguments_description(
+                "event-id: The id of the message that should be edited, or \
+                 last for your last message in the buffer.
+    text: The new text of the message.",
+            )
+            .add_completion("last|%(matrix-own-events)");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for EditCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let mut arguments = arguments.skip(1);
+        let target = arguments.next().unwrap_or_default();
+        let text = arguments.collect::<Vec<String>>().join(" ");
+
+        if text.is_empty() {
+            room.print_error("No new text for the message was given");
+            return;
+        }
+
+        let event_id = if target == "last" {
+            room.last_own_event_id()
+        } else {
+            room.resolve_event_id(&target)
+        };
+
+        let event_id = match event_id {
+            Some(e) => e,
+            None if target == "last" => {
+                room.print_error("No message that could be edited was found");
+                return;
+            }
+            None => {
+                room.print_error(&format!("Invalid event id {}", target));
+                return;
+            }
+        };
+
+        Weechat::spawn(
+            async move { room.edit_message(&event_id, &text).await },
+        )
+        .detach();
+    }
+}
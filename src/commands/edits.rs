@@ -0,0 +1,68 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct EditsCommand {
+    servers: Servers,
+}
+
+impl EditsCommand {
+    pub const DESCRIPTION: &'static str =
+        "Show the edit history of a message in the current room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("edits")
+            .description(Self::DESCRIPTION)
+            .add_argument("<event-id>|last")
+            .arguments_description(
+                "event-id: The id of the message whose edits should be shown, \
+                 or last for the last edited message in the buffer.",
+            )
+            .add_completion("last");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for EditsCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, mut arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let target = arguments.nth(1).unwrap_or_default();
+
+        let event_id = if target == "last" {
+            room.last_edited_event_id()
+        } else {
+            room.resolve_event_id(&target)
+        };
+
+        let event_id = match event_id {
+            Some(e) => e,
+            None if target == "last" => {
+                room.print_error("No edited message was found");
+                return;
+            }
+            None => {
+                room.print_error(&format!("Invalid event id {}", target));
+                return;
+            }
+        };
+
+        Weechat::spawn(async move { room.print_edit_history(&event_id).await })
+            .detach();
+    }
+}
@@ -0,0 +1,57 @@
+use matrix_sdk::ruma::UserId;
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct InviteCommand {
+    servers: Servers,
+}
+
+impl InviteCommand {
+    pub const DESCRIPTION: &'static str = "Invite a user to the current room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("invite")
+            .description(Self::DESCRIPTION)
+            .add_argument("<user-id>")
+            .arguments_description(
+                "user-id: The Matrix user id of the user that should be invited.",
+            )
+            .add_completion("%(matrix-users)");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for InviteCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let user_id = arguments.nth(1).unwrap_or_default();
+
+        match UserId::parse(user_id.as_str()) {
+            Ok(user_id) => {
+                Weechat::spawn(async move { room.invite(user_id).await })
+                    .detach();
+            }
+            Err(_) => room.print_error(&format!(
+                "The given user \"{}\" isn't a valid user ID",
+                user_id
+            )),
+        }
+    }
+}
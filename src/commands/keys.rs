@@ -19,8 +19,9 @@ pub struct KeysCommand {
 }
 
 impl KeysCommand {
-    pub const DESCRIPTION: &'static str = "Import or export E2EE keys.";
-    pub const COMPLETION: &'static str = "import|export %(filename)";
+    pub const DESCRIPTION: &'static str =
+        "Import, export or restore E2EE keys.";
+    pub const COMPLETION: &'static str = "import|export|restore %(filename)";
     pub const SETTINGS: &'static [ArgParseSettings] = &[
         ArgParseSettings::DisableHelpFlags,
         ArgParseSettings::DisableVersion,
@@ -33,11 +34,13 @@ impl KeysCommand {
             .description(Self::DESCRIPTION)
             .add_argument("import <file> <passphrase>")
             .add_argument("export <file> <passphrase>")
+            .add_argument("restore <recovery-key>")
             .arguments_description(
-                "file: Path to a file that is or will contain the E2EE keys export",
+                "        file: Path to a file that is or will contain the E2EE keys export
+recovery-key: The recovery key of the key backup on the server",
             )
             .add_completion(Self::COMPLETION)
-            .add_completion("help import|export");
+            .add_completion("help import|export|restore");
 
         Command::new(
             settings,
@@ -81,6 +84,13 @@ impl KeysCommand {
         Weechat::spawn(export()).detach();
     }
 
+    fn restore(server: MatrixServer, recovery_key: String) {
+        Weechat::spawn(async move {
+            server.restore_key_backup(recovery_key).await;
+        })
+        .detach();
+    }
+
     pub fn run(buffer: &Buffer, servers: &Servers, args: &ArgMatches) {
         if let Some(server) = servers.find_server(buffer) {
             match args.subcommand() {
@@ -96,6 +106,15 @@ impl KeysCommand {
                     );
                     Self::export(server, file, passphrase);
                 }
+                ("restore", Some(args)) => {
+                    // Recovery keys are usually shown in groups of four
+                    // characters separated by spaces.
+                    let recovery_key = args
+                        .values_of("recovery-key")
+                        .expect("No recovery key found")
+                        .collect::<String>();
+                    Self::restore(server, recovery_key);
+                }
                 _ => unreachable!(),
             }
         } else {
@@ -114,6 +133,15 @@ impl KeysCommand {
                 .about("Export your E2EE keys to the given file.")
                 .arg(Arg::with_name("file").required(true))
                 .arg(Arg::with_name("passphrase").required(true)),
+            SubCommand::with_name("restore")
+                .about(
+                    "Restore the E2EE keys from the key backup on the server.",
+                )
+                .arg(
+                    Arg::with_name("recovery-key")
+                        .required(true)
+                        .multiple(true),
+                ),
         ]
     }
 }
@@ -0,0 +1,177 @@
+use clap::{
+    App as Argparse, AppSettings as ArgParseSettings, Arg, ArgMatches,
+    SubCommand,
+};
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use super::parse_and_run;
+use crate::{config::ConfigHandle, room::RoomHandle, Servers};
+
+/// Command operating on the last message in a room, meant to be bound to keys.
+pub struct LastMessageCommand {
+    servers: Servers,
+    config: ConfigHandle,
+}
+
+impl LastMessageCommand {
+    pub const DESCRIPTION: &'static str =
+        "Reply, react to, or edit the last message in the room.";
+
+    pub const SETTINGS: &'static [ArgParseSettings] = &[
+        ArgParseSettings::DisableHelpFlags,
+        ArgParseSettings::DisableVersion,
+        ArgParseSettings::VersionlessSubcommands,
+        ArgParseSettings::SubcommandRequiredElseHelp,
+    ];
+
+    pub fn create(
+        servers: &Servers,
+        config: &ConfigHandle,
+    ) -> Result<Command, ()> {
+        let settings = CommandSettings::new("lastmsg")
+            .description(Self::DESCRIPTION)
+            .add_argument("reply")
+            .add_argument("react [<reaction>]")
+            .add_argument("edit")
+            .add_argument("play")
+            .arguments_description(
+                "   reply: Start a reply to the last message in the room.
+   react: React to the last message in the room, the input.quick_reaction \
+                 option is used if no reaction is given.
+    edit: Put your last message into the input bar, the next message you \
+                 send will replace it. Use edit again to cancel the edit.
+    play: Play the last audio or voice message in the room using the \
+                 input.media_opener command.
+
+The input.reply_key, input.react_key, input.edit_key and input.play_key \
+                 options bind keys to these commands, by default meta-R, \
+                 meta-T and meta-E, which WeeChat doesn't bind itself, and no \
+                 key for play. An empty option leaves the key alone, keys can \
+                 also be bound manually, e.g. /key bind meta-P /lastmsg play",
+            )
+            .add_completion("reply|react|edit|play");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+                config: config.clone(),
+            },
+        )
+    }
+
+    /// Bind the configured keys to the subcommands of this command.
+    ///
+    /// Keys that are set to an empty string won't be bound. The default keys
+    /// aren't bound by WeeChat itself, configured keys replace existing
+    /// bindings.
+    pub fn bind_keys(config: &ConfigHandle) {
+        let (reply, react, edit, play) = {
+            let config = config.borrow();
+            let input = config.input();
+
+            (
+                input.reply_key(),
+                input.react_key(),
+                input.edit_key(),
+                input.play_key(),
+            )
+        };
+
+        let buffer = Weechat::current_buffer();
+
+        for (key, subcommand) in &[
+            (reply, "reply"),
+            (react, "react"),
+            (edit, "edit"),
+            (play, "play"),
+        ] {
+            if !key.is_empty() {
+                let _ = buffer.run_command(&format!(
+                    "/mute /key bind {} /lastmsg {}",
+                    key, subcommand
+                ));
+            }
+        }
+    }
+
+    fn reply(room: RoomHandle) {
+        if let Some(event_id) = room.last_event_id() {
+            if let Ok(buffer) = room.buffer_handle().upgrade() {
+                buffer.set_input(&format!("/reply {} ", event_id));
+            }
+        } else {
+            room.print_error("No message to reply to was found");
+        }
+    }
+
+    fn react(room: RoomHandle, reaction: String) {
+        if let Some(event_id) = room.last_event_id() {
+            Weechat::spawn(async move {
+                room.send_reaction(&event_id, &reaction).await
+            })
+            .detach();
+        } else {
+            room.print_error("No message to react to was found");
+        }
+    }
+
+    fn run(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        match args.subcommand() {
+            ("reply", _) => Self::reply(room),
+            ("react", args) => {
+                let reaction = args
+                    .and_then(|a| a.value_of("reaction"))
+                    .map(|r| r.to_owned())
+                    .unwrap_or_else(|| {
+                        self.config.borrow().input().quick_reaction()
+                    });
+
+                Self::react(room, reaction)
+            }
+            ("edit", _) => room.edit_last_message(),
+            ("play", _) => {
+                Weechat::spawn(async move { room.play_last_audio().await })
+                    .detach()
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn subcommands() -> Vec<Argparse<'static, 'static>> {
+        vec![
+            SubCommand::with_name("reply")
+                .about("Start a reply to the last message in the room."),
+            SubCommand::with_name("react")
+                .about("React to the last message in the room.")
+                .arg(Arg::with_name("reaction").required(false)),
+            SubCommand::with_name("edit")
+                .about("Edit your last message in the room."),
+            SubCommand::with_name("play")
+                .about("Play the last audio message in the room."),
+        ]
+    }
+}
+
+impl CommandCallback for LastMessageCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let argparse = Argparse::new("lastmsg")
+            .about(Self::DESCRIPTION)
+            .settings(Self::SETTINGS)
+            .subcommands(Self::subcommands());
+
+        parse_and_run(argparse, arguments, |matches| self.run(buffer, matches));
+    }
+}
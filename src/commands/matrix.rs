@@ -1,7 +1,13 @@
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
 use clap::{
     App as Argparse, AppSettings as ArgParseSettings, Arg, ArgMatches,
     SubCommand,
 };
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
 use url::Url;
 
 use weechat::{
@@ -12,9 +18,14 @@ use weechat::{
 
 use super::parse_and_run;
 use crate::{
-    commands::{DevicesCommand, KeysCommand},
+    bar_items::HotRooms,
+    commands::{DevicesCommand, KeysCommand, OutboxCommand},
     config::ConfigHandle,
-    MatrixServer, Servers, PLUGIN_NAME,
+    room::{NotifyLevel, StateChange},
+    themes::{Themes, THEME_OPTIONS},
+    transform,
+    utils::{format_duration, fuzzy_score, parse_duration},
+    BufferOwner, MatrixServer, Servers, PLUGIN_NAME,
 };
 
 pub struct MatrixCommand {
@@ -32,31 +43,200 @@ impl MatrixCommand {
             .add_argument("server add <server-name> <hostname>[:<port>]")
             .add_argument("server delete|list|listfull <server-name>")
             .add_argument("connect <server-name>")
-            .add_argument("devices delete|list|set-name")
+            .add_argument("devices delete|list|set-name|verify")
             .add_argument("keys import|export <file> <passphrase>")
+            .add_argument("outbox [list|retry|cancel|edit <number>]")
             .add_argument("disconnect <server-name>")
             .add_argument("reconnect <server-name>")
+            .add_argument("undo")
+            .add_argument("who")
+            .add_argument("thread-of <event-id>|last")
+            .add_argument("source <event-id>|last")
+            .add_argument("atroom <text>")
+            .add_argument("preview <text>")
+            .add_argument("paste-image")
+            .add_argument("mark-read [room|all]")
+            .add_argument("send-at <date> <time> <text>")
+            .add_argument("send-in <duration> <text>")
+            .add_argument("scheduled [list|cancel <id>]")
+            .add_argument("room-name <name>")
+            .add_argument("settings [notify <level>|name [<name>]|hide|unhide]")
+            .add_argument("voice <path>")
+            .add_argument("modlog [<count>]")
+            .add_argument("files [<count>]")
+            .add_argument("stats [<days>]")
+            .add_argument("bans")
+            .add_argument("bridges")
+            .add_argument("download <number> <path>")
+            .add_argument("open <number>")
+            .add_argument("gallery [<search>|up|down|open|download [<path>]]")
+            .add_argument("ping")
+            .add_argument("status")
+            .add_argument("serverinfo")
+            .add_argument("more [<count>]")
+            .add_argument("go <name>")
+            .add_argument("hotrooms [list|go <number>|click <line> <col>]")
+            .add_argument("theme [list|<name>|save <name>|delete <name>]")
+            .add_argument("resolve <room-alias>")
+            .add_argument("predecessor")
+            .add_argument("compose [send|undo|cancel]")
+            .add_argument("encryption-info")
+            .add_argument("debug sync on|off")
+            .add_argument("mute <duration>|off")
+            .add_argument("mute-server <duration>|off")
             .add_argument("help <matrix-command> [<matrix-subcommand>]")
             .arguments_description(&format!(
                 "      server: List, add, or remove Matrix servers.
      connect: Connect to Matrix servers.
   disconnect: Disconnect from one or all Matrix servers.
    reconnect: Reconnect to server(s).
+        undo: Cancel the last message that is waiting for the \
+input.send_delay to pass.
+         who: List the members of the current room with their presence \
+and typing state.
+   thread-of: Print the chain of replies leading up to the given \
+message, or the last message, into a separate buffer.
+      source: Print the JSON source of the given message, or the last \
+message, into a separate buffer, e.g. to see the whole content of a message \
+whose long words were truncated.
+      atroom: Send a message that notifies the whole room, if your \
+power level allows it.
+     preview: Show how the input.transformations option transforms the \
+given text, without sending it.
+ paste-image: Upload the image in the clipboard to the current room, \
+the input.paste_image_command option controls how the clipboard is read.
+   mark-read: Mark the current room, or all the rooms of all servers, as \
+read.
+     send-at: Send a message at the given local time, e.g. send-at \
+"2024-06-01 09:00" Good morning.
+     send-in: Send a message once the given duration passed, e.g. send-in \
+10m Meeting starts now.
+   scheduled: List the scheduled messages of the current server or cancel \
+one of them. Scheduled messages are stored in plaintext in the server \
+directory, in a file only your user can read, until they are sent.
+   room-name: Change the name of the current room.
+    settings: Show or change the settings of the current room, the \
+settings are stored on the server and shared with your other WeeChat \
+instances. The notify level is one of none, highlight, message, all or \
+default, a name without a value restores the room name.
+       voice: Send an Ogg Opus file as a voice message to the current \
+room.
+      modlog: Show the latest kicks, bans, power level changes and \
+redactions in the current room, 10 by default.
+        bans: List the users that are banned from the current room, with \
+the moderator that banned them and the reason, use /unban to lift a ban.
+     bridges: List the bridges of the current room with their bot, \
+protocol and remote channel, detected using the bridge state events and the \
+user ids of the bridged members.
+       files: List the latest files, images, videos and audio messages of \
+the current room, 20 by default.
+       stats: Show the message counts per member, the most active hours and \
+the number of attachments of the messages in the current buffer, optionally \
+only of the given number of past days.
+    download: Save a file of the last files listing to the given path, if \
+the path is a directory the file keeps its name.
+        open: Open a file of the last files listing using the \
+input.media_opener command.
+     gallery: List the images of the current room, optionally only those \
+whose name contains the search text, in a separate buffer. The up and down \
+keys select an image, alt-o opens it using the input.media_opener command \
+and alt-d downloads it to the given path.
+        ping: Measure the round trip time of a request to the homeserver \
+of the current server.
+      status: Show the connection state of the current server and \
+statistics about the processing of sync responses.
+  serverinfo: Show the homeserver, user and device of the current server \
+and the maximal size of uploads the homeserver accepts.
+        more: Fetch the given number of additional events from the room \
+history, or a page of network.history_page_size events.
+          go: Switch to the joined room whose name, alias, or id best \
+matches the given fuzzy name, creating its buffer if needed, prefix the name \
+with <server-name>: to only search the rooms of that server.
+    hotrooms: List the rooms shown in the matrix_hotrooms bar item, or \
+switch to one of them, click is used by the mouse and cursor mode key \
+bindings of the bar item.
+       theme: List the renderer themes or switch to one, a theme sets the \
+signs, colors, markers and time formats of the look section, classic and \
+modern are built in, save stores the current values as a new theme.
+     resolve: Resolve a room alias to a room id and the servers that \
+can be used to join the room.
+ predecessor: Open the room the current room replaces, the room is joined \
+if needed.
+     compose: Open a buffer to compose a long message for the current room \
+in, every line entered there is added to the message, send sends the \
+message and closes the buffer, undo removes the last added lines.
+encryption-info: Show the session rotation settings of the current room \
+and an estimate of the age and message count of the session our messages \
+are encrypted with.
+       debug: Toggle debugging helpers, sync logs the raw sync \
+responses of the current server.
+        mute: Mute the current room for the given duration, e.g. 30m, \
+2h or 1d.
+ mute-server: Mute all the rooms of the current server for the given \
+duration.
      devices: {}
         keys: {}
+      outbox: {}
         help: Show detailed command help.\n
 Use /matrix [command] help to find out more.\n",
                 DevicesCommand::DESCRIPTION,
                 KeysCommand::DESCRIPTION,
+                OutboxCommand::DESCRIPTION,
             ))
             .add_completion("server add|delete|list|listfull")
-            .add_completion("devices list|delete|set-name %(matrix-users)")
+            .add_completion(
+                "devices list|delete|set-name|verify %(matrix-users)",
+            )
             .add_completion(&format!("keys {}", KeysCommand::COMPLETION))
+            .add_completion("outbox list|retry|cancel|edit")
             .add_completion("connect %(matrix_servers)")
             .add_completion("disconnect %(matrix_servers)")
             .add_completion("reconnect %(matrix_servers)")
+            .add_completion("undo")
+            .add_completion("who")
+            .add_completion("thread-of last")
+            .add_completion("source last")
+            .add_completion("atroom")
+            .add_completion("preview")
+            .add_completion("paste-image")
+            .add_completion("mark-read room|all")
+            .add_completion("send-at")
+            .add_completion("send-in 10m|1h|1d")
+            .add_completion("scheduled list|cancel")
+            .add_completion("room-name")
             .add_completion(
-                "help server|connect|disconnect|reconnect|keys|devices",
+                "settings notify|name|hide|unhide none|highlight|message|all|default",
+            )
+            .add_completion("voice %(filename)")
+            .add_completion("modlog")
+            .add_completion("files")
+            .add_completion("stats 1|7|30")
+            .add_completion("bans")
+            .add_completion("bridges")
+            .add_completion("download 1 %(filename)")
+            .add_completion("open 1")
+            .add_completion("gallery up|down|open|download %(filename)")
+            .add_completion("ping")
+            .add_completion("status")
+            .add_completion("serverinfo")
+            .add_completion("more")
+            .add_completion("go")
+            .add_completion("hotrooms list|go|click")
+            .add_completion("theme list|save|delete|%(matrix-themes) %(matrix-themes)")
+            .add_completion("resolve")
+            .add_completion("predecessor")
+            .add_completion("compose send|undo|cancel")
+            .add_completion("encryption-info")
+            .add_completion("debug sync on|off")
+            .add_completion("mute 30m|1h|8h|1d|off")
+            .add_completion("mute-server 30m|1h|8h|1d|off")
+            .add_completion(
+                "help server|connect|disconnect|reconnect|keys|devices|outbox|\
+                 undo|who|thread-of|source|atroom|preview|paste-image|\
+                 mark-read|send-at|send-in|scheduled|room-name|settings|\
+                 voice|modlog|files|stats|bans|bridges|download|open|gallery|\
+                 ping|status|serverinfo|more|go|hotrooms|theme|resolve|\
+                 predecessor|compose|encryption-info|debug|mute|mute-server",
             );
 
         Command::new(
@@ -139,6 +319,9 @@ Use /matrix [command] help to find out more.\n",
 
         let server = self.servers.remove(server_name).unwrap();
 
+        // A server that gets added again under the same name must not be
+        // reconnected by the scheduled reconnect of this one.
+        server.cancel_reconnect();
         drop(server);
 
         Weechat::print(&format!(
@@ -213,17 +396,1036 @@ Use /matrix [command] help to find out more.\n",
         }
     }
 
+    fn undo_command(&self, buffer: &Buffer) {
+        if let Some(room) = self.servers.find_room(buffer) {
+            if !room.cancel_delayed_message() {
+                room.print_error("No message is waiting to be sent");
+            }
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+        }
+    }
+
+    fn who_command(&self, buffer: &Buffer) {
+        if let Some(room) = self.servers.find_room(buffer) {
+            Weechat::spawn(async move { room.print_who().await }).detach();
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+        }
+    }
+
+    fn thread_of_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let target = args
+            .value_of("event-id")
+            .expect("Event id not set but was required");
+
+        if let Some(event_id) = room.resolve_event_id(target) {
+            Weechat::spawn(
+                async move { room.print_reply_chain(event_id).await },
+            )
+            .detach();
+        } else {
+            room.print_error(&format!("Invalid event id {}", target));
+        }
+    }
+
+    fn source_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let target = args
+            .value_of("event-id")
+            .expect("Event id not set but was required");
+
+        if let Some(event_id) = room.resolve_event_id(target) {
+            Weechat::spawn(
+                async move { room.print_event_source(event_id).await },
+            )
+            .detach();
+        } else {
+            room.print_error(&format!("Invalid event id {}", target));
+        }
+    }
+
+    fn atroom_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let text = args
+            .values_of("text")
+            .expect("Text not set but was required")
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        Weechat::spawn(async move { room.send_room_mention(&text).await })
+            .detach();
+    }
+
+    fn preview_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let text = args
+            .values_of("text")
+            .expect("Text not set but was required")
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        let config = self.config.borrow();
+        let input = config.input();
+        let pipeline =
+            transform::pipeline(&input.transformations(), &input.rewrites());
+
+        let steps = pipeline.preview(&text);
+
+        if steps.is_empty() {
+            buffer.print("No transformations are configured");
+            return;
+        }
+
+        buffer.print(&format!("{:>10}: {}", "input", text));
+
+        for (step, text) in steps {
+            buffer.print(&format!("{:>10}: {}", step, text));
+        }
+    }
+
+    fn paste_image_command(&self, buffer: &Buffer) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        Weechat::spawn(async move { room.paste_image().await }).detach();
+    }
+
+    fn mark_read_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let rooms = if args.value_of("scope") == Some("all") {
+            self.servers
+                .borrow()
+                .values()
+                .filter(|s| s.connected())
+                .flat_map(|s| s.rooms())
+                .collect()
+        } else if let Some(room) = self.servers.find_room(buffer) {
+            vec![room]
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        Weechat::spawn(async move {
+            for room in rooms {
+                room.mark_read().await;
+            }
+        })
+        .detach();
+    }
+
+    /// Schedule a message for the room of the given buffer.
+    fn schedule_message(
+        &self,
+        buffer: &Buffer,
+        send_at: SystemTime,
+        args: &ArgMatches,
+    ) {
+        let (server, room) = if let BufferOwner::Room(server, room) =
+            self.servers.buffer_owner(buffer)
+        {
+            (server, room)
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let text = args
+            .values_of("text")
+            .expect("Text not set but was required")
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        let id = server.schedule_message(&room, send_at, text);
+
+        room.print_network(&format!(
+            "Scheduled message {} to be sent at {}",
+            id,
+            DateTime::<Local>::from(send_at).format("%Y-%m-%d %H:%M")
+        ));
+    }
+
+    fn send_at_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let date = args
+            .value_of("date")
+            .expect("Date not set but was required");
+        let time = args
+            .value_of("time")
+            .expect("Time not set but was required");
+        let date_time = format!("{} {}", date, time).replace('"', "");
+
+        let send_at =
+            NaiveDateTime::parse_from_str(&date_time, "%Y-%m-%d %H:%M")
+                .ok()
+                .and_then(|t| Local.from_local_datetime(&t).single());
+
+        if let Some(send_at) = send_at {
+            self.schedule_message(buffer, send_at.into(), args);
+        } else {
+            Weechat::print(&format!(
+                "{}: Invalid time {}, the time needs to be given as \
+                 \"YYYY-MM-DD HH:MM\"",
+                Weechat::prefix(Prefix::Error),
+                date_time
+            ));
+        }
+    }
+
+    fn send_in_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let duration = args
+            .value_of("duration")
+            .and_then(|d| parse_duration(d).ok())
+            .expect("Invalid duration passed the validator");
+
+        if let Some(time) = SystemTime::now().checked_add(duration) {
+            self.schedule_message(buffer, time, args);
+        } else {
+            buffer.print(&format!(
+                "{}: The delay {} is too long",
+                Weechat::prefix(Prefix::Error),
+                format_duration(duration)
+            ));
+        }
+    }
+
+    fn scheduled_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let server = if let Some(server) = self.servers.find_server(buffer) {
+            server
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        match args.subcommand() {
+            ("cancel", Some(args)) => {
+                let id = args
+                    .value_of("id")
+                    .and_then(|i| i.parse().ok())
+                    .expect("Invalid id passed the validator");
+
+                if server.cancel_scheduled_message(id).is_some() {
+                    buffer
+                        .print(&format!("Cancelled scheduled message {}", id));
+                } else {
+                    buffer.print(&format!(
+                        "{}: No scheduled message with the id {}",
+                        Weechat::prefix(Prefix::Error),
+                        id
+                    ));
+                }
+            }
+            _ => {
+                let messages = server.scheduled_messages();
+
+                if messages.is_empty() {
+                    buffer.print("There are no scheduled messages");
+                    return;
+                }
+
+                buffer.print("Scheduled messages:");
+
+                for message in messages {
+                    let room = server
+                        .rooms()
+                        .into_iter()
+                        .find(|r| r.room_id() == &*message.room_id)
+                        .and_then(|r| r.buffer_handle().upgrade().ok())
+                        .map(|b| b.short_name().to_string())
+                        .unwrap_or_else(|| message.room_id.to_string());
+
+                    buffer.print(&format!(
+                        "    {}: {} in {}: {}",
+                        message.id,
+                        DateTime::<Local>::from(message.send_at)
+                            .format("%Y-%m-%d %H:%M"),
+                        room,
+                        message.text
+                    ));
+                }
+            }
+        }
+    }
+
+    fn voice_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let path = PathBuf::from(
+            args.value_of("path")
+                .expect("Path not set but was required"),
+        );
+
+        Weechat::spawn(async move { room.send_voice_message(path).await })
+            .detach();
+    }
+
+    fn modlog_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let count = args
+            .value_of("count")
+            .and_then(|c| c.parse::<usize>().ok())
+            .unwrap_or(10);
+
+        Weechat::spawn(async move { room.print_modlog(count).await }).detach();
+    }
+
+    fn files_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let count = args
+            .value_of("count")
+            .and_then(|c| c.parse::<usize>().ok())
+            .unwrap_or(20);
+
+        Weechat::spawn(async move { room.print_files(count).await }).detach();
+    }
+
+    fn stats_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let days = args.value_of("days").and_then(|d| d.parse::<u64>().ok());
+
+        room.print_stats(days);
+    }
+
+    fn bans_command(&self, buffer: &Buffer) {
+        if let Some(room) = self.servers.find_room(buffer) {
+            Weechat::spawn(async move { room.print_bans().await }).detach();
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+        }
+    }
+
+    fn bridges_command(&self, buffer: &Buffer) {
+        if let Some(room) = self.servers.find_room(buffer) {
+            Weechat::spawn(async move { room.print_bridges().await }).detach();
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+        }
+    }
+
+    fn file_number(args: &ArgMatches) -> usize {
+        args.value_of("number")
+            .and_then(|n| n.parse().ok())
+            .expect("Invalid number passed the validator")
+    }
+
+    fn download_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let number = Self::file_number(args);
+        let path = PathBuf::from(
+            args.value_of("path")
+                .expect("Path not set but was required"),
+        );
+
+        Weechat::spawn(async move { room.download_file(number, path).await })
+            .detach();
+    }
+
+    fn open_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let number = Self::file_number(args);
+
+        Weechat::spawn(async move { room.open_file(number).await }).detach();
+    }
+
+    fn gallery_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        if let Some(room) = self.servers.find_gallery_room(buffer) {
+            match args.subcommand() {
+                ("up", _) => room.move_gallery_selection(-1),
+                ("down", _) => room.move_gallery_selection(1),
+                ("open", _) => {
+                    Weechat::spawn(
+                        async move { room.open_gallery_image().await },
+                    )
+                    .detach();
+                }
+                ("download", Some(subargs)) => match subargs.value_of("path") {
+                    Some(path) => {
+                        let path = PathBuf::from(path);
+
+                        Weechat::spawn(async move {
+                            room.download_gallery_image(path).await
+                        })
+                        .detach();
+                    }
+                    // The key binding can't know the path, let the user
+                    // enter it.
+                    None => buffer.set_input("/matrix gallery download "),
+                },
+                _ => Weechat::print(
+                    "The gallery is already open, use /matrix gallery on the \
+                     room buffer to search it again",
+                ),
+            }
+        } else if let Some(room) = self.servers.find_room(buffer) {
+            if args.subcommand_name().is_some() {
+                room.print_error(
+                    "Use /matrix gallery to open the gallery of the room first",
+                );
+                return;
+            }
+
+            let search = args
+                .values_of("search")
+                .map(|s| s.collect::<Vec<&str>>().join(" "));
+
+            Weechat::spawn(async move { room.open_gallery(search).await })
+                .detach();
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+        }
+    }
+
+    fn ping_command(&self, buffer: &Buffer) {
+        if let Some(server) = self.servers.find_server(buffer) {
+            Weechat::spawn(async move { server.ping().await }).detach();
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+        }
+    }
+
+    fn status_command(&self, buffer: &Buffer) {
+        if let Some(server) = self.servers.find_server(buffer) {
+            server.print_status();
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+        }
+    }
+
+    fn serverinfo_command(&self, buffer: &Buffer) {
+        if let Some(server) = self.servers.find_server(buffer) {
+            Weechat::spawn(async move { server.print_server_info().await })
+                .detach();
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+        }
+    }
+
+    fn more_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let count = args.value_of("count").and_then(|c| c.parse::<u32>().ok());
+
+        Weechat::spawn(async move {
+            if let Some(count) = count {
+                room.get_more_messages(count).await
+            } else {
+                room.get_messages().await
+            }
+        })
+        .detach();
+    }
+
+    fn go_command(&self, args: &ArgMatches) {
+        let name = args
+            .values_of("name")
+            .expect("Room name not set but was required")
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        // A server qualifier restricts the search to the rooms of that
+        // server.
+        let (server_name, pattern) = self.servers.split_qualifier(&name);
+
+        let mut best_match = None;
+
+        for server in self
+            .servers
+            .borrow()
+            .values()
+            .filter(|s| server_name.map_or(true, |n| s.name() == n))
+        {
+            for (room_id, names) in server.joined_room_names() {
+                let score = names
+                    .iter()
+                    .filter_map(|name| fuzzy_score(&pattern, name))
+                    .max();
+
+                if let Some(score) = score {
+                    if best_match.as_ref().map_or(true, |(s, _, _)| score > *s)
+                    {
+                        best_match = Some((score, server.clone(), room_id));
+                    }
+                }
+            }
+        }
+
+        if let Some((_, server, room_id)) = best_match {
+            let room = server.get_or_create_room(&room_id);
+
+            if let Ok(buffer) = room.buffer_handle().upgrade() {
+                buffer.switch_to();
+            }
+        } else {
+            Weechat::print(&format!(
+                "{}{}: No joined room matches {}",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME,
+                pattern
+            ));
+        }
+    }
+
+    fn hotrooms_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = match args.subcommand() {
+            ("go", Some(args)) => {
+                let number: usize = args
+                    .value_of("number")
+                    .and_then(|n| n.parse().ok())
+                    .expect("Invalid number passed the validator");
+
+                let room = HotRooms::rooms(&self.servers, &self.config)
+                    .into_iter()
+                    .nth(number - 1);
+
+                if room.is_none() {
+                    buffer.print(&format!(
+                        "{}: No recently active room with the number {}",
+                        Weechat::prefix(Prefix::Error),
+                        number
+                    ));
+                }
+
+                room
+            }
+            ("click", Some(args)) => {
+                let position = |name| {
+                    args.value_of(name)
+                        .and_then(|p| p.parse().ok())
+                        .expect("Invalid position passed the validator")
+                };
+
+                HotRooms::room_at(
+                    &self.servers,
+                    &self.config,
+                    position("line"),
+                    position("col"),
+                )
+            }
+            _ => {
+                let rooms = HotRooms::rooms(&self.servers, &self.config);
+
+                if rooms.is_empty() {
+                    buffer.print("There are no recently active rooms");
+                    return;
+                }
+
+                buffer.print("Recently active rooms:");
+
+                for (number, room) in rooms.iter().enumerate() {
+                    let (unread, highlights) = room.activity();
+                    let name = room
+                        .buffer_handle()
+                        .upgrade()
+                        .map(|b| b.short_name().to_string())
+                        .unwrap_or_else(|_| room.room_id().to_string());
+
+                    buffer.print(&format!(
+                        "    {}. {} ({} unread, {} highlights)",
+                        number + 1,
+                        name,
+                        unread,
+                        highlights
+                    ));
+                }
+
+                return;
+            }
+        };
+
+        if let Some(buffer) =
+            room.and_then(|r| r.buffer_handle().upgrade().ok())
+        {
+            buffer.switch_to();
+        }
+    }
+
+    fn predecessor_command(&self, buffer: &Buffer) {
+        let (server, room) = if let BufferOwner::Room(server, room) =
+            self.servers.buffer_owner(buffer)
+        {
+            (server, room)
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        if let Some((room_id, servers)) = room.predecessor() {
+            Weechat::spawn(
+                async move { server.open_room(room_id, servers).await },
+            )
+            .detach();
+        } else {
+            room.print_error("This room doesn't replace another room");
+        }
+    }
+
+    fn compose_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self
+            .servers
+            .find_room(buffer)
+            .or_else(|| self.servers.find_composing_room(buffer))
+        {
+            room
+        } else {
+            Weechat::print(
+                "Must be executed on a Matrix room buffer or a compose buffer",
+            );
+            return;
+        };
+
+        match args.subcommand_name() {
+            Some("send") => match room.close_compose_buffer() {
+                Some(draft) if !draft.trim().is_empty() => {
+                    Weechat::spawn(async move { room.send_text(&draft).await })
+                        .detach();
+                }
+                Some(_) => room.print_error("The composed message was empty"),
+                None => room.print_error("No message is being composed"),
+            },
+            Some("undo") => room.undo_compose(),
+            Some("cancel") => {
+                room.close_compose_buffer();
+            }
+            _ => room.open_compose_buffer(),
+        }
+    }
+
+    fn encryption_info_command(&self, buffer: &Buffer) {
+        if let Some(room) = self.servers.find_room(buffer) {
+            room.print_encryption_info();
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+        }
+    }
+
+    fn resolve_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let server = if let Some(s) = self.servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        let alias = args
+            .value_of("room-alias")
+            .expect("Room alias not set but was required")
+            .to_owned();
+
+        Weechat::spawn(async move {
+            match server.resolve_room(&alias).await {
+                Ok(resolved) => {
+                    let servers: Vec<&str> =
+                        resolved.servers.iter().map(|s| s.as_str()).collect();
+
+                    server.print_network(&format!(
+                        "{} resolves to {} (servers: {})",
+                        alias,
+                        resolved.room_id,
+                        servers.join(", ")
+                    ));
+                }
+                Err(e) => server
+                    .print_error(&format!("Error resolving {}: {}", alias, e)),
+            }
+        })
+        .detach();
+    }
+
+    fn debug_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let server = if let Some(s) = self.servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        if let ("sync", Some(args)) = args.subcommand() {
+            let value = args.value_of("state").unwrap_or("on");
+
+            {
+                let mut config = self.config.borrow_mut();
+                let section = config
+                    .search_section_mut("server")
+                    .expect("Can't get server section");
+
+                section
+                    .search_option(&format!("{}.sync_log", server.name()))
+                    .expect("Sync log option wasn't created")
+                    .set(value, true);
+            }
+
+            if server.sync_log().enabled() {
+                server.print_network(&format!(
+                    "Logging sync responses to {}, the messages of \
+                     unencrypted rooms are written to it in plain text, turn \
+                     it off again once you're done debugging",
+                    server.sync_log().path().display()
+                ));
+            } else {
+                server.print_network("Stopped logging sync responses");
+            }
+        }
+    }
+
+    fn room_name_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let name = args
+            .values_of("name")
+            .expect("Name not set but was required")
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        Weechat::spawn(async move {
+            room.change_state(StateChange::Name, name).await
+        })
+        .detach();
+    }
+
+    fn settings_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        match args.subcommand() {
+            ("notify", Some(args)) => {
+                let level = args.value_of("level").and_then(NotifyLevel::parse);
+
+                Weechat::spawn(async move {
+                    room.update_settings(|s| s.notify = level).await
+                })
+                .detach();
+            }
+            ("name", Some(args)) => {
+                let name = args
+                    .values_of("name")
+                    .map(|n| n.collect::<Vec<&str>>().join(" "));
+
+                Weechat::spawn(async move {
+                    room.update_settings(|s| s.name = name).await
+                })
+                .detach();
+            }
+            ("hide", _) => {
+                Weechat::spawn(async move {
+                    room.update_settings(|s| s.hidden = true).await
+                })
+                .detach();
+            }
+            ("unhide", _) => {
+                Weechat::spawn(async move {
+                    room.update_settings(|s| s.hidden = false).await
+                })
+                .detach();
+            }
+            _ => {
+                let settings = room.settings();
+
+                buffer.print(&format!(
+                    "Room settings:\n    \
+                     notify: {}\n    \
+                     name: {}\n    \
+                     hidden: {}",
+                    settings.notify.map_or("default", |n| n.as_str()),
+                    settings.name.as_deref().unwrap_or("-"),
+                    if settings.hidden { "yes" } else { "no" },
+                ));
+            }
+        }
+    }
+
+    fn number_arg() -> Arg<'static, 'static> {
+        Arg::with_name("number").required(true).validator(|n| {
+            match n.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err(format!("Invalid number {}", n)),
+            }
+        })
+    }
+
+    fn position_arg(name: &'static str) -> Arg<'static, 'static> {
+        Arg::with_name(name).required(true).validator(|p| {
+            p.parse::<usize>()
+                .map(|_| ())
+                .map_err(|_| format!("Invalid position {}", p))
+        })
+    }
+
+    fn count_arg() -> Arg<'static, 'static> {
+        Arg::with_name("count").validator(|c| {
+            c.parse::<u32>()
+                .map(|_| ())
+                .map_err(|_| format!("Invalid count {}", c))
+        })
+    }
+
+    fn duration_arg() -> Arg<'static, 'static> {
+        Arg::with_name("duration").required(true).validator(|d| {
+            if d == "off" {
+                Ok(())
+            } else {
+                parse_duration(&d).map(|_| ())
+            }
+        })
+    }
+
+    fn mute_duration(args: &ArgMatches) -> Option<Duration> {
+        args.value_of("duration")
+            .filter(|d| *d != "off")
+            .and_then(|d| parse_duration(d).ok())
+    }
+
+    fn mute_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let duration = Self::mute_duration(args);
+
+        if let BufferOwner::Room(server, room) =
+            self.servers.buffer_owner(buffer)
+        {
+            if let Err(e) = server.mute_room(&room, duration) {
+                room.print_error(&e);
+            } else if let Some(duration) = duration {
+                room.print_network(&format!(
+                    "Muted the room for {}",
+                    format_duration(duration)
+                ));
+            } else {
+                room.print_network("Unmuted the room");
+            }
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+        }
+    }
+
+    fn mute_server_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let duration = Self::mute_duration(args);
+
+        if let Some(server) = self.servers.find_server(buffer) {
+            if let Err(e) = server.mute_server(duration) {
+                server.print_error(&e);
+            } else if let Some(duration) = duration {
+                server.print_network(&format!(
+                    "Muted all the rooms of the server for {}",
+                    format_duration(duration)
+                ));
+            } else {
+                server.print_network("Unmuted the server");
+            }
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+        }
+    }
+
+    fn theme_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let mut themes = Themes::load();
+        let theme = args.value_of("theme");
+
+        let result = match (args.value_of("name"), theme) {
+            (None, _) | (Some("list"), _) => {
+                let current = self.config.borrow().look().theme_values();
+
+                buffer.print("Renderer themes:");
+
+                for name in themes.names() {
+                    let active = themes.get(&name).map_or(false, |values| {
+                        THEME_OPTIONS.iter().all(|o| {
+                            values.get(*o).is_none()
+                                || values.get(*o) == current.get(*o)
+                        })
+                    });
+
+                    buffer.print(&format!(
+                        "    {}{}",
+                        name,
+                        if active { " (active)" } else { "" }
+                    ));
+                }
+
+                Ok(())
+            }
+            (Some("save"), Some(name)) => themes
+                .insert(name, self.config.borrow().look().theme_values())
+                .map(|_| {
+                    buffer.print(&format!(
+                        "{}: Saved the current look options as the theme {}",
+                        PLUGIN_NAME, name
+                    ))
+                }),
+            (Some("delete"), Some(name)) => themes.remove(name).map(|_| {
+                buffer.print(&format!(
+                    "{}: Deleted the theme {}",
+                    PLUGIN_NAME, name
+                ))
+            }),
+            (Some("save"), None) | (Some("delete"), None) => {
+                Err("A theme name is required".to_owned())
+            }
+            (Some(name), _) => match themes.get(name) {
+                Some(values) => {
+                    let rejected = self.config.apply_theme(&values);
+
+                    if rejected.is_empty() {
+                        buffer.print(&format!(
+                            "{}: Switched to the theme {}",
+                            PLUGIN_NAME, name
+                        ));
+                        Ok(())
+                    } else {
+                        Err(format!(
+                            "The theme {} has invalid values for {}",
+                            name,
+                            rejected.join(", ")
+                        ))
+                    }
+                }
+                None => Err(format!("No theme named {}", name)),
+            },
+        };
+
+        if let Err(e) = result {
+            buffer.print(&format!(
+                "{}{}: {}",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME,
+                e
+            ));
+        }
+    }
+
     fn run(&self, buffer: &Buffer, args: &ArgMatches) {
         match args.subcommand() {
             ("connect", Some(subargs)) => self.connect_command(subargs),
             ("disconnect", Some(subargs)) => self.disconnect_command(subargs),
             ("server", Some(subargs)) => self.server_command(subargs),
+            ("undo", _) => self.undo_command(buffer),
+            ("who", _) => self.who_command(buffer),
+            ("thread-of", Some(subargs)) => {
+                self.thread_of_command(buffer, subargs)
+            }
+            ("source", Some(subargs)) => self.source_command(buffer, subargs),
+            ("atroom", Some(subargs)) => self.atroom_command(buffer, subargs),
+            ("preview", Some(subargs)) => self.preview_command(buffer, subargs),
+            ("paste-image", _) => self.paste_image_command(buffer),
+            ("voice", Some(subargs)) => self.voice_command(buffer, subargs),
+            ("send-at", Some(subargs)) => self.send_at_command(buffer, subargs),
+            ("send-in", Some(subargs)) => self.send_in_command(buffer, subargs),
+            ("scheduled", Some(subargs)) => {
+                self.scheduled_command(buffer, subargs)
+            }
+            ("room-name", Some(subargs)) => {
+                self.room_name_command(buffer, subargs)
+            }
+            ("settings", Some(subargs)) => {
+                self.settings_command(buffer, subargs)
+            }
+            ("mark-read", Some(subargs)) => {
+                self.mark_read_command(buffer, subargs)
+            }
+            ("modlog", Some(subargs)) => self.modlog_command(buffer, subargs),
+            ("files", Some(subargs)) => self.files_command(buffer, subargs),
+            ("stats", Some(subargs)) => self.stats_command(buffer, subargs),
+            ("bans", _) => self.bans_command(buffer),
+            ("bridges", _) => self.bridges_command(buffer),
+            ("download", Some(subargs)) => {
+                self.download_command(buffer, subargs)
+            }
+            ("open", Some(subargs)) => self.open_command(buffer, subargs),
+            ("gallery", Some(subargs)) => self.gallery_command(buffer, subargs),
+            ("ping", _) => self.ping_command(buffer),
+            ("status", _) => self.status_command(buffer),
+            ("serverinfo", _) => self.serverinfo_command(buffer),
+            ("more", Some(subargs)) => self.more_command(buffer, subargs),
+            ("go", Some(subargs)) => self.go_command(subargs),
+            ("hotrooms", Some(subargs)) => {
+                self.hotrooms_command(buffer, subargs)
+            }
+            ("resolve", Some(subargs)) => self.resolve_command(buffer, subargs),
+            ("theme", Some(subargs)) => self.theme_command(buffer, subargs),
+            ("predecessor", _) => self.predecessor_command(buffer),
+            ("compose", Some(subargs)) => self.compose_command(buffer, subargs),
+            ("encryption-info", _) => self.encryption_info_command(buffer),
+            ("debug", Some(subargs)) => self.debug_command(buffer, subargs),
+            ("mute", Some(subargs)) => self.mute_command(buffer, subargs),
+            ("mute-server", Some(subargs)) => {
+                self.mute_server_command(buffer, subargs)
+            }
             ("devices", Some(subargs)) => {
                 DevicesCommand::run(buffer, &self.servers, subargs)
             }
             ("keys", Some(subargs)) => {
                 KeysCommand::run(buffer, &self.servers, subargs)
             }
+            ("outbox", Some(subargs)) => {
+                OutboxCommand::run(buffer, &self.servers, subargs)
+            }
             _ => unreachable!(),
         }
     }
@@ -291,6 +1493,12 @@ impl CommandCallback for MatrixCommand {
                     .settings(KeysCommand::SETTINGS)
                     .subcommands(KeysCommand::subcommands()),
             )
+            .subcommand(
+                SubCommand::with_name("outbox")
+                    .about(OutboxCommand::DESCRIPTION)
+                    .settings(OutboxCommand::SETTINGS)
+                    .subcommands(OutboxCommand::subcommands()),
+            )
             .subcommand(
                 SubCommand::with_name("connect")
                     .about("Connect to Matrix servers.")
@@ -309,6 +1517,293 @@ impl CommandCallback for MatrixCommand {
                             .value_name("server-name")
                             .required(true),
                     ),
+            )
+            .subcommand(
+                SubCommand::with_name("undo")
+                    .about("Cancel the last delayed message."),
+            )
+            .subcommand(
+                SubCommand::with_name("who")
+                    .about("List the members of the current room."),
+            )
+            .subcommand(
+                SubCommand::with_name("thread-of")
+                    .about("Print the reply chain leading up to a message.")
+                    .arg(Arg::with_name("event-id").required(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("source")
+                    .about("Print the JSON source of a message.")
+                    .arg(Arg::with_name("event-id").required(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("atroom")
+                    .about("Send a message that notifies the whole room.")
+                    .arg(Arg::with_name("text").required(true).multiple(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("preview")
+                    .about("Show how outgoing messages get transformed.")
+                    .arg(Arg::with_name("text").required(true).multiple(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("paste-image")
+                    .about("Send the image in the clipboard to the room."),
+            )
+            .subcommand(
+                SubCommand::with_name("mark-read")
+                    .about("Mark the current room or all rooms as read.")
+                    .arg(
+                        Arg::with_name("scope")
+                            .possible_values(&["room", "all"])
+                            .default_value("room"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("send-at")
+                    .about("Send a message at the given time.")
+                    .arg(Arg::with_name("date").required(true))
+                    .arg(Arg::with_name("time").required(true))
+                    .arg(Arg::with_name("text").required(true).multiple(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("send-in")
+                    .about("Send a message once the given duration passed.")
+                    .arg(
+                        Arg::with_name("duration")
+                            .required(true)
+                            .validator(|d| parse_duration(&d).map(|_| ())),
+                    )
+                    .arg(Arg::with_name("text").required(true).multiple(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("room-name")
+                    .about("Change the name of the current room.")
+                    .arg(Arg::with_name("name").required(true).multiple(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("hotrooms")
+                    .about("List or switch to recently active rooms.")
+                    .subcommand(
+                        SubCommand::with_name("list")
+                            .about("List the recently active rooms."),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("go")
+                            .about("Switch to a recently active room.")
+                            .arg(Self::number_arg()),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("click")
+                            .about("Switch to the room at a position.")
+                            .arg(Self::position_arg("line"))
+                            .arg(Self::position_arg("col")),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("theme")
+                    .about("List, switch, save or delete renderer themes.")
+                    .arg(Arg::with_name("name"))
+                    .arg(Arg::with_name("theme")),
+            )
+            .subcommand(
+                SubCommand::with_name("settings")
+                    .about("Show or change the settings of the current room.")
+                    .subcommand(
+                        SubCommand::with_name("notify")
+                            .about("Set the notify level of the room.")
+                            .arg(
+                                Arg::with_name("level")
+                                    .required(true)
+                                    .possible_values(&[
+                                        "none",
+                                        "highlight",
+                                        "message",
+                                        "all",
+                                        "default",
+                                    ]),
+                            ),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("name")
+                            .about(
+                                "Set the name of the room buffer, or restore \
+                                 the room name.",
+                            )
+                            .arg(Arg::with_name("name").multiple(true)),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("hide")
+                            .about("Hide the room buffer."),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("unhide")
+                            .about("Show the room buffer again."),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("scheduled")
+                    .about("List or cancel scheduled messages.")
+                    .subcommand(
+                        SubCommand::with_name("list")
+                            .about("List the scheduled messages."),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("cancel")
+                            .about("Cancel a scheduled message.")
+                            .arg(
+                                Arg::with_name("id").required(true).validator(
+                                    |i| {
+                                        i.parse::<u64>().map(|_| ()).map_err(
+                                            |_| format!("Invalid id {}", i),
+                                        )
+                                    },
+                                ),
+                            ),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("voice")
+                    .about("Send a voice message to the room.")
+                    .arg(Arg::with_name("path").required(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("modlog")
+                    .about("Show the latest moderation events in the room.")
+                    .arg(Self::count_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("bans")
+                    .about("List the users that are banned from the room."),
+            )
+            .subcommand(
+                SubCommand::with_name("bridges")
+                    .about("List the bridges of the room."),
+            )
+            .subcommand(
+                SubCommand::with_name("files")
+                    .about("List the latest files in the room.")
+                    .arg(Self::count_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("stats")
+                    .about("Show statistics about the messages in the room.")
+                    .arg(Arg::with_name("days").validator(|d| {
+                        d.parse::<u64>()
+                            .ok()
+                            .filter(|d| *d > 0)
+                            .map(|_| ())
+                            .ok_or_else(|| {
+                                format!("Invalid number of days {}", d)
+                            })
+                    })),
+            )
+            .subcommand(
+                SubCommand::with_name("download")
+                    .about("Save a file of the last files listing.")
+                    .arg(Self::number_arg())
+                    .arg(Arg::with_name("path").required(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("open")
+                    .about("Open a file of the last files listing.")
+                    .arg(Self::number_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("gallery")
+                    .about("List the images of the room in a separate buffer.")
+                    .arg(Arg::with_name("search").multiple(true))
+                    .subcommand(
+                        SubCommand::with_name("up")
+                            .about("Select the previous image."),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("down")
+                            .about("Select the next image."),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("open")
+                            .about("Open the selected image."),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("download")
+                            .about("Save the selected image.")
+                            .arg(Arg::with_name("path")),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("ping")
+                    .about("Measure the round trip time to the homeserver."),
+            )
+            .subcommand(
+                SubCommand::with_name("status")
+                    .about("Show the state of the connection."),
+            )
+            .subcommand(
+                SubCommand::with_name("serverinfo")
+                    .about("Show information about the homeserver."),
+            )
+            .subcommand(
+                SubCommand::with_name("more")
+                    .about("Fetch more room history.")
+                    .arg(Self::count_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("go")
+                    .about("Switch to the room best matching a name.")
+                    .arg(Arg::with_name("name").required(true).multiple(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("resolve")
+                    .about("Resolve a room alias to a room id.")
+                    .arg(Arg::with_name("room-alias").required(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("predecessor")
+                    .about("Open the room the current room replaces."),
+            )
+            .subcommand(SubCommand::with_name("encryption-info").about(
+                "Show an estimate of the outbound group session of the room.",
+            ))
+            .subcommand(
+                SubCommand::with_name("compose")
+                    .about("Compose a long message in a separate buffer.")
+                    .subcommand(
+                        SubCommand::with_name("send")
+                            .about("Send the message and close the buffer."),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("undo")
+                            .about("Remove the last added lines."),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("cancel")
+                            .about("Discard the message and close the buffer."),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("debug")
+                    .about("Toggle debugging helpers.")
+                    .setting(ArgParseSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        SubCommand::with_name("sync")
+                            .about("Log the raw sync responses to a file.")
+                            .arg(
+                                Arg::with_name("state")
+                                    .possible_values(&["on", "off"])
+                                    .required(true),
+                            ),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("mute")
+                    .about("Mute the current room for the given duration.")
+                    .arg(Self::duration_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("mute-server")
+                    .about("Mute all the rooms of the server.")
+                    .arg(Self::duration_arg()),
             );
 
         parse_and_run(argparse, arguments, |args| self.run(buffer, args));
@@ -6,24 +6,75 @@ use weechat::{
 
 use crate::{config::ConfigHandle, Servers};
 
+mod alias;
 mod buffer_clear;
+mod code;
 mod devices;
+mod edit;
+mod edits;
+mod invite;
 mod keys;
+mod last;
 mod matrix;
+mod notify;
+mod outbox;
 mod page_up;
+mod query;
+mod react;
+mod reply;
+mod set_unread;
+mod thread;
+mod topic;
+mod unban;
+mod unreact;
+mod verification;
 
+use alias::CommandAliasHook;
 use buffer_clear::BufferClearCommand;
+use code::{CodeCommand, CodeInputHook};
 use devices::DevicesCommand;
+use edit::EditCommand;
+use edits::EditsCommand;
+use invite::InviteCommand;
 use keys::KeysCommand;
+pub use last::LastMessageCommand;
 use matrix::MatrixCommand;
+use notify::NotifyCommand;
+use outbox::OutboxCommand;
 use page_up::PageUpCommand;
+use query::QueryCommand;
+use react::ReactCommand;
+use reply::ReplyCommand;
+use set_unread::SetUnreadCommand;
+use thread::ThreadCommand;
+use topic::TopicCommand;
+use unban::UnbanCommand;
+use unreact::UnreactCommand;
+use verification::VerificationCommand;
 
 pub struct Commands {
     _matrix: Command,
     _keys: Command,
     _devices: Command,
+    _reply: Command,
+    _last_message: Command,
+    _topic: Command,
+    _invite: Command,
+    _verification: Command,
+    _code: Command,
+    _code_input: CommandRun,
+    _react: Command,
+    _unreact: Command,
+    _thread: Command,
+    _unban: Command,
+    _edit: Command,
+    _edits: Command,
+    _query: Command,
+    _notify: Command,
     _page_up: CommandRun,
     _buffer_clear: CommandRun,
+    _set_unread: CommandRun,
+    _aliases: CommandRun,
 }
 
 impl Commands {
@@ -35,8 +86,25 @@ impl Commands {
             _matrix: MatrixCommand::create(servers, config)?,
             _devices: DevicesCommand::create(servers)?,
             _keys: KeysCommand::create(servers)?,
+            _reply: ReplyCommand::create(servers)?,
+            _last_message: LastMessageCommand::create(servers, config)?,
+            _topic: TopicCommand::create(servers)?,
+            _invite: InviteCommand::create(servers)?,
+            _verification: VerificationCommand::create(servers)?,
+            _code: CodeCommand::create()?,
+            _code_input: CodeInputHook::create(servers)?,
+            _react: ReactCommand::create(servers)?,
+            _unreact: UnreactCommand::create(servers)?,
+            _thread: ThreadCommand::create(servers)?,
+            _unban: UnbanCommand::create(servers)?,
+            _edit: EditCommand::create(servers)?,
+            _edits: EditsCommand::create(servers)?,
+            _query: QueryCommand::create(servers)?,
+            _notify: NotifyCommand::create(servers)?,
             _page_up: PageUpCommand::create(servers)?,
             _buffer_clear: BufferClearCommand::create(servers)?,
+            _set_unread: SetUnreadCommand::create(servers)?,
+            _aliases: CommandAliasHook::create(servers)?,
         })
     }
 }
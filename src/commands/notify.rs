@@ -0,0 +1,71 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::{push_rules::RoomNotificationMode, Servers};
+
+pub struct NotifyCommand {
+    servers: Servers,
+}
+
+impl NotifyCommand {
+    pub const DESCRIPTION: &'static str =
+        "Show or change the notification mode of the current room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("notify")
+            .description(Self::DESCRIPTION)
+            .add_argument("[all|mentions|mute]")
+            .arguments_description(
+                "     all: Every message of the room notifies.
+mentions: Only messages that mention you or contain one of your keywords \
+notify.
+    mute: No message of the room notifies.
+
+The mode is stored in the push rules of your account, so your other clients \
+use it as well. The current mode is shown if no mode is given, it's also \
+available in the matrix_notify bar item and localvar.",
+            )
+            .add_completion("all|mentions|mute");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for NotifyCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, mut arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let mode = if let Some(m) = arguments.nth(1) {
+            m
+        } else {
+            room.print_notification_mode();
+            return;
+        };
+
+        if let Some(mode) = RoomNotificationMode::from_name(&mode) {
+            Weechat::spawn(
+                async move { room.change_notification_mode(mode).await },
+            )
+            .detach();
+        } else {
+            room.print_error(&format!(
+                "Invalid notification mode {}, expected one of {}",
+                mode,
+                RoomNotificationMode::NAMES.join(", ")
+            ));
+        }
+    }
+}
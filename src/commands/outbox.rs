@@ -0,0 +1,115 @@
+use clap::{
+    App as Argparse, AppSettings as ArgParseSettings, Arg, ArgMatches,
+    SubCommand,
+};
+
+use weechat::{buffer::Buffer, Weechat};
+
+use crate::{MatrixServer, Servers};
+
+pub struct OutboxCommand;
+
+impl OutboxCommand {
+    pub const DESCRIPTION: &'static str =
+        "List, retry, cancel or edit outgoing messages";
+
+    pub const SETTINGS: &'static [ArgParseSettings] = &[
+        ArgParseSettings::DisableHelpFlags,
+        ArgParseSettings::DisableVersion,
+        ArgParseSettings::VersionlessSubcommands,
+    ];
+
+    fn number(args: Option<&ArgMatches>) -> usize {
+        args.and_then(|a| a.value_of("number"))
+            .and_then(|n| n.parse().ok())
+            .expect("Outbox number wasn't validated")
+    }
+
+    fn retry(server: MatrixServer, number: usize) {
+        if let Some((room, transaction_id)) = server.outbox().entry(number) {
+            Weechat::spawn(async move {
+                room.retry_message(&transaction_id).await;
+                server.show_outbox();
+            })
+            .detach();
+        } else {
+            server.print_error(&format!("No message {} in the outbox", number));
+        }
+    }
+
+    fn cancel(server: &MatrixServer, number: usize) {
+        if let Some((room, transaction_id)) = server.outbox().entry(number) {
+            if !room.cancel_message(&transaction_id) {
+                server.print_error(&format!(
+                    "Message {} is already being sent",
+                    number
+                ));
+            }
+
+            server.show_outbox();
+        } else {
+            server.print_error(&format!("No message {} in the outbox", number));
+        }
+    }
+
+    fn edit(server: &MatrixServer, number: usize) {
+        if let Some((room, transaction_id)) = server.outbox().entry(number) {
+            if room.edit_queued_message(&transaction_id) {
+                if let Ok(buffer) = room.buffer_handle().upgrade() {
+                    buffer.switch_to();
+                }
+            } else {
+                server.print_error(&format!(
+                    "Message {} is already being sent",
+                    number
+                ));
+            }
+        } else {
+            server.print_error(&format!("No message {} in the outbox", number));
+        }
+    }
+
+    pub fn run(buffer: &Buffer, servers: &Servers, args: &ArgMatches) {
+        let server = if let Some(s) = servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        match args.subcommand() {
+            ("retry", args) => Self::retry(server, Self::number(args)),
+            ("cancel", args) => Self::cancel(&server, Self::number(args)),
+            ("edit", args) => Self::edit(&server, Self::number(args)),
+            _ => server.show_outbox(),
+        }
+    }
+
+    pub fn subcommands() -> Vec<Argparse<'static, 'static>> {
+        let number = || {
+            Arg::with_name("number").required(true).validator(|n| {
+                n.parse::<usize>().map(|_| ()).map_err(|_| {
+                    "The message number must be a positive number".to_owned()
+                })
+            })
+        };
+
+        vec![
+            SubCommand::with_name("list").about(
+                "List the outgoing messages of the server. Unsent \
+                     messages of unencrypted rooms are kept across restarts \
+                     in a file only your user can read, the ones of \
+                     encrypted rooms are lost on restart.",
+            ),
+            SubCommand::with_name("retry")
+                .about("Try to send a failed message again.")
+                .arg(number()),
+            SubCommand::with_name("cancel")
+                .about("Cancel a delayed or failed message.")
+                .arg(number()),
+            SubCommand::with_name("edit")
+                .about("Cancel a message and put it into the input bar.")
+                .arg(number()),
+        ]
+    }
+}
@@ -0,0 +1,88 @@
+use matrix_sdk::ruma::UserId;
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+pub struct QueryCommand {
+    servers: Servers,
+}
+
+impl QueryCommand {
+    pub const DESCRIPTION: &'static str =
+        "Open a direct chat with a user, creating it if needed.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("mquery")
+            .description(Self::DESCRIPTION)
+            .add_argument("[-server <server-name>] [<server-name>:]<user-id>")
+            .arguments_description(
+                " server-name: The server the direct chat should be opened \
+                 on, defaults to the server of the current buffer.
+     user-id: The Matrix user id of the user, it can be prefixed with the \
+                 server name, e.g. work:@alice:example.org.",
+            )
+            .add_completion("-server %(matrix_servers) %(matrix-all-users)")
+            .add_completion("%(matrix-all-users)");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+
+    fn print_error(message: &str) {
+        Weechat::print(&format!(
+            "{}{}: {}",
+            Weechat::prefix(Prefix::Error),
+            PLUGIN_NAME,
+            message
+        ));
+    }
+}
+
+impl CommandCallback for QueryCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let arguments: Vec<String> = arguments.skip(1).collect();
+
+        let (server_name, target) = match arguments.as_slice() {
+            [flag, server, target] if flag == "-server" => {
+                (Some(server.as_str()), target.as_str())
+            }
+            [target] => (None, target.as_str()),
+            _ => {
+                Self::print_error("Invalid arguments, see /help mquery");
+                return;
+            }
+        };
+
+        let (qualifier, user_id) = self.servers.split_qualifier(target);
+
+        let server = match self
+            .servers
+            .resolve_server(buffer, server_name.or(qualifier))
+        {
+            Ok(s) => s,
+            Err(e) => {
+                Self::print_error(&e);
+                return;
+            }
+        };
+
+        match UserId::parse(user_id) {
+            Ok(user_id) => {
+                Weechat::spawn(async move { server.query(user_id).await })
+                    .detach();
+            }
+            Err(_) => server.print_error(&format!(
+                "The given user \"{}\" isn't a valid user ID",
+                user_id
+            )),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct ReactCommand {
+    servers: Servers,
+}
+
+impl ReactCommand {
+    pub const DESCRIPTION: &'static str = "React to a message in the room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("react")
+            .description(Self::DESCRIPTION)
+            .add_argument("<event-id>|last <reaction>")
+            .arguments_description(
+                "event-id: The id of the event that should be reacted to, or \
+                 last for the last message in the buffer.
+reaction: The reaction, usually an emoji.",
+            )
+            .add_completion("last");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for ReactCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let mut arguments = arguments.skip(1);
+        let target = arguments.next().unwrap_or_default();
+        let reaction = arguments.collect::<Vec<String>>().join(" ");
+
+        if reaction.is_empty() {
+            room.print_error("No reaction was given");
+            return;
+        }
+
+        let event_id = if let Some(e) = room.resolve_event_id(&target) {
+            e
+        } else {
+            room.print_error(&format!("Invalid event id {}", target));
+            return;
+        };
+
+        Weechat::spawn(async move {
+            room.send_reaction(&event_id, &reaction).await
+        })
+        .detach();
+    }
+}
@@ -0,0 +1,165 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+pub struct ReplyCommand {
+    servers: Servers,
+}
+
+#[derive(Debug, PartialEq)]
+struct ReplyArgs {
+    target: String,
+    quote: Option<String>,
+    message: String,
+}
+
+impl ReplyCommand {
+    pub const DESCRIPTION: &'static str = "Reply to a message in the room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("reply")
+            .description(Self::DESCRIPTION)
+            .add_argument("<event-id>|last [-q <quote>] <message>")
+            .arguments_description(
+                "event-id: The id of the event that should be replied to, \
+                 or last for the last message in the buffer.
+   quote: Only quote the given part of the message, use double quotes if \
+                 the quote contains spaces.
+ message: The message of the reply.",
+            )
+            .add_completion("last -q");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+
+    /// Parse the arguments of the reply command.
+    ///
+    /// The arguments have the form `<event-id|last> [-q <quote>] <message>`.
+    fn parse_args(args: &str) -> Result<ReplyArgs, &'static str> {
+        let mut parts = args.trim().splitn(2, ' ');
+
+        let target = parts
+            .next()
+            .filter(|t| !t.is_empty())
+            .ok_or("No event to reply to was given")?
+            .to_owned();
+
+        let rest = parts.next().unwrap_or_default().trim_start();
+
+        let (quote, rest) = if let Some(quoted) = rest.strip_prefix("-q ") {
+            let quoted = quoted.trim_start();
+
+            if let Some(quoted) = quoted.strip_prefix('"') {
+                let end = quoted.find('"').ok_or("Unterminated quote")?;
+                (Some(quoted[..end].to_owned()), &quoted[end + 1..])
+            } else {
+                let mut quoted = quoted.splitn(2, ' ');
+                let quote = quoted.next().unwrap_or_default().to_owned();
+                (Some(quote), quoted.next().unwrap_or_default())
+            }
+        } else {
+            (None, rest)
+        };
+
+        let message = rest.trim();
+
+        if message.is_empty() {
+            Err("No reply message was given")
+        } else {
+            Ok(ReplyArgs {
+                target,
+                quote: quote.filter(|q| !q.is_empty()),
+                message: message.to_owned(),
+            })
+        }
+    }
+}
+
+impl CommandCallback for ReplyCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let args = arguments.skip(1).collect::<Vec<String>>().join(" ");
+
+        let args = match Self::parse_args(&args) {
+            Ok(a) => a,
+            Err(e) => {
+                Weechat::print(&format!(
+                    "{}{}: {}",
+                    Weechat::prefix(Prefix::Error),
+                    PLUGIN_NAME,
+                    e
+                ));
+                return;
+            }
+        };
+
+        let event_id = if let Some(e) = room.resolve_event_id(&args.target) {
+            e
+        } else {
+            room.print_error(&format!("Invalid event id {}", args.target));
+            return;
+        };
+
+        Weechat::spawn(async move {
+            room.send_reply(&event_id, args.quote.as_deref(), &args.message)
+                .await
+        })
+        .detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reply() {
+        let args = ReplyCommand::parse_args("last hello world").unwrap();
+
+        assert_eq!(
+            args,
+            ReplyArgs {
+                target: "last".to_owned(),
+                quote: None,
+                message: "hello world".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_reply_with_quote() {
+        let args =
+            ReplyCommand::parse_args("last -q \"selected text\" I agree")
+                .unwrap();
+
+        assert_eq!(args.quote.as_deref(), Some("selected text"));
+        assert_eq!(args.message, "I agree");
+
+        let args = ReplyCommand::parse_args("last -q word I agree").unwrap();
+
+        assert_eq!(args.quote.as_deref(), Some("word"));
+        assert_eq!(args.message, "I agree");
+    }
+
+    #[test]
+    fn test_parse_reply_errors() {
+        assert!(ReplyCommand::parse_args("").is_err());
+        assert!(ReplyCommand::parse_args("last").is_err());
+        assert!(ReplyCommand::parse_args("last -q \"unterminated").is_err());
+    }
+}
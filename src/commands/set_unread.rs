@@ -0,0 +1,53 @@
+use std::borrow::Cow;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{CommandRun, CommandRunCallback},
+    ReturnCode, Weechat,
+};
+
+use crate::Servers;
+
+/// Moves the fully read marker of the account when WeeChat marks buffers as
+/// read, using `/input set_unread` or `/input set_unread_current_buffer`.
+pub struct SetUnreadCommand {
+    servers: Servers,
+}
+
+impl SetUnreadCommand {
+    pub fn create(servers: &Servers) -> Result<CommandRun, ()> {
+        CommandRun::new(
+            "/input set_unread*",
+            SetUnreadCommand {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandRunCallback for SetUnreadCommand {
+    fn callback(
+        &mut self,
+        _: &Weechat,
+        buffer: &Buffer,
+        command: Cow<str>,
+    ) -> ReturnCode {
+        if command.starts_with("/input set_unread_current_buffer") {
+            if let Some(room) = self.servers.find_room(buffer) {
+                room.mark_fully_read();
+            }
+        } else {
+            for room in self
+                .servers
+                .borrow()
+                .values()
+                .filter(|s| s.connected())
+                .flat_map(|s| s.rooms())
+            {
+                room.mark_fully_read();
+            }
+        }
+
+        ReturnCode::Ok
+    }
+}
@@ -0,0 +1,59 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct ThreadCommand {
+    servers: Servers,
+}
+
+impl ThreadCommand {
+    pub const DESCRIPTION: &'static str =
+        "Open a buffer showing the thread of a message.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("thread")
+            .description(Self::DESCRIPTION)
+            .add_argument("<event-id>|last")
+            .arguments_description(
+                "event-id: The id of the message that started the thread, or \
+                 last for the last message in the buffer.
+
+Messages entered in the thread buffer are sent as replies to the thread.",
+            )
+            .add_completion("last");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for ThreadCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, mut arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let target = arguments.nth(1).unwrap_or_default();
+
+        let event_id = if let Some(e) = room.resolve_event_id(&target) {
+            e
+        } else {
+            room.print_error(&format!("Invalid event id {}", target));
+            return;
+        };
+
+        Weechat::spawn(async move { room.open_thread(event_id).await })
+            .detach();
+    }
+}
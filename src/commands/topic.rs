@@ -0,0 +1,55 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::{room::StateChange, Servers};
+
+pub struct TopicCommand {
+    servers: Servers,
+}
+
+impl TopicCommand {
+    pub const DESCRIPTION: &'static str =
+        "Show or change the topic of the current room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("topic")
+            .description(Self::DESCRIPTION)
+            .add_argument("[<topic>]")
+            .arguments_description(
+                "topic: The new topic of the room, the full topic is shown if \
+                 no topic is given.",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for TopicCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let topic = arguments.skip(1).collect::<Vec<String>>().join(" ");
+
+        if topic.is_empty() {
+            room.print_topic();
+        } else {
+            Weechat::spawn(async move {
+                room.change_state(StateChange::Topic, topic).await
+            })
+            .detach();
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct UnbanCommand {
+    servers: Servers,
+}
+
+impl UnbanCommand {
+    pub const DESCRIPTION: &'static str =
+        "Lift the ban of a user from the current room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("unban")
+            .description(Self::DESCRIPTION)
+            .add_argument("<number>|<user-id> [<reason>]")
+            .arguments_description(
+                "  number: The number of the ban in the last /matrix bans \
+                 listing.
+ user-id: The Matrix user id of the banned user.
+  reason: The reason for lifting the ban.",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for UnbanCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let mut arguments = arguments.skip(1);
+        let target = arguments.next().unwrap_or_default();
+        let reason = arguments.collect::<Vec<String>>().join(" ");
+        let reason = Some(reason).filter(|r| !r.is_empty());
+
+        Weechat::spawn(async move { room.unban(&target, reason).await })
+            .detach();
+    }
+}
@@ -0,0 +1,64 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct UnreactCommand {
+    servers: Servers,
+}
+
+impl UnreactCommand {
+    pub const DESCRIPTION: &'static str =
+        "Remove your reactions to a message in the room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("unreact")
+            .description(Self::DESCRIPTION)
+            .add_argument("<event-id>|last [<reaction>]")
+            .arguments_description(
+                "event-id: The id of the event whose reactions should be \
+                 removed, or last for the last message in the buffer.
+reaction: Only remove this reaction, all your reactions to the message \
+                 are removed if no reaction is given.",
+            )
+            .add_completion("last");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for UnreactCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let mut arguments = arguments.skip(1);
+        let target = arguments.next().unwrap_or_default();
+        let reaction = arguments.collect::<Vec<String>>().join(" ");
+
+        let event_id = if let Some(e) = room.resolve_event_id(&target) {
+            e
+        } else {
+            room.print_error(&format!("Invalid event id {}", target));
+            return;
+        };
+
+        Weechat::spawn(async move {
+            let reaction = Some(reaction.as_str()).filter(|r| !r.is_empty());
+            room.redact_reactions(&event_id, reaction).await
+        })
+        .detach();
+    }
+}
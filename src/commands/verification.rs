@@ -0,0 +1,60 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct VerificationCommand {
+    servers: Servers,
+}
+
+impl VerificationCommand {
+    pub const DESCRIPTION: &'static str =
+        "Answer the latest verification request of the current room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("verification")
+            .description(Self::DESCRIPTION)
+            .add_argument("accept|decline")
+            .arguments_description(
+                " accept: Accept the verification request, the verification \
+                 continues on the device of the other user.
+decline: Decline the verification request.",
+            )
+            .add_completion("accept|decline");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for VerificationCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let accept = match arguments.nth(1).as_deref() {
+            Some("accept") => true,
+            Some("decline") => false,
+            _ => {
+                room.print_error("Usage: /verification accept|decline");
+                return;
+            }
+        };
+
+        Weechat::spawn(async move {
+            room.answer_verification_request(accept).await
+        })
+        .detach();
+    }
+}
@@ -1,4 +1,6 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::BTreeSet};
+
+use matrix_sdk::ruma::OwnedUserId;
 
 use weechat::{
     buffer::Buffer,
@@ -8,19 +10,25 @@ use weechat::{
     Weechat,
 };
 
-use crate::Servers;
+use crate::{themes::Themes, Servers};
 
 #[allow(dead_code)]
 pub struct Completions {
     servers: CompletionHook,
     users: CompletionHook,
+    all_users: CompletionHook,
+    own_events: CompletionHook,
+    themes: CompletionHook,
 }
 
 impl Completions {
     pub fn hook_all(servers: Servers) -> Result<Self, ()> {
         Ok(Self {
             servers: ServersCompletion::create(servers.clone())?,
-            users: UsersCompletion::create(servers)?,
+            users: UsersCompletion::create(servers.clone())?,
+            all_users: AllUsersCompletion::create(servers.clone())?,
+            own_events: OwnEventsCompletion::create(servers)?,
+            themes: ThemesCompletion::create()?,
         })
     }
 }
@@ -104,3 +112,130 @@ impl CompletionCallback for UsersCompletion {
         Ok(())
     }
 }
+
+struct AllUsersCompletion {
+    servers: Servers,
+}
+
+impl AllUsersCompletion {
+    fn create(servers: Servers) -> Result<CompletionHook, ()> {
+        let comp = AllUsersCompletion { servers };
+
+        CompletionHook::new(
+            "matrix-all-users",
+            "Completion for the Matrix users of all connected servers, users \
+             of other servers than the one of the current buffer are \
+             prefixed with the server name",
+            comp,
+        )
+    }
+}
+
+impl CompletionCallback for AllUsersCompletion {
+    fn callback(
+        &mut self,
+        _: &Weechat,
+        buffer: &Buffer,
+        _: Cow<str>,
+        completion: &Completion,
+    ) -> Result<(), ()> {
+        let current = self.servers.find_server(buffer);
+        let current = current.as_ref().map(|s| s.name());
+
+        for server in self.servers.borrow().values() {
+            // The members of the rooms are already known, asking the store
+            // for every server on every completion would block WeeChat.
+            let users: BTreeSet<OwnedUserId> =
+                server.rooms().iter().flat_map(|r| r.member_ids()).collect();
+
+            for user in users {
+                let word = if current == Some(server.name()) {
+                    user.to_string()
+                } else {
+                    format!("{}:{}", server.name(), user)
+                };
+
+                completion.add_with_options(
+                    &word,
+                    true,
+                    CompletionPosition::Sorted,
+                )
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct OwnEventsCompletion {
+    servers: Servers,
+}
+
+impl OwnEventsCompletion {
+    /// The number of our latest messages whose event ids are completed.
+    const COUNT: usize = 20;
+
+    fn create(servers: Servers) -> Result<CompletionHook, ()> {
+        let comp = OwnEventsCompletion { servers };
+
+        CompletionHook::new(
+            "matrix-own-events",
+            "Completion for the event ids of your latest messages in the \
+             current room",
+            comp,
+        )
+    }
+}
+
+impl CompletionCallback for OwnEventsCompletion {
+    fn callback(
+        &mut self,
+        _: &Weechat,
+        buffer: &Buffer,
+        _: Cow<str>,
+        completion: &Completion,
+    ) -> Result<(), ()> {
+        if let Some(room) = self.servers.find_room(buffer) {
+            for event_id in room.own_event_ids(Self::COUNT) {
+                completion.add_with_options(
+                    event_id.as_str(),
+                    false,
+                    CompletionPosition::End,
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct ThemesCompletion;
+
+impl ThemesCompletion {
+    fn create() -> Result<CompletionHook, ()> {
+        CompletionHook::new(
+            "matrix-themes",
+            "Completion for the list of renderer themes",
+            ThemesCompletion,
+        )
+    }
+}
+
+impl CompletionCallback for ThemesCompletion {
+    fn callback(
+        &mut self,
+        _: &Weechat,
+        _: &Buffer,
+        _: Cow<str>,
+        completion: &Completion,
+    ) -> Result<(), ()> {
+        for name in Themes::load().names() {
+            completion.add_with_options(
+                &name,
+                false,
+                CompletionPosition::Sorted,
+            );
+        }
+        Ok(())
+    }
+}
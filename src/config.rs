@@ -24,13 +24,17 @@ use strum_macros::EnumVariantNames;
 use weechat::{
     config,
     config::{
-        Conf, ConfigOption, ConfigSection, ConfigSectionSettings,
-        IntegerOptionSettings, OptionChanged, SectionReadCallback,
+        BooleanOptionSettings, Conf, ConfigOption, ConfigSection,
+        ConfigSectionSettings, IntegerOptionSettings, OptionChanged,
+        SectionReadCallback,
     },
     Weechat,
 };
 
-use crate::{MatrixServer, Servers};
+use crate::{
+    themes::{ThemeValues, THEME_OPTIONS},
+    MatrixServer, Servers,
+};
 
 #[derive(EnumVariantNames)]
 #[strum(serialize_all = "kebab_case")]
@@ -38,6 +42,7 @@ pub enum RedactionStyle {
     StrikeThrough,
     Delete,
     Notice,
+    Bracketed,
 }
 
 impl Default for RedactionStyle {
@@ -52,6 +57,7 @@ impl From<i32> for RedactionStyle {
             0 => RedactionStyle::StrikeThrough,
             1 => RedactionStyle::Delete,
             2 => RedactionStyle::Notice,
+            3 => RedactionStyle::Bracketed,
             _ => unreachable!(),
         }
     }
@@ -82,6 +88,104 @@ impl From<i32> for ServerBuffer {
     }
 }
 
+#[derive(EnumVariantNames)]
+#[strum(serialize_all = "kebab_case")]
+pub enum OversizedMessage {
+    Refuse,
+    Split,
+    Upload,
+}
+
+impl Default for OversizedMessage {
+    fn default() -> Self {
+        OversizedMessage::Refuse
+    }
+}
+
+impl From<i32> for OversizedMessage {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => OversizedMessage::Refuse,
+            1 => OversizedMessage::Split,
+            2 => OversizedMessage::Upload,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(EnumVariantNames)]
+#[strum(serialize_all = "kebab_case")]
+pub enum LongWords {
+    Off,
+    Wrap,
+    Truncate,
+}
+
+impl Default for LongWords {
+    fn default() -> Self {
+        LongWords::Off
+    }
+}
+
+impl From<i32> for LongWords {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => LongWords::Off,
+            1 => LongWords::Wrap,
+            2 => LongWords::Truncate,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(EnumVariantNames)]
+#[strum(serialize_all = "kebab_case")]
+pub enum ReadReceipts {
+    Off,
+    Public,
+    Private,
+}
+
+impl Default for ReadReceipts {
+    fn default() -> Self {
+        ReadReceipts::Off
+    }
+}
+
+impl From<i32> for ReadReceipts {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => ReadReceipts::Off,
+            1 => ReadReceipts::Public,
+            2 => ReadReceipts::Private,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(EnumVariantNames)]
+#[strum(serialize_all = "kebab_case")]
+pub enum RenderProfile {
+    Default,
+    Accessible,
+}
+
+impl Default for RenderProfile {
+    fn default() -> Self {
+        RenderProfile::Default
+    }
+}
+
+impl From<i32> for RenderProfile {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => RenderProfile::Default,
+            1 => RenderProfile::Accessible,
+            _ => unreachable!(),
+        }
+    }
+}
+
 config!(
     "matrix-rust",
 
@@ -95,11 +199,20 @@ config!(
 
         encryption_warning_sign: String {
             // Description.
-            "A sign that is used to show that the current room contains unverified devices",
+            "A sign that is used to show that the current room contains unverified devices, \
+             it's followed by the number of unverified devices",
             // Default value.
             "❗",
         },
 
+        key_backup_warning_sign: String {
+            // Description.
+            "A sign that is used to show that there is a problem with the \
+             key backup of the server",
+            // Default value.
+            "🔑",
+        },
+
         public_room_sign: String {
             // Description.
             "A sign indicating that the current room is public",
@@ -115,6 +228,64 @@ config!(
             "⏳",
         },
 
+        typing_sign: String {
+            // Description.
+            "A sign that is shown in front of the users that are typing in \
+             the current room by the matrix_typing bar item",
+            // Default value.
+            "✏",
+        },
+
+        muted_sign: String {
+            // Description.
+            "A sign that is used to show that the current room is muted",
+            // Default value.
+            "🔕",
+        },
+
+        retention_sign: String {
+            // Description.
+            "A sign indicating that the server deletes the messages of the \
+             current room after a short time",
+            // Default value.
+            "⌛",
+        },
+
+        retention_warning_days: Integer {
+            // Description.
+            "Rooms whose messages are deleted by the server sooner than this \
+             number of days, according to their m.room.retention policy, are \
+             marked with the retention sign and a notice, 0 disables this",
+            // Default value.
+            7,
+            0..3650,
+        },
+
+        room_mention_color: String {
+            // Description.
+            "The color of @room mentions, messages mentioning the whole room \
+             are tagged with matrix_room_mention",
+            // Default value.
+            "lightred",
+        },
+
+        low_priority_room_mentions: bool {
+            // Description.
+            "Should @room mentions highlight in rooms that are tagged as low \
+             priority",
+            // Default value.
+            false,
+        },
+
+        hotrooms_count: Integer {
+            // Description.
+            "The number of recently active rooms the matrix_hotrooms bar \
+             item lists",
+            // Default value.
+            5,
+            1..50,
+        },
+
         local_echo: bool {
             // Description
             "Should the sending message be printed out before the server \
@@ -123,11 +294,211 @@ config!(
              true,
         },
 
+        local_echo_color: String {
+            // Description.
+            "The color the nick and the message of a local echo are shown in \
+             until the server confirms the message, an empty value shows \
+             local echoes like confirmed messages",
+            // Default value.
+            "darkgray",
+        },
+
+        long_words: Enum {
+            // Description.
+            "How words longer than look.max_word_length are shown, e.g. \
+             base64 blobs or minified JSON, wrap breaks them up so the \
+             terminal can wrap them, truncate cuts them off, /matrix source \
+             shows the whole message",
+            LongWords,
+        },
+
+        max_word_length: Integer {
+            // Description.
+            "The number of characters after which a word is considered to be \
+             long, see look.long_words",
+            // Default value.
+            100,
+            10..10000,
+        },
+
+        own_prefix_padding: Integer {
+            // Description.
+            "The number of spaces that are appended to the nick of your own \
+             messages, with weechat.look.prefix_align set to right this \
+             moves your nick to the left so your messages stand apart",
+            // Default value.
+            0,
+            0..20,
+        },
+
+        sent_marker: String {
+            // Description.
+            "A marker that is appended to our own messages once the server \
+             accepted them, an empty value disables this",
+            // Default value.
+            "✓",
+        },
+
+        synced_marker: String {
+            // Description.
+            "A marker that is appended to our own messages once they came \
+             back from the server in a sync response, an empty value \
+             disables this",
+            // Default value.
+            "✓✓",
+        },
+
+        read_marker: String {
+            // Description.
+            "A marker that is appended to our own messages once another \
+             member of the room read them, this needs the read_receipts \
+             option of the server, an empty value disables this",
+            // Default value.
+            "👁",
+        },
+
+        edited_marker: String {
+            // Description.
+            "A marker that is appended to messages that were edited, an \
+             empty value disables this",
+            // Default value.
+            "(edited)",
+        },
+
         redaction_style: Enum {
             // Description
-            "The style that should be used when a message needs to be redacted",
+            "The style that should be used when a message needs to be \
+             redacted, the bracketed style marks the redacted text with \
+             [- and -] instead of striking it through with combining \
+             characters, which breaks copy/paste and screen readers",
             RedactionStyle,
         },
+
+        backfill_time_format: String {
+            // Description.
+            "A strftime format that is prepended to backfilled messages \
+             which weren't sent today, so their date is visible, an empty \
+             value disables this",
+            // Default value.
+            "%Y-%m-%d %H:%M",
+        },
+
+        escape_spoofing_chars: bool {
+            // Description.
+            "Escape bidirectional overrides, zero-width characters and \
+             other characters that can be used to spoof nicks or confuse the \
+             terminal in nicks and messages",
+            // Default value.
+            true,
+        },
+
+        render_profile: Enum {
+            // Description
+            "The rendering profile, the accessible profile is meant for \
+             braille displays and screen readers, it removes colors, \
+             describes the type of messages with words, and renders emoji \
+             as shortcodes",
+            RenderProfile,
+        },
+
+        unread_marker: bool {
+            // Description.
+            "Print a -- unread -- line before the first message that arrives \
+             while you're away from a room buffer, unlike the native read \
+             marker it keeps its place when room history gets sorted in, \
+             restored rooms place the line after the fully read marker of \
+             the account",
+            // Default value.
+            true,
+        },
+
+        send_read_receipts: Enum {
+            // Description
+            "Send a read receipt and move the fully read marker when new \
+             messages are shown in the current room buffer or the buffer \
+             gets focused, private receipts aren't shown to the other \
+             members of the room, /matrix mark-read uses the private kind \
+             as well if it's selected",
+            ReadReceipts,
+        },
+
+        allowed_event_types: String {
+            // Description.
+            "A comma separated list of <room>=<event-type> rules, if a room \
+             has rules in this list only events of the listed types are \
+             rendered in it, the room is a room id, an alias or * for all \
+             rooms, event types can end with a * wildcard, e.g. \
+             #announcements:example.org=m.room.message",
+            // Default value.
+            "",
+        },
+
+        denied_event_types: String {
+            // Description.
+            "A comma separated list of <room>=<event-type> rules, events of \
+             the listed types won't be rendered in the room, the room is a \
+             room id, an alias or * for all rooms, event types can end with \
+             a * wildcard, e.g. #busy:example.org=m.reaction",
+            // Default value.
+            "",
+        },
+
+        ignored_content: String {
+            // Description.
+            "A semicolon separated list of <scope> <regex> rules, messages \
+             and membership changes matching the regex won't be shown, the \
+             scope is * for all rooms, a server name, a room id or an alias, \
+             e.g. #bridge:example.org ^\\[bot\\]",
+            // Default value.
+            "",
+        },
+
+        show_display_name_changes: bool {
+            // Description.
+            "Print a line when a room member changes their display name, \
+             the lines are tagged with matrix_display_name_change",
+            // Default value.
+            true,
+        },
+
+        show_avatar_changes: bool {
+            // Description.
+            "Print a line when a room member changes their avatar, the \
+             lines are tagged with matrix_avatar_change",
+            // Default value.
+            true,
+        },
+
+        smart_filter_delay: Integer {
+            // Description.
+            "The number of minutes a member needs to be quiet before their \
+             join, leave and display name change lines are hidden by the \
+             smart filter, see the smart_filters option",
+            // Default value.
+            5,
+            1..10080,
+        },
+
+        show_thread_replies: bool {
+            // Description.
+            "Print the replies to threads in the room buffer, otherwise \
+             only the number of replies is shown on the line of the message \
+             that started the thread, use /thread to read them",
+            // Default value.
+            false,
+        },
+    },
+
+    Section notifications {
+        notify_command: String {
+            // Description.
+            "A command that is run for highlights and direct messages while \
+             no relay client is attached, e.g. notify-send, the sender and \
+             the message are appended to the command as arguments, an empty \
+             value disables external notifications",
+            // Default value.
+            "",
+        },
     },
 
     Section network {
@@ -137,6 +508,69 @@ config!(
             // Default value.
             false,
         },
+
+        auto_download_max_size: Integer {
+            // Description.
+            "The maximal size in KiB of attachments that are downloaded \
+             into the media cache as soon as they arrive, so opening them \
+             is instant, files of encrypted rooms are stored encrypted, 0 \
+             disables automatic downloads",
+            // Default value.
+            0,
+            0..1048576,
+        },
+
+        auto_download_mimetypes: String {
+            // Description.
+            "A comma separated list of mimetypes of attachments that are \
+             downloaded automatically, types can end with a * wildcard, \
+             e.g. image/*,application/pdf",
+            // Default value.
+            "image/*",
+        },
+
+        auto_download_rooms: String {
+            // Description.
+            "A comma separated list of room ids or aliases whose \
+             attachments are downloaded automatically, * for all rooms",
+            // Default value.
+            "*",
+        },
+
+        media_cache_max_size: Integer {
+            // Description.
+            "The maximal size in MiB of the media cache of a server, the \
+             files that were downloaded first are removed once the cache \
+             grows larger",
+            // Default value.
+            100,
+            1..102400,
+        },
+
+        history_page_size: Integer {
+            // Description.
+            "The number of events that are requested at once when room \
+             history is fetched, e.g. when scrolling up in a room",
+            // Default value.
+            20,
+            1..100,
+        },
+
+        auto_mark_read: bool {
+            // Description.
+            "Mark a room as read when its buffer gets closed",
+            // Default value.
+            false,
+        },
+
+        key_backup_monitoring: bool {
+            // Description.
+            "Periodically check that the server-side key backup exists, is \
+             trusted by our devices and keeps receiving keys, a warning is \
+             printed in the server buffer if it isn't",
+            // Default value.
+            true,
+        },
     },
 
     Section input {
@@ -146,6 +580,148 @@ config!(
             // Default value.
             true,
         },
+
+        oversized_messages: Enum {
+            // Description
+            "What should be done with messages that are too large to be \
+             sent as a single event, they can be refused, split into \
+             multiple messages or uploaded as a text file",
+            OversizedMessage,
+        },
+
+        send_delay: Integer {
+            // Description.
+            "The number of seconds a message is held back before it's sent \
+             out, the message can be cancelled with /matrix undo in the \
+             meantime",
+            // Default value.
+            0,
+            0..60,
+        },
+
+        no_typing_rooms: String {
+            // Description.
+            "A comma separated list of room ids or aliases for which typing \
+             notices should never be sent, e.g. bot or bridge admin rooms",
+            // Default value.
+            "",
+        },
+
+        paste_image_command: String {
+            // Description.
+            "The command that prints the image in the clipboard, it's used \
+             by /matrix paste-image, e.g. xclip -selection clipboard -t \
+             image/png -o",
+            // Default value.
+            "wl-paste --type image/png",
+        },
+
+        media_opener: String {
+            // Description.
+            "The command that opens downloaded media, e.g. audio messages \
+             that are played with /lastmsg play, the path of the file is \
+             appended to the command",
+            // Default value.
+            "xdg-open",
+        },
+
+        command_aliases: String {
+            // Description.
+            "A semicolon separated list of <room> /<alias> <template> \
+             entries, running the alias in the room sends the template as a \
+             message, $1 to $9 get replaced by the arguments of the alias \
+             and $* by all of them, * as the room matches all rooms, e.g. \
+             #ops:example.org /deploy Deploying $1 to production",
+            // Default value.
+            "",
+        },
+
+        quick_reaction: String {
+            // Description.
+            "The reaction that is sent by /lastmsg react if no reaction is \
+             given",
+            // Default value.
+            "👍",
+        },
+
+        reply_key: String {
+            // Description.
+            "The key that starts a reply to the last message in a room, the \
+             key is bound to /lastmsg reply on startup, replacing an existing \
+             binding of it, the default isn't bound by WeeChat itself, an \
+             empty value doesn't bind any key",
+            // Default value.
+            "meta-R",
+        },
+
+        react_key: String {
+            // Description.
+            "The key that reacts to the last message in a room with the \
+             quick reaction, the key is bound to /lastmsg react on startup, \
+             replacing an existing binding of it, the default isn't bound by \
+             WeeChat itself, an empty value doesn't bind any key",
+            // Default value.
+            "meta-T",
+        },
+
+        play_key: String {
+            // Description.
+            "The key that plays the last audio or voice message in a room \
+             using the media opener, e.g. meta-P, the key is bound to \
+             /lastmsg play on startup, replacing an existing binding of it, \
+             an empty value doesn't bind any key",
+            // Default value.
+            "",
+        },
+
+        edit_key: String {
+            // Description.
+            "The key that starts editing your last message in a room, the \
+             key is bound to /lastmsg edit on startup, replacing an existing \
+             binding of it, the default isn't bound by WeeChat itself, an \
+             empty value doesn't bind any key",
+            // Default value.
+            "meta-E",
+        },
+
+        transformations: String {
+            // Description.
+            "A comma separated list of transformations that are applied, in \
+             order, to the text of outgoing messages: emoji replaces \
+             shortcodes like :+1: with their emoji, linkify turns bare URLs \
+             into links, rewrite applies the input.rewrites option, use \
+             /matrix preview to test them",
+            // Default value.
+            "",
+        },
+
+        rewrites: String {
+            // Description.
+            "A semicolon separated list of <regex> <replacement> entries \
+             that are applied by the rewrite transformation, the regex can't \
+             contain spaces, use \\s instead, and $1 in the replacement \
+             refers to the first capture group, e.g. \\bteh\\b the",
+            // Default value.
+            "",
+        },
+
+        spell_dictionaries: String {
+            // Description.
+            "A semicolon separated list of <room> <dictionaries> entries \
+             setting the spell checker dictionaries of room buffers, * as \
+             the room matches all rooms and the first matching entry wins, \
+             e.g. #dev:example.org en; * en,de",
+            // Default value.
+            "",
+        },
+
+        unknown_commands_as_messages: bool {
+            // Description.
+            "Send input that starts with an unknown command as a message \
+             instead of printing an error, e.g. to send a file path",
+            // Default value.
+            false,
+        },
     }
 );
 
@@ -211,11 +787,84 @@ impl ConfigHandle {
             look_section
                 .new_integer_option(settings)
                 .expect("Can't create server buffers option");
+
+            let settings = BooleanOptionSettings::new("smart_filters")
+                .description(
+                    "Hide the join, leave and display name change lines of \
+                     members that didn't send a message recently, like the \
+                     smart filter of the IRC plugin does, the lines are \
+                     tagged with irc_smart_filter and matrix_smart_filter and \
+                     hidden by the matrix_smart WeeChat filter",
+                )
+                .default_value(false)
+                .set_change_callback(|_, option| {
+                    Self::toggle_smart_filter(option.value())
+                });
+
+            look_section
+                .new_boolean_option(settings)
+                .expect("Can't create smart filters option");
+
+            let servers = servers.clone();
+
+            let settings = BooleanOptionSettings::new("retention_compliance")
+                .description(
+                    "Don't keep messages of rooms with a m.room.retention \
+                     policy on the disk, the logger is disabled for them and \
+                     their messages aren't stored in the outbox, the media \
+                     cache or the sync log, none of which can expire old \
+                     content once the retention window of the room passed",
+                )
+                .default_value(false)
+                .set_change_callback(move |_, _| {
+                    for server in servers.borrow().values() {
+                        for room in server.rooms() {
+                            room.apply_retention_compliance();
+                        }
+                    }
+                });
+
+            look_section
+                .new_boolean_option(settings)
+                .expect("Can't create retention compliance option");
         }
 
         config
     }
 
+    /// Add or remove the WeeChat filter that hides the lines tagged by the
+    /// smart filter in our buffers.
+    fn toggle_smart_filter(enabled: bool) {
+        let command = if enabled {
+            "/mute /filter add matrix_smart matrix.* irc_smart_filter *"
+        } else {
+            "/mute /filter del matrix_smart"
+        };
+
+        let _ = Weechat::current_buffer().run_command(command);
+    }
+
+    /// Set the look options to the values of a theme.
+    ///
+    /// Returns the names of the options whose value was rejected.
+    pub fn apply_theme(&self, values: &ThemeValues) -> Vec<String> {
+        let config = self.borrow();
+        let look = config.look();
+
+        values
+            .iter()
+            .filter(|(name, value)| {
+                !THEME_OPTIONS.contains(&name.as_str())
+                    || matches!(
+                        look.search_option(name).map(|o| o.set(value, true)),
+                        None | Some(OptionChanged::NotFound)
+                            | Some(OptionChanged::Error)
+                    )
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
     pub fn borrow(&self) -> Ref<'_, Config> {
         self.inner.borrow()
     }
@@ -235,6 +884,56 @@ impl<'a> LookSection<'a> {
             panic!("Server buffer option has the wrong type");
         }
     }
+
+    /// Get the current values of the options that are part of a theme.
+    pub fn theme_values(&self) -> ThemeValues {
+        THEME_OPTIONS
+            .iter()
+            .filter_map(|name| {
+                let value = match self.search_option(name)? {
+                    ConfigOption::Boolean(o) => {
+                        if o.value() { "on" } else { "off" }.to_owned()
+                    }
+                    ConfigOption::Integer(o) => {
+                        let variants = match *name {
+                            "redaction_style" => RedactionStyle::VARIANTS,
+                            "render_profile" => RenderProfile::VARIANTS,
+                            _ => &[],
+                        };
+
+                        variants
+                            .get(o.value() as usize)
+                            .map(|v| v.to_string())
+                            .unwrap_or_else(|| o.value().to_string())
+                    }
+                    ConfigOption::String(o) => o.value().to_string(),
+                    _ => return None,
+                };
+
+                Some((name.to_string(), value))
+            })
+            .collect()
+    }
+
+    pub fn retention_compliance(&self) -> bool {
+        if let ConfigOption::Boolean(o) =
+            self.search_option("retention_compliance").unwrap()
+        {
+            o.value()
+        } else {
+            panic!("Retention compliance option has the wrong type");
+        }
+    }
+
+    pub fn smart_filters(&self) -> bool {
+        if let ConfigOption::Boolean(o) =
+            self.search_option("smart_filters").unwrap()
+        {
+            o.value()
+        } else {
+            panic!("Smart filters option has the wrong type");
+        }
+    }
 }
 
 impl SectionReadCallback for ConfigHandle {
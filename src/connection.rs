@@ -1,80 +1,308 @@
 use std::{
+    cell::Cell,
+    collections::BTreeSet,
+    fmt,
     future::Future,
+    io::{Cursor, Read},
     path::PathBuf,
     rc::{Rc, Weak},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use tokio::{
-    runtime::Runtime,
-    sync::mpsc::{channel, Receiver, Sender},
+    runtime::Handle,
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+    task::JoinHandle,
 };
 
+use mime::Mime;
+use serde_json::json;
 use tracing::error;
 
 use matrix_sdk::{
     self,
+    attachment::AttachmentConfig,
     config::SyncSettings,
+    crypto::AttachmentEncryptor,
     deserialized_responses::AmbiguityChange,
+    encryption::verification::{SasVerification, Verification},
+    media::{MediaFormat, MediaRequest},
     room::{Joined, Messages, MessagesOptions},
     ruma::{
         api::client::{
+            account::whoami::v3::Request as WhoamiRequest,
+            alias::get_alias::v3::Response as GetAliasResponse,
+            backup::{
+                get_backup_keys::v3::{
+                    Request as BackupKeysRequest,
+                    Response as BackupKeysResponse,
+                },
+                get_latest_backup_info::v3::{
+                    Request as KeyBackupRequest, Response as KeyBackupResponse,
+                },
+            },
+            config::set_room_account_data::v3::Request as SetRoomAccountDataRequest,
             device::{
                 delete_devices::v3::Response as DeleteDevicesResponse,
                 get_devices::v3::Response as DevicesResponse,
             },
+            error::ErrorKind,
             filter::{
-                FilterDefinition, LazyLoadOptions, RoomEventFilter, RoomFilter,
+                Filter, FilterDefinition, LazyLoadOptions, RoomEventFilter,
+                RoomFilter,
             },
+            media::get_media_config::v3::Request as MediaConfigRequest,
+            membership::unban_user::v3::Request as UnbanRequest,
             message::send_message_event::v3::Response as RoomSendResponse,
+            push::{
+                delete_pushrule::v3::Request as DeletePushRuleRequest,
+                set_pushrule::v3::Request as SetPushRuleRequest, RuleKind,
+                RuleScope,
+            },
+            receipt::create_receipt::v3::{
+                ReceiptType as CreateReceiptType,
+                Request as CreateReceiptRequest,
+            },
+            relations::get_relating_events_with_rel_type::v1::Request as RelatingEventsRequest,
+            room::create_room::v3::{Request as CreateRoomRequest, RoomPreset},
             session::login::v3::Response as LoginResponse,
-            sync::sync_events::v3::Filter,
-            uiaa::{AuthData, Password, UserIdentifier},
+            sync::sync_events::v3::Filter as SyncFilter,
+            uiaa::{
+                AuthData, FallbackAcknowledgement, Password, UserIdentifier,
+            },
         },
         events::{
-            room::member::RoomMemberEventContent, AnyMessageLikeEventContent,
-            AnySyncStateEvent, AnySyncTimelineEvent, SyncStateEvent,
+            receipt::{ReceiptEventContent, ReceiptType},
+            relation::RelationType,
+            room::{
+                member::RoomMemberEventContent, EncryptedFile,
+                EncryptedFileInit, MediaSource,
+            },
+            AnyMessageLikeEventContent, AnySyncEphemeralRoomEvent,
+            AnySyncMessageLikeEvent, AnySyncStateEvent, AnySyncTimelineEvent,
+            AnyTimelineEvent, EventContent, SyncStateEvent,
         },
-        OwnedDeviceId, OwnedRoomId, OwnedTransactionId,
+        presence::PresenceState,
+        push::{Action, PushCondition, Ruleset},
+        serde::Raw,
+        OwnedDeviceId, OwnedEventId, OwnedRoomAliasId, OwnedRoomId,
+        OwnedServerName, OwnedTransactionId, OwnedUserId, RoomId,
+        RoomOrAliasId, UserId,
     },
-    Client, LoopCtrl, Result as MatrixResult,
+    Client, HttpError, LoopCtrl, Result as MatrixResult,
 };
 
 use weechat::{Task, Weechat};
 
 use crate::{
-    room::PrevBatch,
-    server::{InnerServer, MatrixServer},
+    mentions::{Mentions, MENTIONS_KEY},
+    push_rules::{self, PushOutcome, RoomNotificationMode},
+    room::{PrevBatch, RoomSettings, ROOM_SETTINGS_EVENT_TYPE},
+    server::{InnerServer, MatrixServer, ServerSettings},
+    sync_log::SyncLog,
 };
 
 const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
 
-pub struct InteractiveAuthInfo {
-    pub user: String,
-    pub password: String,
-    pub session: Option<String>,
+/// How long we wait for the other device during an interactive verification.
+const VERIFICATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How often the state of an interactive verification is checked, the
+/// verification events are processed by the sync loop.
+const VERIFICATION_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The number of messages that can wait in the channel between the sync loop
+/// and the response receiver.
+const SYNC_CHANNEL_CAPACITY: usize = 10_000;
+
+pub enum InteractiveAuthInfo {
+    /// Authenticate using our password.
+    Password {
+        user: String,
+        password: String,
+        session: Option<String>,
+    },
+    /// The user completed a stage using the fallback page of the server.
+    Fallback { session: String },
 }
 
 impl InteractiveAuthInfo {
     pub fn as_auth_data(&self) -> AuthData<'_> {
-        AuthData::Password(Password::new(
-            UserIdentifier::UserIdOrLocalpart(&self.user),
-            &self.password,
-        ))
+        match self {
+            InteractiveAuthInfo::Password {
+                user,
+                password,
+                session,
+            } => {
+                let mut auth = Password::new(
+                    UserIdentifier::UserIdOrLocalpart(user),
+                    password,
+                );
+                auth.session = session.as_deref();
+
+                AuthData::Password(auth)
+            }
+            InteractiveAuthInfo::Fallback { session } => {
+                AuthData::FallbackAcknowledgement(FallbackAcknowledgement::new(
+                    session,
+                ))
+            }
+        }
+    }
+}
+
+/// The kind of an error that stopped the sync loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientErrorKind {
+    /// Reading or writing the files of the server failed.
+    Io,
+    /// The server rejected our username or password.
+    BadCredentials,
+    /// Our access token isn't valid anymore, e.g. because the device got
+    /// logged out.
+    UnknownToken,
+    /// Our access token isn't valid anymore but the device and its
+    /// encryption keys are kept, logging in again with the same device
+    /// resumes the session.
+    SoftLogout,
+    /// We sent too many requests.
+    RateLimited,
+    /// The server couldn't be reached or didn't answer properly.
+    Network,
+    /// Any other error.
+    Other,
+}
+
+/// An error that stopped the sync loop.
+#[derive(Clone, Debug)]
+pub struct ClientError {
+    pub kind: ClientErrorKind,
+    /// Can reconnecting fix the error without any action of the user.
+    pub retryable: bool,
+    /// The HTTP status code of the failed request, if the server answered it.
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+impl ClientError {
+    fn io(message: String) -> Self {
+        Self {
+            kind: ClientErrorKind::Io,
+            retryable: false,
+            status: None,
+            message,
+        }
+    }
+
+    /// Classify an error of the SDK.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - A description of what failed.
+    ///
+    /// * `error` - The error of the SDK.
+    ///
+    /// * `login` - Did the error happen while logging in, a forbidden login
+    /// means that the credentials are wrong.
+    fn from_sdk(context: &str, error: &matrix_sdk::Error, login: bool) -> Self {
+        let api_error = error.as_client_api_error();
+        let status = api_error.map(|e| e.status_code.as_u16());
+
+        let kind = match api_error.map(|e| &e.kind) {
+            Some(ErrorKind::Forbidden) if login => {
+                ClientErrorKind::BadCredentials
+            }
+            Some(ErrorKind::UnknownToken { soft_logout: true }) => {
+                ClientErrorKind::SoftLogout
+            }
+            Some(ErrorKind::UnknownToken { .. }) => {
+                ClientErrorKind::UnknownToken
+            }
+            Some(ErrorKind::LimitExceeded { .. }) => {
+                ClientErrorKind::RateLimited
+            }
+            Some(_) if status.map_or(false, |s| s >= 500) => {
+                ClientErrorKind::Network
+            }
+            Some(_) => ClientErrorKind::Other,
+            // The request didn't reach the server or we didn't get a
+            // response.
+            None if matches!(
+                error,
+                matrix_sdk::Error::Http(HttpError::Reqwest(_))
+            ) =>
+            {
+                ClientErrorKind::Network
+            }
+            None => ClientErrorKind::Other,
+        };
+
+        Self {
+            kind,
+            retryable: matches!(
+                kind,
+                ClientErrorKind::RateLimited | ClientErrorKind::Network
+            ),
+            status,
+            message: format!("{}: {}", context, error),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{} (HTTP {})", self.message, status),
+            None => write!(f, "{}", self.message),
+        }
     }
 }
 
 pub enum ClientMessage {
     LoginMessage(LoginResponse),
     SyncState(OwnedRoomId, AnySyncStateEvent),
-    SyncEvent(OwnedRoomId, AnySyncTimelineEvent),
+    PushRules(Ruleset),
+    SyncEvent(
+        OwnedRoomId,
+        AnySyncTimelineEvent,
+        Option<Mentions>,
+        Option<PushOutcome>,
+    ),
     MemberEvent(
         OwnedRoomId,
         SyncStateEvent<RoomMemberEventContent>,
         bool,
         Option<AmbiguityChange>,
     ),
+    /// The member state events of a room, coalesced into a single message.
+    MemberEvents(
+        OwnedRoomId,
+        Vec<(
+            SyncStateEvent<RoomMemberEventContent>,
+            Option<AmbiguityChange>,
+        )>,
+    ),
+    /// The plugin settings of a room changed.
+    RoomSettings(OwnedRoomId, RoomSettings),
+    /// Messages of the given rooms had to be dropped since the channel was
+    /// full, the rooms need to be resynced.
+    Resync(Vec<OwnedRoomId>),
     RestoredRoom(Joined),
+    TypingUsers(OwnedRoomId, Vec<OwnedUserId>),
+    /// Other members of the room read the given events.
+    ReadReceipts(OwnedRoomId, Vec<OwnedEventId>),
+    /// One of our sessions read the room up to the given event.
+    OwnReadReceipt(OwnedRoomId, OwnedEventId),
+    CustomEvent(OwnedRoomId, Raw<AnySyncTimelineEvent>),
+    /// The device lists of some users changed.
+    DeviceListsChanged,
+    /// The first sync of the connection finished, the flag tells if this
+    /// was the first login of our device.
+    InitialSync(bool),
 }
 
 /// Struct representing an active connection to the homeserver.
@@ -82,7 +310,7 @@ pub enum ClientMessage {
 /// Since the rust-sdk `Client` object uses reqwest for the HTTP client making
 /// requests requires the request to be made on a tokio runtime. The connection
 /// wraps the `Client` object and makes sure that requests are run on the
-/// runtime the `Connection` holds.
+/// runtime of the plugin, which is shared between all the servers.
 ///
 /// While this struct is alive a sync loop will be going on. To cancel the sync
 /// loop drop the object.
@@ -90,8 +318,41 @@ pub enum ClientMessage {
 pub struct Connection {
     #[allow(dead_code)]
     receiver_task: Rc<Task<()>>,
+    #[allow(dead_code)]
+    sync_task: Rc<SyncTask>,
     client: Client,
-    pub runtime: Rc<Runtime>,
+    runtime: Handle,
+    stats: Arc<SyncChannelStats>,
+    /// The maximal size of uploads the homeserver accepts, fetched on the
+    /// first upload.
+    max_upload_size: Rc<Cell<Option<u64>>>,
+}
+
+/// Statistics about the channel between the sync loop and the response
+/// receiver.
+#[derive(Debug, Default)]
+pub struct SyncChannelStats {
+    /// The highest number of messages that were waiting in the channel at
+    /// once.
+    pub max_queued: AtomicUsize,
+    /// The number of member events that were coalesced into batches.
+    pub coalesced_member_events: AtomicUsize,
+    /// The number of messages that were dropped since the channel was full.
+    pub dropped_messages: AtomicUsize,
+    /// The number of times a room was resynced after its messages were
+    /// dropped.
+    pub resynced_rooms: AtomicUsize,
+}
+
+/// The sync loop of a connection running on the shared runtime, the loop is
+/// aborted once this is dropped.
+#[derive(Debug)]
+struct SyncTask(JoinHandle<()>);
+
+impl Drop for SyncTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
 impl Connection {
@@ -110,8 +371,13 @@ impl Connection {
             .expect("Tokio error while sending a message")
     }
 
-    pub fn new(server: &MatrixServer, client: &Client) -> Self {
-        let (tx, rx) = channel(10_000);
+    pub fn new(
+        server: &MatrixServer,
+        client: &Client,
+        runtime: Handle,
+    ) -> Self {
+        let (tx, rx) = channel(SYNC_CHANNEL_CAPACITY);
+        let stats = Arc::new(SyncChannelStats::default());
 
         let server_name = server.name();
 
@@ -120,24 +386,55 @@ impl Connection {
             server.clone_weak(),
         ));
 
-        let runtime = Runtime::new().unwrap();
-
-        runtime.spawn(Connection::sync_loop(
+        let sync_task = runtime.spawn(Connection::sync_loop(
             client.clone(),
             tx,
-            server.user_name(),
-            server.password(),
+            server.settings(),
             server_name.to_string(),
             server.get_server_path(),
+            server.sync_log().clone(),
+            stats.clone(),
+            server.take_soft_logout(),
         ));
 
         Self {
             client: client.clone(),
-            runtime: runtime.into(),
+            runtime,
             receiver_task: receiver_task.into(),
+            sync_task: SyncTask(sync_task).into(),
+            stats,
+            max_upload_size: Rc::new(Cell::new(None)),
         }
     }
 
+    /// Get the statistics of the channel between the sync loop and the
+    /// response receiver.
+    pub fn stats(&self) -> &SyncChannelStats {
+        &self.stats
+    }
+
+    /// Get the maximal size of uploads the homeserver accepts, in bytes.
+    ///
+    /// The limit is fetched from the media config of the homeserver once
+    /// and cached afterwards.
+    pub async fn max_upload_size(&self) -> MatrixResult<u64> {
+        if let Some(size) = self.max_upload_size.get() {
+            return Ok(size);
+        }
+
+        let client = self.client.clone();
+        let response =
+            self.spawn(async move {
+                client.send(MediaConfigRequest::new(), None).await
+            })
+            .await?;
+
+        let size = u64::from(response.upload_size);
+        self.max_upload_size.set(Some(size));
+
+        Ok(size)
+    }
+
     /// Send a message to the given room.
     ///
     /// # Arguments
@@ -149,14 +446,354 @@ impl Connection {
     ///
     /// * `transaction_id` - Attach an unique id to this message, later on the
     /// event will contain the same id in the unsigned part of the event.
+    ///
+    /// * `mentions` - The users, or the whole room, the message intentionally
+    /// mentions.
     pub async fn send_message(
         &self,
         room: Joined,
         content: AnyMessageLikeEventContent,
         transaction_id: Option<OwnedTransactionId>,
+        mentions: Option<Mentions>,
+    ) -> MatrixResult<RoomSendResponse> {
+        self.spawn(async move {
+            if let Some(mentions) = mentions {
+                let event_type = content.event_type().to_string();
+                let mut content = serde_json::to_value(&content)?;
+                content[MENTIONS_KEY] = mentions.to_json();
+
+                room.send_raw(content, &event_type, transaction_id.as_deref())
+                    .await
+            } else {
+                room.send(content, transaction_id.as_deref()).await
+            }
+        })
+        .await
+    }
+
+    /// Upload the given data and send it to the given room as an attachment.
+    ///
+    /// # Arguments
+    ///
+    /// * `room` - The room which the attachment should be sent to.
+    ///
+    /// * `body` - The body of the attachment event, usually the file name.
+    ///
+    /// * `content_type` - The media type of the attachment.
+    ///
+    /// * `data` - The raw content of the attachment.
+    pub async fn send_attachment(
+        &self,
+        room: Joined,
+        body: String,
+        content_type: Mime,
+        data: Vec<u8>,
     ) -> MatrixResult<RoomSendResponse> {
         self.spawn(async move {
-            room.send(content, transaction_id.as_deref()).await
+            room.send_attachment(
+                &body,
+                &content_type,
+                data,
+                AttachmentConfig::new(),
+            )
+            .await
+        })
+        .await
+    }
+
+    /// Upload an Ogg Opus file and send it to the given room as a voice
+    /// message.
+    ///
+    /// Voice messages are audio messages carrying the MSC3245 voice flag and
+    /// the MSC1767 audio metadata, clients use these to show the waveform of
+    /// the recording. The file gets encrypted if the room is encrypted.
+    pub async fn send_voice_message(
+        &self,
+        room: Joined,
+        body: String,
+        data: Vec<u8>,
+        duration: Duration,
+        waveform: Vec<u16>,
+    ) -> MatrixResult<RoomSendResponse> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            let duration = duration.as_millis() as u64;
+
+            let mut content = json!({
+                "msgtype": "m.audio",
+                "body": body,
+                "info": {
+                    "mimetype": "audio/ogg",
+                    "size": data.len(),
+                    "duration": duration,
+                },
+                "org.matrix.msc1767.audio": {
+                    "duration": duration,
+                    "waveform": waveform,
+                },
+                "org.matrix.msc3245.voice": {},
+            });
+
+            if room.is_encrypted() {
+                let mut reader = Cursor::new(data);
+                let mut encryptor = AttachmentEncryptor::new(&mut reader);
+                let mut encrypted = Vec::new();
+                encryptor.read_to_end(&mut encrypted)?;
+                let info = encryptor.finish();
+
+                let response = client
+                    .media()
+                    .upload(&mime::APPLICATION_OCTET_STREAM, encrypted)
+                    .await?;
+
+                let file: EncryptedFile = EncryptedFileInit {
+                    url: response.content_uri,
+                    key: info.web_key,
+                    iv: info.iv,
+                    hashes: info.hashes,
+                    v: info.version,
+                }
+                .into();
+
+                content["file"] = serde_json::to_value(file)?;
+            } else {
+                let content_type: Mime =
+                    "audio/ogg".parse().expect("Invalid media type");
+                let response =
+                    client.media().upload(&content_type, data).await?;
+
+                content["url"] = json!(response.content_uri);
+            }
+
+            room.send_raw(content, "m.room.message", None).await
+        })
+        .await
+    }
+
+    /// Download the content of the given media, encrypted media gets
+    /// decrypted.
+    pub async fn download_media(
+        &self,
+        source: MediaSource,
+    ) -> MatrixResult<Vec<u8>> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            let request = MediaRequest {
+                source,
+                format: MediaFormat::File,
+            };
+
+            client.media().get_media_content(&request, true).await
+        })
+        .await
+    }
+
+    /// Move our read receipt and fully read marker to the given event.
+    ///
+    /// A private read receipt is only visible to our own sessions, the other
+    /// members of the room don't see how far we read.
+    pub async fn mark_read(
+        &self,
+        room: Joined,
+        event_id: OwnedEventId,
+        private: bool,
+    ) -> MatrixResult<()> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            if !private {
+                return room.read_marker(&event_id, Some(&event_id)).await;
+            }
+
+            room.read_marker(&event_id, None).await?;
+
+            let request = CreateReceiptRequest::new(
+                room.room_id(),
+                CreateReceiptType::ReadPrivate,
+                &event_id,
+            );
+            client.send(request, None).await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Move our fully read marker to the given event, our read receipt stays
+    /// where it is.
+    pub async fn set_fully_read(
+        &self,
+        room: Joined,
+        event_id: OwnedEventId,
+    ) -> MatrixResult<()> {
+        self.spawn(async move { room.read_marker(&event_id, None).await })
+            .await
+    }
+
+    /// Invite the given user to the given room.
+    pub async fn invite_user(
+        &self,
+        room: Joined,
+        user_id: OwnedUserId,
+    ) -> MatrixResult<()> {
+        self.spawn(async move { room.invite_user_by_id(&user_id).await })
+            .await
+    }
+
+    /// Lift the ban of the given user in the given room.
+    pub async fn unban_user(
+        &self,
+        room: Joined,
+        user_id: OwnedUserId,
+        reason: Option<String>,
+    ) -> MatrixResult<()> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            let mut request = UnbanRequest::new(room.room_id(), &user_id);
+            request.reason = reason.as_deref();
+
+            client.send(request, None).await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Change how the push rules of the account notify about the messages of
+    /// the given room.
+    ///
+    /// The rules of the other modes are removed, so the room ends up with at
+    /// most a single rule.
+    pub async fn set_room_notification_mode(
+        &self,
+        room_id: OwnedRoomId,
+        mode: RoomNotificationMode,
+    ) -> MatrixResult<()> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            let stale_kinds = match mode {
+                RoomNotificationMode::All => {
+                    vec![RuleKind::Override, RuleKind::Room]
+                }
+                RoomNotificationMode::Mentions => vec![RuleKind::Override],
+                RoomNotificationMode::Mute => vec![RuleKind::Room],
+            };
+
+            for kind in stale_kinds {
+                let request = DeletePushRuleRequest::new(
+                    RuleScope::Global,
+                    kind,
+                    room_id.as_str(),
+                );
+
+                match client.send(request, None).await {
+                    Ok(_) => (),
+                    Err(e)
+                        if e.client_api_error_kind()
+                            == Some(&ErrorKind::NotFound) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            let actions = [Action::DontNotify];
+            let conditions = [PushCondition::EventMatch {
+                key: "room_id".to_owned(),
+                pattern: room_id.to_string(),
+            }];
+
+            let request = match mode {
+                RoomNotificationMode::All => return Ok(()),
+                RoomNotificationMode::Mentions => SetPushRuleRequest::new(
+                    RuleScope::Global,
+                    RuleKind::Room,
+                    room_id.as_str(),
+                    &actions,
+                ),
+                RoomNotificationMode::Mute => {
+                    let mut request = SetPushRuleRequest::new(
+                        RuleScope::Global,
+                        RuleKind::Override,
+                        room_id.as_str(),
+                        &actions,
+                    );
+                    request.conditions = &conditions;
+                    request
+                }
+            };
+
+            client.send(request, None).await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Join the given room, the servers are asked to let us in if our
+    /// homeserver isn't part of the room.
+    pub async fn join_room(
+        &self,
+        room_id: OwnedRoomId,
+        servers: Vec<OwnedServerName>,
+    ) -> MatrixResult<()> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            client
+                .join_room_by_id_or_alias(
+                    <&RoomOrAliasId>::from(&*room_id),
+                    &servers,
+                )
+                .await
+                .map(|_| ())
+        })
+        .await
+    }
+
+    /// Create a private room marked as a direct chat and invite the given
+    /// user to it.
+    pub async fn create_direct_room(
+        &self,
+        user_id: OwnedUserId,
+    ) -> MatrixResult<OwnedRoomId> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            let invite = [user_id];
+            let mut request = CreateRoomRequest::new();
+            request.invite = &invite;
+            request.is_direct = true;
+            request.preset = Some(RoomPreset::TrustedPrivateChat);
+
+            Ok(client.create_room(request).await?.room_id)
+        })
+        .await
+    }
+
+    /// Ask the homeserver which room the given alias points to.
+    pub async fn resolve_room_alias(
+        &self,
+        alias: OwnedRoomAliasId,
+    ) -> MatrixResult<GetAliasResponse> {
+        let client = self.client.clone();
+        Ok(self
+            .spawn(async move { client.resolve_room_alias(&alias).await })
+            .await?)
+    }
+
+    /// Set the human readable name of one of our devices.
+    pub async fn rename_device(
+        &self,
+        device_id: OwnedDeviceId,
+        name: String,
+    ) -> MatrixResult<()> {
+        let client = self.client.clone();
+        self.spawn(async move {
+            client.rename_device(&device_id, &name).await?;
+            Ok(())
         })
         .await
     }
@@ -184,10 +821,11 @@ impl Connection {
         &self,
         room: Joined,
         prev_batch: PrevBatch,
+        limit: u32,
     ) -> MatrixResult<Messages> {
         Ok(self
             .spawn(async move {
-                let request = match &prev_batch {
+                let mut request = match &prev_batch {
                     PrevBatch::Backwards(t) => {
                         MessagesOptions::backward().from(Some(t.as_ref()))
                     }
@@ -195,18 +833,333 @@ impl Connection {
                         MessagesOptions::forward().from(Some(t.as_ref()))
                     }
                 };
+                request.limit = limit.into();
 
                 room.messages(request).await
             })
             .await?)
     }
 
+    /// Fetch a page of room history going backwards from the given token,
+    /// only events of the given types are returned.
+    pub async fn filtered_room_messages(
+        &self,
+        room: Joined,
+        from: String,
+        limit: u32,
+        types: &'static [&'static str],
+    ) -> MatrixResult<Messages> {
+        Ok(self
+            .spawn(async move {
+                let types: Vec<String> =
+                    types.iter().map(|t| t.to_string()).collect();
+
+                let mut request =
+                    MessagesOptions::backward().from(Some(from.as_str()));
+                request.limit = limit.into();
+                request.filter.types = Some(&types);
+
+                room.messages(request).await
+            })
+            .await?)
+    }
+
+    /// Fetch the latest events that relate to the given event with the given
+    /// relation type, newest first.
+    ///
+    /// Encrypted events are fetched again one by one, this way they get
+    /// decrypted.
+    pub async fn relating_events(
+        &self,
+        room: Joined,
+        event_id: OwnedEventId,
+        relation_type: RelationType,
+        limit: u32,
+    ) -> MatrixResult<Vec<Raw<AnyTimelineEvent>>> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            let mut request = RelatingEventsRequest::new(
+                room.room_id(),
+                &event_id,
+                relation_type,
+            );
+            request.limit = Some(limit.into());
+
+            let response = client.send(request, None).await?;
+            let mut events = Vec::new();
+
+            for event in response.chunk {
+                let encrypted = event.get_field::<String>("type")?.as_deref()
+                    == Some("m.room.encrypted");
+
+                match event.get_field::<OwnedEventId>("event_id")? {
+                    Some(event_id) if encrypted => {
+                        events.push(room.event(&event_id).await?.event)
+                    }
+                    _ => events.push(event.cast()),
+                }
+            }
+
+            Ok(events)
+        })
+        .await
+    }
+
+    /// Measure the round trip time of a minimal authenticated request to the
+    /// homeserver.
+    pub async fn ping(&self) -> MatrixResult<Duration> {
+        let client = self.client.clone();
+        Ok(self
+            .spawn(async move {
+                let start = Instant::now();
+
+                client
+                    .send(WhoamiRequest::new(), None)
+                    .await
+                    .map(|_| start.elapsed())
+            })
+            .await?)
+    }
+
     /// Get the list of our own devices.
     pub async fn devices(&self) -> MatrixResult<DevicesResponse> {
         let client = self.client.clone();
         Ok(self.spawn(async move { client.devices().await }).await?)
     }
 
+    /// Get the info of the latest version of the server-side key backup.
+    ///
+    /// Returns `None` if there is no key backup.
+    pub async fn key_backup(&self) -> MatrixResult<Option<KeyBackupResponse>> {
+        let client = self.client.clone();
+        Ok(self
+            .spawn(async move {
+                match client.send(KeyBackupRequest::new(), None).await {
+                    Ok(response) => Ok(Some(response)),
+                    Err(e)
+                        if e.client_api_error_kind()
+                            == Some(&ErrorKind::NotFound) =>
+                    {
+                        Ok(None)
+                    }
+                    Err(e) => Err(e),
+                }
+            })
+            .await?)
+    }
+
+    /// Get all the room keys that are stored in the given version of the
+    /// server-side key backup.
+    pub async fn backed_up_room_keys(
+        &self,
+        version: String,
+    ) -> MatrixResult<BackupKeysResponse> {
+        let client = self.client.clone();
+        Ok(self
+            .spawn(async move {
+                client.send(BackupKeysRequest::new(&version), None).await
+            })
+            .await?)
+    }
+
+    /// Request the verification of one of our own devices.
+    ///
+    /// Waits until the other device accepted the request and the emojis of
+    /// the verification can be compared.
+    ///
+    /// Returns `None` if the device is unknown, or if the request was
+    /// cancelled or not answered in time.
+    pub async fn request_device_verification(
+        &self,
+        device_id: OwnedDeviceId,
+    ) -> MatrixResult<Option<SasVerification>> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            let user_id = client
+                .user_id()
+                .map(ToOwned::to_owned)
+                .ok_or(matrix_sdk::Error::AuthenticationRequired)?;
+
+            let device = if let Some(d) =
+                client.encryption().get_device(&user_id, &device_id).await?
+            {
+                d
+            } else {
+                return Ok(None);
+            };
+
+            let request = device.request_verification().await?;
+            let flow_id = request.flow_id().to_owned();
+            let deadline = Instant::now() + VERIFICATION_TIMEOUT;
+
+            // Either device may start the emoji verification once the request
+            // was accepted.
+            let sas = loop {
+                if request.is_cancelled() || Instant::now() > deadline {
+                    return Ok(None);
+                }
+
+                if let Some(Verification::SasV1(sas)) = client
+                    .encryption()
+                    .get_verification(&user_id, &flow_id)
+                    .await
+                {
+                    if !sas.we_started() {
+                        sas.accept().await?;
+                    }
+
+                    break sas;
+                } else if request.is_ready() {
+                    if let Some(sas) = request.start_sas().await? {
+                        break sas;
+                    }
+                }
+
+                tokio::time::sleep(VERIFICATION_POLL_INTERVAL).await;
+            };
+
+            while !sas.can_be_presented() {
+                if sas.is_cancelled() || Instant::now() > deadline {
+                    return Ok(None);
+                }
+
+                tokio::time::sleep(VERIFICATION_POLL_INTERVAL).await;
+            }
+
+            Ok(Some(sas))
+        })
+        .await
+    }
+
+    /// Confirm that the emojis of the given verification match, or cancel the
+    /// verification if they don't.
+    ///
+    /// Returns true once the other device confirmed the verification as well,
+    /// false if the verification was cancelled or timed out.
+    pub async fn finish_verification(
+        &self,
+        sas: SasVerification,
+        matches: bool,
+    ) -> MatrixResult<bool> {
+        self.spawn(async move {
+            if !matches {
+                sas.mismatch().await?;
+                return Ok(false);
+            }
+
+            sas.confirm().await?;
+
+            let deadline = Instant::now() + VERIFICATION_TIMEOUT;
+
+            while !sas.is_done() {
+                if sas.is_cancelled() || Instant::now() > deadline {
+                    return Ok(false);
+                }
+
+                tokio::time::sleep(VERIFICATION_POLL_INTERVAL).await;
+            }
+
+            Ok(true)
+        })
+        .await
+    }
+
+    /// Accept or cancel the verification request with the given flow id.
+    ///
+    /// Returns false if the SDK doesn't know about the request, e.g. because
+    /// it timed out.
+    pub async fn answer_verification_request(
+        &self,
+        user_id: OwnedUserId,
+        flow_id: OwnedEventId,
+        accept: bool,
+    ) -> MatrixResult<bool> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            let request = if let Some(r) = client
+                .encryption()
+                .get_verification_request(&user_id, flow_id.as_str())
+                .await
+            {
+                r
+            } else {
+                return Ok(false);
+            };
+
+            if accept {
+                request.accept().await?;
+            } else {
+                request.cancel().await?;
+            }
+
+            Ok(true)
+        })
+        .await
+    }
+
+    /// Send a state event with the given type and content, and an empty
+    /// state key, to the given room.
+    pub async fn send_state_event(
+        &self,
+        room: Joined,
+        event_type: &'static str,
+        content: serde_json::Value,
+    ) -> MatrixResult<OwnedEventId> {
+        self.spawn(async move {
+            room.send_state_event_raw(content, event_type, "")
+                .await
+                .map(|r| r.event_id)
+        })
+        .await
+    }
+
+    /// Redact the given event in the given room.
+    pub async fn redact_event(
+        &self,
+        room: Joined,
+        event_id: OwnedEventId,
+        reason: Option<String>,
+    ) -> MatrixResult<()> {
+        self.spawn(async move {
+            room.redact(&event_id, reason.as_deref(), None)
+                .await
+                .map(|_| ())
+        })
+        .await
+    }
+
+    /// Store custom account data of the given type for the given room.
+    pub async fn set_room_account_data(
+        &self,
+        room: Joined,
+        event_type: &str,
+        content: serde_json::Value,
+    ) -> MatrixResult<()> {
+        let client = self.client.clone();
+        let event_type = event_type.to_owned();
+
+        self.spawn(async move {
+            let user_id = client
+                .user_id()
+                .ok_or(matrix_sdk::Error::AuthenticationRequired)?;
+
+            let request = SetRoomAccountDataRequest::new_raw(
+                Raw::new(&content)?.cast(),
+                event_type.into(),
+                room.room_id(),
+                user_id,
+            );
+
+            client.send(request, None).await?;
+
+            Ok(())
+        })
+        .await
+    }
+
     /// Set or reset a typing notice.
     ///
     /// # Arguments
@@ -264,7 +1217,7 @@ impl Connection {
     /// This runs on the main Weechat thread and listens for responses coming
     /// from the client running in the tokio executor.
     pub async fn response_receiver(
-        mut receiver: Receiver<Result<ClientMessage, String>>,
+        mut receiver: Receiver<Result<ClientMessage, ClientError>>,
         server: Weak<InnerServer>,
     ) {
         while let Some(message) = receiver.recv().await {
@@ -277,15 +1230,34 @@ impl Connection {
             match message {
                 Ok(message) => match message {
                     ClientMessage::LoginMessage(r) => server.receive_login(r),
-                    ClientMessage::SyncEvent(r, e) => {
-                        server.receive_joined_timeline_event(&r, e).await
+                    ClientMessage::SyncEvent(r, e, m, p) => {
+                        server.receive_joined_timeline_event(&r, e, m, p).await
                     }
                     ClientMessage::SyncState(r, e) => {
                         server.receive_joined_state_event(&r, e).await
                     }
+                    ClientMessage::PushRules(r) => server.receive_push_rules(r),
                     ClientMessage::RestoredRoom(room) => {
                         server.restore_room(room).await
                     }
+                    ClientMessage::TypingUsers(room_id, users) => {
+                        server.receive_typing_users(&room_id, users)
+                    }
+                    ClientMessage::ReadReceipts(room_id, event_ids) => {
+                        server.receive_read_receipts(&room_id, &event_ids)
+                    }
+                    ClientMessage::OwnReadReceipt(room_id, event_id) => {
+                        server.receive_own_read_receipt(&room_id, &event_id)
+                    }
+                    ClientMessage::CustomEvent(room_id, e) => {
+                        server.receive_custom_event(&room_id, e).await
+                    }
+                    ClientMessage::DeviceListsChanged => {
+                        server.device_lists_changed()
+                    }
+                    ClientMessage::InitialSync(first_login) => {
+                        server.initial_sync_done(first_login).await
+                    }
                     ClientMessage::MemberEvent(
                         room_id,
                         e,
@@ -296,64 +1268,128 @@ impl Connection {
                             .receive_member(room_id, e, is_state, change)
                             .await
                     }
+                    ClientMessage::MemberEvents(room_id, members) => {
+                        server.receive_members(room_id, members).await
+                    }
+                    ClientMessage::RoomSettings(room_id, settings) => {
+                        server.receive_room_settings(&room_id, settings)
+                    }
+                    ClientMessage::Resync(room_ids) => {
+                        server.resync_rooms(room_ids).await
+                    }
                 },
-                Err(e) => server.print_error(&format!("Ruma error {}", e)),
+                Err(e) => server.receive_error(e),
             };
         }
     }
 
+    /// The name under which the sync filter for the given settings is stored.
+    ///
+    /// Filters are cached by their name, so the name needs to change if the
+    /// settings that affect the filter change.
+    fn sync_filter_name(settings: &ServerSettings) -> String {
+        format!(
+            "sync-presence-{}-receipts-{}",
+            settings.presence, settings.read_receipts
+        )
+    }
+
     #[allow(clippy::field_reassign_with_default)]
-    fn sync_filter() -> FilterDefinition<'static> {
+    fn sync_filter<'a>(
+        settings: &ServerSettings,
+        ignored_ephemeral_types: &'a [String],
+    ) -> FilterDefinition<'a> {
         let mut filter = FilterDefinition::default();
         let mut room_filter = RoomFilter::default();
         let mut event_filter = RoomEventFilter::default();
+        let mut ephemeral_filter = RoomEventFilter::default();
 
         event_filter.lazy_load_options = LazyLoadOptions::Enabled {
             include_redundant_members: false,
         };
         event_filter.limit = Some(10u16.into());
 
+        ephemeral_filter.not_types = ignored_ephemeral_types;
+
         room_filter.state = event_filter;
+        room_filter.ephemeral = ephemeral_filter;
         filter.room = room_filter;
 
+        if !settings.presence {
+            filter.presence = Filter::ignore_all();
+        }
+
         filter
     }
 
+    /// Is the event of a type that the plugin doesn't know about.
+    fn is_custom_event(event: &AnySyncTimelineEvent) -> bool {
+        matches!(
+            event,
+            AnySyncTimelineEvent::MessageLike(
+                AnySyncMessageLikeEvent::_Custom(_)
+            ) | AnySyncTimelineEvent::State(AnySyncStateEvent::_Custom(_))
+        )
+    }
+
     /// Main client sync loop.
     /// This runs on the per server tokio executor.
     /// It communicates with the main Weechat thread using a async channel.
+    ///
+    /// If `soft_logout` is set the server invalidated our access token but
+    /// kept our device, the token is refreshed or we log in again with the
+    /// same device, this way the encryption keys of the device stay valid.
+    #[allow(clippy::too_many_arguments)]
     pub async fn sync_loop(
         client: Client,
-        channel: Sender<Result<ClientMessage, String>>,
-        username: String,
-        password: String,
+        channel: Sender<Result<ClientMessage, ClientError>>,
+        settings: ServerSettings,
         server_name: String,
         server_path: PathBuf,
+        sync_log: SyncLog,
+        stats: Arc<SyncChannelStats>,
+        soft_logout: bool,
     ) {
-        if !client.logged_in() {
+        let username = &settings.username;
+        let password = &settings.password;
+        let device_name = settings.device_name(&server_name);
+        let mut first_login = false;
+
+        let relogin = soft_logout
+            && !matches!(client.refresh_access_token().await, Ok(Some(_)));
+
+        if !client.logged_in() || relogin {
             let device_id =
-                Connection::load_device_id(&username, server_path.clone());
+                Connection::load_device_id(username, server_path.clone()).map(
+                    |d| {
+                        // The client knows our device if the file went
+                        // missing since we logged in.
+                        d.or_else(|| client.device_id().map(|d| d.to_string()))
+                    },
+                );
 
             let device_id = match device_id {
                 Err(e) => {
                     // TODO: do we want to do something with channel.send()
                     // errors?
                     let _ = channel
-                        .send(Err(format!(
-                        "Error while reading the device id for server {}: {:?}",
-                        server_name, e
-                    )))
+                        .send(Err(ClientError::io(format!(
+                            "Error while reading the device id for server {}: \
+                             {:?}",
+                            server_name, e
+                        ))))
                         .await;
                     return;
                 }
                 Ok(d) => d,
             };
 
-            let first_login = device_id.is_none();
+            first_login = device_id.is_none();
 
             let mut builder = client
-                .login_username(&username, &password)
-                .initial_device_display_name("WeeChat-Matrix-rs");
+                .login_username(username, password)
+                .initial_device_display_name(&device_name)
+                .request_refresh_token();
 
             if let Some(device_id) = device_id.as_ref() {
                 builder = builder.device_id(device_id);
@@ -362,15 +1398,17 @@ impl Connection {
             match builder.send().await {
                 Ok(response) => {
                     if let Err(e) = Connection::save_device_id(
-                        &username,
+                        username,
                         server_path.clone(),
                         &response,
                     ) {
                         let _ = channel
-                            .send(Err(format!(
-                            "Error while writing the device id for server {}: {:?}",
-                            server_name, e
-                        ))).await;
+                            .send(Err(ClientError::io(format!(
+                                "Error while writing the device id for server \
+                                 {}: {:?}",
+                                server_name, e
+                            ))))
+                            .await;
                         return;
                     }
 
@@ -384,13 +1422,19 @@ impl Connection {
                 }
                 Err(e) => {
                     let _ = channel
-                        .send(Err(format!("Failed to log in: {:?}", e)))
+                        .send(Err(ClientError::from_sdk(
+                            "Failed to log in",
+                            &e,
+                            true,
+                        )))
                         .await;
                     return;
                 }
             }
 
-            if !first_login {
+            // The rooms are still there after a soft logout, they only need
+            // to be restored if we just started.
+            if !first_login && !relogin {
                 for room in client.joined_rooms() {
                     if channel
                         .send(Ok(ClientMessage::RestoredRoom(room)))
@@ -403,15 +1447,36 @@ impl Connection {
             }
         }
 
-        let filter = client
-            .get_or_upload_filter("sync", Connection::sync_filter())
+        let ignored_ephemeral_types = if settings.read_receipts {
+            vec![]
+        } else {
+            vec!["m.receipt".to_owned()]
+        };
+
+        let filter = match client
+            .get_or_upload_filter(
+                &Connection::sync_filter_name(&settings),
+                Connection::sync_filter(&settings, &ignored_ephemeral_types),
+            )
             .await
-            .unwrap();
+        {
+            Ok(f) => f,
+            Err(e) => {
+                let _ = channel
+                    .send(Err(ClientError::from_sdk(
+                        "Failed to upload the sync filter",
+                        &e,
+                        false,
+                    )))
+                    .await;
+                return;
+            }
+        };
 
         let sync_token = client.sync_token().await;
         let sync_settings = SyncSettings::new()
             .timeout(DEFAULT_SYNC_TIMEOUT)
-            .filter(Filter::FilterId(&filter));
+            .filter(SyncFilter::FilterId(&filter));
 
         let sync_settings = if let Some(t) = sync_token {
             sync_settings.token(t)
@@ -419,13 +1484,72 @@ impl Connection {
             sync_settings
         };
 
+        // Syncing marks us as online unless we say otherwise, spectators
+        // shouldn't show up as online.
+        let sync_settings = if settings.read_only {
+            sync_settings.set_presence(PresenceState::Offline)
+        } else {
+            sync_settings
+        };
+
         let sync_channel = &channel;
+        let sync_log = &sync_log;
+        let sync_stats = &stats;
 
         let client_ref = &client;
 
-        let _ret = client
+        // Our own devices, and their keys, are only known once the first sync
+        // finished, checks that need them have to wait until then.
+        let initial_sync = AtomicBool::new(true);
+        let initial_sync = &initial_sync;
+
+        // The SDK queries the keys of users whose device lists changed at
+        // the start of the next sync, the device lists are only up to date
+        // after that.
+        let device_lists_changed = AtomicBool::new(false);
+        let device_lists_changed = &device_lists_changed;
+
+        // The push rules decide which events highlight, they are kept up to
+        // date using the account data of the sync responses.
+        let push_rules =
+            std::sync::Mutex::new(push_rules::fetch(&client).await);
+        let push_rules = &push_rules;
+
+        let ret = client
             .sync_with_callback(sync_settings, |response| async move {
+                sync_log.log(&response);
+
+                let (rules, rules_changed) = {
+                    let mut rules = push_rules.lock().unwrap();
+                    let update =
+                        push_rules::from_account_data(&response.account_data);
+                    let changed = update.is_some();
+
+                    if let Some(r) = update {
+                        *rules = Some(r);
+                    }
+
+                    (rules.clone(), changed)
+                };
+
+                // The rooms need the rules to know their notification mode.
+                if rules_changed || initial_sync.load(Ordering::SeqCst) {
+                    if let Some(r) = rules.clone() {
+                        if sync_channel
+                            .send(Ok(ClientMessage::PushRules(r)))
+                            .await
+                            .is_err()
+                        {
+                            return LoopCtrl::Break;
+                        }
+                    }
+                }
+
+                let mut dropped_rooms = BTreeSet::new();
+
                 for (room_id, room) in response.rooms.join {
+                    let mut members = Vec::new();
+
                     for event in room
                         .state
                         .events
@@ -440,37 +1564,59 @@ impl Connection {
                                 .and_then(|c| c.get(m.event_id()))
                                 .cloned();
 
-                            if sync_channel
-                                .send(Ok(ClientMessage::MemberEvent(
-                                    room_id.clone(),
-                                    m,
-                                    true,
-                                    change,
-                                )))
-                                .await
-                                .is_err()
-                            {
-                                return LoopCtrl::Break;
-                            }
-                        } else if sync_channel
-                            .send(Ok(ClientMessage::SyncState(
+                            members.push((m, change));
+                        } else if !Self::queue_message(
+                            sync_channel,
+                            sync_stats,
+                            &room_id,
+                            ClientMessage::SyncState(room_id.clone(), event),
+                            &mut dropped_rooms,
+                        ) {
+                            return LoopCtrl::Break;
+                        }
+                    }
+
+                    // The state of big rooms contains thousands of member
+                    // events, they are sent as a single message so they don't
+                    // fill up the channel.
+                    if !members.is_empty() {
+                        sync_stats
+                            .coalesced_member_events
+                            .fetch_add(members.len(), Ordering::Relaxed);
+
+                        if !Self::queue_message(
+                            sync_channel,
+                            sync_stats,
+                            &room_id,
+                            ClientMessage::MemberEvents(
                                 room_id.clone(),
-                                event,
-                            )))
-                            .await
-                            .is_err()
-                        {
+                                members,
+                            ),
+                            &mut dropped_rooms,
+                        ) {
                             return LoopCtrl::Break;
                         }
                     }
 
-                    for event in room
-                        .timeline
-                        .events
-                        .iter()
-                        .filter_map(|e| e.event.deserialize().ok())
+                    let push_context = match (
+                        &rules,
+                        client_ref.get_joined_room(&room_id),
+                        client_ref.user_id(),
+                    ) {
+                        (Some(_), Some(r), Some(user_id))
+                            if !room.timeline.events.is_empty() =>
+                        {
+                            Some(push_rules::room_context(&r, user_id).await)
+                        }
+                        _ => None,
+                    };
+
+                    for (raw, event) in
+                        room.timeline.events.iter().filter_map(|e| {
+                            e.event.deserialize().ok().map(|d| (&e.event, d))
+                        })
                     {
-                        if let AnySyncTimelineEvent::State(
+                        let message = if let AnySyncTimelineEvent::State(
                             AnySyncStateEvent::RoomMember(m),
                         ) = event
                         {
@@ -481,26 +1627,128 @@ impl Connection {
                                 .and_then(|c| c.get(m.event_id()))
                                 .cloned();
 
-                            if sync_channel
-                                .send(Ok(ClientMessage::MemberEvent(
-                                    room_id.clone(),
-                                    m,
-                                    false,
-                                    change,
-                                )))
-                                .await
-                                .is_err()
+                            ClientMessage::MemberEvent(
+                                room_id.clone(),
+                                m,
+                                false,
+                                change,
+                            )
+                        } else if Self::is_custom_event(&event) {
+                            ClientMessage::CustomEvent(
+                                room_id.clone(),
+                                raw.clone(),
+                            )
+                        } else {
+                            let push = rules
+                                .as_ref()
+                                .zip(push_context.as_ref())
+                                .map(|(rules, context)| {
+                                    PushOutcome::evaluate(rules, raw, context)
+                                });
+
+                            ClientMessage::SyncEvent(
+                                room_id.clone(),
+                                event,
+                                Mentions::from_event(raw),
+                                push,
+                            )
+                        };
+
+                        if !Self::queue_message(
+                            sync_channel,
+                            sync_stats,
+                            &room_id,
+                            message,
+                            &mut dropped_rooms,
+                        ) {
+                            return LoopCtrl::Break;
+                        }
+                    }
+
+                    for content in
+                        room.account_data.events.iter().filter_map(|e| {
+                            if e.get_field::<String>("type").ok()??
+                                == ROOM_SETTINGS_EVENT_TYPE
                             {
-                                return LoopCtrl::Break;
+                                e.get_field::<serde_json::Value>("content")
+                                    .ok()?
+                            } else {
+                                None
                             }
-                        } else if sync_channel
-                            .send(Ok(ClientMessage::SyncEvent(
+                        })
+                    {
+                        if !Self::queue_message(
+                            sync_channel,
+                            sync_stats,
+                            &room_id,
+                            ClientMessage::RoomSettings(
                                 room_id.clone(),
-                                event,
-                            )))
-                            .await
-                            .is_err()
-                        {
+                                RoomSettings::from_json(&content),
+                            ),
+                            &mut dropped_rooms,
+                        ) {
+                            return LoopCtrl::Break;
+                        }
+                    }
+
+                    for event in room
+                        .ephemeral
+                        .events
+                        .iter()
+                        .filter_map(|e| e.deserialize().ok())
+                    {
+                        let message = match event {
+                            AnySyncEphemeralRoomEvent::Typing(t) => {
+                                ClientMessage::TypingUsers(
+                                    room_id.clone(),
+                                    t.content.user_ids,
+                                )
+                            }
+                            AnySyncEphemeralRoomEvent::Receipt(r) => {
+                                let own_receipt = Self::own_read_receipt(
+                                    &r.content,
+                                    client_ref.user_id(),
+                                );
+
+                                if let Some(event_id) = own_receipt {
+                                    if !Self::queue_message(
+                                        sync_channel,
+                                        sync_stats,
+                                        &room_id,
+                                        ClientMessage::OwnReadReceipt(
+                                            room_id.clone(),
+                                            event_id,
+                                        ),
+                                        &mut dropped_rooms,
+                                    ) {
+                                        return LoopCtrl::Break;
+                                    }
+                                }
+
+                                let event_ids = Self::read_by_others(
+                                    &r.content,
+                                    client_ref.user_id(),
+                                );
+
+                                if event_ids.is_empty() {
+                                    continue;
+                                }
+
+                                ClientMessage::ReadReceipts(
+                                    room_id.clone(),
+                                    event_ids,
+                                )
+                            }
+                            _ => continue,
+                        };
+
+                        if !Self::queue_message(
+                            sync_channel,
+                            sync_stats,
+                            &room_id,
+                            message,
+                            &mut dropped_rooms,
+                        ) {
                             return LoopCtrl::Break;
                         }
                     }
@@ -509,37 +1757,44 @@ impl Connection {
                         if !r.are_members_synced() {
                             let room_id = room_id.clone();
                             let channel = sync_channel.clone();
+                            let stats = sync_stats.clone();
 
                             tokio::spawn(async move {
-                                if let Ok(Some(members)) =
+                                if let Ok(Some(response)) =
                                     r.sync_members().await
                                 {
-                                    for member in members.chunk.into_iter() {
-                                        let change = members
-                                            .ambiguity_changes
-                                            .changes
-                                            .get(&room_id)
-                                            .and_then(|c| {
-                                                c.get(member.event_id())
-                                            })
-                                            .cloned();
-
-                                        if let Err(e) = channel
-                                            .send(Ok(
-                                                ClientMessage::MemberEvent(
-                                                    room_id.clone(),
-                                                    member.into(),
-                                                    true,
-                                                    change,
-                                                ),
-                                            ))
-                                            .await
-                                        {
-                                            error!(
-                                                "Failed to send room member {}",
-                                                e
-                                            );
-                                        }
+                                    let members: Vec<_> = response
+                                        .chunk
+                                        .into_iter()
+                                        .map(|member| {
+                                            let change = response
+                                                .ambiguity_changes
+                                                .changes
+                                                .get(&room_id)
+                                                .and_then(|c| {
+                                                    c.get(member.event_id())
+                                                })
+                                                .cloned();
+
+                                            (member.into(), change)
+                                        })
+                                        .collect();
+
+                                    stats.coalesced_member_events.fetch_add(
+                                        members.len(),
+                                        Ordering::Relaxed,
+                                    );
+
+                                    if let Err(e) = channel
+                                        .send(Ok(ClientMessage::MemberEvents(
+                                            room_id, members,
+                                        )))
+                                        .await
+                                    {
+                                        error!(
+                                            "Failed to send room members {}",
+                                            e
+                                        );
                                     }
                                 }
                             });
@@ -547,8 +1802,129 @@ impl Connection {
                     }
                 }
 
+                // Waiting for room in the channel here, instead of for every
+                // event, lets the receiver catch up before the next sync.
+                if !dropped_rooms.is_empty() {
+                    sync_stats
+                        .resynced_rooms
+                        .fetch_add(dropped_rooms.len(), Ordering::Relaxed);
+
+                    if sync_channel
+                        .send(Ok(ClientMessage::Resync(
+                            dropped_rooms.into_iter().collect(),
+                        )))
+                        .await
+                        .is_err()
+                    {
+                        return LoopCtrl::Break;
+                    }
+                }
+
+                let changed = !response.device_lists.changed.is_empty();
+
+                if (changed
+                    || device_lists_changed.swap(changed, Ordering::SeqCst))
+                    && sync_channel
+                        .send(Ok(ClientMessage::DeviceListsChanged))
+                        .await
+                        .is_err()
+                {
+                    return LoopCtrl::Break;
+                }
+
+                if initial_sync.swap(false, Ordering::SeqCst)
+                    && sync_channel
+                        .send(Ok(ClientMessage::InitialSync(first_login)))
+                        .await
+                        .is_err()
+                {
+                    return LoopCtrl::Break;
+                }
+
                 LoopCtrl::Continue
             })
             .await;
+
+        if let Err(e) = ret {
+            let _ = channel
+                .send(Err(ClientError::from_sdk("Sync failed", &e, false)))
+                .await;
+        }
+    }
+
+    /// Queue a message for the response receiver without waiting for room in
+    /// the channel, so a receiver that can't keep up doesn't stall the sync
+    /// loop.
+    ///
+    /// Messages that don't fit into the channel are dropped, as are all the
+    /// following messages of the same room, the room gets resynced instead.
+    /// Returns false if the receiver is gone.
+    fn queue_message(
+        channel: &Sender<Result<ClientMessage, ClientError>>,
+        stats: &SyncChannelStats,
+        room_id: &RoomId,
+        message: ClientMessage,
+        dropped_rooms: &mut BTreeSet<OwnedRoomId>,
+    ) -> bool {
+        if dropped_rooms.contains(room_id) {
+            stats.dropped_messages.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+
+        match channel.try_send(Ok(message)) {
+            Ok(()) => {
+                stats.max_queued.fetch_max(
+                    SYNC_CHANNEL_CAPACITY - channel.capacity(),
+                    Ordering::Relaxed,
+                );
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                stats.dropped_messages.fetch_add(1, Ordering::Relaxed);
+                dropped_rooms.insert(room_id.to_owned());
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Get the latest event our own user has a public or private read receipt
+    /// on, the receipts of our other sessions tell us that the room was read
+    /// elsewhere.
+    fn own_read_receipt(
+        content: &ReceiptEventContent,
+        own_user_id: Option<&UserId>,
+    ) -> Option<OwnedEventId> {
+        let own_user_id = own_user_id?;
+
+        content
+            .iter()
+            .filter_map(|(event_id, receipts)| {
+                let receipt = [ReceiptType::Read, ReceiptType::ReadPrivate]
+                    .iter()
+                    .filter_map(|t| receipts.get(t)?.get(own_user_id))
+                    .next()?;
+
+                Some((receipt.ts, event_id))
+            })
+            .max_by_key(|(ts, _)| *ts)
+            .map(|(_, event_id)| event_id.to_owned())
+    }
+
+    /// Get the events of a receipt event that were read by someone else than
+    /// us.
+    fn read_by_others(
+        content: &ReceiptEventContent,
+        own_user_id: Option<&UserId>,
+    ) -> Vec<OwnedEventId> {
+        content
+            .iter()
+            .filter(|(_, receipts)| {
+                receipts.get(&ReceiptType::Read).map_or(false, |users| {
+                    users.keys().any(|u| Some(u.as_ref()) != own_user_id)
+                })
+            })
+            .map(|(event_id, _)| event_id.to_owned())
+            .collect()
     }
 }
@@ -0,0 +1,172 @@
+//! Emoji shortcodes.
+//!
+//! A table of commonly used emoji and their shortcodes, used to render emoji
+//! as text for braille displays and screen readers that can't read them, and
+//! to replace the shortcodes of outgoing messages with their emoji.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+const VARIATION_SELECTOR: char = '\u{fe0f}';
+
+const SHORTCODES: &[(&str, &str)] = &[
+    ("😀", "grinning"),
+    ("😃", "smiley"),
+    ("😄", "smile"),
+    ("😁", "grin"),
+    ("😆", "laughing"),
+    ("😅", "sweat_smile"),
+    ("😂", "joy"),
+    ("🤣", "rofl"),
+    ("🙂", "slightly_smiling_face"),
+    ("🙃", "upside_down_face"),
+    ("😉", "wink"),
+    ("😊", "blush"),
+    ("😇", "innocent"),
+    ("😍", "heart_eyes"),
+    ("😘", "kissing_heart"),
+    ("😋", "yum"),
+    ("😛", "stuck_out_tongue"),
+    ("😜", "stuck_out_tongue_winking_eye"),
+    ("🤔", "thinking"),
+    ("🤨", "raised_eyebrow"),
+    ("😐", "neutral_face"),
+    ("😑", "expressionless"),
+    ("😶", "no_mouth"),
+    ("😏", "smirk"),
+    ("😒", "unamused"),
+    ("🙄", "roll_eyes"),
+    ("😬", "grimacing"),
+    ("😌", "relieved"),
+    ("😔", "pensive"),
+    ("😴", "sleeping"),
+    ("😷", "mask"),
+    ("🤯", "exploding_head"),
+    ("😎", "sunglasses"),
+    ("😕", "confused"),
+    ("😟", "worried"),
+    ("😮", "open_mouth"),
+    ("😲", "astonished"),
+    ("😳", "flushed"),
+    ("😢", "cry"),
+    ("😭", "sob"),
+    ("😱", "scream"),
+    ("😞", "disappointed"),
+    ("😓", "sweat"),
+    ("😩", "weary"),
+    ("😡", "rage"),
+    ("😠", "angry"),
+    ("💀", "skull"),
+    ("💩", "poop"),
+    ("👋", "wave"),
+    ("👌", "ok_hand"),
+    ("✌", "v"),
+    ("🤞", "crossed_fingers"),
+    ("👍", "+1"),
+    ("👎", "-1"),
+    ("👏", "clap"),
+    ("🙌", "raised_hands"),
+    ("🙏", "pray"),
+    ("💪", "muscle"),
+    ("👀", "eyes"),
+    ("❤", "heart"),
+    ("💔", "broken_heart"),
+    ("💯", "100"),
+    ("✅", "white_check_mark"),
+    ("❌", "x"),
+    ("❗", "exclamation"),
+    ("❓", "question"),
+    ("⚠", "warning"),
+    ("🔥", "fire"),
+    ("✨", "sparkles"),
+    ("⭐", "star"),
+    ("🎉", "tada"),
+    ("🎂", "birthday"),
+    ("🚀", "rocket"),
+    ("🐛", "bug"),
+    ("🔒", "lock"),
+    ("🔓", "unlock"),
+    ("🔑", "key"),
+    ("🔕", "no_bell"),
+    ("⏳", "hourglass_flowing_sand"),
+    ("🌍", "earth_africa"),
+    ("☕", "coffee"),
+    ("🍺", "beer"),
+    ("🍕", "pizza"),
+];
+
+/// Get the shortcode of the given emoji.
+pub fn shortcode(emoji: &str) -> Option<&'static str> {
+    let emoji = emoji.trim_end_matches(VARIATION_SELECTOR);
+
+    SHORTCODES
+        .iter()
+        .find(|(e, _)| *e == emoji)
+        .map(|(_, shortcode)| *shortcode)
+}
+
+/// Get the emoji of the given shortcode, the shortcode is given without the
+/// surrounding colons.
+pub fn emoji(shortcode: &str) -> Option<&'static str> {
+    SHORTCODES
+        .iter()
+        .find(|(_, s)| *s == shortcode)
+        .map(|(emoji, _)| *emoji)
+}
+
+/// Replace all the known shortcodes in the given text, e.g. `:+1:`, with
+/// their emoji.
+pub fn replace_shortcodes(text: &str) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find(':') {
+        replaced.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        let found = after
+            .find(':')
+            .and_then(|end| emoji(&after[..end]).map(|e| (e, end)));
+
+        if let Some((emoji, end)) = found {
+            replaced.push_str(emoji);
+            rest = &after[end + 1..];
+        } else {
+            replaced.push(':');
+            rest = after;
+        }
+    }
+
+    replaced.push_str(rest);
+    replaced
+}
+
+/// Replace all the emoji in the given text that have a known shortcode with
+/// the shortcode, e.g. `:+1:`.
+pub fn replace_with_shortcodes(text: &str) -> String {
+    text.graphemes(true)
+        .map(|g| {
+            shortcode(g)
+                .map(|s| format!(":{}:", s))
+                .unwrap_or_else(|| g.to_owned())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_with_shortcodes() {
+        assert_eq!(replace_with_shortcodes("Nice 👍🎉"), "Nice :+1::tada:");
+        assert_eq!(replace_with_shortcodes("I ❤️ Rust"), "I :heart: Rust");
+        assert_eq!(replace_with_shortcodes("No emoji"), "No emoji");
+    }
+
+    #[test]
+    fn test_replace_shortcodes() {
+        assert_eq!(replace_shortcodes("Nice :+1::tada:"), "Nice 👍🎉");
+        assert_eq!(replace_shortcodes("At 10:30: :coffee:"), "At 10:30: ☕");
+        assert_eq!(replace_shortcodes(":unknown: :"), ":unknown: :");
+    }
+}
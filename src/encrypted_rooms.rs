@@ -0,0 +1,73 @@
+//! The list of rooms that had encryption enabled.
+//!
+//! Once encryption is enabled in a room it can't be disabled again. A room
+//! whose state claims otherwise, e.g. after a state reset by a malicious or
+//! broken server, is still treated as encrypted and we refuse to send
+//! plaintext messages to it.
+//!
+//! The room ids are stored in the server directory, this way a downgrade is
+//! detected even if it happened while WeeChat wasn't running.
+
+use std::{collections::HashSet, path::PathBuf};
+
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use serde_json::Value;
+use tracing::error;
+
+#[derive(Debug, Default)]
+pub struct EncryptedRooms {
+    path: PathBuf,
+    rooms: HashSet<OwnedRoomId>,
+}
+
+impl EncryptedRooms {
+    /// Load the list of encrypted rooms from the given file.
+    ///
+    /// A missing or invalid file results in an empty list.
+    pub fn load(path: PathBuf) -> Self {
+        let value: Value = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|c| serde_json::from_str(&c).ok())
+            .unwrap_or_default();
+
+        let rooms = value
+            .as_array()
+            .map(|rooms| {
+                rooms
+                    .iter()
+                    .filter_map(|r| RoomId::parse(r.as_str()?).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self { path, rooms }
+    }
+
+    fn save(&self) {
+        let rooms: Vec<&str> = self.rooms.iter().map(|r| r.as_str()).collect();
+
+        let ret = self
+            .path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| {
+                std::fs::write(&self.path, Value::from(rooms).to_string())
+            });
+
+        if let Err(e) = ret {
+            error!("Error saving the list of encrypted rooms: {}", e);
+        }
+    }
+
+    /// Remember that encryption is enabled in the given room.
+    pub fn add(&mut self, room_id: &RoomId) {
+        if self.rooms.insert(room_id.to_owned()) {
+            self.save();
+        }
+    }
+
+    /// Did the given room have encryption enabled at some point.
+    pub fn contains(&self, room_id: &RoomId) -> bool {
+        self.rooms.contains(room_id)
+    }
+}
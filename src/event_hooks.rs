@@ -0,0 +1,171 @@
+//! Hooks for event types that the plugin doesn't know about.
+//!
+//! Bridges and bots often send their own event types, e.g.
+//! `com.example.game.move`, these would otherwise be silently dropped. Other
+//! modules of the plugin can register a renderer for such an event type using
+//! [`EventHooks::register`].
+//!
+//! Every custom event is additionally sent out as a
+//! `matrix_custom_event_<event-type>` signal so scripts can handle them as
+//! well. The signal data is a JSON object containing the `buffer` full name,
+//! the `room_id`, the event `type`, its `content` and the raw `event`.
+//!
+//! This is a string signal and not a hsignal, the WeeChat bindings we use
+//! can't create hashtables. The keys of the JSON object are the ones a
+//! hsignal hashtable would have, scripts only need to decode the JSON.
+
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use serde_json::{json, Value};
+use weechat::{hooks::SignalData, Weechat};
+
+use crate::{
+    render::{Render, RenderedContent, RenderedLine},
+    room::WeechatRoomMember,
+    utils::escape_spoofing_chars,
+};
+
+/// A renderer for a custom event type.
+///
+/// The renderer gets the sender and the raw JSON of the event and returns the
+/// message that should be printed, or `None` if the event should stay hidden.
+pub type CustomEventRenderer =
+    Rc<dyn Fn(&WeechatRoomMember, &Value) -> Option<String>>;
+
+/// The event type under which the fallback renderer is registered, it's used
+/// for events that don't have their own renderer.
+const FALLBACK_EVENT_TYPE: &str = "*";
+
+#[derive(Clone)]
+pub struct EventHooks {
+    renderers: Rc<RefCell<HashMap<String, CustomEventRenderer>>>,
+}
+
+impl std::fmt::Debug for EventHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let renderers = self.renderers.borrow();
+        let event_types: Vec<&String> = renderers.keys().collect();
+
+        f.debug_struct("EventHooks")
+            .field("event_types", &event_types)
+            .finish()
+    }
+}
+
+impl Default for EventHooks {
+    fn default() -> Self {
+        let hooks = Self {
+            renderers: Rc::new(RefCell::new(HashMap::new())),
+        };
+
+        hooks.register(FALLBACK_EVENT_TYPE, Self::render_body);
+
+        hooks
+    }
+}
+
+impl EventHooks {
+    /// Register a renderer for the given event type.
+    ///
+    /// A previously registered renderer for the same event type gets
+    /// replaced.
+    pub fn register(
+        &self,
+        event_type: &str,
+        renderer: impl Fn(&WeechatRoomMember, &Value) -> Option<String> + 'static,
+    ) {
+        self.renderers
+            .borrow_mut()
+            .insert(event_type.to_owned(), Rc::new(renderer));
+    }
+
+    /// Render the given custom event using the renderer that is registered
+    /// for its event type.
+    pub fn render(
+        &self,
+        sender: &WeechatRoomMember,
+        event: &Value,
+    ) -> Option<CustomEvent> {
+        let event_type = event["type"].as_str()?;
+
+        let (renderer, fallback) = {
+            let renderers = self.renderers.borrow();
+
+            match renderers.get(event_type) {
+                Some(r) => (r.clone(), false),
+                None => (renderers.get(FALLBACK_EVENT_TYPE).cloned()?, true),
+            }
+        };
+
+        renderer(sender, event).map(|message| CustomEvent { message, fallback })
+    }
+
+    /// Let scripts know that a custom event was received.
+    pub fn send_signal(buffer_name: &str, room_id: &str, event: &Value) {
+        let event_type = event["type"].as_str().unwrap_or_default();
+
+        let data = json!({
+            "buffer": buffer_name,
+            "room_id": room_id,
+            "type": event_type,
+            "content": event["content"],
+            "event": event,
+        })
+        .to_string();
+
+        Weechat::hook_signal_send(
+            &format!("matrix_custom_event_{}", event_type),
+            SignalData::String(data.into()),
+        );
+    }
+
+    /// The fallback renderer, events which contain a textual body, which
+    /// bridges often include, get the body printed out.
+    ///
+    /// The body comes from an event we know nothing about, so it's escaped.
+    fn render_body(_: &WeechatRoomMember, event: &Value) -> Option<String> {
+        event["content"]["body"]
+            .as_str()
+            .filter(|b| !b.is_empty())
+            .map(|b| escape_spoofing_chars(b).into_owned())
+    }
+}
+
+/// A custom event that was rendered by one of the registered renderers.
+pub struct CustomEvent {
+    message: String,
+    /// Was the event rendered by the fallback renderer.
+    fallback: bool,
+}
+
+impl CustomEvent {
+    /// Was the event rendered by the fallback renderer, such events
+    /// shouldn't notify.
+    pub fn is_fallback(&self) -> bool {
+        self.fallback
+    }
+}
+
+impl Render for CustomEvent {
+    const TAGS: &'static [&'static str] = &["matrix_custom"];
+    type RenderContext = ();
+
+    fn render(&self, _: &Self::RenderContext) -> RenderedContent {
+        let mut tags = self.tags();
+
+        if self.fallback {
+            tags.push("notify_none".to_owned());
+        }
+
+        let lines = self
+            .message
+            .lines()
+            .map(|l| RenderedLine {
+                message: l.to_owned(),
+                tags: tags.clone(),
+            })
+            .collect();
+
+        RenderedContent { lines }
+    }
+}
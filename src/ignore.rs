@@ -0,0 +1,96 @@
+//! Content based ignore rules.
+//!
+//! Besides ignoring users, messages can be ignored based on their content,
+//! e.g. the repetitive output of a bot or the join floods of a bridge. The
+//! rules live in the `look.ignored_content` option as a semicolon separated
+//! list of `<scope> <regex>` entries. The scope is either `*` for all rooms,
+//! the name of a server for all the rooms of the server, or a room id or
+//! alias.
+
+use std::{cell::RefCell, rc::Rc};
+
+use matrix_sdk::ruma::{RoomAliasId, RoomId};
+use regex::Regex;
+use weechat::{Prefix, Weechat};
+
+pub struct IgnoreRule {
+    scope: String,
+    regex: Regex,
+}
+
+impl IgnoreRule {
+    fn parse(entry: &str) -> Option<Result<Self, regex::Error>> {
+        let mut parts = entry.trim().splitn(2, ' ');
+        let scope = parts.next()?.trim();
+        let regex = parts.next()?.trim();
+
+        if scope.is_empty() || regex.is_empty() {
+            return None;
+        }
+
+        Some(Regex::new(regex).map(|regex| Self {
+            scope: scope.to_owned(),
+            regex,
+        }))
+    }
+}
+
+thread_local! {
+    /// The option value the rules were last parsed from, with the parsed
+    /// rules, so the regexes only get compiled when the option changes.
+    static RULES: RefCell<(String, Rc<Vec<IgnoreRule>>)> =
+        RefCell::new((String::new(), Rc::new(Vec::new())));
+}
+
+/// Parse the ignore rules of the given option value.
+///
+/// Rules with an invalid regex are skipped, an error is printed once when
+/// the option value changes.
+fn rules(option: &str) -> Rc<Vec<IgnoreRule>> {
+    RULES.with(|cache| {
+        let mut cache = cache.borrow_mut();
+
+        if cache.0 != option {
+            let rules = option
+                .split(';')
+                .filter_map(IgnoreRule::parse)
+                .filter_map(|rule| {
+                    rule.map_err(|e| {
+                        Weechat::print(&format!(
+                            "{}: Invalid regex in look.ignored_content: {}",
+                            Weechat::prefix(Prefix::Error),
+                            e
+                        ))
+                    })
+                    .ok()
+                })
+                .collect();
+
+            *cache = (option.to_owned(), Rc::new(rules));
+        }
+
+        cache.1.clone()
+    })
+}
+
+/// Check if the given text should be ignored in the given room.
+pub fn is_ignored(
+    option: &str,
+    text: &str,
+    server_name: &str,
+    room_id: &RoomId,
+    alias: Option<&RoomAliasId>,
+) -> bool {
+    if option.is_empty() {
+        return false;
+    }
+
+    rules(option).iter().any(|rule| {
+        let scope_matches = rule.scope == "*"
+            || rule.scope == server_name
+            || rule.scope == room_id.as_str()
+            || alias.map_or(false, |a| rule.scope == a.as_str());
+
+        scope_matches && rule.regex.is_match(text)
+    })
+}
@@ -0,0 +1,224 @@
+//! Health monitoring of the server-side key backup.
+//!
+//! Other clients upload the room keys they receive to the key backup, this is
+//! what allows new devices to decrypt the history of encrypted rooms. A backup
+//! that silently went away, got replaced or stopped receiving keys is only
+//! noticed once keys are missing, so the backup is checked periodically.
+//!
+//! The last seen backup version and key count are stored in the server
+//! directory, this way a replaced backup is noticed across restarts as well.
+//!
+//! The keys in the backup can be restored using the recovery key of the
+//! backup, this is how the history of encrypted rooms becomes readable after
+//! the first login.
+
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use matrix_sdk::{
+    crypto::{olm::ExportedRoomKey, store::RecoveryKey},
+    ruma::RoomId,
+};
+use serde_json::{json, Value};
+use tracing::error;
+
+/// How long the number of backed up keys may stay the same before the backup
+/// is considered to be stale.
+const STALE_AFTER: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackupProblem {
+    /// There is no key backup on the server.
+    Missing,
+    /// The backup isn't signed by our device or one of our verified devices.
+    Untrusted(String),
+    /// No keys were added to the backup for a long time.
+    Stale(String),
+    /// The backup version changed since the last check.
+    VersionChanged { old: String, new: String },
+}
+
+impl BackupProblem {
+    pub fn description(&self) -> String {
+        match self {
+            BackupProblem::Missing => {
+                "There is no key backup on the server, the history of \
+                 encrypted rooms can't be restored on new devices"
+                    .to_owned()
+            }
+            BackupProblem::Untrusted(version) => format!(
+                "The key backup (version {}) isn't signed by this device or \
+                 one of your verified devices",
+                version
+            ),
+            BackupProblem::Stale(version) => format!(
+                "No keys were added to the key backup (version {}) in the \
+                 last {} days",
+                version,
+                STALE_AFTER.as_secs() / (24 * 60 * 60)
+            ),
+            BackupProblem::VersionChanged { old, new } => format!(
+                "The key backup was replaced, the version changed from {} to \
+                 {}",
+                old, new
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct KeyBackupMonitor {
+    path: PathBuf,
+    version: Option<String>,
+    count: u64,
+    last_growth: Option<SystemTime>,
+    problem: Option<BackupProblem>,
+}
+
+impl KeyBackupMonitor {
+    /// Load the state of the last check from the given file.
+    ///
+    /// A missing or invalid file results in a monitor that didn't see a
+    /// backup yet.
+    pub fn load(path: PathBuf) -> Self {
+        let value: Value = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|c| serde_json::from_str(&c).ok())
+            .unwrap_or_default();
+
+        Self {
+            path,
+            version: value["version"].as_str().map(ToOwned::to_owned),
+            count: value["count"].as_u64().unwrap_or_default(),
+            last_growth: value["last_growth"]
+                .as_u64()
+                .map(|t| UNIX_EPOCH + Duration::from_secs(t)),
+            problem: None,
+        }
+    }
+
+    fn save(&self) {
+        let value = json!({
+            "version": self.version,
+            "count": self.count,
+            "last_growth": self.last_growth.and_then(|t| {
+                t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+            }),
+        });
+
+        let ret = self
+            .path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&self.path, value.to_string()));
+
+        if let Err(e) = ret {
+            error!("Error saving the key backup state: {}", e);
+        }
+    }
+
+    /// The problem the last check found, if any.
+    pub fn problem(&self) -> Option<&BackupProblem> {
+        self.problem.as_ref()
+    }
+
+    /// Update the monitor with the result of a check.
+    ///
+    /// # Arguments
+    ///
+    /// * `backup` - The version and key count of the latest backup, `None` if
+    /// there is no backup.
+    ///
+    /// * `trusted` - Is the backup signed by a device we trust.
+    ///
+    /// Returns the problem that was found if it differs from the problem the
+    /// previous check found, so every problem is reported only once.
+    pub fn update(
+        &mut self,
+        backup: Option<(String, u64)>,
+        trusted: bool,
+    ) -> Option<BackupProblem> {
+        let now = SystemTime::now();
+
+        let problem = match backup {
+            None => Some(BackupProblem::Missing),
+            Some((version, count)) => {
+                let old_version = self.version.replace(version.clone());
+
+                let problem = match old_version {
+                    Some(old) if old != version => {
+                        self.last_growth = Some(now);
+                        Some(BackupProblem::VersionChanged {
+                            old,
+                            new: version,
+                        })
+                    }
+                    _ if !trusted => Some(BackupProblem::Untrusted(version)),
+                    _ if count > self.count || self.last_growth.is_none() => {
+                        self.last_growth = Some(now);
+                        None
+                    }
+                    _ => self
+                        .last_growth
+                        .and_then(|t| now.duration_since(t).ok())
+                        .filter(|d| *d > STALE_AFTER)
+                        .map(|_| BackupProblem::Stale(version)),
+                };
+
+                self.count = count;
+                self.save();
+
+                problem
+            }
+        };
+
+        if problem == self.problem {
+            None
+        } else {
+            self.problem = problem.clone();
+            problem
+        }
+    }
+}
+
+/// Decrypt a room key that was stored in the key backup.
+///
+/// Returns `None` if the key can't be decrypted with the given recovery key.
+///
+/// # Arguments
+///
+/// * `recovery_key` - The recovery key of the backup.
+///
+/// * `room_id` - The id of the room the key belongs to.
+///
+/// * `session_id` - The id of the megolm session of the key.
+///
+/// * `key_data` - The `KeyBackupData` the server returned for the key.
+pub fn decrypt_room_key(
+    recovery_key: &RecoveryKey,
+    room_id: &RoomId,
+    session_id: &str,
+    key_data: &Value,
+) -> Option<ExportedRoomKey> {
+    let session_data = &key_data["session_data"];
+
+    let decrypted = recovery_key
+        .decrypt_v1(
+            session_data["ephemeral"].as_str()?,
+            session_data["mac"].as_str()?,
+            session_data["ciphertext"].as_str()?,
+        )
+        .ok()?;
+
+    let mut key: Value = serde_json::from_str(&decrypted).ok()?;
+
+    // The room and session id of a backed up key are part of its path on the
+    // server, not of the encrypted key.
+    let object = key.as_object_mut()?;
+    object.insert("room_id".to_owned(), json!(room_id));
+    object.insert("session_id".to_owned(), json!(session_id));
+
+    serde_json::from_value(key).ok()
+}
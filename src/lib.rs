@@ -1,12 +1,28 @@
+mod auth_prompt;
 mod bar_items;
 mod commands;
 mod completions;
 mod config;
 mod connection;
 mod debug;
+mod emoji;
+mod encrypted_rooms;
+mod event_hooks;
+mod ignore;
+mod key_backup;
+mod media_cache;
+mod mentions;
+mod mute;
+mod outbox;
+mod push_rules;
+mod relay;
 mod render;
 mod room;
+mod scheduled;
 mod server;
+mod sync_log;
+mod themes;
+mod transform;
 mod utils;
 
 use std::{
@@ -15,6 +31,7 @@ use std::{
     rc::Rc,
 };
 
+use matrix_sdk::ruma::EventId;
 use tokio::runtime::{Handle, Runtime};
 use tracing_subscriber::layer::SubscriberExt;
 
@@ -25,8 +42,14 @@ use weechat::{
 };
 
 use crate::{
-    bar_items::BarItems, commands::Commands, completions::Completions,
-    config::ConfigHandle, room::RoomHandle, server::MatrixServer,
+    bar_items::BarItems,
+    commands::{Commands, LastMessageCommand},
+    completions::Completions,
+    config::ConfigHandle,
+    event_hooks::EventHooks,
+    room::RoomHandle,
+    server::MatrixServer,
+    utils::split_server_qualifier,
 };
 
 const PLUGIN_NAME: &str = "matrix";
@@ -35,6 +58,7 @@ const PLUGIN_NAME: &str = "matrix";
 pub struct Servers {
     inner: Rc<RefCell<HashMap<String, MatrixServer>>>,
     runtime: Handle,
+    event_hooks: EventHooks,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -67,6 +91,7 @@ impl Servers {
         Servers {
             inner: Rc::new(RefCell::new(HashMap::new())),
             runtime: handle,
+            event_hooks: EventHooks::default(),
         }
     }
 
@@ -78,6 +103,11 @@ impl Servers {
         &self.runtime
     }
 
+    /// Get the registry of renderers for custom event types.
+    pub fn event_hooks(&self) -> &EventHooks {
+        &self.event_hooks
+    }
+
     pub fn is_empty(&self) -> bool {
         self.inner.borrow().is_empty()
     }
@@ -104,6 +134,50 @@ impl Servers {
         self.inner.borrow_mut().remove(server_name)
     }
 
+    /// Find the server a command should act on.
+    ///
+    /// A server given by name wins, otherwise the server of the buffer is
+    /// used. Outside of Matrix buffers the only connected server is picked.
+    pub fn resolve_server(
+        &self,
+        buffer: &Buffer,
+        server_name: Option<&str>,
+    ) -> Result<MatrixServer, String> {
+        if let Some(name) = server_name {
+            return self
+                .get(name)
+                .ok_or_else(|| format!("Server {} not found", name));
+        }
+
+        if let Some(server) = self.find_server(buffer) {
+            return Ok(server);
+        }
+
+        let mut connected = self
+            .borrow()
+            .values()
+            .filter(|s| s.connected())
+            .cloned()
+            .collect::<Vec<MatrixServer>>();
+
+        match connected.len() {
+            0 => Err("You aren't connected to any server".to_owned()),
+            1 => Ok(connected.remove(0)),
+            _ => Err("Multiple servers are connected, qualify the target \
+                      with a server name, e.g. <server-name>:<user-id>"
+                .to_owned()),
+        }
+    }
+
+    /// Split a target that is qualified with the name of one of our servers,
+    /// e.g. `work:@alice:example.org`, into the server name and the target.
+    pub fn split_qualifier<'a>(
+        &self,
+        target: &'a str,
+    ) -> (Option<&'a str>, &'a str) {
+        split_server_qualifier(target, |name| self.contains(name))
+    }
+
     pub fn buffer_owner(&self, buffer: &Buffer) -> BufferOwner {
         let servers = self.borrow();
 
@@ -114,6 +188,10 @@ impl Servers {
                 }
             }
 
+            if server.outbox().is_buffer(buffer) {
+                return BufferOwner::Server(server.clone());
+            }
+
             for room in server.rooms() {
                 let buffer_handle = room.buffer_handle();
 
@@ -143,18 +221,100 @@ impl Servers {
     pub fn find_room(&self, buffer: &Buffer) -> Option<RoomHandle> {
         self.buffer_owner(buffer).into_room()
     }
+
+    /// Find the room that the given compose buffer belongs to.
+    pub fn find_composing_room(&self, buffer: &Buffer) -> Option<RoomHandle> {
+        self.borrow()
+            .values()
+            .flat_map(|s| s.rooms())
+            .find(|r| r.is_compose_buffer(buffer))
+    }
+
+    /// Find the room that the given gallery buffer belongs to.
+    pub fn find_gallery_room(&self, buffer: &Buffer) -> Option<RoomHandle> {
+        self.borrow()
+            .values()
+            .flat_map(|s| s.rooms())
+            .find(|r| r.is_gallery_buffer(buffer))
+    }
+
+    /// Get the rooms of all servers that had a message most recently, most
+    /// recent first.
+    ///
+    /// Rooms that didn't get a message since we connected aren't included.
+    pub fn hot_rooms(&self, count: usize) -> Vec<RoomHandle> {
+        let mut rooms: Vec<RoomHandle> = self
+            .borrow()
+            .values()
+            .flat_map(|s| s.rooms())
+            .filter(|r| r.last_activity().is_some())
+            .collect();
+
+        rooms.sort_by_key(|r| std::cmp::Reverse(r.last_activity()));
+        rooms.truncate(count);
+
+        rooms
+    }
 }
 
 impl SignalCallback for Servers {
     fn callback(
         &mut self,
         _: &Weechat,
-        _signal_name: &str,
+        signal_name: &str,
         data: Option<SignalData>,
     ) -> ReturnCode {
+        match signal_name {
+            "relay_client_connected" => {
+                relay::client_connected();
+                return ReturnCode::Ok;
+            }
+            "relay_client_disconnected" => {
+                relay::client_disconnected();
+                return ReturnCode::Ok;
+            }
+            "matrix_get_annotations" => {
+                // Scripts ask for the annotations of a message by sending
+                // its event id, the answer is a matrix_annotations signal.
+                if let Some(SignalData::String(event_id)) = &data {
+                    if let Ok(event_id) = EventId::parse(event_id.trim()) {
+                        let room = self
+                            .borrow()
+                            .values()
+                            .flat_map(|s| s.rooms())
+                            .find(|r| r.shows_event(&event_id));
+
+                        if let Some(room) = room {
+                            room.send_annotations_signal(&event_id);
+                        }
+                    }
+                }
+
+                return ReturnCode::Ok;
+            }
+            "window_switch" => {
+                // The signal carries the window, the room that is shown in it
+                // is the current buffer.
+                if let Some(room) = self.find_room(&Weechat::current_buffer()) {
+                    room.send_read_receipt();
+                }
+
+                return ReturnCode::Ok;
+            }
+            _ => (),
+        }
+
         if let Some(SignalData::Buffer(buffer)) = data {
             if let Some(room) = self.find_room(&buffer) {
-                room.update_typing_notice();
+                match signal_name {
+                    "buffer_switch" => {
+                        room.reset_activity();
+                        room.send_read_receipt();
+
+                        Weechat::bar_item_update("matrix_activity");
+                    }
+                    _ => room.update_typing_notice(),
+                }
             }
         }
         ReturnCode::Ok
@@ -173,6 +333,16 @@ struct Matrix {
     #[allow(dead_code)]
     typing_notice_signal: SignalHook,
     #[allow(dead_code)]
+    buffer_switch_signal: SignalHook,
+    #[allow(dead_code)]
+    window_switch_signal: SignalHook,
+    #[allow(dead_code)]
+    annotations_signal: SignalHook,
+    #[allow(dead_code)]
+    relay_connect_signal: SignalHook,
+    #[allow(dead_code)]
+    relay_disconnect_signal: SignalHook,
+    #[allow(dead_code)]
     completions: Completions,
     debug_buffer: RefCell<Option<BufferHandle>>,
 }
@@ -224,7 +394,7 @@ impl Plugin for Matrix {
         let config = ConfigHandle::new(&servers);
         let commands = Commands::hook_all(&servers, &config)?;
 
-        let bar_items = BarItems::hook_all(servers.clone())?;
+        let bar_items = BarItems::hook_all(servers.clone(), &config)?;
         let completions = Completions::hook_all(servers.clone())?;
 
         let subscriber = tracing_subscriber::registry()
@@ -242,12 +412,27 @@ impl Plugin for Matrix {
             }
         }
 
+        LastMessageCommand::bind_keys(&config);
+
         if servers.is_empty() {
             Matrix::create_default_server(servers.clone(), &config)
         }
 
         let typing = SignalHook::new("input_text_changed", servers.clone())
             .expect("Can't create signal hook for the typing notice cb");
+        let buffer_switch = SignalHook::new("buffer_switch", servers.clone())
+            .expect("Can't create signal hook for the buffer switch cb");
+        let window_switch = SignalHook::new("window_switch", servers.clone())
+            .expect("Can't create signal hook for the window switch cb");
+        let annotations =
+            SignalHook::new("matrix_get_annotations", servers.clone())
+                .expect("Can't create signal hook for the annotations cb");
+        let relay_connect =
+            SignalHook::new("relay_client_connected", servers.clone())
+                .expect("Can't create signal hook for the relay connect cb");
+        let relay_disconnect =
+            SignalHook::new("relay_client_disconnected", servers.clone())
+                .expect("Can't create signal hook for the relay disconnect cb");
 
         let plugin = Matrix {
             global_runtime,
@@ -258,6 +443,11 @@ impl Plugin for Matrix {
             completions,
             debug_buffer: RefCell::new(None),
             typing_notice_signal: typing,
+            buffer_switch_signal: buffer_switch,
+            window_switch_signal: window_switch,
+            annotations_signal: annotations,
+            relay_connect_signal: relay_connect,
+            relay_disconnect_signal: relay_disconnect,
         };
 
         Weechat::spawn(async move {
@@ -0,0 +1,144 @@
+//! A cache of downloaded attachments.
+//!
+//! Small attachments can be downloaded as soon as they arrive, controlled by
+//! the `network.auto_download_*` options, this way opening them is instant
+//! and the files stay available even if they get removed from the media
+//! repository of the homeserver.
+//!
+//! The files are stored in the server directory, named after their content
+//! URI. Files of encrypted rooms are stored encrypted, the way they were
+//! downloaded, and only decrypted when they are read from the cache, the keys
+//! to decrypt them are part of the events that reference them.
+//!
+//! Once the cache grows larger than the `network.media_cache_max_size`
+//! option allows, the files that were stored first are removed.
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::PathBuf,
+    rc::Rc,
+    time::SystemTime,
+};
+
+use matrix_sdk::{
+    crypto::AttachmentDecryptor, ruma::events::room::MediaSource,
+};
+
+#[derive(Clone, Debug)]
+pub struct MediaCache {
+    path: Rc<PathBuf>,
+}
+
+impl MediaCache {
+    /// Create a cache that stores its files in the given directory.
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path: Rc::new(path),
+        }
+    }
+
+    fn file_path(&self, source: &MediaSource) -> PathBuf {
+        let uri = match source {
+            MediaSource::Plain(uri) => uri.as_str(),
+            MediaSource::Encrypted(file) => file.url.as_str(),
+        };
+
+        let name: String = uri
+            .trim_start_matches("mxc://")
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+
+        self.path.join(name)
+    }
+
+    /// Is the content of the given media in the cache.
+    pub fn contains(&self, source: &MediaSource) -> bool {
+        self.file_path(source).is_file()
+    }
+
+    /// Get the content of the given media if it's in the cache, encrypted
+    /// media is decrypted.
+    pub fn get(&self, source: &MediaSource) -> Option<Vec<u8>> {
+        let data = fs::read(self.file_path(source)).ok()?;
+
+        match source {
+            MediaSource::Plain(_) => Some(data),
+            MediaSource::Encrypted(file) => {
+                let mut reader = io::Cursor::new(data);
+                let mut decryptor = AttachmentDecryptor::new(
+                    &mut reader,
+                    (**file).clone().into(),
+                )
+                .ok()?;
+                let mut decrypted = Vec::new();
+
+                decryptor.read_to_end(&mut decrypted).ok()?;
+
+                Some(decrypted)
+            }
+        }
+    }
+
+    /// Store the content of the given media in the cache, the content of
+    /// encrypted media needs to be stored as it was downloaded, encrypted.
+    ///
+    /// Files are removed from the cache afterwards until it isn't larger than
+    /// the given number of bytes.
+    pub fn insert(
+        &self,
+        source: &MediaSource,
+        data: &[u8],
+        max_size: u64,
+    ) -> io::Result<()> {
+        fs::create_dir_all(&*self.path)?;
+        fs::write(self.file_path(source), data)?;
+
+        self.evict(max_size)
+    }
+
+    /// Remove the oldest files from the cache until it isn't larger than the
+    /// given number of bytes.
+    fn evict(&self, max_size: u64) -> io::Result<()> {
+        let mut files: Vec<(SystemTime, u64, PathBuf)> =
+            fs::read_dir(&*self.path)?
+                .filter_map(|entry| {
+                    let entry = entry.ok()?;
+                    let metadata = entry.metadata().ok()?;
+
+                    metadata.is_file().then(|| {
+                        (
+                            metadata
+                                .modified()
+                                .unwrap_or(SystemTime::UNIX_EPOCH),
+                            metadata.len(),
+                            entry.path(),
+                        )
+                    })
+                })
+                .collect();
+
+        let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+
+        // Newest first, so the oldest files can be popped off.
+        files.sort_by(|a, b| b.0.cmp(&a.0));
+
+        while size > max_size {
+            if let Some((_, len, path)) = files.pop() {
+                fs::remove_file(path)?;
+                size -= len;
+            } else {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+//! Intentional mentions, as specified in MSC3952.
+//!
+//! Messages carry a `m.mentions` object in their content which lists the
+//! users that are mentioned, and if the whole room is mentioned. Clients
+//! should decide if a message highlights using this list instead of matching
+//! the body of the message against the user's name.
+
+use matrix_sdk::ruma::{
+    events::AnySyncTimelineEvent, serde::Raw, OwnedUserId, UserId,
+};
+use serde_json::{json, Value};
+
+/// The key of the mentions object inside of the event content.
+pub const MENTIONS_KEY: &str = "m.mentions";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Mentions {
+    /// The users that are mentioned.
+    pub user_ids: Vec<OwnedUserId>,
+    /// Is the whole room mentioned.
+    pub room: bool,
+}
+
+impl Mentions {
+    /// Get the mentions of the given event.
+    ///
+    /// Returns `None` if the event doesn't contain a mentions object, in
+    /// which case the body of the message needs to be used to detect
+    /// mentions.
+    pub fn from_event(event: &Raw<AnySyncTimelineEvent>) -> Option<Self> {
+        let content = event.get_field::<Value>("content").ok().flatten()?;
+
+        Self::from_json(content.get(MENTIONS_KEY)?)
+    }
+
+    fn from_json(mentions: &Value) -> Option<Self> {
+        let mentions = mentions.as_object()?;
+
+        let user_ids = mentions
+            .get("user_ids")
+            .and_then(|u| u.as_array())
+            .map(|u| {
+                u.iter()
+                    .filter_map(|u| u.as_str())
+                    .filter_map(|u| UserId::parse(u).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let room = mentions
+            .get("room")
+            .and_then(|r| r.as_bool())
+            .unwrap_or_default();
+
+        Some(Self { user_ids, room })
+    }
+
+    /// Is the given user in the list of mentioned users.
+    pub fn mentions_user(&self, user_id: &UserId) -> bool {
+        self.user_ids.iter().any(|u| u == user_id)
+    }
+
+    /// Convert the mentions to the JSON object that goes into the event
+    /// content.
+    pub fn to_json(&self) -> Value {
+        let mut mentions = json!({});
+
+        if !self.user_ids.is_empty() {
+            mentions["user_ids"] = json!(self.user_ids);
+        }
+
+        if self.room {
+            mentions["room"] = json!(true);
+        }
+
+        mentions
+    }
+}
@@ -0,0 +1,223 @@
+//! Mute timers.
+//!
+//! Rooms, or all the rooms of a server, can be muted for a limited amount of
+//! time. Muted buffers aren't added to the hotlist and don't notify until the
+//! timer expires.
+//!
+//! The timers are stored in the server directory so they survive restarts,
+//! together with the notify levels the rooms had before they got muted so
+//! the levels can be restored once the timers expire.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use serde_json::{json, Value};
+use tracing::error;
+
+#[derive(Debug, Default)]
+pub struct MuteTimers {
+    path: PathBuf,
+    server: Option<SystemTime>,
+    rooms: HashMap<OwnedRoomId, SystemTime>,
+    /// The notify levels of the muted room buffers from before they got
+    /// muted.
+    levels: HashMap<OwnedRoomId, String>,
+}
+
+impl MuteTimers {
+    fn to_timestamp(time: &SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+
+    fn from_timestamp(timestamp: &Value) -> Option<SystemTime> {
+        timestamp
+            .as_u64()
+            .map(|t| UNIX_EPOCH + Duration::from_secs(t))
+    }
+
+    /// Load the mute timers from the given file.
+    ///
+    /// A missing or invalid file results in no active timers.
+    pub fn load(path: PathBuf) -> Self {
+        let value: Value = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|c| serde_json::from_str(&c).ok())
+            .unwrap_or_default();
+
+        let server = Self::from_timestamp(&value["server"]);
+        let rooms = value["rooms"]
+            .as_object()
+            .map(|rooms| {
+                rooms
+                    .iter()
+                    .filter_map(|(room_id, until)| {
+                        Some((
+                            RoomId::parse(room_id).ok()?,
+                            Self::from_timestamp(until)?,
+                        ))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let levels = value["levels"]
+            .as_object()
+            .map(|levels| {
+                levels
+                    .iter()
+                    .filter_map(|(room_id, level)| {
+                        Some((
+                            RoomId::parse(room_id).ok()?,
+                            level.as_str()?.to_owned(),
+                        ))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut timers = Self {
+            path,
+            server,
+            rooms,
+            levels,
+        };
+        timers.expire();
+
+        timers
+    }
+
+    fn save(&self) {
+        let rooms: serde_json::Map<String, Value> = self
+            .rooms
+            .iter()
+            .map(|(r, t)| (r.to_string(), json!(Self::to_timestamp(t))))
+            .collect();
+
+        let value = json!({
+            "server": self.server.as_ref().map(Self::to_timestamp),
+            "rooms": rooms,
+            "levels": self.levels,
+        });
+
+        let ret = self
+            .path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&self.path, value.to_string()));
+
+        if let Err(e) = ret {
+            error!("Error saving the mute timers: {}", e);
+        }
+    }
+
+    /// Get the time at which a timer with the given duration expires.
+    fn expiry(duration: Duration) -> Result<SystemTime, String> {
+        SystemTime::now()
+            .checked_add(duration)
+            .ok_or_else(|| "The mute duration is too long".to_owned())
+    }
+
+    /// Mute the whole server for the given duration, or unmute it if no
+    /// duration is given.
+    pub fn mute_server(
+        &mut self,
+        duration: Option<Duration>,
+    ) -> Result<(), String> {
+        self.server = duration.map(Self::expiry).transpose()?;
+        self.save();
+
+        Ok(())
+    }
+
+    /// Mute a room for the given duration, or unmute it if no duration is
+    /// given.
+    pub fn mute_room(
+        &mut self,
+        room_id: &RoomId,
+        duration: Option<Duration>,
+    ) -> Result<(), String> {
+        if let Some(d) = duration {
+            self.rooms.insert(room_id.to_owned(), Self::expiry(d)?);
+        } else {
+            self.rooms.remove(room_id);
+        }
+
+        self.save();
+
+        Ok(())
+    }
+
+    /// Remember the notify level a room buffer had before it got muted.
+    ///
+    /// A level that is already remembered is kept, the buffer might have
+    /// been muted before a restart.
+    pub fn save_level(&mut self, room_id: &RoomId, level: String) {
+        if !self.levels.contains_key(room_id) {
+            self.levels.insert(room_id.to_owned(), level);
+            self.save();
+        }
+    }
+
+    /// Take the notify level a room buffer had before it got muted.
+    pub fn take_level(&mut self, room_id: &RoomId) -> Option<String> {
+        let level = self.levels.remove(room_id);
+
+        if level.is_some() {
+            self.save();
+        }
+
+        level
+    }
+
+    fn is_active(until: Option<&SystemTime>) -> bool {
+        until.map_or(false, |u| *u > SystemTime::now())
+    }
+
+    pub fn is_server_muted(&self) -> bool {
+        Self::is_active(self.server.as_ref())
+    }
+
+    /// Is the given room muted, either by itself or because the whole server
+    /// is muted.
+    pub fn is_room_muted(&self, room_id: &RoomId) -> bool {
+        self.is_server_muted() || Self::is_active(self.rooms.get(room_id))
+    }
+
+    /// Remove all the timers that expired.
+    ///
+    /// Returns true if any timer was removed.
+    pub fn expire(&mut self) -> bool {
+        let server_expired = self.server.is_some() && !self.is_server_muted();
+
+        if server_expired {
+            self.server = None;
+        }
+
+        let room_count = self.rooms.len();
+        self.rooms.retain(|_, until| Self::is_active(Some(until)));
+
+        let expired = server_expired || room_count != self.rooms.len();
+
+        if expired {
+            self.save();
+        }
+
+        expired
+    }
+
+    /// The time that is left until each of the active timers expires.
+    pub fn remaining(&self) -> Vec<Duration> {
+        let now = SystemTime::now();
+
+        self.server
+            .iter()
+            .chain(self.rooms.values())
+            .filter_map(|until| until.duration_since(now).ok())
+            .collect()
+    }
+}
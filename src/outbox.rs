@@ -0,0 +1,140 @@
+//! Outbox buffer module.
+//!
+//! Every server has an outbox buffer that lists the messages of its rooms
+//! which are still waiting to be sent out, are being sent out, or which failed
+//! to be sent.
+//!
+//! The outbox is a snapshot of the outgoing message queues of the rooms, it
+//! gets refreshed every time the outbox command is used. Messages are
+//! referenced by their number in the last snapshot.
+//!
+//! The queues are stored in the `outbox` directory of the server, messages
+//! that weren't sent out before WeeChat quit show up as failed messages after
+//! a restart and can be retried or cancelled.
+
+use std::{cell::RefCell, rc::Rc};
+
+use matrix_sdk::ruma::OwnedTransactionId;
+use weechat::{
+    buffer::{Buffer, BufferBuilder, BufferHandle},
+    Weechat,
+};
+
+use crate::{
+    room::{OutgoingState, RoomHandle},
+    utils::format_duration,
+};
+
+#[derive(Clone, Default)]
+pub struct Outbox {
+    buffer: Rc<RefCell<Option<BufferHandle>>>,
+    entries: Rc<RefCell<Vec<(RoomHandle, OwnedTransactionId)>>>,
+}
+
+impl Outbox {
+    fn create_buffer(server_name: &str) -> BufferHandle {
+        let buffer_handle =
+            BufferBuilder::new(&format!("outbox.{}", server_name))
+                .build()
+                .expect("Can't create Matrix outbox buffer");
+
+        let buffer = buffer_handle
+            .upgrade()
+            .expect("Can't upgrade newly created outbox buffer");
+
+        buffer.set_title(&format!(
+            "Outgoing messages of {}, use /matrix outbox retry|cancel|edit \
+             <number> to manage them",
+            server_name
+        ));
+        buffer.set_short_name(&format!("outbox.{}", server_name));
+        buffer.set_localvar("server", server_name);
+
+        buffer_handle
+    }
+
+    fn buffer(&self, server_name: &str) -> Buffer {
+        let mut buffer = self.buffer.borrow_mut();
+
+        if let Some(b) = buffer.as_ref().and_then(|b| b.upgrade().ok()) {
+            return b;
+        }
+
+        let handle = Self::create_buffer(server_name);
+        *buffer = Some(handle.clone());
+
+        handle.upgrade().expect("Can't upgrade the outbox buffer")
+    }
+
+    fn format_state(state: &OutgoingState) -> String {
+        match state {
+            OutgoingState::Delayed => "delayed".to_owned(),
+            OutgoingState::Sending => "sending".to_owned(),
+            OutgoingState::Failed(e) => format!(
+                "{}failed{}: {}",
+                Weechat::color("red"),
+                Weechat::color("reset"),
+                e
+            ),
+        }
+    }
+
+    /// Refresh the outbox buffer with the outgoing messages of the given
+    /// rooms and switch to it.
+    pub fn show(&self, server_name: &str, rooms: &[RoomHandle]) {
+        let buffer = self.buffer(server_name);
+        let mut entries = self.entries.borrow_mut();
+
+        entries.clear();
+        buffer.clear();
+
+        for room in rooms {
+            let room_name = room
+                .buffer_handle()
+                .upgrade()
+                .map(|b| b.short_name().to_string())
+                .unwrap_or_else(|_| room.room_id().to_string());
+
+            for (transaction_id, message) in room.outgoing_messages() {
+                entries.push((room.clone(), transaction_id));
+
+                buffer.print(&format!(
+                    "{:>3}. {}{}{} [{}, {}] {}",
+                    entries.len(),
+                    Weechat::color("chat_channel"),
+                    room_name,
+                    Weechat::color("reset"),
+                    format_duration(message.queued_at.elapsed()),
+                    Self::format_state(&message.state),
+                    message.content.body().lines().next().unwrap_or_default(),
+                ));
+            }
+        }
+
+        if entries.is_empty() {
+            buffer.print("No outgoing messages");
+        }
+
+        buffer.switch_to();
+    }
+
+    /// Is the given buffer the outbox buffer.
+    pub fn is_buffer(&self, buffer: &Buffer) -> bool {
+        self.buffer
+            .borrow()
+            .as_ref()
+            .and_then(|b| b.upgrade().ok())
+            .map_or(false, |b| &b == buffer)
+    }
+
+    /// Get the room and transaction id of the message with the given number
+    /// in the last outbox listing.
+    pub fn entry(
+        &self,
+        number: usize,
+    ) -> Option<(RoomHandle, OwnedTransactionId)> {
+        number
+            .checked_sub(1)
+            .and_then(|n| self.entries.borrow().get(n).cloned())
+    }
+}
@@ -0,0 +1,211 @@
+//! Evaluation of the push rules of the account.
+//!
+//! The server decides which events send a push notification and which of
+//! them highlight using the push rules of the account. Those rules are
+//! evaluated for the events we receive as well, this way keyword rules, room
+//! specific rules and @room mentions highlight the same messages in WeeChat
+//! that other clients notify about.
+//!
+//! The rules are fetched once we're logged in and kept up to date using the
+//! `m.push_rules` account data of the sync responses.
+//!
+//! The notification mode of a room is stored in the push rules as well, the
+//! same way Element stores it: a room rule that doesn't notify limits the
+//! notifications to mentions and keywords, an override rule that doesn't
+//! notify mutes the room.
+
+use matrix_sdk::{
+    room::Joined,
+    ruma::{
+        api::client::push::get_pushrules_all::v3::Request as PushRulesRequest,
+        events::{
+            room::power_levels::RoomPowerLevelsEventContent,
+            AnyGlobalAccountDataEvent, AnySyncStateEvent, StateEventType,
+            SyncStateEvent,
+        },
+        push::{Action, PushConditionRoomCtx, Ruleset, Tweak},
+        serde::Raw,
+        RoomId, UInt, UserId,
+    },
+    Client,
+};
+use tracing::error;
+
+/// What the push rules of the account say about an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PushOutcome {
+    /// Should the event notify the user.
+    pub notify: bool,
+    /// Should the event highlight.
+    pub highlight: bool,
+}
+
+impl PushOutcome {
+    /// Evaluate the push rules for the given event.
+    ///
+    /// # Arguments
+    ///
+    /// * `rules` - The push rules of the account.
+    ///
+    /// * `event` - The event the rules should be evaluated for.
+    ///
+    /// * `context` - The context of the room the event was sent to.
+    pub fn evaluate<T>(
+        rules: &Ruleset,
+        event: &Raw<T>,
+        context: &PushConditionRoomCtx,
+    ) -> Self {
+        let actions = rules.get_actions(event, context);
+
+        Self {
+            notify: actions.iter().any(|a| matches!(a, Action::Notify)),
+            highlight: actions
+                .iter()
+                .any(|a| matches!(a, Action::SetTweak(Tweak::Highlight(true)))),
+        }
+    }
+}
+
+/// How the push rules of the account notify about the messages of a room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomNotificationMode {
+    /// Every message notifies, the default.
+    All,
+    /// Only messages that mention us or contain one of our keywords notify.
+    Mentions,
+    /// No message notifies.
+    Mute,
+}
+
+impl RoomNotificationMode {
+    /// The names of the modes, as accepted by `/notify`.
+    pub const NAMES: &'static [&'static str] = &["all", "mentions", "mute"];
+
+    /// Get the notification mode of the given room out of the push rules.
+    pub fn from_rules(rules: &Ruleset, room_id: &RoomId) -> Self {
+        let silent = |actions: &[Action]| {
+            !actions.iter().any(|a| matches!(a, Action::Notify))
+        };
+
+        if rules.override_.iter().any(|r| {
+            r.enabled
+                && r.rule_id.as_str() == room_id.as_str()
+                && silent(&r.actions)
+        }) {
+            Self::Mute
+        } else if rules.room.iter().any(|r| {
+            r.enabled
+                && r.rule_id.as_str() == room_id.as_str()
+                && silent(&r.actions)
+        }) {
+            Self::Mentions
+        } else {
+            Self::All
+        }
+    }
+
+    /// Parse the name of a mode.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "all" => Some(Self::All),
+            "mentions" => Some(Self::Mentions),
+            "mute" => Some(Self::Mute),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::All => "all",
+            Self::Mentions => "mentions",
+            Self::Mute => "mute",
+        }
+    }
+
+    /// Describe the mode for the user.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::All => "all messages notify",
+            Self::Mentions => "only mentions and keywords notify",
+            Self::Mute => "no message notifies",
+        }
+    }
+}
+
+/// Fetch the push rules of the account from the server.
+pub async fn fetch(client: &Client) -> Option<Ruleset> {
+    match client.send(PushRulesRequest::new(), None).await {
+        Ok(response) => Some(response.global),
+        Err(e) => {
+            error!("Error fetching the push rules: {}", e);
+            None
+        }
+    }
+}
+
+/// Get the push rules out of the global account data of a sync response,
+/// `None` if they didn't change.
+pub fn from_account_data(
+    events: &[Raw<AnyGlobalAccountDataEvent>],
+) -> Option<Ruleset> {
+    events
+        .iter()
+        .filter_map(|e| e.deserialize().ok())
+        .filter_map(|e| {
+            if let AnyGlobalAccountDataEvent::PushRules(e) = e {
+                Some(e.content.global)
+            } else {
+                None
+            }
+        })
+        .last()
+}
+
+/// Build the context the conditions of the push rules are evaluated in for
+/// the given room.
+///
+/// # Arguments
+///
+/// * `room` - The room the events were sent to.
+///
+/// * `own_user_id` - Our own user id, used to find our display name in the
+/// room.
+pub async fn room_context(
+    room: &Joined,
+    own_user_id: &UserId,
+) -> PushConditionRoomCtx {
+    let user_display_name = room
+        .get_member_no_sync(own_user_id)
+        .await
+        .ok()
+        .flatten()
+        .map(|m| m.name().to_owned())
+        .unwrap_or_else(|| own_user_id.localpart().to_owned());
+
+    let power_levels = room
+        .get_state_event(StateEventType::RoomPowerLevels, "")
+        .await
+        .ok()
+        .flatten()
+        .and_then(|e| e.deserialize().ok())
+        .and_then(|e| {
+            if let AnySyncStateEvent::RoomPowerLevels(
+                SyncStateEvent::Original(e),
+            ) = e
+            {
+                Some(e.content)
+            } else {
+                None
+            }
+        })
+        .unwrap_or_else(RoomPowerLevelsEventContent::default);
+
+    PushConditionRoomCtx {
+        room_id: room.room_id().to_owned(),
+        member_count: UInt::new_saturating(room.joined_members_count()),
+        user_display_name,
+        users_power_levels: power_levels.users,
+        default_power_level: power_levels.users_default,
+        notification_power_levels: power_levels.notifications,
+    }
+}
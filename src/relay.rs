@@ -0,0 +1,33 @@
+//! Tracking of attached relay clients.
+//!
+//! When WeeChat runs as a bouncer the user reads messages through a relay
+//! client, e.g. a mobile app. The relay client takes care of notifications
+//! while it's attached. Relay clients don't switch the current buffer, so
+//! what the user views through them can't be told apart from the buffer of
+//! the local interface.
+//!
+//! The number of attached clients is updated from the relay signals, clients
+//! that were already attached before the plugin got loaded aren't counted.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static ATTACHED_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Remember that a relay client got attached.
+pub fn client_connected() {
+    ATTACHED_CLIENTS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Remember that a relay client got detached.
+pub fn client_disconnected() {
+    let _ = ATTACHED_CLIENTS.fetch_update(
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+        |c| Some(c.saturating_sub(1)),
+    );
+}
+
+/// Is at least one relay client attached.
+pub fn is_attached() -> bool {
+    ATTACHED_CLIENTS.load(Ordering::SeqCst) > 0
+}
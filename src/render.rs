@@ -1,28 +1,46 @@
+use std::{borrow::Cow, collections::BTreeSet, time::Duration};
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    Local, TimeZone,
+};
 use url::Url;
 
 use matrix_sdk::ruma::{
     events::{
         room::{
             encrypted::RoomEncryptedEventContent,
+            history_visibility::HistoryVisibility,
             member::{MembershipChange, RoomMemberEventContent},
             message::{
                 AudioMessageEventContent, EmoteMessageEventContent,
                 FileMessageEventContent, ImageMessageEventContent,
+                KeyVerificationRequestEventContent,
                 LocationMessageEventContent, NoticeMessageEventContent,
                 RedactedRoomMessageEventContent,
                 ServerNoticeMessageEventContent, TextMessageEventContent,
                 VideoMessageEventContent,
             },
+            power_levels::RoomPowerLevelsEventContent,
             EncryptedFile, MediaSource,
         },
-        OriginalSyncStateEvent, RedactedSyncMessageLikeEvent,
+        AnySyncStateEvent, OriginalSyncStateEvent,
+        RedactedSyncMessageLikeEvent, SyncStateEvent,
     },
-    uint, EventId, MilliSecondsSinceUnixEpoch, MxcUri, TransactionId, UserId,
+    uint, EventId, MilliSecondsSinceUnixEpoch, MxcUri, OwnedUserId,
+    TransactionId, UserId,
 };
 
 use weechat::{Prefix, Weechat};
 
-use crate::{room::WeechatRoomMember, utils::ToTag};
+use crate::{
+    emoji::replace_with_shortcodes,
+    room::WeechatRoomMember,
+    utils::{
+        escape_spoofing_chars, limit_word_length, replace_word, unescape_html,
+        ToTag,
+    },
+};
 
 /// The rendered version of an event.
 pub struct RenderedEvent {
@@ -36,11 +54,299 @@ impl RenderedEvent {
     const MSG_TAGS: &'static [&'static str] = &["notify_message"];
     const SELF_TAGS: &'static [&'static str] =
         &["notify_none", "no_highlight", "self_msg"];
+    const EDITED_TAGS: &'static [&'static str] = &["matrix_edited"];
+    const QUOTE_TAGS: &'static [&'static str] =
+        &["matrix_reply_quote", "no_highlight"];
+
+    /// Words describing the type of a message, for messages whose type is
+    /// otherwise only conveyed by colors or markers.
+    const TYPE_DESCRIPTIONS: &'static [(&'static str, &'static str)] = &[
+        ("matrix_redacted", "redacted"),
+        ("matrix_encrypted", "encrypted"),
+        ("matrix_edited", "edited"),
+        ("matrix_notice", "notice"),
+        ("matrix_server_notice", "server notice"),
+    ];
 
     pub fn add_self_tags(self) -> Self {
         self.add_tags(Self::SELF_TAGS)
     }
 
+    pub fn add_edited_tags(self) -> Self {
+        self.add_tags(Self::EDITED_TAGS)
+    }
+
+    /// Append the given marker to the last line of the event, telling the
+    /// reader that the content of the event was edited.
+    pub fn add_edited_marker(mut self, marker: &str) -> Self {
+        if marker.is_empty() {
+            return self;
+        }
+
+        if let Some(line) = self.content.lines.last_mut() {
+            line.message = format!(
+                "{} {}{}{}",
+                line.message,
+                Weechat::color("chat_delimiters"),
+                marker,
+                Weechat::color("reset"),
+            );
+        }
+
+        self
+    }
+
+    /// Prepend the timestamp of the event, formatted using the given strftime
+    /// format, to the first line of the event.
+    ///
+    /// This is done only if the event wasn't sent today, since WeeChat shows
+    /// only the time of day for lines by default.
+    ///
+    /// An invalid format is replaced by the default format of the
+    /// `look.backfill_time_format` option.
+    pub fn add_date(mut self, format: &str) -> Self {
+        if format.is_empty() {
+            return self;
+        }
+
+        let format = valid_strftime_format(format);
+
+        let date = if let Some(date) =
+            Local.timestamp_opt(self.message_timestamp, 0).single()
+        {
+            date
+        } else {
+            return self;
+        };
+
+        if date.date() == Local::today() {
+            return self;
+        }
+
+        if let Some(line) = self.content.lines.first_mut() {
+            line.message = format!(
+                "{}[{}]{} {}",
+                Weechat::color("chat_delimiters"),
+                date.format(format),
+                Weechat::color("reset"),
+                line.message
+            );
+        }
+
+        self
+    }
+
+    /// Escape characters that can be used to spoof nicks or confuse the
+    /// terminal in the prefix and the lines of the event.
+    pub fn escape_spoofing_chars(mut self) -> Self {
+        self.prefix = escape_spoofing_chars(&self.prefix).into_owned();
+
+        for line in &mut self.content.lines {
+            if let Cow::Owned(escaped) = escape_spoofing_chars(&line.message) {
+                line.message = escaped;
+            }
+        }
+
+        self
+    }
+
+    /// Break up or truncate the words of the event that are longer than the
+    /// given number of characters.
+    ///
+    /// If a word was truncated the last line points to `/matrix source`,
+    /// which shows the whole message.
+    pub fn limit_word_length(mut self, max: usize, truncate: bool) -> Self {
+        let mut truncated = false;
+
+        for line in &mut self.content.lines {
+            if let (Cow::Owned(limited), t) =
+                limit_word_length(&line.message, max, truncate)
+            {
+                line.message = limited;
+                truncated |= t;
+            }
+        }
+
+        if truncated {
+            if let Some(line) = self.content.lines.last_mut() {
+                line.message.push_str(&format!(
+                    " {}(truncated, see /matrix source){}",
+                    Weechat::color("chat_delimiters"),
+                    Weechat::color("reset")
+                ));
+            }
+        }
+
+        self
+    }
+
+    /// Make the event readable on braille displays and screen readers.
+    ///
+    /// Colors are removed, emoji are replaced with their shortcodes, and the
+    /// first line gets prefixed with a word describing the type of the
+    /// message if the type would otherwise only be conveyed with colors.
+    pub fn make_accessible(mut self) -> Self {
+        self.prefix = Weechat::remove_color(&self.prefix);
+
+        for (i, line) in self.content.lines.iter_mut().enumerate() {
+            let message =
+                replace_with_shortcodes(&Weechat::remove_color(&line.message));
+
+            let description = Self::TYPE_DESCRIPTIONS
+                .iter()
+                .find(|(tag, _)| line.tags.iter().any(|t| t == tag))
+                .map(|(_, description)| description);
+
+            line.message = match description {
+                Some(description) if i == 0 => {
+                    format!("{}: {}", description, message)
+                }
+                _ => message,
+            };
+        }
+
+        self
+    }
+
+    /// Show the whole event, including the prefix, in the given color, e.g.
+    /// to dim a local echo until the server confirms it.
+    ///
+    /// An empty color leaves the event as it is.
+    pub fn colorize(mut self, color: &str) -> Self {
+        if color.is_empty() {
+            return self;
+        }
+
+        let color = Weechat::color(color);
+
+        self.prefix =
+            format!("{}{}", color, Weechat::remove_color(&self.prefix));
+
+        for line in &mut self.content.lines {
+            line.message = format!(
+                "{}{}{}",
+                color,
+                Weechat::remove_color(&line.message),
+                Weechat::color("reset")
+            );
+        }
+
+        self
+    }
+
+    /// Append the given number of spaces to the prefix, with a right aligned
+    /// prefix this moves the nick to the left.
+    pub fn pad_prefix(mut self, padding: usize) -> Self {
+        self.prefix.push_str(&" ".repeat(padding));
+        self
+    }
+
+    /// Color the `@room` mentions in the event and tag it as a room mention,
+    /// `@room` as part of a longer word, e.g. `foo@roomy`, is left alone.
+    ///
+    /// If `highlight` is set the event is additionally tagged so WeeChat
+    /// highlights it.
+    pub fn mark_room_mention(mut self, color: &str, highlight: bool) -> Self {
+        let mention = format!("{}@room{}", color, Weechat::color("resetcolor"));
+
+        for line in &mut self.content.lines {
+            line.message = replace_word(&line.message, "@room", &mention);
+        }
+
+        if highlight {
+            self.add_tags(&["matrix_room_mention", "notify_highlight"])
+        } else {
+            self.add_tags(&["matrix_room_mention"])
+        }
+    }
+
+    /// Color the text of user pills in the event, text that only contains
+    /// the pill text as part of a longer word is left alone.
+    ///
+    /// # Arguments
+    ///
+    /// * `pills` - The text of every pill with the WeeChat color it should
+    /// be shown in.
+    pub fn color_pills(mut self, pills: &[(String, String)]) -> Self {
+        for (text, color) in pills.iter().filter(|(t, _)| !t.is_empty()) {
+            let pill =
+                format!("{}{}{}", color, text, Weechat::color("resetcolor"));
+
+            for line in &mut self.content.lines {
+                line.message = replace_word(&line.message, text, &pill);
+            }
+        }
+
+        self
+    }
+
+    /// Tag the event so WeeChat highlights it.
+    pub fn mark_mention(self) -> Self {
+        self.add_tags(&["notify_highlight"])
+    }
+
+    /// Tag the event so WeeChat doesn't highlight it.
+    pub fn suppress_highlight(self) -> Self {
+        self.add_tags(&["no_highlight"])
+    }
+
+    /// Lower the notify level of the message so it only adds the buffer to
+    /// the hotlist with a low priority.
+    pub fn lower_notify_level(mut self) -> Self {
+        for line in &mut self.content.lines {
+            for tag in line.tags.iter_mut().filter(|t| *t == "notify_message") {
+                *tag = "notify_low".to_owned();
+            }
+        }
+
+        self
+    }
+
+    /// Add a quote of the message the event replies to above the event.
+    ///
+    /// Only the first `MAX_QUOTE_LINES` lines of the quote are shown, the
+    /// quote lines can't highlight.
+    pub fn add_reply_quote(mut self, sender: &str, quote: &[&str]) -> Self {
+        const MAX_QUOTE_LINES: usize = 3;
+
+        let mut tags = self
+            .content
+            .lines
+            .first()
+            .map(|l| l.tags.clone())
+            .unwrap_or_default();
+        tags.extend(Self::QUOTE_TAGS.iter().map(|t| t.to_string()));
+
+        let quote_lines =
+            quote
+                .iter()
+                .take(MAX_QUOTE_LINES)
+                .enumerate()
+                .map(|(i, line)| {
+                    let message = if i == 0 {
+                        format!("{}: {}", sender, line)
+                    } else {
+                        line.to_string()
+                    };
+
+                    RenderedLine {
+                        message: format!(
+                            "{}> {}{}",
+                            Weechat::color("darkgray"),
+                            message,
+                            Weechat::color("reset")
+                        ),
+                        tags: tags.clone(),
+                    }
+                });
+
+        self.content
+            .lines
+            .splice(0..0, quote_lines.collect::<Vec<_>>());
+
+        self
+    }
+
     pub fn add_msg_tags(self) -> Self {
         self.add_tags(Self::MSG_TAGS)
     }
@@ -164,13 +470,6 @@ pub trait Render {
         let uuid_tag = format!("matrix_echo_{}", uuid.to_string());
 
         for line in &mut content.lines {
-            let message = Weechat::remove_color(&line.message);
-            line.message = format!(
-                "{}{}{}",
-                Weechat::color_pair("darkgray", "default"),
-                message,
-                Weechat::color("reset")
-            );
             line.tags.push(uuid_tag.clone())
         }
 
@@ -277,6 +576,46 @@ impl Render for NoticeMessageEventContent {
     }
 }
 
+impl Render for KeyVerificationRequestEventContent {
+    const TAGS: &'static [&'static str] = &["matrix_verification_request"];
+    /// The sender of the request and if the request is meant for us.
+    type RenderContext = (WeechatRoomMember, bool);
+
+    fn prefix(&self, _: &WeechatRoomMember) -> String {
+        Weechat::prefix(Prefix::Network)
+    }
+
+    fn render(&self, context: &Self::RenderContext) -> RenderedContent {
+        let (sender, to_us) = context;
+
+        let message = if *to_us {
+            format!(
+                "{} wants to verify your devices \
+                {color_delim}[{color_reset}/verification accept{color_delim}]\
+                {color_reset} \
+                {color_delim}[{color_reset}/verification decline{color_delim}]\
+                {color_reset}",
+                sender.nick(),
+                color_delim = Weechat::color("chat_delimiters"),
+                color_reset = Weechat::color("reset"),
+            )
+        } else {
+            format!(
+                "{} wants to verify the devices of {}",
+                sender.nick(),
+                self.to
+            )
+        };
+
+        let line = RenderedLine {
+            message,
+            tags: self.tags(),
+        };
+
+        RenderedContent { lines: vec![line] }
+    }
+}
+
 impl Render for ServerNoticeMessageEventContent {
     const TAGS: &'static [&'static str] = &["matrix_server_notice"];
     type RenderContext = WeechatRoomMember;
@@ -305,6 +644,20 @@ impl Render for ServerNoticeMessageEventContent {
     }
 }
 
+/// The default strftime format of the `look.backfill_time_format` option.
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+/// Check that the given strftime format is valid, the default date format is
+/// returned instead if it isn't, formatting a date with an invalid format
+/// panics.
+fn valid_strftime_format(format: &str) -> &str {
+    if StrftimeItems::new(format).any(|i| matches!(i, Item::Error)) {
+        DEFAULT_DATE_FORMAT
+    } else {
+        format
+    }
+}
+
 /// Create an HTTP download path from a matrix content URI
 fn mxc_to_http_download_path(
     mxc_url: Url,
@@ -407,10 +760,19 @@ impl<C: HasUrlOrFile> Render for C {
         }
         .unwrap_or_else(|_| self.resolve_url().to_string());
 
+        let duration = self
+            .duration()
+            .map(|d| {
+                let seconds = d.as_secs();
+                format!(" ({}:{:02})", seconds / 60, seconds % 60)
+            })
+            .unwrap_or_default();
+
         let message = format!(
-            "{color_delimiter}<{color_reset}{}{color_delimiter}>\
+            "{color_delimiter}<{color_reset}{}{}{color_delimiter}>\
                 [{color_reset}{}{color_delimiter}]{color_reset}",
             self.body(),
+            duration,
             mxc_url,
             color_delimiter = Weechat::color("color_delimiter"),
             color_reset = Weechat::color("reset")
@@ -450,16 +812,22 @@ impl Render for RoomEncryptedEventContent {
 }
 
 impl Render for RedactedSyncMessageLikeEvent<RedactedRoomMessageEventContent> {
-    type RenderContext = WeechatRoomMember;
+    /// The member that redacted the event, if known.
+    type RenderContext = Option<WeechatRoomMember>;
     const TAGS: &'static [&'static str] = &["matrix_redacted"];
 
     fn render(&self, redacter: &Self::RenderContext) -> RenderedContent {
+        let redacter = redacter
+            .as_ref()
+            .map(|r| r.nick())
+            .unwrap_or_else(|| "unknown".to_owned());
+
         // TODO: add the redaction reason.
         let message = format!(
             "{}<{}Message redacted by: {}{}>{}",
             Weechat::color("chat_delimiters"),
             Weechat::color("logger.color.backlog_line"),
-            redacter.nick(),
+            redacter,
             Weechat::color("chat_delimiters"),
             Weechat::color("reset"),
         );
@@ -522,12 +890,18 @@ pub trait HasUrlOrFile {
     fn encrypted_file(&self) -> Option<&EncryptedFile>;
 
     fn source(&self) -> &MediaSource;
+
+    /// The play time of audio and video messages.
+    fn duration(&self) -> Option<Duration>;
 }
 
 // Same as above: a simple macro to implement the trait for structs with `url`
 // and `file` fields.
 macro_rules! has_url_or_file {
     ($content: ident) => {
+        has_url_or_file!($content, |_: &$content| None);
+    };
+    ($content: ident, $duration: expr) => {
         impl HasUrlOrFile for $content {
             fn body(&self) -> &str {
                 &self.body
@@ -551,6 +925,10 @@ macro_rules! has_url_or_file {
                     _ => None,
                 }
             }
+
+            fn duration(&self) -> Option<Duration> {
+                ($duration)(self)
+            }
         }
     };
 }
@@ -560,19 +938,379 @@ has_formatted_body!(EmoteMessageEventContent);
 has_formatted_body!(NoticeMessageEventContent);
 has_formatted_body!(TextMessageEventContent);
 
-has_url_or_file!(AudioMessageEventContent);
+has_url_or_file!(AudioMessageEventContent, |c: &AudioMessageEventContent| c
+    .info
+    .as_ref()
+    .and_then(|i| i.duration));
 has_url_or_file!(FileMessageEventContent);
 has_url_or_file!(ImageMessageEventContent);
-has_url_or_file!(VideoMessageEventContent);
+has_url_or_file!(VideoMessageEventContent, |c: &VideoMessageEventContent| c
+    .info
+    .as_ref()
+    .and_then(|i| i.duration));
+
+/// Render a HTML formatted body into a string that can be printed in Weechat.
+///
+/// Only a small subset of HTML is supported, line breaks, paragraphs and list
+/// items are converted to new lines, bold, italic and underline tags are
+/// converted to the corresponding Weechat attributes while other tags are
+/// stripped.
+pub fn render_html(html: &str) -> String {
+    let mut output = String::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        let end = if let Some(end) = rest[start..].find('>') {
+            start + end
+        } else {
+            break;
+        };
 
-/// Rendering implementation for membership events (joins, leaves, bans, profile
-/// changes, etc).
+        output.push_str(&unescape_html(&rest[..start]));
+
+        let tag = rest[start + 1..end].trim().to_lowercase();
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+
+        match (name, closing) {
+            ("br", _) | ("p", true) | ("li", true) | ("div", true) => {
+                output.push('\n')
+            }
+            ("li", false) => output.push_str("• "),
+            ("b", false) | ("strong", false) => {
+                output.push_str(&Weechat::color("bold"))
+            }
+            ("b", true) | ("strong", true) => {
+                output.push_str(&Weechat::color("-bold"))
+            }
+            ("i", false) | ("em", false) => {
+                output.push_str(&Weechat::color("italic"))
+            }
+            ("i", true) | ("em", true) => {
+                output.push_str(&Weechat::color("-italic"))
+            }
+            ("u", false) => output.push_str(&Weechat::color("underline")),
+            ("u", true) => output.push_str(&Weechat::color("-underline")),
+            _ => (),
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    output.push_str(&unescape_html(rest));
+
+    output.trim_end_matches('\n').to_owned()
+}
+
+/// Decode the percent encoded characters of a matrix.to link.
+fn percent_decode(text: &str) -> String {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = if byte == b'%' && tail.len() >= 2 {
+            std::str::from_utf8(&tail[..2])
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+        } else {
+            None
+        };
+
+        if let Some(decoded) = decoded {
+            bytes.push(decoded);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Find the user pills, links to matrix.to user permalinks, in a HTML
+/// formatted body.
+///
+/// Returns the user id every pill links to with the text of the pill, the
+/// pills of a reply fallback are skipped since they only name the sender of
+/// the replied to message.
+pub fn user_pills(html: &str) -> Vec<(OwnedUserId, String)> {
+    const PERMALINK: &str = "https://matrix.to/#/";
+
+    let html = match (html.find("<mx-reply>"), html.find("</mx-reply>")) {
+        (Some(start), Some(end)) if start < end => Cow::Owned(format!(
+            "{}{}",
+            &html[..start],
+            &html[end + "</mx-reply>".len()..]
+        )),
+        _ => Cow::Borrowed(html),
+    };
+
+    let mut pills = Vec::new();
+    let mut rest = html.as_ref();
+
+    while let Some(start) = rest.find("<a ") {
+        rest = &rest[start..];
+
+        let (tag_end, link_end) = match (rest.find('>'), rest.find("</a>")) {
+            (Some(t), Some(l)) if t < l => (t, l),
+            _ => break,
+        };
+
+        let tag = &rest[..tag_end];
+        let text = &rest[tag_end + 1..link_end];
+        rest = &rest[link_end + "</a>".len()..];
+
+        let href = tag.find("href=").and_then(|h| {
+            let value = &tag[h + "href=".len()..];
+            let quote =
+                value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+            let value = &value[1..];
+            value.find(quote).map(|end| &value[..end])
+        });
+
+        let user_id = href
+            .and_then(|h| h.strip_prefix(PERMALINK))
+            .map(|h| percent_decode(h.split('?').next().unwrap_or_default()))
+            .and_then(|u| UserId::parse(u).ok());
+
+        if let Some(user_id) = user_id {
+            // Strip the tags of formatted pill texts.
+            let mut plain = String::new();
+            let mut in_tag = false;
+
+            for c in text.chars() {
+                match c {
+                    '<' => in_tag = true,
+                    '>' => in_tag = false,
+                    c if !in_tag => plain.push(c),
+                    _ => (),
+                }
+            }
+
+            pills.push((user_id, unescape_html(&plain)));
+        }
+    }
+
+    pills
+}
+
+/// The kinds of profile changes a membership event can contain, every kind
+/// is printed as a separate line and can be hidden on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProfileChange {
+    DisplayName,
+    Avatar,
+}
+
+impl ProfileChange {
+    /// The tag of the lines that show this kind of change.
+    pub fn tag(&self) -> &'static str {
+        match self {
+            ProfileChange::DisplayName => "matrix_display_name_change",
+            ProfileChange::Avatar => "matrix_avatar_change",
+        }
+    }
+}
+
+fn formatted_name(member: &WeechatRoomMember) -> String {
+    match member.display_name() {
+        Some(display_name) => format!(
+            "{name} {color_delim}({color_reset}{user_id}{color_delim}){color_reset}",
+            name = display_name,
+            user_id = member.user_id(),
+            color_delim = Weechat::color("chat_delimiters"),
+            color_reset = Weechat::color("reset")
+        ),
+        None => member.user_id().to_string(),
+    }
+}
+
+/// Render the profile changes of a membership event, every change gets its
+/// own line.
+pub fn render_profile_changes(
+    event: &OriginalSyncStateEvent<RoomMemberEventContent>,
+    target: &WeechatRoomMember,
+) -> Vec<(ProfileChange, String)> {
+    let (displayname_change, avatar_url_change) =
+        match event.membership_change() {
+            MembershipChange::ProfileChanged {
+                displayname_change,
+                avatar_url_change,
+            } => (displayname_change, avatar_url_change),
+            _ => return Vec::new(),
+        };
+
+    let prefix = Weechat::prefix(Prefix::Network);
+    let color_action = Weechat::color("magenta");
+    let color_reset = Weechat::color("reset");
+
+    let mut lines = Vec::new();
+
+    if displayname_change.is_some() {
+        // The target already carries the new display name, so the change is
+        // attributed to the old name.
+        let old_name = event
+            .prev_content()
+            .and_then(|p| p.displayname.clone())
+            .unwrap_or_else(|| target.user_id().to_string());
+
+        let line = match &event.content.displayname {
+            Some(name) => format!(
+                "{}{} {}changed their display name to{} {}",
+                prefix, old_name, color_action, color_reset, name
+            ),
+            None => format!(
+                "{}{} {}removed their display name{}",
+                prefix, old_name, color_action, color_reset
+            ),
+        };
+
+        lines.push((ProfileChange::DisplayName, line));
+    }
+
+    if avatar_url_change.is_some() {
+        let line = format!(
+            "{}{} {}changed their avatar{}",
+            prefix,
+            formatted_name(target),
+            color_action,
+            color_reset
+        );
+
+        lines.push((ProfileChange::Avatar, line));
+    }
+
+    lines
+}
+
+/// Describe a change of the room state, e.g. a new topic or the room getting
+/// encrypted, the description follows the name of the sender.
+///
+/// Returns `None` for redacted events and for state events that don't get a
+/// notice, memberships are rendered by `render_membership()`.
+pub fn render_state_change(event: &AnySyncStateEvent) -> Option<String> {
+    let description = match event {
+        AnySyncStateEvent::RoomTopic(SyncStateEvent::Original(e)) => {
+            if e.content.topic.is_empty() {
+                "removed the topic".to_owned()
+            } else {
+                format!("changed the topic to: {}", e.content.topic)
+            }
+        }
+        AnySyncStateEvent::RoomName(SyncStateEvent::Original(e)) => {
+            match &e.content.name {
+                Some(name) => {
+                    format!("changed the room name to {}", name.as_str())
+                }
+                None => "removed the room name".to_owned(),
+            }
+        }
+        AnySyncStateEvent::RoomEncryption(SyncStateEvent::Original(e)) => {
+            format!("enabled encryption ({})", e.content.algorithm)
+        }
+        AnySyncStateEvent::RoomHistoryVisibility(SyncStateEvent::Original(
+            e,
+        )) => {
+            let visibility = match &e.content.history_visibility {
+                HistoryVisibility::Invited => {
+                    "all room members, from the point they were invited"
+                }
+                HistoryVisibility::Joined => {
+                    "all room members, from the point they joined"
+                }
+                HistoryVisibility::Shared => "all room members",
+                HistoryVisibility::WorldReadable => "anyone",
+                v => {
+                    return Some(format!(
+                        "changed the history visibility to {}",
+                        v.as_str()
+                    ))
+                }
+            };
+
+            format!("made future room history visible to {}", visibility)
+        }
+        AnySyncStateEvent::RoomPowerLevels(SyncStateEvent::Original(e)) => {
+            describe_power_levels(&e.content, e.unsigned.prev_content.as_ref())
+        }
+        _ => return None,
+    };
+
+    Some(description)
+}
+
+fn describe_power_levels(
+    new: &RoomPowerLevelsEventContent,
+    old: Option<&RoomPowerLevelsEventContent>,
+) -> String {
+    let old = if let Some(old) = old {
+        old
+    } else {
+        return "set the power levels of the room".to_owned();
+    };
+
+    let level = |levels: &RoomPowerLevelsEventContent, user_id: &UserId| {
+        levels
+            .users
+            .get(user_id)
+            .copied()
+            .unwrap_or(levels.users_default)
+    };
+
+    let changes: Vec<String> = new
+        .users
+        .keys()
+        .chain(old.users.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter_map(|user_id| {
+            let (from, to) = (level(old, user_id), level(new, user_id));
+
+            if from != to {
+                Some(format!("{} from {} to {}", user_id, from, to))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if changes.is_empty() {
+        "changed the power level settings of the room".to_owned()
+    } else {
+        format!("changed the power level of {}", changes.join(", "))
+    }
+}
+
+/// Get the tag of the lines that show the given membership change, this way
+/// the user can filter out certain kinds of changes.
+pub fn membership_tag(change: &MembershipChange<'_>) -> &'static str {
+    use MembershipChange::*;
+
+    match change {
+        Joined => "matrix_join",
+        Left => "matrix_leave",
+        Invited | InvitationRejected | InvitationRevoked => "matrix_invite",
+        Kicked => "matrix_kick",
+        Banned | KickedAndBanned => "matrix_ban",
+        Unbanned => "matrix_unban",
+        _ => "matrix_membership",
+    }
+}
+
+/// Rendering implementation for membership events (joins, leaves, bans, etc).
+///
+/// Profile changes are rendered by `render_profile_changes()`, they are
+/// joined into a single message here.
 pub fn render_membership(
     event: &OriginalSyncStateEvent<RoomMemberEventContent>,
     sender: &WeechatRoomMember,
     target: &WeechatRoomMember,
 ) -> String {
-    const _TAGS: &[&str] = &["matrix_membership"];
     use MembershipChange::*;
     let change_op = event.membership_change();
 
@@ -588,30 +1326,19 @@ pub fn render_membership(
         KickedAndBanned => "was kicked and banned by",
         InvitationRejected => "rejected the invitation",
         InvitationRevoked => "had the invitation revoked by",
-        ProfileChanged { .. } => "_",
+        ProfileChanged { .. } => {
+            return render_profile_changes(event, target)
+                .into_iter()
+                .map(|(_, line)| line)
+                .collect::<Vec<String>>()
+                .join("\n");
+        }
         _ => "performed an unimplemented operation",
     };
 
-    fn formatted_name(member: &WeechatRoomMember) -> String {
-        match member.display_name() {
-            Some(display_name) => {
-                format!(
-                    "{name} {color_delim}({color_reset}{user_id}{color_delim}){color_reset}",
-                    name = display_name,
-                    user_id = member.user_id(),
-                    color_delim = Weechat::color("chat_delimiters"),
-                    color_reset = Weechat::color("reset"))
-            }
-
-            Option::None => member.user_id().to_string(),
-        }
-    }
-
     let (prefix, color_action) = match change_op {
         Joined => (Prefix::Join, "green"),
-        Banned | ProfileChanged { .. } | Invited => {
-            (Prefix::Network, "magenta")
-        }
+        Banned | Invited => (Prefix::Network, "magenta"),
         _ => (Prefix::Quit, "red"),
     };
 
@@ -641,67 +1368,6 @@ pub fn render_membership(
 
     // TODO: we should return the tags as well.
     match change_op {
-        ProfileChanged {
-            displayname_change,
-            avatar_url_change,
-        } => {
-            let new_display_name = &event.content.displayname;
-
-            // TODO: Should we display the new avatar URL?
-            // let new_avatar = self.content.avatar_url.as_ref();
-
-            match (displayname_change.is_some(), avatar_url_change.is_some()) {
-                (false, true) =>
-                    format!(
-                        "{prefix}{target} {color_action}changed their avatar{color_reset}",
-                        prefix = Weechat::prefix(prefix),
-                        target = target_name,
-                        color_action = color_action,
-                        color_reset = color_reset
-                        ),
-                (true, false) => {
-                    match new_display_name {
-                        Some(name) => format!(
-                            "{prefix}{target} {color_action}changed their display name to{color_reset} {new}",
-                            prefix = Weechat::prefix(prefix),
-                            target = event.prev_content().as_ref().map(|p| p.displayname.clone()).flatten().unwrap_or(target_name),
-                            new = name,
-                            color_action = color_action,
-                            color_reset = color_reset
-                            ),
-                        Option::None => format!(
-                            "{prefix}{target} {color_action}removed their display name{color_reset}",
-                            prefix = Weechat::prefix(prefix),
-                            target = target_name,
-                            color_action = color_action,
-                            color_reset = color_reset
-                            ),
-                    }
-                }
-                (true, true) =>
-                    match new_display_name {
-                        Some(name) => format!(
-                            "{prefix}{target} {color_action}changed their avatar \
-                            and changed their display name to{color_reset} {new}",
-                            prefix = Weechat::prefix(prefix),
-                            target = target_name,
-                            new = name,
-                            color_action = color_action,
-                            color_reset = color_reset
-                            ),
-                        Option::None => format!(
-                            "{prefix}{target} {color_action}changed their \
-                            avatar and removed display name{color_reset}",
-                            prefix = Weechat::prefix(prefix),
-                            target = target_name,
-                            color_action = color_action,
-                            color_reset = color_reset
-                            ),
-                    }
-                (false, false) =>
-                    "Cannot happen: got profile changed but nothing really changed".to_string()
-            }
-        }
         Banned | Unbanned | Kicked | Invited | InvitationRevoked
         | KickedAndBanned => format!(
             "{prefix}{target} {op} {sender}",
@@ -771,4 +1437,114 @@ mod tests {
             mxc_to_emxc(&mxc_url, &homeserver, &encrypt_info).unwrap()
         );
     }
+
+    fn state_event(
+        event_type: &str,
+        content: serde_json::Value,
+        prev_content: Option<serde_json::Value>,
+    ) -> AnySyncStateEvent {
+        let mut event = serde_json::json!({
+            "type": event_type,
+            "event_id": "$event:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 1,
+            "state_key": "",
+            "content": content,
+        });
+
+        if let Some(prev_content) = prev_content {
+            event["unsigned"] =
+                serde_json::json!({ "prev_content": prev_content });
+        }
+
+        serde_json::from_value(event).unwrap()
+    }
+
+    #[test]
+    fn test_render_state_change() {
+        let event = state_event(
+            "m.room.encryption",
+            serde_json::json!({ "algorithm": "m.megolm.v1.aes-sha2" }),
+            None,
+        );
+        assert_eq!(
+            render_state_change(&event).unwrap(),
+            "enabled encryption (m.megolm.v1.aes-sha2)"
+        );
+
+        let event = state_event(
+            "m.room.topic",
+            serde_json::json!({ "topic": "" }),
+            None,
+        );
+        assert_eq!(render_state_change(&event).unwrap(), "removed the topic");
+
+        let event = state_event(
+            "m.room.history_visibility",
+            serde_json::json!({ "history_visibility": "joined" }),
+            None,
+        );
+        assert_eq!(
+            render_state_change(&event).unwrap(),
+            "made future room history visible to all room members, from \
+             the point they joined"
+        );
+
+        let event = state_event(
+            "m.room.join_rules",
+            serde_json::json!({ "join_rule": "public" }),
+            None,
+        );
+        assert!(render_state_change(&event).is_none());
+    }
+
+    #[test]
+    fn test_user_pills() {
+        let html = "<mx-reply><blockquote><a href=\"https://matrix.to/#/\
+                    !room:example.org/$event\">In reply to</a> \
+                    <a href=\"https://matrix.to/#/@bob:example.org\">\
+                    @bob:example.org</a></blockquote></mx-reply>\
+                    <a href=\"https://matrix.to/#/%40alice%3Aexample.org\">\
+                    <b>Alice &amp; co</b></a>: hello, see \
+                    <a href='https://example.org'>this</a>";
+
+        let alice = UserId::parse("@alice:example.org").unwrap();
+
+        assert_eq!(user_pills(html), vec![(alice, "Alice & co".to_owned())]);
+        assert!(user_pills("no pills here").is_empty());
+    }
+
+    #[test]
+    fn test_render_power_level_change() {
+        let event = state_event(
+            "m.room.power_levels",
+            serde_json::json!({
+                "users": { "@alice:example.org": 100, "@bob:example.org": 50 }
+            }),
+            Some(serde_json::json!({
+                "users": { "@alice:example.org": 100 }
+            })),
+        );
+        assert_eq!(
+            render_state_change(&event).unwrap(),
+            "changed the power level of @bob:example.org from 0 to 50"
+        );
+
+        let event = state_event(
+            "m.room.power_levels",
+            serde_json::json!({ "users": {}, "ban": 100 }),
+            Some(serde_json::json!({ "users": {} })),
+        );
+        assert_eq!(
+            render_state_change(&event).unwrap(),
+            "changed the power level settings of the room"
+        );
+    }
+
+    #[test]
+    fn test_valid_strftime_format() {
+        assert_eq!(valid_strftime_format("%d.%m. %H:%M"), "%d.%m. %H:%M");
+        assert_eq!(valid_strftime_format("%Y-%Q"), DEFAULT_DATE_FORMAT);
+        assert_eq!(valid_strftime_format("%"), DEFAULT_DATE_FORMAT);
+    }
 }
@@ -0,0 +1,169 @@
+//! Detection of the bridges of a room.
+//!
+//! Bridges announce themselves using `m.bridge` state events, older bridges
+//! use the unstable `uk.half-shot.bridge` event type. The bot and the remote
+//! users of a bridge are appservice users, their user ids share a namespace
+//! like `@_discord_1234:example.org` or `@telegram_1234:example.org`.
+//! Members in such a namespace hint at a bridge as well, even if no state
+//! event was sent for it.
+
+use std::collections::BTreeMap;
+
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+use serde_json::Value;
+
+/// The state event types bridges are announced with.
+pub const BRIDGE_EVENT_TYPES: &[&str] = &["m.bridge", "uk.half-shot.bridge"];
+
+/// A bridge that was detected in a room.
+#[derive(Clone, Debug, Default)]
+pub struct Bridge {
+    /// The user id of the bot of the bridge.
+    pub bot: Option<OwnedUserId>,
+    /// The name of the remote protocol, e.g. Discord.
+    pub protocol: Option<String>,
+    /// The name of the remote network, e.g. the Discord server.
+    pub network: Option<String>,
+    /// The name of the remote channel the room is bridged to.
+    pub channel: Option<String>,
+    /// The prefix the user ids of the bridged users start with.
+    pub namespace: Option<String>,
+    /// The number of members in the namespace of the bridge.
+    pub users: usize,
+}
+
+impl Bridge {
+    /// Parse a bridge state event.
+    pub fn from_state_event(event: &Value) -> Option<Self> {
+        let content = event.get("content")?.as_object()?;
+
+        // An empty content removes the bridge.
+        if content.is_empty() {
+            return None;
+        }
+
+        let name = |key: &str| {
+            let section = content.get(key)?;
+
+            section["displayname"]
+                .as_str()
+                .or_else(|| section["id"].as_str())
+                .map(|n| n.to_owned())
+        };
+
+        let bot = content
+            .get("bridgebot")
+            .and_then(|b| b.as_str())
+            .and_then(|b| UserId::parse(b).ok());
+        let namespace = bot.as_deref().and_then(bot_namespace);
+
+        Some(Self {
+            bot,
+            protocol: name("protocol"),
+            network: name("network"),
+            channel: name("channel"),
+            namespace,
+            users: 0,
+        })
+    }
+
+    /// Describe the bridge in a single line.
+    pub fn describe(&self) -> String {
+        let mut description = self
+            .protocol
+            .clone()
+            .unwrap_or_else(|| "Unknown protocol".to_owned());
+
+        if let Some(bot) = &self.bot {
+            description.push_str(&format!(" (bot {})", bot));
+        }
+
+        if let Some(channel) = &self.channel {
+            description.push_str(&format!(", channel {}", channel));
+        }
+
+        if let Some(network) = &self.network {
+            description.push_str(&format!(", network {}", network));
+        }
+
+        if let Some(namespace) = &self.namespace {
+            description.push_str(&format!(
+                ", {} bridged users in the {}* namespace",
+                self.users, namespace
+            ));
+        }
+
+        if self.bot.is_none() && self.protocol.is_none() {
+            description.push_str(", no bridge state event");
+        }
+
+        description
+    }
+}
+
+/// Guess the namespace of the users of a bridge from the user id of its bot,
+/// e.g. `_discord_` for `@_discord_bot` and `telegram_` for `@telegrambot`.
+fn bot_namespace(bot: &UserId) -> Option<String> {
+    let localpart = bot.localpart();
+
+    if let Some(namespace) = user_namespace(localpart) {
+        Some(namespace)
+    } else {
+        localpart
+            .strip_suffix("bot")
+            .filter(|p| !p.is_empty())
+            .map(|p| format!("{}_", p.trim_end_matches('_')))
+    }
+}
+
+/// Get the underscore delimited namespace of an appservice user, e.g.
+/// `_discord_` for `_discord_1234`.
+fn user_namespace(localpart: &str) -> Option<String> {
+    let rest = localpart.strip_prefix('_')?;
+    let end = rest.find('_').filter(|e| *e > 0)?;
+
+    Some(format!("_{}_", &rest[..end]))
+}
+
+/// Count the members of every bridge namespace, namespaces without a bridge
+/// state event are added as bridges of their own.
+///
+/// # Arguments
+///
+/// * `bridges` - The bridges that were announced with state events.
+///
+/// * `members` - The user ids of the members of the room.
+pub fn count_users(bridges: &mut Vec<Bridge>, members: &[OwnedUserId]) {
+    let mut unannounced: BTreeMap<String, usize> = BTreeMap::new();
+
+    for member in members {
+        if bridges.iter().any(|b| b.bot.as_ref() == Some(member)) {
+            continue;
+        }
+
+        let bridge = bridges.iter_mut().find(|b| {
+            b.namespace
+                .as_ref()
+                .map_or(false, |n| member.localpart().starts_with(n.as_str()))
+                && b.bot.as_ref().map_or(true, |bot| {
+                    bot.server_name() == member.server_name()
+                })
+        });
+
+        if let Some(bridge) = bridge {
+            bridge.users += 1;
+        } else if let Some(namespace) = user_namespace(member.localpart()) {
+            *unannounced.entry(namespace).or_default() += 1;
+        }
+    }
+
+    // A single member in a namespace is most likely a user that likes
+    // underscores.
+    bridges.extend(unannounced.into_iter().filter(|(_, u)| *u > 1).map(
+        |(namespace, users)| Bridge {
+            namespace: Some(namespace),
+            users,
+            ..Default::default()
+        },
+    ));
+}
@@ -0,0 +1,85 @@
+//! Scratch buffers to compose long messages in.
+//!
+//! Every line that is entered in the compose buffer of a room is added to a
+//! draft, the draft is sent to the room as a single message once the user is
+//! done, markdown included.
+
+use std::{borrow::Cow, cell::RefCell, rc::Rc};
+
+use weechat::{
+    buffer::{Buffer, BufferBuilder, BufferHandle},
+    Weechat,
+};
+
+pub struct ComposeBuffer {
+    buffer: BufferHandle,
+    draft: Rc<RefCell<Vec<String>>>,
+}
+
+impl ComposeBuffer {
+    /// Create a compose buffer for the given room buffer.
+    pub fn new(room_buffer: &Buffer) -> Option<Self> {
+        let draft = Rc::new(RefCell::new(Vec::new()));
+
+        let buffer =
+            BufferBuilder::new(&format!("{}.compose", room_buffer.name()))
+                .input_callback({
+                    let draft = draft.clone();
+
+                    move |_: &Weechat, buffer: &Buffer, input: Cow<str>| {
+                        draft.borrow_mut().push(input.to_string());
+                        Self::print_draft(buffer, &draft.borrow());
+                        Ok(())
+                    }
+                })
+                .build()
+                .ok()?;
+
+        let compose_buffer = buffer.upgrade().ok()?;
+
+        compose_buffer
+            .set_short_name(&format!("{}.compose", room_buffer.short_name()));
+        compose_buffer.set_title(&format!(
+            "Composing a message for {}, use /matrix compose send|undo|cancel",
+            room_buffer.short_name()
+        ));
+        compose_buffer.set_localvar("type", "private");
+        compose_buffer.enable_multiline();
+
+        Some(Self { buffer, draft })
+    }
+
+    fn print_draft(buffer: &Buffer, draft: &[String]) {
+        buffer.clear();
+
+        for line in draft.iter().flat_map(|d| d.lines()) {
+            buffer.print(line);
+        }
+    }
+
+    /// Get the compose buffer, if it wasn't closed.
+    pub fn buffer(&self) -> Option<Buffer> {
+        self.buffer.upgrade().ok()
+    }
+
+    /// Remove the last entered piece of the draft.
+    pub fn undo(&self) {
+        self.draft.borrow_mut().pop();
+
+        if let Some(buffer) = self.buffer() {
+            Self::print_draft(&buffer, &self.draft.borrow());
+        }
+    }
+
+    /// Get the message that was composed so far.
+    pub fn draft(&self) -> String {
+        self.draft.borrow().join("\n")
+    }
+
+    /// Close the compose buffer, the draft is discarded.
+    pub fn close(&self) {
+        if let Some(buffer) = self.buffer() {
+            buffer.close();
+        }
+    }
+}
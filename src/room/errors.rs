@@ -0,0 +1,145 @@
+//! Explanations for the errors the homeserver returns for our requests.
+//!
+//! The errors of the SDK show the raw error response of the server, the
+//! common ones are instead turned into messages that tell the user what went
+//! wrong and, if possible, what they can do about it.
+
+use std::time::Duration;
+
+use matrix_sdk::ruma::{
+    api::client::error::ErrorKind,
+    events::{room::power_levels::RoomPowerLevelsEventContent, RoomEventType},
+    Int, UserId,
+};
+
+use crate::utils::format_duration;
+
+/// Something we tried to do in a room that may require a power level.
+#[derive(Clone, Copy, Debug)]
+pub enum RoomAction<'a> {
+    /// Sending a message like event of the given type.
+    Message(&'a str),
+    /// Sending a state event of the given type.
+    State(&'a str),
+    /// Banning or unbanning a user.
+    Ban,
+    /// Inviting a user.
+    Invite,
+}
+
+impl RoomAction<'_> {
+    fn description(&self) -> String {
+        match self {
+            RoomAction::Message(t) => format!("send {} events", t),
+            RoomAction::State(t) => format!("change the {} state", t),
+            RoomAction::Ban => "ban or unban users".to_owned(),
+            RoomAction::Invite => "invite users".to_owned(),
+        }
+    }
+
+    fn required_level(
+        &self,
+        power_levels: &RoomPowerLevelsEventContent,
+    ) -> Int {
+        let event_level = |event_type: &str, default: Int| {
+            power_levels
+                .events
+                .get(&RoomEventType::from(event_type))
+                .copied()
+                .unwrap_or(default)
+        };
+
+        match self {
+            RoomAction::Message(t) => {
+                event_level(t, power_levels.events_default)
+            }
+            RoomAction::State(t) => event_level(t, power_levels.state_default),
+            RoomAction::Ban => power_levels.ban,
+            RoomAction::Invite => power_levels.invite,
+        }
+    }
+}
+
+/// Check if our power level allows the given action.
+///
+/// Returns a description of the missing power level if it doesn't, the
+/// action is allowed if the power levels of the room aren't known.
+///
+/// # Arguments
+///
+/// * `action` - What we want to do in the room.
+///
+/// * `power_levels` - The power levels of the room.
+///
+/// * `own_user_id` - Our own user id.
+pub fn missing_power_level(
+    action: RoomAction<'_>,
+    power_levels: Option<&RoomPowerLevelsEventContent>,
+    own_user_id: &UserId,
+) -> Option<String> {
+    let power_levels = power_levels?;
+
+    let required = action.required_level(power_levels);
+    let own = power_levels
+        .users
+        .get(own_user_id)
+        .copied()
+        .unwrap_or(power_levels.users_default);
+
+    if own < required {
+        Some(format!(
+            "you aren't allowed to {}, it requires power level {} and yours \
+             is {}",
+            action.description(),
+            required,
+            own
+        ))
+    } else {
+        None
+    }
+}
+
+/// Describe the error of a request we sent to a room.
+///
+/// # Arguments
+///
+/// * `error` - The error the request failed with.
+///
+/// * `action` - What the request tried to do.
+///
+/// * `power_levels` - The power levels of the room, used to tell the user
+/// which power level the action requires.
+///
+/// * `own_user_id` - Our own user id.
+pub fn describe_error(
+    error: &matrix_sdk::Error,
+    action: RoomAction<'_>,
+    power_levels: Option<&RoomPowerLevelsEventContent>,
+    own_user_id: &UserId,
+) -> String {
+    match error.client_api_error_kind() {
+        Some(ErrorKind::Forbidden) => {
+            missing_power_level(action, power_levels, own_user_id)
+                .unwrap_or_else(|| {
+                    format!(
+                        "the server didn't allow you to {}",
+                        action.description()
+                    )
+                })
+        }
+        Some(ErrorKind::TooLarge) => {
+            "the request is too large for the server".to_owned()
+        }
+        Some(ErrorKind::LimitExceeded {
+            retry_after_ms: Some(retry_after),
+        }) => format!(
+            "you are sending too many requests, the server asks you to wait \
+             {}",
+            format_duration((*retry_after).max(Duration::from_secs(1)))
+        ),
+        Some(ErrorKind::LimitExceeded { .. }) => {
+            "you are sending too many requests, try again later".to_owned()
+        }
+        _ => error.to_string(),
+    }
+}
@@ -0,0 +1,195 @@
+//! Buffers listing the images of a room.
+//!
+//! Every image gets a row with its name, dimensions, size, sender and date,
+//! newest first. The selection is moved using the up and down keys, or by
+//! entering the number of a row, alt-o opens the selected image using the
+//! media opener and alt-d downloads it.
+
+use std::{borrow::Cow, cell::Cell, rc::Rc};
+
+use matrix_sdk::ruma::{events::room::MediaSource, MilliSecondsSinceUnixEpoch};
+use weechat::{
+    buffer::{Buffer, BufferBuilder, BufferHandle},
+    Prefix, Weechat,
+};
+
+use crate::utils::{format_size, format_timestamp};
+
+/// The buffer local key bindings of the gallery and the `/matrix gallery`
+/// subcommands they run.
+const KEY_BINDINGS: &[(&str, &str)] = &[
+    ("meta2-A", "up"),
+    ("meta2-B", "down"),
+    ("meta-o", "open"),
+    ("meta-d", "download"),
+];
+
+/// An image listed in the gallery.
+#[derive(Clone, Debug)]
+pub struct GalleryItem {
+    pub name: String,
+    pub source: MediaSource,
+    /// The nick of the sender.
+    pub sender: String,
+    pub timestamp: MilliSecondsSinceUnixEpoch,
+    pub size: Option<u64>,
+    /// The width and height of the image in pixels.
+    pub dimensions: Option<(u64, u64)>,
+}
+
+impl GalleryItem {
+    fn row(&self, number: usize, selected: bool) -> String {
+        let date = format_timestamp(self.timestamp, "%Y-%m-%d %H:%M");
+
+        let details: Vec<String> = self
+            .dimensions
+            .map(|(width, height)| format!("{}x{}", width, height))
+            .into_iter()
+            .chain(self.size.map(format_size))
+            .collect();
+
+        let details = if details.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", details.join(", "))
+        };
+
+        let (marker, reverse, reset) = if selected {
+            ("> ", Weechat::color("reverse"), Weechat::color("-reverse"))
+        } else {
+            ("  ", "", "")
+        };
+
+        format!(
+            "{}{:>3}. {}[{}]{} {}{}{} from {}{}",
+            marker,
+            number,
+            Weechat::color("chat_delimiters"),
+            date,
+            Weechat::color("reset"),
+            reverse,
+            self.name,
+            details,
+            self.sender,
+            reset,
+        )
+    }
+}
+
+pub struct GalleryBuffer {
+    buffer: BufferHandle,
+    items: Rc<Vec<GalleryItem>>,
+    selected: Rc<Cell<usize>>,
+}
+
+impl GalleryBuffer {
+    /// Create a gallery buffer for the given room buffer and switch to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_buffer` - The buffer of the room the images were sent to.
+    ///
+    /// * `title` - The title of the gallery buffer.
+    ///
+    /// * `items` - The images that should be listed, newest first.
+    pub fn new(
+        room_buffer: &Buffer,
+        title: &str,
+        items: Vec<GalleryItem>,
+    ) -> Option<Self> {
+        let items = Rc::new(items);
+        let selected = Rc::new(Cell::new(0));
+
+        let buffer =
+            BufferBuilder::new(&format!("{}.gallery", room_buffer.name()))
+                .input_callback({
+                    let items = items.clone();
+                    let selected = selected.clone();
+
+                    move |_: &Weechat, buffer: &Buffer, input: Cow<str>| {
+                        match input.trim().parse::<usize>() {
+                            Ok(n) if n >= 1 && n <= items.len() => {
+                                selected.set(n - 1);
+                                Self::print_items(buffer, &items, n - 1);
+                            }
+                            _ => buffer.print(&format!(
+                                "{}Enter the number of an image to select it",
+                                Weechat::prefix(Prefix::Error)
+                            )),
+                        }
+                        Ok(())
+                    }
+                })
+                .build()
+                .ok()?;
+
+        let gallery_buffer = buffer.upgrade().ok()?;
+
+        gallery_buffer
+            .set_short_name(&format!("{}.gallery", room_buffer.short_name()));
+        gallery_buffer.set_title(title);
+        gallery_buffer.set_localvar("type", "private");
+
+        for (key, subcommand) in KEY_BINDINGS {
+            let _ = gallery_buffer.run_command(&format!(
+                "/mute /buffer set key_bind_{} /matrix gallery {}",
+                key, subcommand
+            ));
+        }
+
+        Self::print_items(&gallery_buffer, &items, 0);
+        gallery_buffer.switch_to();
+
+        Some(Self {
+            buffer,
+            items,
+            selected,
+        })
+    }
+
+    fn print_items(buffer: &Buffer, items: &[GalleryItem], selected: usize) {
+        buffer.clear();
+
+        for (i, item) in items.iter().enumerate() {
+            buffer.print(&item.row(i + 1, i == selected));
+        }
+    }
+
+    /// Get the gallery buffer, if it wasn't closed.
+    pub fn buffer(&self) -> Option<Buffer> {
+        self.buffer.upgrade().ok()
+    }
+
+    /// Move the selection by the given number of rows, the selection stops
+    /// at the first and last row.
+    pub fn move_selection(&self, offset: isize) {
+        let last = self.items.len().saturating_sub(1) as isize;
+        let selected =
+            (self.selected.get() as isize + offset).max(0).min(last) as usize;
+
+        self.selected.set(selected);
+
+        if let Some(buffer) = self.buffer() {
+            Self::print_items(&buffer, &self.items, selected);
+        }
+    }
+
+    /// Get the selected image.
+    pub fn selected(&self) -> Option<GalleryItem> {
+        self.items.get(self.selected.get()).cloned()
+    }
+
+    /// Print a message below the rows of the gallery.
+    pub fn print(&self, message: &str) {
+        if let Some(buffer) = self.buffer() {
+            buffer.print(message);
+        }
+    }
+
+    /// Close the gallery buffer.
+    pub fn close(&self) {
+        if let Some(buffer) = self.buffer() {
+            buffer.close();
+        }
+    }
+}
@@ -1,4 +1,6 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    borrow::Cow, cell::RefCell, collections::HashMap, rc::Rc, time::Duration,
+};
 
 use dashmap::DashMap;
 use tokio::runtime::Handle;
@@ -9,10 +11,13 @@ use matrix_sdk::{
     room::{Joined, RoomMember},
     ruma::{
         events::{
-            room::member::{MembershipState, RoomMemberEventContent},
-            SyncStateEvent,
+            room::member::{
+                MembershipChange, MembershipState, RoomMemberEventContent,
+            },
+            room::power_levels::RoomPowerLevelsEventContent,
+            OriginalSyncStateEvent, SyncStateEvent,
         },
-        uint, OwnedUserId, UserId,
+        uint, MilliSecondsSinceUnixEpoch, OwnedUserId, UserId,
     },
     StoreError,
 };
@@ -22,7 +27,98 @@ use weechat::{
     Prefix, Weechat,
 };
 
-use crate::render::render_membership;
+use crate::{
+    config::Config,
+    ignore,
+    render::{
+        membership_tag, render_membership, render_profile_changes,
+        ProfileChange,
+    },
+    server::ServerSettings,
+    utils::{contains_word, escape_spoofing_chars},
+};
+
+/// The time window over which nicklist updates and membership lines get
+/// collected before they are applied to the buffer.
+const BATCH_WINDOW: Duration = Duration::from_millis(250);
+
+/// The number of membership lines a batch can contain before the lines get
+/// replaced by a single summary line.
+const FLOOD_THRESHOLD: usize = 10;
+
+/// The tags of lines the smart filter hides, the IRC tag lets filters that
+/// were set up for IRC buffers hide our lines as well.
+const SMART_FILTER_TAGS: &[&str] = &["irc_smart_filter", "matrix_smart_filter"];
+
+/// Nicklist updates and membership lines that are waiting to be applied.
+///
+/// Join and leave floods, e.g. when a bridge reconnects, would otherwise
+/// update the nicklist and print a line for every single event.
+#[derive(Default)]
+struct PendingUpdates {
+    /// Is a flush of the pending updates already scheduled.
+    scheduled: bool,
+    /// The members whose nicklist entry needs to be updated, `true` if the
+    /// member should be in the nicklist, `false` if it should be removed.
+    nicks: HashMap<OwnedUserId, bool>,
+    /// Membership lines that should be printed, with their timestamp and
+    /// tags.
+    lines: Vec<(i64, MembershipState, Vec<&'static str>, String)>,
+}
+
+/// The power levels members need to be listed in the nicklist groups of the
+/// ops, half-ops and voiced members.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NicklistThresholds {
+    pub op: i64,
+    pub halfop: i64,
+    pub voice: i64,
+}
+
+impl Default for NicklistThresholds {
+    fn default() -> Self {
+        Self {
+            op: 100,
+            halfop: 50,
+            voice: 1,
+        }
+    }
+}
+
+impl NicklistThresholds {
+    /// Parse the thresholds from a comma separated list of the op, half-op
+    /// and voice power levels, e.g. `100,50,1`.
+    ///
+    /// The levels need to be in descending order.
+    pub fn parse(value: &str) -> Option<Self> {
+        let levels = value
+            .split(',')
+            .map(|l| l.trim().parse::<i64>().ok())
+            .collect::<Option<Vec<i64>>>()?;
+
+        match levels.as_slice() {
+            [op, halfop, voice] if op >= halfop && halfop >= voice => {
+                Some(Self {
+                    op: *op,
+                    halfop: *halfop,
+                    voice: *voice,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the nicklist group and the nick prefix of a member with the given
+    /// power level.
+    fn group(&self, power_level: i64) -> (&'static str, &'static str) {
+        match power_level {
+            p if p >= self.op => ("000|o", "&"),
+            p if p >= self.halfop => ("001|h", "@"),
+            p if p >= self.voice => ("002|v", "+"),
+            _ => ("999|...", " "),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Members {
@@ -30,7 +126,17 @@ pub struct Members {
     pub(super) runtime: Handle,
     ambiguity_map: Rc<DashMap<OwnedUserId, bool>>,
     nicks: Rc<DashMap<OwnedUserId, String>>,
+    /// The nicklist groups the nicks were added to.
+    nick_groups: Rc<DashMap<OwnedUserId, &'static str>>,
+    server_settings: Rc<RefCell<ServerSettings>>,
+    pending: Rc<RefCell<PendingUpdates>>,
+    config: Rc<RefCell<Config>>,
     pub(super) buffer: Rc<RefCell<Option<BufferHandle>>>,
+    /// A name set in the room settings that replaces the calculated name.
+    pub(super) name_override: Rc<RefCell<Option<String>>>,
+    /// The UNIX timestamp of the last message of every member that sent one,
+    /// used to tag the membership lines of idle members for smart filtering.
+    last_messages: Rc<DashMap<OwnedUserId, i64>>,
 }
 
 #[derive(Clone, Debug)]
@@ -38,19 +144,64 @@ pub struct WeechatRoomMember {
     inner: RoomMember,
     color: Rc<String>,
     ambiguous_nick: Rc<bool>,
+    escape_nick: bool,
+    thresholds: NicklistThresholds,
 }
 
 impl Members {
-    pub fn new(room: Joined, runtime: Handle) -> Self {
+    pub fn new(
+        room: Joined,
+        runtime: Handle,
+        config: Rc<RefCell<Config>>,
+        server_settings: Rc<RefCell<ServerSettings>>,
+    ) -> Self {
         Self {
             room,
             runtime,
+            config,
+            server_settings,
             nicks: DashMap::new().into(),
+            nick_groups: DashMap::new().into(),
             ambiguity_map: DashMap::new().into(),
+            pending: Rc::new(RefCell::new(PendingUpdates::default())),
             buffer: RefCell::new(None).into(),
+            name_override: RefCell::new(None).into(),
+            last_messages: DashMap::new().into(),
         }
     }
 
+    /// Remember that the given member sent a message at the given time.
+    pub fn record_message(
+        &self,
+        user_id: &UserId,
+        timestamp: MilliSecondsSinceUnixEpoch,
+    ) {
+        let timestamp: i64 = (timestamp.0 / uint!(1000)).into();
+
+        let mut last =
+            self.last_messages.entry(user_id.to_owned()).or_default();
+        *last = (*last).max(timestamp);
+    }
+
+    /// Should a membership line of the given member, printed at the given
+    /// time, get hidden by the smart filter, i.e. the member didn't send a
+    /// message recently.
+    fn is_smart_filtered(&self, user_id: &UserId, timestamp: i64) -> bool {
+        let (enabled, delay) = {
+            let config = self.config.borrow();
+            let look = config.look();
+            (look.smart_filters(), look.smart_filter_delay())
+        };
+
+        if !enabled {
+            return false;
+        }
+
+        self.last_messages
+            .get(user_id)
+            .map_or(true, |last| timestamp - *last > i64::from(delay) * 60)
+    }
+
     fn buffer(&self) -> BufferHandle {
         self.buffer
             .borrow()
@@ -59,6 +210,10 @@ impl Members {
     }
 
     fn add_nick(&self, buffer: &Buffer, member: &WeechatRoomMember) {
+        // TODO: Sort the nicks within a group by display name using a locale
+        // aware collation. WeeChat orders the nicks of a group by comparing
+        // their names case insensitively, the nicklist API has no way to hand
+        // it a different order, so this needs support from WeeChat first.
         let nick = member.nick();
 
         let group = buffer
@@ -82,6 +237,8 @@ impl Members {
         };
 
         self.nicks.insert(member.user_id().to_owned(), nick);
+        self.nick_groups
+            .insert(member.user_id().to_owned(), member.nicklist_group_name());
     }
 
     pub async fn restore_member(&self, user_id: OwnedUserId) {
@@ -133,10 +290,6 @@ impl Members {
             return;
         };
 
-        if let Some(nick) = self.nicks.get(user_id) {
-            buffer.remove_nick(&nick);
-        }
-
         let member = self.get(user_id).await.unwrap_or_else(|| {
             panic!(
                 "Couldn't find member {} in {}",
@@ -145,11 +298,180 @@ impl Members {
             )
         });
 
+        // Avatar changes and repeated joins don't change the nick, keep the
+        // nicklist entry instead of removing and re-adding it.
+        if let Some(nick) = self.nicks.get(user_id).map(|n| n.clone()) {
+            let group = self.nick_groups.get(user_id).map(|g| *g);
+
+            if nick == member.nick()
+                && group == Some(member.nicklist_group_name())
+            {
+                return;
+            }
+
+            buffer.remove_nick(&nick);
+        }
+
         self.add_nick(&buffer, &member);
     }
 
+    /// Remember that the nicklist entry of the given member needs to be
+    /// updated, the update is applied once the batch window passes.
+    fn queue_nick_update(&self, user_id: &UserId, present: bool) {
+        self.pending
+            .borrow_mut()
+            .nicks
+            .insert(user_id.to_owned(), present);
+        self.schedule_flush();
+    }
+
+    fn queue_line(
+        &self,
+        timestamp: i64,
+        state: MembershipState,
+        tags: Vec<&'static str>,
+        line: String,
+    ) {
+        if self.is_ignored(&line) {
+            return;
+        }
+
+        self.pending
+            .borrow_mut()
+            .lines
+            .push((timestamp, state, tags, line));
+        self.schedule_flush();
+    }
+
+    /// Check if a membership line matches one of the `look.ignored_content`
+    /// rules of the room.
+    fn is_ignored(&self, line: &str) -> bool {
+        let server_name = self
+            .buffer()
+            .upgrade()
+            .ok()
+            .and_then(|b| b.get_localvar("server").map(|s| s.to_string()))
+            .unwrap_or_default();
+
+        ignore::is_ignored(
+            &self.config.borrow().look().ignored_content(),
+            &Weechat::remove_color(line),
+            &server_name,
+            self.room().room_id(),
+            self.room().canonical_alias().as_deref(),
+        )
+    }
+
+    fn schedule_flush(&self) {
+        {
+            let mut pending = self.pending.borrow_mut();
+
+            if pending.scheduled {
+                return;
+            }
+
+            pending.scheduled = true;
+        }
+
+        let members = self.clone();
+
+        Weechat::spawn(async move {
+            let _ = members
+                .runtime
+                .spawn(tokio::time::sleep(BATCH_WINDOW))
+                .await;
+            members.flush().await;
+        })
+        .detach();
+    }
+
+    /// Apply all the pending nicklist updates and print out the pending
+    /// membership lines.
+    async fn flush(&self) {
+        self.flush_lines();
+
+        let nicks = {
+            let mut pending = self.pending.borrow_mut();
+            pending.scheduled = false;
+
+            std::mem::take(&mut pending.nicks)
+        };
+
+        let buffer = if let Ok(b) = self.buffer().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        for (user_id, present) in nicks {
+            if present {
+                self.update_member(&user_id).await;
+            } else if let Some((_, nick)) = self.nicks.remove(&user_id) {
+                self.nick_groups.remove(&user_id);
+                buffer.remove_nick(&nick);
+            }
+        }
+
+        // Names of rooms without display names can get affected by the
+        // member list so we need to update them.
+        self.update_buffer_name();
+    }
+
+    /// Print out the pending membership lines right away.
+    ///
+    /// This needs to happen before other events get printed, otherwise the
+    /// membership lines would end up below events that came after them.
+    pub fn flush_lines(&self) {
+        let lines = std::mem::take(&mut self.pending.borrow_mut().lines);
+
+        if lines.is_empty() {
+            return;
+        }
+
+        let buffer = if let Ok(b) = self.buffer().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        if lines.len() > FLOOD_THRESHOLD {
+            let (joined, left) = lines.iter().fold(
+                (0, 0),
+                |(joined, left), (_, state, tags, _)| match state {
+                    _ if !tags.contains(&"matrix_membership") => (joined, left),
+                    MembershipState::Join => (joined + 1, left),
+                    MembershipState::Leave | MembershipState::Ban => {
+                        (joined, left + 1)
+                    }
+                    _ => (joined, left),
+                },
+            );
+
+            let timestamp = lines.last().map(|(t, _, _, _)| *t);
+
+            buffer.print_date_tags(
+                timestamp.unwrap_or_default(),
+                &["matrix_membership"],
+                &format!(
+                    "{}{} members joined and {} members left the room \
+                     {}({} membership changes){}",
+                    Weechat::prefix(Prefix::Network),
+                    joined,
+                    left,
+                    Weechat::color("chat_delimiters"),
+                    lines.len(),
+                    Weechat::color("reset"),
+                ),
+            );
+        } else {
+            for (timestamp, _, tags, line) in lines {
+                buffer.print_date_tags(timestamp, &tags, &line);
+            }
+        }
+    }
+
     /// Add a new Weechat room member.
-    pub async fn add_or_modify(
+    pub fn add_or_modify(
         &self,
         user_id: &UserId,
         ambiguity_change: Option<&AmbiguityChange>,
@@ -160,23 +482,20 @@ impl Members {
 
             if let Some(disambiguated) = &change.disambiguated_member {
                 self.ambiguity_map.insert(disambiguated.clone(), false);
-                self.update_member(disambiguated).await;
+                self.queue_nick_update(disambiguated, true);
             }
 
             if let Some(ambiguated) = &change.ambiguated_member {
                 self.ambiguity_map.insert(ambiguated.clone(), true);
-                self.update_member(ambiguated).await;
+                self.queue_nick_update(ambiguated, true);
             }
         }
 
-        self.update_member(user_id).await;
+        self.queue_nick_update(user_id, true);
     }
 
     /// Remove a Weechat room member by user ID.
-    ///
-    /// Returns either the removed Weechat room member, or an error if the
-    /// member does not exist.
-    async fn remove(
+    fn remove(
         &self,
         user_id: &UserId,
         ambiguity_change: Option<&AmbiguityChange>,
@@ -186,25 +505,51 @@ impl Members {
         if let Some(change) = ambiguity_change {
             if let Some(disambiguated) = &change.disambiguated_member {
                 self.ambiguity_map.insert(disambiguated.clone(), false);
-                self.update_member(disambiguated).await;
+                self.queue_nick_update(disambiguated, true);
             }
 
             if let Some(ambiguated) = &change.ambiguated_member {
                 self.ambiguity_map.insert(ambiguated.clone(), true);
-                self.update_member(ambiguated).await;
+                self.queue_nick_update(ambiguated, true);
             }
         }
 
-        let buffer = self.buffer();
+        self.queue_nick_update(user_id, false);
+    }
 
-        let buffer = if let Ok(b) = buffer.upgrade() {
-            b
-        } else {
-            return;
+    /// Move the members whose power level changed into the nicklist group of
+    /// their new power level.
+    pub fn handle_power_levels(
+        &self,
+        new: &RoomPowerLevelsEventContent,
+        old: Option<&RoomPowerLevelsEventContent>,
+    ) {
+        let user_ids: Vec<OwnedUserId> = match old {
+            Some(old) if old.users_default == new.users_default => new
+                .users
+                .keys()
+                .chain(old.users.keys())
+                .filter(|u| old.users.get(*u) != new.users.get(*u))
+                .cloned()
+                .collect(),
+            _ => self.nicks.iter().map(|e| e.key().clone()).collect(),
         };
 
-        if let Some((_, nick)) = self.nicks.remove(user_id) {
-            buffer.remove_nick(&nick);
+        for user_id in user_ids {
+            if self.nicks.contains_key(&user_id) {
+                self.queue_nick_update(&user_id, true);
+            }
+        }
+    }
+
+    /// Move all the members into the nicklist group of their power level,
+    /// e.g. after the nicklist thresholds of the server changed.
+    pub fn refresh_nicklist(&self) {
+        let user_ids: Vec<OwnedUserId> =
+            self.nicks.iter().map(|e| e.key().clone()).collect();
+
+        for user_id in user_ids {
+            self.queue_nick_update(&user_id, true);
         }
     }
 
@@ -228,6 +573,12 @@ impl Members {
         {
             Ok(m) => m.map(|m| WeechatRoomMember {
                 color: Rc::new(color),
+                escape_nick: self
+                    .config
+                    .borrow()
+                    .look()
+                    .escape_spoofing_chars(),
+                thresholds: self.server_settings.borrow().nicklist_thresholds,
                 ambiguous_nick: Rc::new(
                     self.ambiguity_map
                         .get(m.user_id())
@@ -251,8 +602,48 @@ impl Members {
         &self.room
     }
 
+    /// Find the members that are mentioned in the given text, either by their
+    /// user id or by the nick that the nick completion inserts.
+    pub fn mentioned_users(&self, text: &str) -> Vec<OwnedUserId> {
+        self.nicks
+            .iter()
+            .filter(|e| {
+                contains_word(text, e.key().as_str())
+                    || contains_word(text, e.value())
+            })
+            .map(|e| e.key().clone())
+            .collect()
+    }
+
+    /// Get the user ids of the members we know of, without asking the store.
+    pub fn user_ids(&self) -> Vec<OwnedUserId> {
+        self.nicks.iter().map(|e| e.key().clone()).collect()
+    }
+
+    /// Get the user id of the other user in the room, if the room is a
+    /// direct chat with a single other user.
+    pub fn direct_peer(&self) -> Option<OwnedUserId> {
+        let mut targets = self.room().direct_targets().into_iter();
+
+        match (targets.next(), targets.next()) {
+            (Some(peer), None) => Some(peer),
+            _ => None,
+        }
+    }
+
     pub fn calculate_buffer_name(&self) -> Result<String, StoreError> {
         let room = self.room();
+
+        // Direct chats are named after the current display name of the
+        // other user, the same way query buffers are named after the nick.
+        if let Some(peer) = self.direct_peer() {
+            if let Some(member) =
+                self.runtime.block_on(room.get_member_no_sync(&peer))?
+            {
+                return Ok(member.name().to_owned());
+            }
+        }
+
         let room_name = self.runtime.block_on(room.display_name())?.to_string();
 
         let room_name = if room_name == "#" {
@@ -275,6 +666,15 @@ impl Members {
             return;
         };
 
+        if let Some(peer) = self.direct_peer() {
+            buffer.set_localvar("channel", peer.as_str());
+        }
+
+        if let Some(name) = self.name_override.borrow().as_deref() {
+            buffer.set_short_name(name);
+            return;
+        }
+
         match self.calculate_buffer_name() {
             Ok(name) => buffer.set_short_name(&name),
             Err(e) => {
@@ -337,48 +737,120 @@ impl Members {
         //
         // For leaves and bans we just need to remove the member.
         match event.content.membership {
-            Invite | Join => {
-                self.add_or_modify(&target_id, ambiguity_change).await
-            }
-            Leave | Ban => self.remove(&target_id, ambiguity_change).await,
+            Invite | Join => self.add_or_modify(&target_id, ambiguity_change),
+            Leave | Ban => self.remove(&target_id, ambiguity_change),
             _ => (),
         };
 
-        // Names of rooms without display names can get affected by the
-        // member list so we need to update them.
-        self.update_buffer_name();
-
         if !state_event {
             let sender = self.get(&sender_id).await;
             let target = self.get(&target_id).await;
+            let timestamp: i64 =
+                (event.origin_server_ts.0 / uint!(1000)).into();
 
-            // Display the event message
-            let message = match (&sender, &target) {
-                (Some(sender), Some(target)) => {
-                    render_membership(event, sender, target)
+            if let (MembershipChange::ProfileChanged { .. }, Some(target)) =
+                (event.membership_change(), &target)
+            {
+                let (show_display_names, show_avatars) = {
+                    let config = self.config.borrow();
+                    let look = config.look();
+                    (
+                        look.show_display_name_changes(),
+                        look.show_avatar_changes(),
+                    )
+                };
+
+                for (change, line) in render_profile_changes(event, target) {
+                    let shown = match change {
+                        ProfileChange::DisplayName => show_display_names,
+                        ProfileChange::Avatar => show_avatars,
+                    };
+
+                    if shown {
+                        let mut tags = vec![change.tag()];
+
+                        if change == ProfileChange::DisplayName
+                            && self.is_smart_filtered(&target_id, timestamp)
+                        {
+                            tags.extend(SMART_FILTER_TAGS);
+                        }
+
+                        self.queue_line(
+                            timestamp,
+                            event.content.membership.clone(),
+                            tags,
+                            line,
+                        );
+                    }
                 }
+            } else {
+                self.queue_membership_line(event, timestamp, &sender, &target);
+            }
+        }
 
-                _ => {
-                    if sender.is_none() {
-                        error!(
-                            "Cannot render event since event sender {} is not a room member",
-                            sender_id);
-                    }
+        if self.direct_peer().as_ref() == Some(&target_id) {
+            self.update_buffer_name();
+        }
+    }
 
-                    if target.is_none() {
-                        error!(
-                            "Cannot render event since event target {} is not a room member",
-                            target_id);
-                    }
+    /// Render a membership event that isn't a profile change and queue the
+    /// line for printing.
+    fn queue_membership_line(
+        &self,
+        event: &OriginalSyncStateEvent<RoomMemberEventContent>,
+        timestamp: i64,
+        sender: &Option<WeechatRoomMember>,
+        target: &Option<WeechatRoomMember>,
+    ) {
+        let sender_id = &event.sender;
+        let target_id = &event.state_key;
+
+        // Display the event message
+        let message = match (sender, target) {
+            (Some(sender), Some(target)) => {
+                render_membership(event, sender, target)
+            }
 
-                    "ERROR: cannot render event since sender or target are not a room member".into()
+            _ => {
+                if sender.is_none() {
+                    error!(
+                        "Cannot render event since event sender {} is not a room member",
+                        sender_id);
                 }
-            };
 
-            let timestamp: i64 =
-                (event.origin_server_ts.0 / uint!(1000)).into();
-            buffer.print_date_tags(timestamp as i64, &[], &message);
+                if target.is_none() {
+                    error!(
+                        "Cannot render event since event target {} is not a room member",
+                        target_id);
+                }
+
+                "ERROR: cannot render event since sender or target are not a room member".into()
+            }
+        };
+
+        let change = event.membership_change();
+        let tag = membership_tag(&change);
+        let mut tags = vec!["matrix_membership"];
+
+        if tag != "matrix_membership" {
+            tags.push(tag);
         }
+
+        // Like the smart filter of the IRC plugin, joins and leaves of
+        // members that didn't take part in the conversation can be hidden.
+        if matches!(change, MembershipChange::Joined | MembershipChange::Left)
+            && UserId::parse(target_id.as_str())
+                .map_or(false, |t| self.is_smart_filtered(&t, timestamp))
+        {
+            tags.extend(SMART_FILTER_TAGS);
+        }
+
+        self.queue_line(
+            timestamp,
+            event.content.membership.clone(),
+            tags,
+            message,
+        );
     }
 }
 
@@ -395,26 +867,20 @@ impl WeechatRoomMember {
         &self.color
     }
 
-    fn nick_raw(&self) -> &str {
-        self.inner.name()
+    fn nick_raw(&self) -> Cow<str> {
+        if self.escape_nick {
+            escape_spoofing_chars(self.inner.name())
+        } else {
+            Cow::Borrowed(self.inner.name())
+        }
     }
 
-    fn nicklist_group_name(&self) -> &str {
-        match self.inner.normalized_power_level() {
-            p if p >= 100 => "000|o",
-            p if p >= 50 => "001|h",
-            p if p > 0 => "002|v",
-            _ => "999|...",
-        }
+    fn nicklist_group_name(&self) -> &'static str {
+        self.thresholds.group(self.inner.normalized_power_level()).0
     }
 
-    fn nicklist_prefix(&self) -> &str {
-        match self.inner.normalized_power_level() {
-            p if p >= 100 => "&",
-            p if p >= 50 => "@",
-            p if p > 0 => "+",
-            _ => " ",
-        }
+    fn nicklist_prefix(&self) -> &'static str {
+        self.thresholds.group(self.inner.normalized_power_level()).1
     }
 
     fn prefix(&self) -> &str {
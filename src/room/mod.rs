@@ -22,23 +22,43 @@
 //! we're sending ourselves before we receive them in a sync response, or if we
 //! decrypt a previously undecryptable event.
 
+mod bridges;
+mod compose;
+mod errors;
+mod gallery;
 mod members;
-
+mod outbound_session;
+mod settings;
+mod stats;
+
+use bridges::{Bridge, BRIDGE_EVENT_TYPES};
+use chrono::{Local, TimeZone, Timelike};
+use compose::ComposeBuffer;
+use errors::RoomAction;
+use gallery::{GalleryBuffer, GalleryItem};
 use members::Members;
-pub use members::WeechatRoomMember;
+pub use members::{NicklistThresholds, WeechatRoomMember};
+use outbound_session::{OutboundSessionTracker, RotationSettings};
+pub use settings::{NotifyLevel, RoomSettings, ROOM_SETTINGS_EVENT_TYPE};
+use stats::{RoomStats, ATTACHMENT_TAG, MESSAGE_TAGS};
 use tokio::runtime::Handle;
-use tracing::{debug, trace};
+use tracing::{debug, error, trace};
 
 use std::{
     borrow::Cow,
-    cell::RefCell,
-    collections::HashMap,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    io::Write,
     ops::Deref,
+    path::PathBuf,
+    process::{Command, Stdio},
     rc::Rc,
     sync::{
         atomic::{AtomicBool, Ordering},
         Mutex, MutexGuard,
     },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use unicode_segmentation::UnicodeSegmentation;
@@ -50,40 +70,264 @@ use matrix_sdk::{
     room::Joined,
     ruma::{
         events::{
+            reaction::{ReactionEventContent, Relation as AnnotationRelation},
+            relation::RelationType,
             room::{
-                member::RoomMemberEventContent,
+                create::RoomCreateEventContent,
+                history_visibility::HistoryVisibility,
+                member::{
+                    MembershipChange, MembershipState, RoomMemberEventContent,
+                },
                 message::{
-                    MessageType, RoomMessageEventContent,
-                    TextMessageEventContent,
+                    AudioMessageEventContent, InReplyTo, MessageFormat,
+                    MessageType, Relation, Replacement,
+                    RoomMessageEventContent, TextMessageEventContent, Thread,
                 },
-                redaction::SyncRoomRedactionEvent,
+                power_levels::RoomPowerLevelsEventContent,
+                redaction::{RoomRedactionEvent, SyncRoomRedactionEvent},
+                MediaSource,
             },
-            AnyMessageLikeEventContent, AnySyncMessageLikeEvent,
-            AnySyncStateEvent, AnySyncTimelineEvent, AnyTimelineEvent,
-            OriginalSyncMessageLikeEvent, SyncMessageLikeEvent, SyncStateEvent,
+            tag::TagName,
+            AnyMessageLikeEvent, AnyMessageLikeEventContent, AnyStateEvent,
+            AnySyncMessageLikeEvent, AnySyncStateEvent, AnySyncTimelineEvent,
+            AnyTimelineEvent, MessageLikeEvent, OriginalSyncMessageLikeEvent,
+            OriginalSyncStateEvent, RoomAccountDataEventType, StateEvent,
+            StateEventType, SyncMessageLikeEvent, SyncStateEvent,
         },
-        EventId, MilliSecondsSinceUnixEpoch, OwnedRoomAliasId,
-        OwnedTransactionId, RoomId, TransactionId, UserId,
+        serde::Raw,
+        uint, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId,
+        OwnedRoomAliasId, OwnedRoomId, OwnedServerName, OwnedTransactionId,
+        OwnedUserId, RoomId, TransactionId, UserId,
     },
     StoreError,
 };
 
 use weechat::{
     buffer::{
-        Buffer, BufferBuilderAsync, BufferHandle, BufferInputCallbackAsync,
-        BufferLine, LineData,
+        Buffer, BufferBuilder, BufferBuilderAsync, BufferHandle,
+        BufferInputCallbackAsync, BufferLine, LineData,
     },
-    Weechat,
+    hooks::SignalData,
+    Prefix, Weechat,
 };
 
 use crate::{
-    config::{Config, RedactionStyle},
+    config::{
+        Config, LongWords, OversizedMessage, ReadReceipts, RedactionStyle,
+        RenderProfile,
+    },
     connection::Connection,
-    render::{Render, RenderedEvent},
-    utils::{Edit, ToTag},
+    event_hooks::EventHooks,
+    ignore,
+    media_cache::MediaCache,
+    mentions::Mentions,
+    push_rules::{PushOutcome, RoomNotificationMode},
+    relay,
+    render::{
+        render_html, render_state_change, user_pills, Render, RenderedEvent,
+    },
+    server::{MatrixServer, ServerSettings},
+    sync_log::SyncLog,
+    transform,
+    utils::{
+        command_alias_template, contains_word, escape_html,
+        escape_spoofing_chars, event_type_matches, event_type_patterns,
+        expand_alias_template, flatten_lines, format_duration, format_size,
+        format_timestamp, image_type, media_extension, ogg_opus_info,
+        room_rule, split_message, split_reply_fallback, strip_mx_reply,
+        write_private_file, Edit, Substitution, ToTag, WORD_BREAK,
+    },
     PLUGIN_NAME,
 };
 
+/// The maximal size of the content of an event we're willing to send.
+///
+/// The spec limits the size of a whole event, including the signatures and
+/// hashes that the homeserver adds, to 65536 bytes, so leave some room for
+/// those.
+const MAX_CONTENT_SIZE: usize = 60_000;
+
+/// The number of bytes an encrypted event adds around the ciphertext, the
+/// algorithm, sender key, device and session ids, as well as the header, MAC
+/// and signature of the Megolm message and the padding of the cipher.
+const ENCRYPTION_OVERHEAD: usize = 1_024;
+
+/// The smallest chunk size, in bytes, we split oversized messages into before
+/// giving up.
+const MIN_CHUNK_SIZE: usize = 256;
+
+/// The maximal number of events we request from the server at once when room
+/// history is fetched, servers cap the number of returned events anyways.
+const MAX_HISTORY_PAGE_SIZE: u32 = 100;
+
+/// The event types that are part of the moderation log of a room.
+const MODLOG_EVENT_TYPES: &[&str] =
+    &["m.room.member", "m.room.power_levels", "m.room.redaction"];
+
+/// The maximal number of history pages that are searched for moderation
+/// events.
+const MODLOG_MAX_PAGES: usize = 10;
+
+/// The event types that can contain files, encrypted events are included since
+/// their type is only known once they are decrypted.
+const FILES_EVENT_TYPES: &[&str] = &["m.room.message", "m.room.encrypted"];
+
+/// The maximal number of history pages that are searched for files.
+const FILES_MAX_PAGES: usize = 10;
+
+/// The maximal number of images the gallery lists.
+const GALLERY_MAX_IMAGES: usize = 100;
+
+/// The tag of the line that marks where the unread messages start.
+const UNREAD_MARKER_TAG: &str = "matrix_unread_marker";
+
+/// The maximal number of replies that are followed upwards when a reply chain
+/// is printed.
+const MAX_REPLY_CHAIN_LENGTH: usize = 50;
+
+/// The maximal number of thread replies that are fetched when a thread buffer
+/// is opened.
+const MAX_THREAD_REPLIES: u32 = 100;
+
+/// The maximal number of edits that are fetched to show the edit history of
+/// a message.
+const MAX_EDITS: u32 = 100;
+
+/// The event type of the room retention policy, as defined in MSC1763.
+const RETENTION_EVENT_TYPE: &str = "m.room.retention";
+
+/// The timestamp and the event id of an edit.
+type LatestEdit = (MilliSecondsSinceUnixEpoch, OwnedEventId);
+
+/// The timestamp and the event id of a thread reply.
+type ThreadReply = (MilliSecondsSinceUnixEpoch, OwnedEventId);
+
+/// The timestamp, the media source and the mimetype of an audio message.
+type LastAudio = (MilliSecondsSinceUnixEpoch, MediaSource, Option<String>);
+
+/// How long a confirmed topic or name change of ours is remembered, changes
+/// of other users in this time are reported as conflicting changes.
+const STATE_CONFLICT_WINDOW: Duration = Duration::from_secs(30);
+
+/// The room state that can be changed by the user with an optimistic echo.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StateChange {
+    Topic,
+    Name,
+}
+
+impl StateChange {
+    fn event_type(&self) -> &'static str {
+        match self {
+            StateChange::Topic => "m.room.topic",
+            StateChange::Name => "m.room.name",
+        }
+    }
+
+    /// The field of the event content holding the value.
+    fn field(&self) -> &'static str {
+        match self {
+            StateChange::Topic => "topic",
+            StateChange::Name => "name",
+        }
+    }
+}
+
+/// A topic or name change of ours, and when it came back through sync.
+#[derive(Clone, Debug)]
+struct PendingStateChange {
+    value: String,
+    confirmed_at: Option<Instant>,
+}
+
+/// The file of a file, image, video or audio message.
+#[derive(Clone, Debug)]
+struct Attachment {
+    name: String,
+    source: MediaSource,
+    size: Option<u64>,
+    mimetype: Option<String>,
+}
+
+/// A ban of a user from the room, as listed by `/matrix bans`.
+#[derive(Clone, Debug)]
+struct RoomBan {
+    user_id: OwnedUserId,
+    moderator: OwnedUserId,
+    reason: Option<String>,
+    timestamp: MilliSecondsSinceUnixEpoch,
+}
+
+/// A file that was sent to the room, as listed by `/matrix files`.
+#[derive(Clone, Debug)]
+struct RoomFile {
+    attachment: Attachment,
+    sender: OwnedUserId,
+    timestamp: MilliSecondsSinceUnixEpoch,
+    /// The width and height of images.
+    dimensions: Option<(u64, u64)>,
+}
+
+/// The prefix of the tag that records the delivery state of our messages on
+/// their last line, e.g. `matrix_delivery_read`.
+const DELIVERY_TAG_PREFIX: &str = "matrix_delivery_";
+
+/// The tag of lines that end with a delivery marker.
+const DELIVERY_MARKER_TAG: &str = "matrix_delivery_marker";
+
+/// How far one of our own messages made it, the state only ever advances.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum DeliveryState {
+    /// The server accepted the message.
+    Sent,
+    /// The message came back in our own sync response.
+    Synced,
+    /// Another member of the room read the message.
+    Read,
+}
+
+impl DeliveryState {
+    /// The tag of the last line of a message in this state.
+    fn tag(self) -> String {
+        let name = match self {
+            Self::Sent => "sent",
+            Self::Synced => "synced",
+            Self::Read => "read",
+        };
+
+        format!("{}{}", DELIVERY_TAG_PREFIX, name)
+    }
+
+    /// Get the delivery state a line is tagged with.
+    fn from_tags(tags: &[Cow<str>]) -> Option<Self> {
+        [Self::Sent, Self::Synced, Self::Read]
+            .iter()
+            .rev()
+            .copied()
+            .find(|s| tags.iter().any(|t| *t == s.tag()))
+    }
+}
+
+/// A reaction to one of the messages in the room.
+#[derive(Clone, Debug)]
+struct Reaction {
+    event_id: OwnedEventId,
+    sender: OwnedUserId,
+    key: String,
+}
+
+/// The maximal number of relations that wait for the event they relate to.
+const MAX_PENDING_RELATIONS: usize = 500;
+
+/// An edit or redaction whose target wasn't printed yet, e.g. because it
+/// arrived over federation before the target, or because backfilling handles
+/// newer events first.
+#[derive(Clone, Debug)]
+enum PendingRelation {
+    Edit(AnySyncMessageLikeEvent),
+    Redaction(SyncRoomRedactionEvent),
+}
+
 #[derive(Clone)]
 pub struct RoomHandle {
     inner: MatrixRoom,
@@ -158,26 +402,234 @@ pub struct MatrixRoom {
     prev_batch: Rc<RefCell<Option<PrevBatch>>>,
 
     outgoing_messages: MessageQueue,
+    delayed_messages: Rc<RefCell<Vec<OwnedTransactionId>>>,
+    editing: Rc<RefCell<Option<OwnedEventId>>>,
+    unread_messages: Rc<Cell<u64>>,
+    /// The event our latest read receipt was sent for.
+    last_read_receipt: Rc<RefCell<Option<OwnedEventId>>>,
+    /// The fully read marker of the account, until the unread marker line
+    /// got placed after the event it points to.
+    fully_read: Rc<RefCell<Option<OwnedEventId>>>,
+    /// The event the fully read marker of the account points to, as far as
+    /// we know, used to avoid moving the marker to where it already is.
+    fully_read_event: Rc<RefCell<Option<OwnedEventId>>>,
+    typing_users: Rc<RefCell<Vec<OwnedUserId>>>,
+    /// The nicks of the users that are typing, without our own.
+    typing_nicks: Rc<RefCell<Vec<String>>>,
+    /// The latest applied edit, and its timestamp, for every edited event.
+    latest_edits: Rc<RefCell<HashMap<OwnedEventId, LatestEdit>>>,
+    /// The maximal lifetime of events in the room, according to its
+    /// retention policy.
+    retention: Rc<Cell<Option<Duration>>>,
+    /// Did we disable the logger of the room because of its retention
+    /// policy.
+    retention_logger_disabled: Rc<Cell<bool>>,
+    sync_log: SyncLog,
+    reply_chain_buffer: Rc<RefCell<Option<BufferHandle>>>,
+    source_buffer: Rc<RefCell<Option<BufferHandle>>>,
+    compose_buffer: Rc<RefCell<Option<ComposeBuffer>>>,
+    gallery_buffer: Rc<RefCell<Option<GalleryBuffer>>>,
+    /// The notification mode of the room according to the push rules, once
+    /// they were fetched.
+    notification_mode: Rc<Cell<Option<RoomNotificationMode>>>,
+    /// Our estimate of the outbound group session of the room.
+    outbound_session: Rc<RefCell<OutboundSessionTracker>>,
+    last_audio: Rc<RefCell<Option<LastAudio>>>,
+    /// The sender and the flow id of the latest in-room verification request
+    /// that was sent to us.
+    verification_request: Rc<RefCell<Option<(OwnedUserId, OwnedEventId)>>>,
+    /// The number of unverified devices in the room, computed in the
+    /// background when device lists or the members change.
+    unverified_devices: Rc<Cell<usize>>,
+    /// The number of the latest computation of the unverified devices, 0 if
+    /// they were never computed.
+    unverified_devices_update: Rc<Cell<u64>>,
+    /// The room had encryption enabled but its state claims that it's
+    /// disabled now.
+    encryption_downgraded: Rc<Cell<bool>>,
+    server_settings: Rc<RefCell<ServerSettings>>,
+    settings: Rc<RefCell<RoomSettings>>,
+    pending_state: Rc<RefCell<HashMap<StateChange, PendingStateChange>>>,
+    /// The timestamp of the latest message that was printed in the buffer
+    /// from a sync response.
+    last_activity: Rc<Cell<Option<MilliSecondsSinceUnixEpoch>>>,
+    /// The files of the last `/matrix files` listing, newest first.
+    files: Rc<RefCell<Vec<RoomFile>>>,
+    /// The bans of the last `/matrix bans` listing, newest first.
+    bans: Rc<RefCell<Vec<RoomBan>>>,
+    media_cache: MediaCache,
+    /// The reactions of the messages in the room, by the id of the message.
+    reactions: Rc<RefCell<HashMap<OwnedEventId, Vec<Reaction>>>>,
+    /// The relations that are applied once their target gets printed, with
+    /// the id of the target, oldest first.
+    pending_relations: Rc<RefCell<VecDeque<(OwnedEventId, PendingRelation)>>>,
+    /// The reaction and thread annotations that were appended to the lines
+    /// of messages.
+    annotations: Rc<RefCell<HashMap<OwnedEventId, String>>>,
+    /// The known replies of the threads in the room and their timestamps, by
+    /// the id of the thread root, oldest first.
+    threads: Rc<RefCell<HashMap<OwnedEventId, Vec<ThreadReply>>>>,
+    /// The ids of the edits that were received, by the id of the edited
+    /// message.
+    edits: Rc<RefCell<HashMap<OwnedEventId, HashSet<OwnedEventId>>>>,
+    thread_buffers: Rc<RefCell<HashMap<OwnedEventId, BufferHandle>>>,
 
     members: Members,
 }
 
+/// The state of a message in our outgoing message queue.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutgoingState {
+    /// The message is being held back by the send delay.
+    Delayed,
+    /// The message is being sent to the server.
+    Sending,
+    /// Sending the message failed with the given error.
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct OutgoingMessage {
+    /// Did we print out a local echo for this message.
+    pub echo: bool,
+    pub content: RoomMessageEventContent,
+    pub state: OutgoingState,
+    pub queued_at: Instant,
+}
+
+/// The outgoing message queue of a room.
+///
+/// The queue is persisted in the server directory, messages that weren't sent
+/// out when WeeChat quit are restored as failed messages, they can be retried
+/// or cancelled using the outbox.
+///
+/// The queues of encrypted rooms are only kept in memory, the content of
+/// their messages shouldn't end up on the disk in plaintext.
 #[derive(Debug, Clone, Default)]
 pub struct MessageQueue {
-    queue: Rc<
-        RefCell<HashMap<OwnedTransactionId, (bool, RoomMessageEventContent)>>,
-    >,
+    queue: Rc<RefCell<HashMap<OwnedTransactionId, OutgoingMessage>>>,
+    path: Rc<PathBuf>,
+    persistent: Rc<Cell<bool>>,
 }
 
 impl MessageQueue {
-    fn new() -> Self {
+    /// Load the queue that was persisted to the given file.
+    ///
+    /// A missing or invalid file results in an empty queue. If the queue
+    /// isn't persistent the file is removed once the queue changes.
+    fn load(path: PathBuf, persistent: bool) -> Self {
+        let entries: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .ok()
+            .and_then(|c| serde_json::from_str(&c).ok())
+            .unwrap_or_default();
+
+        let queue = entries
+            .into_iter()
+            .filter_map(|entry| {
+                let transaction_id: OwnedTransactionId =
+                    entry["transaction_id"].as_str()?.into();
+                let content =
+                    serde_json::from_value(entry["content"].clone()).ok()?;
+
+                // Instants can't be persisted, so the age of the message is.
+                let queued_at = entry["queued_at"]
+                    .as_u64()
+                    .map(|t| UNIX_EPOCH + Duration::from_secs(t))
+                    .and_then(|t| SystemTime::now().duration_since(t).ok())
+                    .and_then(|age| Instant::now().checked_sub(age))
+                    .unwrap_or_else(Instant::now);
+
+                let error = entry["error"]
+                    .as_str()
+                    .unwrap_or("sending got interrupted by a restart")
+                    .to_owned();
+
+                Some((
+                    transaction_id,
+                    OutgoingMessage {
+                        echo: false,
+                        content,
+                        state: OutgoingState::Failed(error),
+                        queued_at,
+                    },
+                ))
+            })
+            .collect();
+
         Self {
-            queue: Rc::new(RefCell::new(HashMap::new())),
+            queue: Rc::new(RefCell::new(queue)),
+            path: Rc::new(path),
+            persistent: Rc::new(Cell::new(persistent)),
+        }
+    }
+
+    /// Set if the queue is persisted, the file of the queue is removed once
+    /// it isn't.
+    fn set_persistent(&self, persistent: bool) {
+        if self.persistent.replace(persistent) != persistent {
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        let queue = self.queue.borrow();
+
+        if queue.is_empty() || !self.persistent.get() {
+            let _ = fs::remove_file(&*self.path);
+            return;
+        }
+
+        let entries: Vec<serde_json::Value> = queue
+            .iter()
+            .filter_map(|(transaction_id, message)| {
+                let queued_at = SystemTime::now()
+                    .checked_sub(message.queued_at.elapsed())
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs());
+
+                let error = match &message.state {
+                    OutgoingState::Failed(e) => Some(e),
+                    _ => None,
+                };
+
+                Some(serde_json::json!({
+                    "transaction_id": transaction_id.as_str(),
+                    "content": serde_json::to_value(&message.content).ok()?,
+                    "queued_at": queued_at,
+                    "error": error,
+                }))
+            })
+            .collect();
+
+        let ret = write_private_file(
+            &self.path,
+            serde_json::Value::from(entries).to_string().as_bytes(),
+        );
+
+        if let Err(e) = ret {
+            error!("Error saving the outgoing message queue: {}", e);
         }
     }
 
+    fn insert(
+        &self,
+        uuid: OwnedTransactionId,
+        content: RoomMessageEventContent,
+        echo: bool,
+    ) {
+        let message = OutgoingMessage {
+            echo,
+            content,
+            state: OutgoingState::Sending,
+            queued_at: Instant::now(),
+        };
+
+        self.queue.borrow_mut().insert(uuid, message);
+        self.save();
+    }
+
     fn add(&self, uuid: OwnedTransactionId, content: RoomMessageEventContent) {
-        self.queue.borrow_mut().insert(uuid, (false, content));
+        self.insert(uuid, content, false);
     }
 
     fn add_with_echo(
@@ -185,14 +637,43 @@ impl MessageQueue {
         uuid: OwnedTransactionId,
         content: RoomMessageEventContent,
     ) {
-        self.queue.borrow_mut().insert(uuid, (true, content));
+        self.insert(uuid, content, true);
     }
 
-    fn remove(
-        &self,
-        uuid: &TransactionId,
-    ) -> Option<(bool, RoomMessageEventContent)> {
-        self.queue.borrow_mut().remove(uuid)
+    fn get(&self, uuid: &TransactionId) -> Option<OutgoingMessage> {
+        self.queue.borrow().get(uuid).cloned()
+    }
+
+    fn set_state(&self, uuid: &TransactionId, state: OutgoingState) {
+        if let Some(message) = self.queue.borrow_mut().get_mut(uuid) {
+            message.state = state;
+        }
+
+        self.save();
+    }
+
+    fn remove(&self, uuid: &TransactionId) -> Option<OutgoingMessage> {
+        let message = self.queue.borrow_mut().remove(uuid);
+
+        if message.is_some() {
+            self.save();
+        }
+
+        message
+    }
+
+    /// Get all the queued messages, oldest first.
+    fn messages(&self) -> Vec<(OwnedTransactionId, OutgoingMessage)> {
+        let mut messages: Vec<_> = self
+            .queue
+            .borrow()
+            .iter()
+            .map(|(t, m)| (t.clone(), m.clone()))
+            .collect();
+
+        messages.sort_by_key(|(_, m)| m.queued_at);
+
+        messages
     }
 }
 
@@ -202,12 +683,19 @@ impl RoomHandle {
         runtime: Handle,
         connection: &Rc<RefCell<Option<Connection>>>,
         config: Rc<RefCell<Config>>,
+        server_settings: Rc<RefCell<ServerSettings>>,
         room: Joined,
         homeserver: Url,
         room_id: &RoomId,
         own_user_id: &UserId,
+        sync_log: SyncLog,
     ) -> Self {
-        let members = Members::new(room.clone(), runtime.clone());
+        let members = Members::new(
+            room.clone(),
+            runtime.clone(),
+            config.clone(),
+            server_settings.clone(),
+        );
 
         let own_nick = runtime
             .block_on(room.get_member_no_sync(own_user_id))
@@ -227,8 +715,56 @@ impl RoomHandle {
             own_user_id: own_user_id.into(),
             members: members.clone(),
             buffer: members.buffer,
-            outgoing_messages: MessageQueue::new(),
+            outgoing_messages: MessageQueue::load(
+                MatrixServer::server_path(server_name).join("outbox").join(
+                    format!(
+                        "{}.json",
+                        MatrixRoom::media_file_name(room_id.as_str())
+                    ),
+                ),
+                !room.is_encrypted(),
+            ),
+            delayed_messages: Rc::new(RefCell::new(Vec::new())),
             messages_in_flight: IntMutex::new(),
+            editing: Rc::new(RefCell::new(None)),
+            unread_messages: Rc::new(Cell::new(0)),
+            last_read_receipt: Rc::new(RefCell::new(None)),
+            fully_read: Rc::new(RefCell::new(None)),
+            fully_read_event: Rc::new(RefCell::new(None)),
+            typing_users: Rc::new(RefCell::new(Vec::new())),
+            typing_nicks: Rc::new(RefCell::new(Vec::new())),
+            latest_edits: Rc::new(RefCell::new(HashMap::new())),
+            retention: Rc::new(Cell::new(None)),
+            retention_logger_disabled: Rc::new(Cell::new(false)),
+            sync_log,
+            reply_chain_buffer: Rc::new(RefCell::new(None)),
+            source_buffer: Rc::new(RefCell::new(None)),
+            compose_buffer: Rc::new(RefCell::new(None)),
+            gallery_buffer: Rc::new(RefCell::new(None)),
+            notification_mode: Rc::new(Cell::new(None)),
+            outbound_session: Rc::new(RefCell::new(
+                OutboundSessionTracker::default(),
+            )),
+            last_audio: Rc::new(RefCell::new(None)),
+            verification_request: Rc::new(RefCell::new(None)),
+            unverified_devices: Rc::new(Cell::new(0)),
+            unverified_devices_update: Rc::new(Cell::new(0)),
+            encryption_downgraded: Rc::new(Cell::new(false)),
+            server_settings,
+            settings: Rc::new(RefCell::new(RoomSettings::default())),
+            pending_state: Rc::new(RefCell::new(HashMap::new())),
+            last_activity: Rc::new(Cell::new(None)),
+            files: Rc::new(RefCell::new(Vec::new())),
+            bans: Rc::new(RefCell::new(Vec::new())),
+            reactions: Rc::new(RefCell::new(HashMap::new())),
+            pending_relations: Rc::new(RefCell::new(VecDeque::new())),
+            annotations: Rc::new(RefCell::new(HashMap::new())),
+            threads: Rc::new(RefCell::new(HashMap::new())),
+            edits: Rc::new(RefCell::new(HashMap::new())),
+            thread_buffers: Rc::new(RefCell::new(HashMap::new())),
+            media_cache: MediaCache::new(
+                MatrixServer::server_path(server_name).join("media"),
+            ),
             room,
         };
 
@@ -236,10 +772,15 @@ impl RoomHandle {
 
         let buffer_handle = BufferBuilderAsync::new(&buffer_name)
             .input_callback(room.clone())
-            .close_callback(|_weechat: &Weechat, _buffer: &Buffer| {
-                // TODO: remove the roombuffer from the server here.
-                // TODO: leave the room if the plugin isn't unloading.
-                Ok(())
+            .close_callback({
+                let room = room.clone();
+
+                move |_weechat: &Weechat, _buffer: &Buffer| {
+                    room.auto_mark_read();
+                    // TODO: remove the roombuffer from the server here.
+                    // TODO: leave the room if the plugin isn't unloading.
+                    Ok(())
+                }
             })
             .build()
             .expect("Can't create new room buffer");
@@ -301,6 +842,11 @@ impl RoomHandle {
 
         *room.members.buffer.borrow_mut() = Some(buffer_handle.clone());
 
+        room.update_localvars();
+        room.update_space_localvar();
+        room.update_input_settings();
+        room.update_retention();
+
         Self { inner: room }
     }
 
@@ -345,6 +891,8 @@ impl RoomHandle {
 
         room_buffer.update_buffer_name();
         room_buffer.set_topic();
+        room_buffer.load_settings().await;
+        room_buffer.load_fully_read_marker().await;
 
         Ok(room_buffer)
     }
@@ -353,697 +901,6569 @@ impl RoomHandle {
 #[async_trait(?Send)]
 impl BufferInputCallbackAsync for MatrixRoom {
     async fn callback(&mut self, _: BufferHandle, input: String) {
-        let content = if self.config.borrow().input().markdown_input() {
-            RoomMessageEventContent::new(MessageType::Text(
-                TextMessageEventContent::markdown(input),
-            ))
-        } else {
-            RoomMessageEventContent::new(MessageType::Text(
-                TextMessageEventContent::plain(input),
-            ))
-        };
+        let editing = self.editing.borrow_mut().take();
 
-        self.send_message(content).await;
+        if let Some(event_id) = editing {
+            let input = self.transform_input(&input);
+            self.send_edit(&event_id, &input).await;
+        } else if let Some(substitution) = Substitution::parse(&input) {
+            self.correct_last_message(&substitution).await;
+        } else {
+            let input = self.transform_input(&input);
+            self.send_text(&input).await
+        }
     }
 }
 
 impl MatrixRoom {
+    /// Is the room encrypted.
+    ///
+    /// Encryption can't be disabled once it's enabled, rooms whose state
+    /// claims otherwise are still considered to be encrypted.
     pub fn is_encrypted(&self) -> bool {
-        self.room.is_encrypted()
+        self.room.is_encrypted() || self.encryption_downgraded.get()
     }
 
-    pub fn contains_only_verified_devices(&self) -> bool {
-        self.members
-            .runtime
-            .block_on(self.room.contains_only_verified_devices())
-            .unwrap_or_default()
-    }
+    /// Mark the room as one whose state claims that encryption got disabled
+    /// after it was enabled.
+    pub fn set_encryption_downgraded(&self) {
+        if self.encryption_downgraded.replace(true) {
+            return;
+        }
 
-    pub fn is_public(&self) -> bool {
-        self.room.is_public()
-    }
+        self.print_error(
+            "The room state claims that encryption is disabled, but \
+             encryption can't be disabled once it's enabled. The room is \
+             still treated as encrypted and no messages will be sent to it.",
+        );
 
-    pub fn is_direct(&self) -> bool {
-        self.room.is_direct()
+        self.update_localvars();
+        Weechat::bar_item_update("buffer_modes");
     }
 
-    pub fn alias(&self) -> Option<OwnedRoomAliasId> {
-        self.room.canonical_alias()
+    /// Is the server of the room in read-only spectator mode.
+    fn read_only(&self) -> bool {
+        self.server_settings.borrow().read_only
     }
 
-    pub fn room_id(&self) -> &RoomId {
-        &self.room_id
+    /// Check if sending to the room needs to be refused since the server is
+    /// in read-only mode, prints an error if so.
+    fn refuse_read_only(&self) -> bool {
+        let refuse = self.read_only();
+
+        if refuse {
+            self.print_error(
+                "Refusing to send to the room, the server is in read-only \
+                 mode",
+            );
+        }
+
+        refuse
     }
 
-    pub fn buffer_handle(&self) -> BufferHandle {
-        self.buffer
-            .borrow()
-            .as_ref()
-            .expect("Room struct wasn't initialized properly")
-            .clone()
+    /// Check if sending a message to the room needs to be refused, either
+    /// because of the read-only mode or the encryption of the room.
+    fn refuse_send(&self) -> bool {
+        self.refuse_read_only() || self.refuse_plaintext()
     }
 
-    fn print_rendered_event(&self, rendered: RenderedEvent) {
-        let buffer = self.buffer_handle();
+    /// Check if sending to the room needs to be refused since the SDK would
+    /// send unencrypted events to it, prints an error if so.
+    fn refuse_plaintext(&self) -> bool {
+        let refuse = self.encryption_downgraded.get();
 
-        if let Ok(buffer) = buffer.upgrade() {
-            for line in rendered.content.lines {
-                let message = format!("{}{}", &rendered.prefix, &line.message);
-                let tags: Vec<&str> =
-                    line.tags.iter().map(|t| t.as_str()).collect();
-                buffer.print_date_tags(
-                    rendered.message_timestamp,
-                    &tags,
-                    &message,
-                )
-            }
+        if refuse {
+            self.print_error(
+                "Refusing to send an unencrypted message to a room that had \
+                 encryption enabled",
+            );
         }
+
+        refuse
     }
 
-    async fn redact_event(&self, event: &SyncRoomRedactionEvent) {
-        let event = if let SyncRoomRedactionEvent::Original(e) = event {
-            e
-        } else {
-            // Redacted redaction events don't contain enough data to be applied, so there's
-            // nothing to do here.
-            return;
-        };
+    /// Update the localvars that expose the state of the room to buflist
+    /// conditions and triggers.
+    fn update_localvars(&self) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            let encrypted = if self.is_encrypted() { "1" } else { "0" };
 
-        let buffer_handle = self.buffer_handle();
+            buffer.set_localvar("encrypted", encrypted);
+            buffer.set_localvar(
+                "members",
+                &self.room.joined_members_count().to_string(),
+            );
+            buffer.set_localvar(
+                "unread",
+                &self.unread_messages.get().to_string(),
+            );
+            buffer.set_localvar(
+                "typing",
+                &self.typing_nicks.borrow().len().to_string(),
+            );
+        }
+    }
 
-        let buffer = if let Ok(b) = buffer_handle.upgrade() {
+    /// Configure the spell checker and the handling of unknown commands of
+    /// the room buffer according to the input options.
+    ///
+    /// The dictionaries are exposed in the `spell_lang` localvar as well.
+    /// Messages composed with /code stay unchecked, the spell plugin skips
+    /// commands that aren't listed in spell.check.commands.
+    fn update_input_settings(&self) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
             b
         } else {
             return;
         };
 
-        // TODO: remove this unwrap.
-        let redacter = self.members.get(&event.sender).await.unwrap();
+        let (rules, unknown_commands) = {
+            let config = self.config.borrow();
+            let input = config.input();
+            (
+                input.spell_dictionaries(),
+                input.unknown_commands_as_messages(),
+            )
+        };
 
-        let event_id_tag =
-            Cow::from(format!("{}_id_{}", PLUGIN_NAME, event.redacts));
-        let tag = Cow::from("matrix_redacted");
+        let room_matches =
+            |room: &str| room == "*" || self.matches_room_list(room);
 
-        let reason = if let Some(r) = &event.content.reason {
-            format!(", reason: {}", r)
+        if let Some(dictionaries) = room_rule(&rules, room_matches) {
+            buffer.set_localvar("spell_lang", dictionaries);
+            let _ = buffer
+                .run_command(&format!("/mute /spell setdict {}", dictionaries));
+        }
+
+        let _ = buffer.run_command(&format!(
+            "/mute /buffer set input_get_unknown_commands {}",
+            if unknown_commands { "1" } else { "0" }
+        ));
+    }
+
+    /// Move all the members into the nicklist group of their power level.
+    pub fn refresh_nicklist(&self) {
+        self.members.refresh_nicklist();
+    }
+
+    /// Get the id of the space the room belongs to.
+    ///
+    /// A room can claim to be part of multiple spaces, the one marked as
+    /// canonical is preferred.
+    fn parent_space(&self) -> Option<String> {
+        let events = self
+            .members
+            .runtime
+            .block_on(self.room.get_state_events(StateEventType::SpaceParent))
+            .ok()?;
+
+        // Parent events without a via list are considered to be removed.
+        let parents: Vec<serde_json::Value> = events
+            .iter()
+            .filter_map(|e| e.deserialize_as::<serde_json::Value>().ok())
+            .filter(|e| {
+                e["content"]["via"]
+                    .as_array()
+                    .map_or(false, |v| !v.is_empty())
+            })
+            .collect();
+
+        parents
+            .iter()
+            .find(|e| e["content"]["canonical"].as_bool() == Some(true))
+            .or_else(|| parents.first())
+            .and_then(|e| e["state_key"].as_str())
+            .map(|s| s.to_owned())
+    }
+
+    fn update_space_localvar(&self) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            let space = self.parent_space().unwrap_or_default();
+            buffer.set_localvar("space", &space);
+        }
+    }
+
+    /// Get the number of devices of the room members, besides our own
+    /// device, that aren't verified.
+    ///
+    /// This only reads the cached count, the status bar item needs it on
+    /// every update. The first call starts the computation of the count.
+    pub fn unverified_device_count(&self) -> usize {
+        if self.unverified_devices_update.get() == 0 {
+            self.reset_unverified_device_count();
+        }
+
+        self.unverified_devices.get()
+    }
+
+    /// Recompute the number of unverified devices in the background, the
+    /// status bar item is updated once the new count is known.
+    pub fn reset_unverified_device_count(&self) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
         } else {
-            "".to_owned()
+            return;
         };
-        let redaction_message = format!(
-            "{}<{}Message redacted by: {}{}{}>{}",
-            Weechat::color("chat_delimiters"),
-            Weechat::color("logger.color.backlog_line"),
-            redacter.nick(),
-            reason,
-            Weechat::color("chat_delimiters"),
-            Weechat::color("reset"),
-        );
 
-        let redaction_style = self.config.borrow().look().redaction_style();
+        let update = self.unverified_devices_update.get() + 1;
+        self.unverified_devices_update.set(update);
 
-        let predicate = |l: &BufferLine| {
-            let tags = l.tags();
-            tags.contains(&event_id_tag)
-                && !tags.contains(&Cow::from("matrix_redacted"))
-        };
+        let client = connection.client().clone();
+        let room = self.room.clone();
+        let own_device_id = client.device_id().map(ToOwned::to_owned);
 
-        let strike_through = |string: Cow<str>| {
-            Weechat::remove_color(&string)
-                .graphemes(true)
-                .map(|g| format!("{}\u{0336}", g))
-                .collect::<Vec<String>>()
-                .join("")
-        };
+        let count = async move {
+            let mut count = 0;
 
-        let redact_first_line = |message: Cow<str>| match redaction_style {
-            RedactionStyle::Delete => redaction_message.clone(),
-            RedactionStyle::Notice => {
-                format!("{} {}", message, redaction_message)
-            }
-            RedactionStyle::StrikeThrough => {
-                format!("{} {}", strike_through(message), redaction_message)
+            for user_id in room.joined_user_ids().await.unwrap_or_default() {
+                if let Ok(devices) =
+                    client.encryption().get_user_devices(&user_id).await
+                {
+                    count += devices
+                        .devices()
+                        .filter(|d| {
+                            !d.is_verified()
+                                && Some(d.device_id())
+                                    != own_device_id.as_deref()
+                        })
+                        .count();
+                }
             }
-        };
 
-        let redact_string = |message: Cow<str>| match redaction_style {
-            RedactionStyle::Delete => redaction_message.clone(),
-            RedactionStyle::Notice => {
-                format!("{} {}", message, redaction_message)
-            }
-            RedactionStyle::StrikeThrough => strike_through(message),
+            count
         };
 
-        fn modify_line<F>(line: BufferLine, tag: Cow<str>, redaction_func: F)
-        where
+        let unverified_devices = self.unverified_devices.clone();
+        let latest_update = self.unverified_devices_update.clone();
+
+        Weechat::spawn(async move {
+            let count = connection.spawn(count).await;
+
+            // A newer computation was started in the meantime.
+            if latest_update.get() == update {
+                unverified_devices.set(count);
+                Weechat::bar_item_update("buffer_modes");
+            }
+        })
+        .detach();
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.room.is_public()
+    }
+
+    pub fn is_direct(&self) -> bool {
+        self.room.is_direct()
+    }
+
+    pub fn alias(&self) -> Option<OwnedRoomAliasId> {
+        self.room.canonical_alias()
+    }
+
+    pub fn room_id(&self) -> &RoomId {
+        &self.room_id
+    }
+
+    /// Check if the room is part of a comma separated list of room ids and
+    /// aliases, as used by our config options.
+    pub fn matches_room_list(&self, list: &str) -> bool {
+        let alias = self.alias();
+
+        list.split(',').map(str::trim).any(|entry| {
+            entry == self.room_id().as_str()
+                || alias.as_ref().map_or(false, |a| a.as_str() == entry)
+        })
+    }
+
+    /// Run the command alias that the given command invokes in this room, if
+    /// there is one in the `input.command_aliases` option.
+    ///
+    /// Returns true if the command was an alias and the expanded template was
+    /// sent as a message.
+    pub fn run_command_alias(&self, command: &str) -> bool {
+        let aliases = self.config.borrow().input().command_aliases();
+
+        let mut words = command.split_whitespace();
+        let alias = if let Some(a) = words.next() {
+            a
+        } else {
+            return false;
+        };
+        let args: Vec<&str> = words.collect();
+
+        let room_matches =
+            |room: &str| room == "*" || self.matches_room_list(room);
+
+        let template = if let Some(t) =
+            command_alias_template(&aliases, alias, room_matches)
+        {
+            t
+        } else {
+            return false;
+        };
+
+        let content =
+            self.text_content(&expand_alias_template(template, &args));
+        let room = self.clone();
+
+        Weechat::spawn(async move { room.send_message(content).await })
+            .detach();
+
+        true
+    }
+
+    /// Check if the content of a message matches one of the
+    /// `look.ignored_content` rules of the room.
+    fn is_content_ignored(
+        &self,
+        content: Option<AnyMessageLikeEventContent>,
+    ) -> bool {
+        let body = if let Some(AnyMessageLikeEventContent::RoomMessage(c)) =
+            &content
+        {
+            c.msgtype.body()
+        } else {
+            return false;
+        };
+
+        let server_name = self
+            .buffer_handle()
+            .upgrade()
+            .ok()
+            .and_then(|b| b.get_localvar("server").map(|s| s.to_string()))
+            .unwrap_or_default();
+
+        ignore::is_ignored(
+            &self.config.borrow().look().ignored_content(),
+            body,
+            &server_name,
+            self.room_id(),
+            self.alias().as_deref(),
+        )
+    }
+
+    /// Check if events of the given type should be rendered in the room,
+    /// according to the `look.allowed_event_types` and
+    /// `look.denied_event_types` options.
+    fn is_event_type_shown(&self, event_type: &str) -> bool {
+        let (allowed, denied) = {
+            let config = self.config.borrow();
+            let look = config.look();
+
+            (look.allowed_event_types(), look.denied_event_types())
+        };
+
+        let room_matches =
+            |room: &str| room == "*" || self.matches_room_list(room);
+        let type_matches =
+            |pattern: &&str| event_type_matches(pattern, event_type);
+
+        let allowed = event_type_patterns(&allowed, room_matches);
+        let denied = event_type_patterns(&denied, room_matches);
+
+        (allowed.is_empty() || allowed.iter().any(type_matches))
+            && !denied.iter().any(type_matches)
+    }
+
+    /// Get the maximal lifetime of events in the room from its
+    /// `m.room.retention` state event.
+    fn retention_max_lifetime(&self) -> Option<Duration> {
+        let event = self
+            .members
+            .runtime
+            .block_on(self.room.get_state_event(
+                StateEventType::from(RETENTION_EVENT_TYPE),
+                "",
+            ))
+            .ok()
+            .flatten()?;
+
+        let event = event.deserialize_as::<serde_json::Value>().ok()?;
+
+        event["content"]["max_lifetime"]
+            .as_u64()
+            .map(Duration::from_millis)
+    }
+
+    /// Check if the server deletes the messages of the room sooner than the
+    /// `look.retention_warning_days` option allows.
+    pub fn has_short_retention(&self) -> bool {
+        let days = self.config.borrow().look().retention_warning_days();
+
+        match self.retention.get() {
+            Some(lifetime) if days > 0 => {
+                lifetime < Duration::from_secs(days as u64 * 24 * 60 * 60)
+            }
+            _ => false,
+        }
+    }
+
+    /// Re-read the retention policy of the room, print a notice if the
+    /// messages of the room are deleted after a short time and apply the
+    /// `look.retention_compliance` option to the room.
+    fn update_retention(&self) {
+        let lifetime = self.retention_max_lifetime();
+
+        if lifetime == self.retention.get() {
+            return;
+        }
+
+        self.retention.set(lifetime);
+        Weechat::bar_item_update("buffer_modes");
+        self.apply_retention_compliance();
+
+        let lifetime = if let Some(l) = lifetime {
+            l
+        } else {
+            return;
+        };
+
+        if self.has_short_retention() {
+            self.print_network(&format!(
+                "Messages in this room are deleted by the server after {}",
+                format_duration(lifetime)
+            ));
+        }
+    }
+
+    /// Should the messages of the room be kept off the disk because of its
+    /// retention policy and the `look.retention_compliance` option.
+    fn retention_compliant(&self) -> bool {
+        self.retention.get().is_some()
+            && self.config.borrow().look().retention_compliance()
+    }
+
+    /// Disable the logger of the room and stop storing its messages locally
+    /// if the room has a retention policy and the `look.retention_compliance`
+    /// option is enabled, undo this otherwise.
+    ///
+    /// The logger is only enabled again if we were the ones that disabled
+    /// it.
+    pub fn apply_retention_compliance(&self) {
+        let compliant = self.retention_compliant();
+
+        self.sync_log.set_room_redacted(self.room_id(), compliant);
+        self.update_outbox_persistence();
+
+        if compliant == self.retention_logger_disabled.get() {
+            return;
+        }
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            let command = if compliant {
+                "/mute /logger disable".to_owned()
+            } else {
+                // Disabling the logger sets the log level of the buffer to 0,
+                // unsetting it restores the level the buffer had.
+                format!("/mute /unset logger.level.{}", buffer.full_name())
+            };
+
+            let _ = buffer.run_command(&command);
+            self.retention_logger_disabled.set(compliant);
+        }
+    }
+
+    /// Persist the outgoing messages only if the room is unencrypted and its
+    /// retention policy doesn't keep us from doing so.
+    fn update_outbox_persistence(&self) {
+        self.outgoing_messages.set_persistent(
+            !self.is_encrypted() && !self.retention_compliant(),
+        );
+    }
+
+    pub fn buffer_handle(&self) -> BufferHandle {
+        self.buffer
+            .borrow()
+            .as_ref()
+            .expect("Room struct wasn't initialized properly")
+            .clone()
+    }
+
+    /// Is the room buffer muted.
+    pub fn is_muted(&self) -> bool {
+        self.buffer_handle().upgrade().map_or(false, |b| {
+            b.get_localvar("matrix_muted").map_or(false, |m| m == "1")
+        })
+    }
+
+    /// Get the notify level that is set for the room buffer, `reset` if the
+    /// buffer uses the default level.
+    pub fn buffer_notify_level(&self) -> String {
+        let level = self.buffer_handle().upgrade().ok().and_then(|b| {
+            Weechat::eval_string_expression(&format!(
+                "${{weechat.notify.{}}}",
+                b.full_name()
+            ))
+            .ok()
+        });
+
+        level
+            .filter(|l| !l.is_empty())
+            .unwrap_or_else(|| "reset".to_owned())
+    }
+
+    /// Mute or unmute the room buffer.
+    ///
+    /// A muted buffer isn't added to the hotlist and doesn't notify.
+    ///
+    /// # Arguments
+    ///
+    /// * `muted` - Should the buffer be muted.
+    ///
+    /// * `level` - The notify level that is restored when the buffer gets
+    /// unmuted, the level of the room settings is used if none is given.
+    pub fn set_muted(&self, muted: bool, level: Option<&str>) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        if muted == self.is_muted() && level.is_none() {
+            return;
+        }
+
+        let (localvar, level) = if muted {
+            ("1", "none")
+        } else {
+            ("0", level.unwrap_or_else(|| self.notify_level()))
+        };
+
+        buffer.set_localvar("matrix_muted", localvar);
+        let _ = buffer.run_command(&format!("/mute /buffer notify {}", level));
+    }
+
+    /// Set the notification mode of the room according to the push rules,
+    /// the mode is exposed in the `matrix_notify` localvar.
+    pub fn set_notification_mode(&self, mode: RoomNotificationMode) {
+        self.notification_mode.set(Some(mode));
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.set_localvar("matrix_notify", mode.as_str());
+        }
+
+        Weechat::bar_item_update("matrix_notify");
+    }
+
+    /// Get the notification mode of the room according to the push rules,
+    /// `None` if the push rules weren't fetched yet.
+    pub fn notification_mode(&self) -> Option<RoomNotificationMode> {
+        self.notification_mode.get()
+    }
+
+    /// Print the notification mode of the room.
+    pub fn print_notification_mode(&self) {
+        match self.notification_mode() {
+            Some(mode) => self.print_network(&format!(
+                "Notification mode of the room: {} ({})",
+                mode.as_str(),
+                mode.description()
+            )),
+            None => self.print_network(
+                "The notification mode of the room isn't known yet, the push \
+                 rules weren't fetched",
+            ),
+        }
+    }
+
+    /// Change the notification mode of the room by writing the matching
+    /// push rule.
+    pub async fn change_notification_mode(&self, mode: RoomNotificationMode) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        match connection
+            .set_room_notification_mode(self.room_id().to_owned(), mode)
+            .await
+        {
+            Ok(()) => {
+                self.set_notification_mode(mode);
+                self.print_network(&format!(
+                    "Changed the notification mode of the room to {}, {}",
+                    mode.as_str(),
+                    mode.description()
+                ));
+            }
+            Err(e) => self.print_error(&format!(
+                "Error changing the notification mode: {}",
+                e
+            )),
+        }
+    }
+
+    /// The notify level of the buffer according to the room settings.
+    fn notify_level(&self) -> &'static str {
+        self.settings
+            .borrow()
+            .notify
+            .map_or("reset", |n| n.as_str())
+    }
+
+    /// Get the plugin settings of the room.
+    pub fn settings(&self) -> RoomSettings {
+        self.settings.borrow().clone()
+    }
+
+    /// Load the settings of a restored room from the store.
+    async fn load_settings(&self) {
+        let room = self.room.clone();
+
+        let event = self
+            .members
+            .runtime
+            .spawn(async move {
+                room.account_data(ROOM_SETTINGS_EVENT_TYPE.into()).await
+            })
+            .await
+            .expect("Fetching the room settings from the store panicked");
+
+        match event {
+            Ok(Some(event)) => {
+                if let Ok(Some(content)) =
+                    event.get_field::<serde_json::Value>("content")
+                {
+                    self.apply_settings(RoomSettings::from_json(&content));
+                }
+            }
+            Ok(None) => (),
+            Err(e) => self.print_error(&format!(
+                "Error loading the room settings from the store: {}",
+                e
+            )),
+        }
+    }
+
+    /// Load the fully read marker of the account from the store, the unread
+    /// marker line is placed after the event it points to once the event is
+    /// printed.
+    async fn load_fully_read_marker(&self) {
+        let room = self.room.clone();
+
+        let event = self
+            .members
+            .runtime
+            .spawn(async move {
+                room.account_data(RoomAccountDataEventType::FullyRead).await
+            })
+            .await
+            .expect("Fetching the fully read marker from the store panicked");
+
+        let event_id = match event {
+            Ok(Some(event)) => event
+                .get_field::<serde_json::Value>("content")
+                .ok()
+                .flatten()
+                .and_then(|c| c["event_id"].as_str().map(EventId::parse))
+                .and_then(|e| e.ok()),
+            Ok(None) => None,
+            Err(e) => {
+                error!(
+                    "Error loading the fully read marker of {}: {}",
+                    self.room_id(),
+                    e
+                );
+                None
+            }
+        };
+
+        *self.fully_read_event.borrow_mut() = event_id.clone();
+        *self.fully_read.borrow_mut() = event_id;
+        self.place_fully_read_marker();
+    }
+
+    /// Apply the given settings to the room buffer.
+    pub fn apply_settings(&self, settings: RoomSettings) {
+        let old = self.settings.replace(settings.clone());
+
+        if old == settings {
+            return;
+        }
+
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        if old.notify != settings.notify
+            && buffer
+                .get_localvar("matrix_muted")
+                .map_or(true, |m| m != "1")
+        {
+            let _ = buffer.run_command(&format!(
+                "/mute /buffer notify {}",
+                self.notify_level()
+            ));
+        }
+
+        if old.hidden != settings.hidden {
+            let command = if settings.hidden { "hide" } else { "unhide" };
+            let _ = buffer.run_command(&format!("/mute /buffer {}", command));
+        }
+
+        if old.name != settings.name {
+            *self.members.name_override.borrow_mut() = settings.name;
+            self.update_buffer_name();
+        }
+    }
+
+    /// Change the settings of the room and store them in the room account
+    /// data, so other WeeChat instances pick them up as well.
+    pub async fn update_settings(
+        &self,
+        update: impl FnOnce(&mut RoomSettings),
+    ) {
+        let mut settings = self.settings();
+        update(&mut settings);
+        self.apply_settings(settings.clone());
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error(
+                "Not connected, the room settings will only be stored once \
+                 they're changed again while being connected",
+            );
+            return;
+        };
+
+        if let Err(e) = connection
+            .set_room_account_data(
+                self.room().clone(),
+                ROOM_SETTINGS_EVENT_TYPE,
+                settings.to_json(),
+            )
+            .await
+        {
+            self.print_error(&format!(
+                "Error storing the room settings on the server: {}",
+                e
+            ));
+        }
+    }
+
+    /// Print a network message to the room buffer.
+    pub fn print_network(&self, message: &str) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.print(&format!(
+                "{}{}: {}",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME,
+                message
+            ));
+        }
+    }
+
+    /// Print an error message to the room buffer.
+    pub fn print_error(&self, message: &str) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.print(&format!(
+                "{}{}: {}",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME,
+                message
+            ));
+        }
+    }
+
+    /// Find the id of the last printed event whose tags match the given
+    /// predicate.
+    fn find_last_event<P>(&self, predicate: P) -> Option<OwnedEventId>
+    where
+        P: Fn(&[Cow<str>]) -> bool,
+    {
+        let buffer = self.buffer_handle().upgrade().ok()?;
+
+        let event_id = buffer.lines().rev().find_map(|l| {
+            let tags = l.tags();
+
+            if predicate(&tags) {
+                tags.iter().find_map(|t| {
+                    t.strip_prefix("matrix_id_")
+                        .and_then(|e| EventId::parse(e).ok())
+                })
+            } else {
+                None
+            }
+        });
+
+        event_id
+    }
+
+    /// Find the id of the last event that is printed in the buffer.
+    pub fn last_event_id(&self) -> Option<OwnedEventId> {
+        self.find_last_event(|_| true)
+    }
+
+    /// Find the id of the last text message we sent that is printed in the
+    /// buffer.
+    pub fn last_own_event_id(&self) -> Option<OwnedEventId> {
+        let sender_tag = Cow::from(self.own_user_id.to_tag());
+
+        self.find_last_event(|tags| {
+            tags.contains(&sender_tag)
+                && tags.contains(&Cow::from("matrix_text"))
+                && !tags.contains(&Cow::from("matrix_redacted"))
+        })
+    }
+
+    /// Get the ids of our latest text messages that are printed in the
+    /// buffer, newest first.
+    pub fn own_event_ids(&self, count: usize) -> Vec<OwnedEventId> {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return Vec::new();
+        };
+
+        let sender_tag = Cow::from(self.own_user_id.to_tag());
+        let mut event_ids: Vec<OwnedEventId> = Vec::new();
+
+        for line in buffer.lines().rev() {
+            if event_ids.len() >= count {
+                break;
+            }
+
+            let tags = line.tags();
+
+            if !tags.contains(&sender_tag)
+                || !tags.contains(&Cow::from("matrix_text"))
+                || tags.contains(&Cow::from("matrix_redacted"))
+            {
+                continue;
+            }
+
+            let event_id = tags.iter().find_map(|t| {
+                t.strip_prefix("matrix_id_")
+                    .and_then(|e| EventId::parse(e).ok())
+            });
+
+            if let Some(event_id) = event_id {
+                if !event_ids.contains(&event_id) {
+                    event_ids.push(event_id);
+                }
+            }
+        }
+
+        event_ids
+    }
+
+    /// Find the id of the last edited message that is printed in the buffer.
+    pub fn last_edited_event_id(&self) -> Option<OwnedEventId> {
+        self.find_last_event(|tags| tags.contains(&Cow::from("matrix_edited")))
+    }
+
+    /// Resolve an event given by the user, either as an event id or as `last`
+    /// for the last event that is printed in the buffer.
+    pub fn resolve_event_id(&self, target: &str) -> Option<OwnedEventId> {
+        if target == "last" {
+            self.last_event_id()
+        } else {
+            EventId::parse(target).ok()
+        }
+    }
+
+    /// Get the sender and the plain text body of an event that is printed in
+    /// the buffer.
+    pub fn printed_event(
+        &self,
+        event_id: &EventId,
+    ) -> Option<(OwnedUserId, String)> {
+        let buffer = self.buffer_handle().upgrade().ok()?;
+        let event_id_tag = Cow::from(event_id.to_tag());
+
+        let quote_tag = Cow::from("matrix_reply_quote");
+
+        // The quote of the replied to message isn't part of the message.
+        let lines: Vec<BufferLine> = buffer
+            .lines()
+            .filter(|l| {
+                let tags = l.tags();
+                tags.contains(&event_id_tag) && !tags.contains(&quote_tag)
+            })
+            .collect();
+
+        let sender = lines.first()?.tags().iter().find_map(|t| {
+            t.strip_prefix("matrix_sender_")
+                .and_then(|u| UserId::parse(u).ok())
+        })?;
+
+        // The delivery marker and the reactions aren't part of the message.
+        let mut messages: Vec<String> = lines
+            .iter()
+            .map(|l| {
+                Self::strip_delivery_marker(&l.message(), &l.tags()).to_owned()
+            })
+            .collect();
+
+        if let (Some(annotation), Some(last)) =
+            (self.annotations.borrow().get(event_id), messages.last_mut())
+        {
+            if let Some(m) = last.strip_suffix(annotation.as_str()) {
+                *last = m.to_owned();
+            }
+        }
+
+        // Neither is the marker of edited messages.
+        let edited_tag = Cow::from("matrix_edited");
+
+        if let (true, Some(last)) = (
+            lines.iter().any(|l| l.tags().contains(&edited_tag)),
+            messages.last_mut(),
+        ) {
+            let marker = format!(
+                " {}{}{}",
+                Weechat::color("chat_delimiters"),
+                self.config.borrow().look().edited_marker(),
+                Weechat::color("reset"),
+            );
+
+            if let Some(m) = last.strip_suffix(&marker) {
+                *last = m.to_owned();
+            }
+        }
+
+        // Long words might have been broken up when the event was printed.
+        let body = messages
+            .iter()
+            .map(|m| Weechat::remove_color(m).replace(WORD_BREAK, ""))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        Some((sender, body))
+    }
+
+    fn is_accessible(&self) -> bool {
+        matches!(
+            self.config.borrow().look().render_profile(),
+            RenderProfile::Accessible
+        )
+    }
+
+    /// Apply the configured render profile to a rendered event before it gets
+    /// printed out.
+    fn apply_render_profile(&self, rendered: RenderedEvent) -> RenderedEvent {
+        let rendered = if self.config.borrow().look().escape_spoofing_chars() {
+            rendered.escape_spoofing_chars()
+        } else {
+            rendered
+        };
+
+        let own_message = rendered
+            .content
+            .lines
+            .first()
+            .map_or(false, |l| l.tags.iter().any(|t| t == "self_msg"));
+
+        let rendered = if own_message {
+            let padding = self.config.borrow().look().own_prefix_padding();
+            rendered.pad_prefix(padding as usize)
+        } else {
+            rendered
+        };
+
+        let (long_words, max_word_length) = {
+            let config = self.config.borrow();
+            let look = config.look();
+            (look.long_words(), look.max_word_length() as usize)
+        };
+
+        let rendered = match long_words {
+            LongWords::Off => rendered,
+            LongWords::Wrap => {
+                rendered.limit_word_length(max_word_length, false)
+            }
+            LongWords::Truncate => {
+                rendered.limit_word_length(max_word_length, true)
+            }
+        };
+
+        if self.is_accessible() {
+            rendered.make_accessible()
+        } else {
+            rendered
+        }
+    }
+
+    fn print_rendered_event(&self, rendered: RenderedEvent) {
+        self.members.flush_lines();
+
+        let rendered = self.apply_render_profile(rendered);
+        let buffer = self.buffer_handle();
+
+        if let Ok(buffer) = buffer.upgrade() {
+            for line in rendered.content.lines {
+                let message = format!("{}{}", &rendered.prefix, &line.message);
+                let tags: Vec<&str> =
+                    line.tags.iter().map(|t| t.as_str()).collect();
+                buffer.print_date_tags(
+                    rendered.message_timestamp,
+                    &tags,
+                    &message,
+                )
+            }
+        }
+    }
+
+    async fn redact_event(&self, redaction: &SyncRoomRedactionEvent) {
+        let event = if let SyncRoomRedactionEvent::Original(e) = redaction {
+            e
+        } else {
+            // Redacted redaction events don't contain enough data to be applied, so there's
+            // nothing to do here.
+            return;
+        };
+
+        let buffer_handle = self.buffer_handle();
+
+        let buffer = if let Ok(b) = buffer_handle.upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        // TODO: remove this unwrap.
+        let redacter = self.members.get(&event.sender).await.unwrap();
+
+        let event_id_tag =
+            Cow::from(format!("{}_id_{}", PLUGIN_NAME, event.redacts));
+        let tag = Cow::from("matrix_redacted");
+
+        let reason = if let Some(r) = &event.content.reason {
+            format!(", reason: {}", r)
+        } else {
+            "".to_owned()
+        };
+        let redaction_message = format!(
+            "{}<{}Message redacted by: {}{}{}>{}",
+            Weechat::color("chat_delimiters"),
+            Weechat::color("logger.color.backlog_line"),
+            redacter.nick(),
+            reason,
+            Weechat::color("chat_delimiters"),
+            Weechat::color("reset"),
+        );
+
+        let redaction_style = self.config.borrow().look().redaction_style();
+        let accessible = self.is_accessible();
+
+        let redaction_message = if accessible {
+            Weechat::remove_color(&redaction_message)
+        } else {
+            redaction_message
+        };
+
+        let predicate = |l: &BufferLine| {
+            let tags = l.tags();
+            tags.contains(&event_id_tag)
+                && !tags.contains(&Cow::from("matrix_redacted"))
+        };
+
+        let strike_through = |string: Cow<str>| {
+            Weechat::remove_color(&string)
+                .graphemes(true)
+                .map(|g| format!("{}\u{0336}", g))
+                .collect::<Vec<String>>()
+                .join("")
+        };
+
+        let bracketed = |string: Cow<str>| {
+            format!(
+                "{}[-{}{}{}-]{}",
+                Weechat::color("chat_delimiters"),
+                Weechat::color("reset"),
+                Weechat::remove_color(&string),
+                Weechat::color("chat_delimiters"),
+                Weechat::color("reset"),
+            )
+        };
+
+        let redact_first_line = |message: Cow<str>| match redaction_style {
+            RedactionStyle::Delete => redaction_message.clone(),
+            // Combining characters confuse screen readers, so the accessible
+            // profile describes the redaction with words instead.
+            _ if accessible => format!(
+                "redacted: {} {}",
+                Weechat::remove_color(&message),
+                redaction_message
+            ),
+            RedactionStyle::Notice => {
+                format!("{} {}", message, redaction_message)
+            }
+            RedactionStyle::StrikeThrough => {
+                format!("{} {}", strike_through(message), redaction_message)
+            }
+            RedactionStyle::Bracketed => {
+                format!("{} {}", bracketed(message), redaction_message)
+            }
+        };
+
+        let redact_string = |message: Cow<str>| match redaction_style {
+            RedactionStyle::Delete => redaction_message.clone(),
+            _ if accessible => Weechat::remove_color(&message),
+            RedactionStyle::Notice => {
+                format!("{} {}", message, redaction_message)
+            }
+            RedactionStyle::StrikeThrough => strike_through(message),
+            RedactionStyle::Bracketed => bracketed(message),
+        };
+
+        fn modify_line<F>(line: BufferLine, tag: Cow<str>, redaction_func: F)
+        where
             F: Fn(Cow<str>) -> String,
         {
-            let message = line.message();
-            let new_message = redaction_func(message);
+            let mut tags = line.tags();
+            let message = line.message();
+
+            // The delivery marker isn't part of the message, it's dropped
+            // together with the delivery state.
+            let message =
+                MatrixRoom::strip_delivery_marker(&message, &tags).to_owned();
+            let new_message = redaction_func(Cow::from(message));
+
+            tags.retain(|t| !t.starts_with(DELIVERY_TAG_PREFIX));
+            tags.push(tag);
+            let tags: Vec<&str> = tags.iter().map(|t| t.as_ref()).collect();
+
+            line.set_message(&new_message);
+            line.set_tags(&tags);
+        }
+
+        let mut lines = buffer.lines();
+        let first_line = lines.rfind(predicate);
+
+        if let Some(line) = first_line {
+            modify_line(line, tag.clone(), redact_first_line);
+        } else {
+            self.queue_relation(
+                &event.redacts,
+                PendingRelation::Redaction(redaction.clone()),
+            );
+            return;
+        }
+
+        while let Some(line) = lines.next_back().filter(predicate) {
+            modify_line(line, tag.clone(), redact_string);
+        }
+    }
+
+    async fn render_message_content(
+        &self,
+        event_id: &EventId,
+        send_time: MilliSecondsSinceUnixEpoch,
+        sender: &WeechatRoomMember,
+        content: &AnyMessageLikeEventContent,
+    ) -> Option<RenderedEvent> {
+        use AnyMessageLikeEventContent::*;
+        use MessageType::*;
+
+        // Replies get a quote of the replied to message instead of the reply
+        // fallback.
+        let reply = match content {
+            RoomMessage(c) => Self::strip_reply_fallback(c),
+            _ => None,
+        };
+        let content = reply.as_ref().map_or(content, |(_, _, c)| c);
+
+        let rendered = match content {
+            RoomEncrypted(c) => {
+                c.render_with_prefix(send_time, event_id, sender, &())
+            }
+            RoomMessage(c) => match &c.msgtype {
+                Text(c) => {
+                    c.render_with_prefix(send_time, event_id, sender, &())
+                }
+                Emote(c) => {
+                    c.render_with_prefix(send_time, event_id, &sender, &sender)
+                }
+                Notice(c) => {
+                    c.render_with_prefix(send_time, event_id, &sender, &sender)
+                }
+                ServerNotice(c) => {
+                    c.render_with_prefix(send_time, event_id, &sender, &sender)
+                }
+                Location(c) => {
+                    c.render_with_prefix(send_time, event_id, &sender, &sender)
+                }
+                Audio(c) => {
+                    self.remember_audio(send_time, c);
+                    c.render_with_prefix(
+                        send_time,
+                        event_id,
+                        &sender,
+                        &self.homeserver,
+                    )
+                }
+                Video(c) => c.render_with_prefix(
+                    send_time,
+                    event_id,
+                    &sender,
+                    &self.homeserver,
+                ),
+                File(c) => c.render_with_prefix(
+                    send_time,
+                    event_id,
+                    &sender,
+                    &self.homeserver,
+                ),
+                Image(c) => c.render_with_prefix(
+                    send_time,
+                    event_id,
+                    &sender,
+                    &self.homeserver,
+                ),
+                VerificationRequest(c) => {
+                    let to_us = c.to == *self.own_user_id
+                        && sender.user_id() != &*self.own_user_id;
+
+                    if to_us {
+                        *self.verification_request.borrow_mut() = Some((
+                            sender.user_id().to_owned(),
+                            event_id.to_owned(),
+                        ));
+                    }
+
+                    c.render_with_prefix(
+                        send_time,
+                        event_id,
+                        &sender,
+                        &(sender.clone(), to_us),
+                    )
+                }
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        Some(self.add_reply_quote(rendered, &reply).await)
+    }
+
+    /// Add the quote of a reply, as returned by `strip_reply_fallback()`,
+    /// above the rendered reply.
+    async fn add_reply_quote(
+        &self,
+        rendered: RenderedEvent,
+        reply: &Option<(String, Vec<String>, AnyMessageLikeEventContent)>,
+    ) -> RenderedEvent {
+        if let Some((quote_sender, quote, _)) = reply {
+            let nick = match UserId::parse(quote_sender.as_str()) {
+                Ok(user_id) => self.nick_or_user_id(&user_id).await,
+                Err(_) => quote_sender.to_owned(),
+            };
+            let quote: Vec<&str> = quote.iter().map(String::as_str).collect();
+
+            rendered.add_reply_quote(&nick, &quote)
+        } else {
+            rendered
+        }
+    }
+
+    /// Remove the reply fallback from the body of a reply.
+    ///
+    /// Returns the sender and the lines of the replied to message that the
+    /// fallback quotes, with the content of the reply without the fallback.
+    fn strip_reply_fallback(
+        content: &RoomMessageEventContent,
+    ) -> Option<(String, Vec<String>, AnyMessageLikeEventContent)> {
+        if !matches!(content.relates_to, Some(Relation::Reply { .. })) {
+            return None;
+        }
+
+        let mut content = content.clone();
+
+        let (body, formatted) = match &mut content.msgtype {
+            MessageType::Text(c) => (&mut c.body, &mut c.formatted),
+            MessageType::Notice(c) => (&mut c.body, &mut c.formatted),
+            MessageType::Emote(c) => (&mut c.body, &mut c.formatted),
+            _ => return None,
+        };
+
+        if let Some(formatted) = formatted {
+            formatted.body = strip_mx_reply(&formatted.body).into_owned();
+        }
+
+        let (sender, quote, reply) = {
+            let (sender, quote, reply) = split_reply_fallback(body)?;
+            (
+                sender.to_owned(),
+                quote.into_iter().map(str::to_owned).collect(),
+                reply.to_owned(),
+            )
+        };
+
+        *body = reply;
+
+        Some((
+            sender,
+            quote,
+            AnyMessageLikeEventContent::RoomMessage(content),
+        ))
+    }
+
+    /// Accept or decline the latest verification request that was sent to us
+    /// in this room.
+    ///
+    /// Accepting sends a `m.key.verification.ready` event to the room.
+    pub async fn answer_verification_request(&self, accept: bool) {
+        let (user_id, flow_id) = if let Some(r) =
+            self.verification_request.borrow().clone()
+        {
+            r
+        } else {
+            self.print_error("There is no verification request in this room");
+            return;
+        };
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        match connection
+            .answer_verification_request(user_id.clone(), flow_id, accept)
+            .await
+        {
+            Ok(true) => {
+                self.verification_request.borrow_mut().take();
+
+                self.print_network(&if accept {
+                    format!(
+                        "Accepted the verification request of {}, continue \
+                         the verification on their device",
+                        user_id
+                    )
+                } else {
+                    format!("Declined the verification request of {}", user_id)
+                });
+            }
+            Ok(false) => {
+                self.verification_request.borrow_mut().take();
+                self.print_error(
+                    "The verification request expired or was cancelled",
+                );
+            }
+            Err(e) => self.print_error(&format!(
+                "Error answering the verification request: {}",
+                e
+            )),
+        }
+    }
+
+    /// Remember the audio message so it can be played with `/lastmsg play`,
+    /// unless a newer audio message was already seen.
+    fn remember_audio(
+        &self,
+        send_time: MilliSecondsSinceUnixEpoch,
+        content: &AudioMessageEventContent,
+    ) {
+        let mut last_audio = self.last_audio.borrow_mut();
+
+        if last_audio
+            .as_ref()
+            .map_or(true, |(t, _, _)| *t <= send_time)
+        {
+            let mimetype =
+                content.info.as_ref().and_then(|i| i.mimetype.clone());
+            *last_audio = Some((send_time, content.source.clone(), mimetype));
+        }
+    }
+
+    /// Download the last audio message of the room and open it using the
+    /// `input.media_opener` command.
+    pub async fn play_last_audio(&self) {
+        let (source, mimetype) = if let Some((_, source, mimetype)) =
+            self.last_audio.borrow().clone()
+        {
+            (source, mimetype)
+        } else {
+            self.print_error("No audio message to play was found");
+            return;
+        };
+
+        self.open_media(source, mimetype.as_deref(), "audio message")
+            .await;
+    }
+
+    /// Turn the body of a media message into a name that is safe to use as
+    /// a file name.
+    fn media_file_name(body: &str) -> String {
+        body.chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+
+    /// Get the content of the given media, from the media cache if it was
+    /// downloaded before.
+    async fn media_content(
+        &self,
+        source: MediaSource,
+    ) -> Result<Vec<u8>, String> {
+        if let Some(data) = self.media_cache.get(&source) {
+            return Ok(data);
+        }
+
+        let connection = self
+            .connection
+            .borrow()
+            .clone()
+            .ok_or_else(|| "not connected".to_owned())?;
+
+        connection
+            .download_media(source)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Should the given attachment be downloaded as soon as it arrives,
+    /// according to the `network.auto_download_*` options.
+    fn should_auto_download(&self, attachment: &Attachment) -> bool {
+        let (max_size, mimetypes, rooms) = {
+            let config = self.config.borrow();
+            let network = config.network();
+
+            (
+                network.auto_download_max_size(),
+                network.auto_download_mimetypes(),
+                network.auto_download_rooms(),
+            )
+        };
+
+        let size_fits = attachment
+            .size
+            .map_or(false, |s| max_size > 0 && s <= max_size as u64 * 1024);
+
+        let mimetype_matches =
+            attachment.mimetype.as_ref().map_or(false, |m| {
+                mimetypes
+                    .split(',')
+                    .map(str::trim)
+                    .any(|pattern| event_type_matches(pattern, m))
+            });
+
+        let room_matches = rooms.split(',').any(|r| r.trim() == "*")
+            || self.matches_room_list(&rooms);
+
+        size_fits && mimetype_matches && room_matches
+    }
+
+    /// Download the attachment of a new message into the media cache if the
+    /// `network.auto_download_*` options allow it.
+    ///
+    /// Nothing is cached for rooms whose retention policy the
+    /// `look.retention_compliance` option asks us to respect, the cache can't
+    /// expire the files once the retention window passed.
+    fn auto_download(&self, content: &RoomMessageEventContent) {
+        if self.retention_compliant() {
+            return;
+        }
+
+        let attachment = match Self::attachment_of(content) {
+            Some(a) if self.should_auto_download(&a) => a,
+            _ => return,
+        };
+
+        if self.media_cache.contains(&attachment.source) {
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            return;
+        };
+
+        let cache = self.media_cache.clone();
+        let (max_size, cache_size) = {
+            let config = self.config.borrow();
+            let network = config.network();
+
+            (
+                network.auto_download_max_size() as u64 * 1024,
+                network.media_cache_max_size() as u64 * 1024 * 1024,
+            )
+        };
+
+        // Encrypted files are cached the way they are stored on the server,
+        // encrypted.
+        let download_source = match &attachment.source {
+            MediaSource::Encrypted(file) => {
+                MediaSource::Plain(file.url.clone())
+            }
+            source => source.clone(),
+        };
+
+        Weechat::spawn(async move {
+            match connection.download_media(download_source).await {
+                // The size in the event is only what the sender claims.
+                Ok(data) if data.len() as u64 > max_size => debug!(
+                    "Not caching the attachment {}, it's larger than it \
+                     claimed to be ({})",
+                    attachment.name,
+                    format_size(data.len() as u64)
+                ),
+                Ok(data) => {
+                    if let Err(e) =
+                        cache.insert(&attachment.source, &data, cache_size)
+                    {
+                        error!(
+                            "Error caching the attachment {}: {}",
+                            attachment.name, e
+                        );
+                    }
+                }
+                Err(e) => debug!(
+                    "Error downloading the attachment {}: {}",
+                    attachment.name, e
+                ),
+            }
+        })
+        .detach();
+    }
+
+    /// Download the given media and open it using the `input.media_opener`
+    /// command.
+    ///
+    /// The media is written to a new temporary file that only we can read,
+    /// the extension of the file is picked from a list of known media types
+    /// using the given mimetype, or the type of the content for images.
+    ///
+    /// The description of the media is used in error messages.
+    async fn open_media(
+        &self,
+        source: MediaSource,
+        mimetype: Option<&str>,
+        description: &str,
+    ) {
+        let opener = self.config.borrow().input().media_opener();
+        let mut args = opener.split_whitespace();
+
+        let program = if let Some(p) = args.next() {
+            p
+        } else {
+            self.print_error("The input.media_opener option is empty");
+            return;
+        };
+
+        let data = match self.media_content(source).await {
+            Ok(d) => d,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error downloading the {}: {}",
+                    description, e
+                ));
+                return;
+            }
+        };
+
+        let extension = mimetype
+            .and_then(media_extension)
+            .or_else(|| {
+                image_type(&data).and_then(|(_, m)| media_extension(m.as_ref()))
+            })
+            .map(|e| format!(".{}", e))
+            .unwrap_or_default();
+
+        // The opener might still need the file after it exits, e.g. xdg-open
+        // hands it off to another program, so the file is kept around.
+        let path = tempfile::Builder::new()
+            .prefix("weechat-matrix-")
+            .suffix(&extension)
+            .tempfile()
+            .and_then(|mut file| {
+                file.write_all(&data)?;
+                file.keep().map_err(|e| e.error)
+            });
+
+        let path = match path {
+            Ok((_, path)) => path,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error saving the {} to a temporary file: {}",
+                    description, e
+                ));
+                return;
+            }
+        };
+
+        match Command::new(program)
+            .args(args)
+            .arg(&path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(mut child) => {
+                // Reap the media opener once it exits.
+                self.members.runtime.spawn_blocking(move || child.wait());
+            }
+            Err(e) => self.print_error(&format!(
+                "Error running the media opener \"{}\": {}",
+                opener, e
+            )),
+        }
+    }
+
+    /// Send the given Ogg Opus file to the room as a voice message.
+    pub async fn send_voice_message(&self, path: PathBuf) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        if self.refuse_send() {
+            return;
+        }
+
+        let data = match fs::read(&path) {
+            Ok(d) => d,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error reading {}: {}",
+                    path.display(),
+                    e
+                ));
+                return;
+            }
+        };
+
+        let info = if let Some(i) = ogg_opus_info(&data) {
+            i
+        } else {
+            self.print_error(&format!(
+                "Error {} isn't an Ogg Opus file",
+                path.display()
+            ));
+            return;
+        };
+
+        let body = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "voice-message.ogg".to_owned());
+
+        if !self
+            .upload_allowed(&connection, "voice message", data.len())
+            .await
+        {
+            return;
+        }
+
+        match connection
+            .send_voice_message(
+                self.room().clone(),
+                body,
+                data,
+                info.duration,
+                info.waveform,
+            )
+            .await
+        {
+            Ok(_) => self.track_outbound_message(),
+            Err(e) => self.print_error(&format!(
+                "Error sending the voice message: {}",
+                self.describe_error(&e, RoomAction::Message("m.room.message"))
+            )),
+        }
+    }
+
+    async fn render_sync_message(
+        &self,
+        event: &AnySyncMessageLikeEvent,
+    ) -> Option<RenderedEvent> {
+        // TODO: remove this expect.
+        let sender =
+            self.members.get(event.sender()).await.expect(
+                "Rendering a message but the sender isn't in the nicklist",
+            );
+
+        if let Some(content) = event.original_content() {
+            let send_time = event.origin_server_ts();
+            self.render_message_content(
+                event.event_id(),
+                send_time,
+                &sender,
+                &content,
+            )
+            .await
+            .map(|r| {
+                // TODO: the tags are different if the room is a DM.
+                if sender.user_id() == &*self.own_user_id {
+                    r.add_self_tags()
+                } else {
+                    r.add_msg_tags()
+                }
+            })
+        } else {
+            self.render_redacted_event(event).await
+        }
+    }
+
+    // Add the content of the message to our outgoing message queue and print out
+    // a local echo line if local echo is enabled.
+    async fn queue_outgoing_message(
+        &self,
+        transaction_id: &TransactionId,
+        content: &RoomMessageEventContent,
+    ) {
+        // Edits modify an existing line, so they don't get a local echo.
+        if self.config.borrow().look().local_echo() && !content.is_edit() {
+            // The echo of a reply needs the same lines as the reply will
+            // have once it's rendered, so the quote replaces the fallback
+            // here as well.
+            let reply = Self::strip_reply_fallback(content);
+            let msgtype = match &reply {
+                Some((_, _, AnyMessageLikeEventContent::RoomMessage(c))) => {
+                    &c.msgtype
+                }
+                _ => &content.msgtype,
+            };
+
+            if let MessageType::Text(c) = msgtype {
+                let sender =
+                    self.members.get(&self.own_user_id).await.unwrap_or_else(
+                        || panic!("No own member {}", self.own_user_id),
+                    );
+
+                let color = self.config.borrow().look().local_echo_color();
+                let local_echo =
+                    c.render_with_prefix_for_echo(&sender, transaction_id, &());
+                let local_echo = self
+                    .add_reply_quote(local_echo, &reply)
+                    .await
+                    .add_self_tags()
+                    .colorize(&color);
+                self.print_rendered_event(local_echo);
+
+                self.outgoing_messages
+                    .add_with_echo(transaction_id.to_owned(), content.clone());
+            } else {
+                self.outgoing_messages
+                    .add(transaction_id.to_owned(), content.clone());
+            }
+        } else {
+            self.outgoing_messages
+                .add(transaction_id.to_owned(), content.clone());
+        }
+    }
+
+    /// Send the given content to the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content that should be sent to the server.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let content = MessageEventContent::Text(TextMessageEventContent {
+    ///     body: "Hello world".to_owned(),
+    ///     formatted: None,
+    ///     relates_to: None,
+    /// });
+    /// let content = AnyMessageEventContent::RoomMessage(content);
+    ///
+    /// buffer.send_message(content).await
+    /// ```
+    pub async fn send_message(&self, content: RoomMessageEventContent) {
+        if self.refuse_send() {
+            return;
+        }
+
+        let transaction_id = TransactionId::new();
+
+        let connection = self.connection.borrow().clone();
+
+        if connection.is_some() {
+            self.queue_outgoing_message(&transaction_id, &content).await;
+
+            if self.wait_for_send_delay(&transaction_id).await {
+                self.send_queued_message(&transaction_id).await;
+            }
+        } else if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.print("Error not connected");
+        }
+    }
+
+    /// Send out a message from our outgoing message queue.
+    ///
+    /// The message stays in the queue, marked as failed, if the server
+    /// didn't accept it.
+    async fn send_queued_message(&self, transaction_id: &TransactionId) {
+        let content =
+            if let Some(message) = self.outgoing_messages.get(transaction_id) {
+                message.content
+            } else {
+                return;
+            };
+
+        let connection = self.connection.borrow().clone();
+
+        // Messages that were queued, or are retried, after the encryption of
+        // the room got disabled stay in the queue as failed messages.
+        let result = if self.encryption_downgraded.get() {
+            Err("the encryption of the room got disabled".to_owned())
+        } else if self.read_only() {
+            Err("the server is in read-only mode".to_owned())
+        } else if let Some(c) = connection {
+            self.outgoing_messages
+                .set_state(transaction_id, OutgoingState::Sending);
+
+            let mentions = self.mentions_of(&content);
+
+            c.send_message(
+                self.room().clone(),
+                AnyMessageLikeEventContent::RoomMessage(content),
+                Some(transaction_id.to_owned()),
+                Some(mentions),
+            )
+            .await
+            .map_err(|e| {
+                self.describe_error(&e, RoomAction::Message("m.room.message"))
+            })
+        } else {
+            Err("not connected".to_owned())
+        };
+
+        match result {
+            Ok(r) => {
+                self.track_outbound_message();
+                self.handle_outgoing_message(transaction_id, &r.event_id)
+                    .await;
+                self.set_delivery_state(&r.event_id, DeliveryState::Sent);
+            }
+            Err(e) => {
+                self.outgoing_messages.set_state(
+                    transaction_id,
+                    OutgoingState::Failed(e.clone()),
+                );
+                self.modify_local_echo(transaction_id, |m| {
+                    format!("{}{}", m, Self::failed_echo_marker())
+                });
+                self.print_error(&format!(
+                    "Error sending message: {}, use /matrix outbox to retry \
+                     or cancel it",
+                    e
+                ));
+            }
+        }
+    }
+
+    /// The marker that is appended to our messages in the given delivery
+    /// state.
+    fn delivery_marker(&self, state: DeliveryState) -> String {
+        let config = self.config.borrow();
+
+        let marker = match state {
+            DeliveryState::Sent => config.look().sent_marker(),
+            DeliveryState::Synced => config.look().synced_marker(),
+            DeliveryState::Read => config.look().read_marker(),
+        };
+
+        if marker.is_empty() {
+            String::new()
+        } else {
+            format!(
+                " {}{}{}",
+                Weechat::color("chat_delimiters"),
+                marker,
+                Weechat::color("reset"),
+            )
+        }
+    }
+
+    /// Remove the delivery marker from the message of a line with the given
+    /// tags.
+    ///
+    /// The marker is always the last part of the line, lines that end with
+    /// one are tagged with `DELIVERY_MARKER_TAG`. Anything that reads the
+    /// text of our messages back from the buffer needs to strip it.
+    fn strip_delivery_marker<'a>(
+        message: &'a str,
+        tags: &[Cow<str>],
+    ) -> &'a str {
+        if !tags.iter().any(|t| t == DELIVERY_MARKER_TAG) {
+            return message;
+        }
+
+        let delimiter = format!(" {}", Weechat::color("chat_delimiters"));
+
+        message.rfind(&delimiter).map_or(message, |i| &message[..i])
+    }
+
+    /// Advance the delivery state of one of our messages and replace the
+    /// marker on the last line of the message.
+    ///
+    /// The state is stored in the tags of the line. Events that aren't
+    /// printed in the buffer, e.g. reactions, aren't tracked.
+    fn set_delivery_state(&self, event_id: &EventId, state: DeliveryState) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let event_id_tag = Cow::from(event_id.to_tag());
+
+        let line = if let Some(l) =
+            buffer.lines().rfind(|l| l.tags().contains(&event_id_tag))
+        {
+            l
+        } else {
+            return;
+        };
+
+        let tags = line.tags();
+
+        if DeliveryState::from_tags(&tags).map_or(false, |s| s >= state) {
+            return;
+        }
+
+        let marker = self.delivery_marker(state);
+        let message = line.message();
+        let message = format!(
+            "{}{}",
+            Self::strip_delivery_marker(&message, &tags),
+            marker
+        );
+
+        let state_tag = state.tag();
+        let mut new_tags: Vec<&str> = tags
+            .iter()
+            .map(|t| t.as_ref())
+            .filter(|t| !t.starts_with(DELIVERY_TAG_PREFIX))
+            .collect();
+        new_tags.push(&state_tag);
+
+        if !marker.is_empty() {
+            new_tags.push(DELIVERY_MARKER_TAG);
+        }
+
+        line.set_message(&message);
+        line.set_tags(&new_tags);
+    }
+
+    /// Handle the read receipts of other members of the room.
+    ///
+    /// A receipt for an event means that all the events before it were read
+    /// as well, so all of our messages that are printed above one of the
+    /// given events are marked as read.
+    pub fn handle_read_receipts(&self, event_ids: &[OwnedEventId]) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let receipt_tags: Vec<Cow<str>> =
+            event_ids.iter().map(|e| Cow::from(e.to_tag())).collect();
+
+        let mut read_up_to = None;
+        let mut unread = Vec::new();
+
+        for (position, line) in buffer.lines().enumerate() {
+            let tags = line.tags();
+
+            if receipt_tags.iter().any(|t| tags.contains(t)) {
+                read_up_to = Some(position);
+            }
+
+            if DeliveryState::from_tags(&tags)
+                .map_or(false, |s| s < DeliveryState::Read)
+            {
+                let event_id = tags.iter().find_map(|t| {
+                    t.strip_prefix("matrix_id_")
+                        .and_then(|e| EventId::parse(e).ok())
+                });
+
+                if let Some(event_id) = event_id {
+                    unread.push((event_id, position));
+                }
+            }
+        }
+
+        if let Some(read_up_to) = read_up_to {
+            for (event_id, _) in unread.iter().filter(|(_, p)| *p <= read_up_to)
+            {
+                self.set_delivery_state(event_id, DeliveryState::Read);
+            }
+        }
+    }
+
+    fn failed_echo_marker() -> String {
+        format!(
+            " {}<{}Failed to send{}>{}",
+            Weechat::color("chat_delimiters"),
+            Weechat::color("red"),
+            Weechat::color("chat_delimiters"),
+            Weechat::color("reset"),
+        )
+    }
+
+    /// Get the messages that are still waiting in our outgoing message queue,
+    /// oldest first.
+    pub fn outgoing_messages(
+        &self,
+    ) -> Vec<(OwnedTransactionId, OutgoingMessage)> {
+        self.outgoing_messages.messages()
+    }
+
+    /// Try to send out a message that previously failed to be sent.
+    pub async fn retry_message(&self, transaction_id: &TransactionId) {
+        match self.outgoing_messages.get(transaction_id).map(|m| m.state) {
+            Some(OutgoingState::Failed(_)) => {
+                let marker = Self::failed_echo_marker();
+                self.modify_local_echo(transaction_id, |m| {
+                    m.strip_suffix(&marker).unwrap_or(m).to_owned()
+                });
+                self.send_queued_message(transaction_id).await;
+            }
+            Some(_) => self.print_error("The message is still being sent"),
+            None => self.print_error("No such message in the outbox"),
+        }
+    }
+
+    /// Hold back a queued message for the configured send delay.
+    ///
+    /// Returns false if the message was cancelled while we were waiting.
+    async fn wait_for_send_delay(
+        &self,
+        transaction_id: &TransactionId,
+    ) -> bool {
+        let delay = self.config.borrow().input().send_delay();
+
+        if delay <= 0 {
+            return true;
+        }
+
+        let marker = format!(
+            " {}(sending in {}s){}",
+            Weechat::color("chat_delimiters"),
+            delay,
+            Weechat::color("reset")
+        );
+
+        self.delayed_messages
+            .borrow_mut()
+            .push(transaction_id.to_owned());
+        self.outgoing_messages
+            .set_state(transaction_id, OutgoingState::Delayed);
+        self.modify_local_echo(transaction_id, |m| format!("{}{}", m, marker));
+
+        let _ = self
+            .members
+            .runtime
+            .spawn(tokio::time::sleep(Duration::from_secs(delay as u64)))
+            .await;
+
+        let still_queued = {
+            let mut delayed = self.delayed_messages.borrow_mut();
+
+            if let Some(position) =
+                delayed.iter().position(|t| t == transaction_id)
+            {
+                delayed.remove(position);
+                true
+            } else {
+                false
+            }
+        };
+
+        if still_queued {
+            self.modify_local_echo(transaction_id, |m| {
+                m.strip_suffix(&marker).unwrap_or(m).to_owned()
+            });
+        }
+
+        still_queued
+    }
+
+    /// Cancel the last message that is still being held back by the send
+    /// delay.
+    ///
+    /// Returns false if there was no message to cancel.
+    pub fn cancel_delayed_message(&self) -> bool {
+        let transaction_id = self.delayed_messages.borrow_mut().pop();
+
+        if let Some(t) = transaction_id {
+            self.cancel_message(&t)
+        } else {
+            false
+        }
+    }
+
+    /// Remove a delayed or failed message from our outgoing message queue.
+    ///
+    /// Returns false if the message wasn't found or if it's already being
+    /// sent out.
+    pub fn cancel_message(&self, transaction_id: &TransactionId) -> bool {
+        match self.outgoing_messages.get(transaction_id).map(|m| m.state) {
+            Some(OutgoingState::Sending) | None => return false,
+            Some(_) => (),
+        }
+
+        self.delayed_messages
+            .borrow_mut()
+            .retain(|t| t != transaction_id);
+        self.outgoing_messages.remove(transaction_id);
+
+        let cancelled = format!(
+            "{}<{}Message cancelled{}>{}",
+            Weechat::color("chat_delimiters"),
+            Weechat::color("logger.color.backlog_line"),
+            Weechat::color("chat_delimiters"),
+            Weechat::color("reset"),
+        );
+
+        if !self.modify_local_echo(transaction_id, |_| cancelled.clone()) {
+            self.print_network("Message cancelled");
+        }
+
+        true
+    }
+
+    /// Cancel a delayed or failed message and put its body into the input
+    /// bar so it can be edited and sent again.
+    pub fn edit_queued_message(&self, transaction_id: &TransactionId) -> bool {
+        let body =
+            if let Some(message) = self.outgoing_messages.get(transaction_id) {
+                message.content.body().to_owned()
+            } else {
+                return false;
+            };
+
+        if !self.cancel_message(transaction_id) {
+            return false;
+        }
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.set_input(&body);
+        }
+
+        true
+    }
+
+    /// Modify the lines of the local echo of the given transaction.
+    ///
+    /// Only the last line of the local echo is modified. Returns false if no
+    /// local echo was found.
+    fn modify_local_echo<F>(
+        &self,
+        transaction_id: &TransactionId,
+        func: F,
+    ) -> bool
+    where
+        F: Fn(&str) -> String,
+    {
+        let echo_tag = Cow::from(format!("matrix_echo_{}", transaction_id));
+
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return false;
+        };
+
+        let line = buffer.lines().rfind(|l| l.tags().contains(&echo_tag));
+
+        if let Some(line) = line {
+            line.set_message(&func(&line.message()));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Remember a reaction to one of the messages in the room and update
+    /// the reaction annotation of the message.
+    fn handle_reaction(
+        &self,
+        event: &OriginalSyncMessageLikeEvent<ReactionEventContent>,
+    ) {
+        let target = event.content.relates_to.event_id.clone();
+
+        {
+            let mut reactions = self.reactions.borrow_mut();
+            let reactions = reactions.entry(target.clone()).or_default();
+
+            if reactions.iter().any(|r| r.event_id == event.event_id) {
+                return;
+            }
+
+            reactions.push(Reaction {
+                event_id: event.event_id.clone(),
+                sender: event.sender.clone(),
+                key: event.content.relates_to.key.clone(),
+            });
+        }
+
+        self.update_annotation(&target);
+    }
+
+    /// Forget a reaction that got redacted.
+    ///
+    /// Returns false if the redacted event isn't a known reaction.
+    fn remove_reaction(&self, event_id: &EventId) -> bool {
+        let target = {
+            let mut reactions = self.reactions.borrow_mut();
+
+            let target = reactions.iter_mut().find_map(|(target, r)| {
+                let index = r.iter().position(|r| *r.event_id == *event_id)?;
+                r.remove(index);
+                Some(target.clone())
+            });
+
+            if let Some(target) = &target {
+                if reactions.get(target).map_or(false, Vec::is_empty) {
+                    reactions.remove(target);
+                }
+            }
+
+            target
+        };
+
+        if let Some(target) = target {
+            self.update_annotation(&target);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Format the reactions of the given message, e.g. ` [👍 3] [❤ 1]`.
+    ///
+    /// The reactions we sent ourselves are highlighted.
+    fn reaction_annotation(&self, event_id: &EventId) -> String {
+        let reactions = self.reactions.borrow();
+
+        let reactions = if let Some(r) = reactions.get(event_id) {
+            r
+        } else {
+            return String::new();
+        };
+
+        // The keys in the order they were first used, with their count and
+        // whether we used them.
+        let mut keys: Vec<(&str, usize, bool)> = Vec::new();
+
+        for reaction in reactions {
+            let own = *reaction.sender == *self.own_user_id;
+
+            if let Some(k) = keys.iter_mut().find(|k| k.0 == reaction.key) {
+                k.1 += 1;
+                k.2 |= own;
+            } else {
+                keys.push((&reaction.key, 1, own));
+            }
+        }
+
+        keys.iter()
+            .map(|(key, count, own)| {
+                format!(
+                    " {}[{}{} {}{}]{}",
+                    Weechat::color("chat_delimiters"),
+                    Weechat::color(if *own {
+                        "chat_nick_self"
+                    } else {
+                        "reset"
+                    }),
+                    key,
+                    count,
+                    Weechat::color("chat_delimiters"),
+                    Weechat::color("reset"),
+                )
+            })
+            .collect()
+    }
+
+    /// Format the number of replies of the thread the given message started,
+    /// e.g. ` ↪ 4 replies`.
+    fn thread_annotation(&self, event_id: &EventId) -> String {
+        let count = self.threads.borrow().get(event_id).map_or(0, Vec::len);
+
+        match count {
+            0 => String::new(),
+            1 => format!(
+                " {}↪ 1 reply{}",
+                Weechat::color("chat_delimiters"),
+                Weechat::color("reset")
+            ),
+            n => format!(
+                " {}↪ {} replies{}",
+                Weechat::color("chat_delimiters"),
+                n,
+                Weechat::color("reset")
+            ),
+        }
+    }
+
+    /// Replace the reaction and thread annotation on the last line of the
+    /// given message.
+    ///
+    /// The annotation is placed in front of the delivery marker, if the
+    /// message has one.
+    fn update_annotation(&self, event_id: &EventId) {
+        let annotation = format!(
+            "{}{}",
+            self.reaction_annotation(event_id),
+            self.thread_annotation(event_id)
+        );
+        let old_annotation = self
+            .annotations
+            .borrow()
+            .get(event_id)
+            .cloned()
+            .unwrap_or_default();
+        let modified = self.modify_event_line(event_id, |m, tags| {
+            let without_marker = Self::strip_delivery_marker(m, tags);
+            let marker = &m[without_marker.len()..];
+            let m = without_marker
+                .strip_suffix(&old_annotation)
+                .unwrap_or(without_marker);
+            format!("{}{}{}", m, annotation, marker)
+        });
+
+        let mut annotations = self.annotations.borrow_mut();
+
+        if !modified || annotation.is_empty() {
+            annotations.remove(event_id);
+        } else {
+            annotations.insert(event_id.to_owned(), annotation);
+        }
+
+        drop(annotations);
+        self.send_annotations_signal(event_id);
+    }
+
+    /// Does the buffer show the message with the given event id.
+    pub fn shows_event(&self, event_id: &EventId) -> bool {
+        let tag = Cow::from(event_id.to_tag());
+
+        self.buffer_handle()
+            .upgrade()
+            .map_or(false, |b| b.lines().any(|l| l.tags().contains(&tag)))
+    }
+
+    /// Get the aggregated annotations of the given message as a JSON
+    /// object: the reactions with their count and senders, the number of
+    /// edits and the number of thread replies.
+    pub fn annotations_summary(&self, event_id: &EventId) -> serde_json::Value {
+        // The keys in the order they were first used.
+        let mut reactions: Vec<(String, Vec<String>)> = Vec::new();
+
+        for reaction in
+            self.reactions.borrow().get(event_id).into_iter().flatten()
+        {
+            let sender = reaction.sender.to_string();
+
+            if let Some(r) = reactions.iter_mut().find(|r| r.0 == reaction.key)
+            {
+                r.1.push(sender);
+            } else {
+                reactions.push((reaction.key.clone(), vec![sender]));
+            }
+        }
+
+        let reactions: Vec<serde_json::Value> = reactions
+            .into_iter()
+            .map(|(key, senders)| {
+                serde_json::json!({
+                    "key": key,
+                    "count": senders.len(),
+                    "own": senders.contains(&self.own_user_id.to_string()),
+                    "senders": senders,
+                })
+            })
+            .collect();
+
+        let buffer_name = self
+            .buffer_handle()
+            .upgrade()
+            .map(|b| b.full_name().to_string())
+            .unwrap_or_default();
+
+        serde_json::json!({
+            "buffer": buffer_name,
+            "room_id": self.room_id(),
+            "event_id": event_id,
+            "reactions": reactions,
+            "edits": self.edits.borrow().get(event_id).map_or(0, HashSet::len),
+            "thread_replies":
+                self.threads.borrow().get(event_id).map_or(0, Vec::len),
+        })
+    }
+
+    /// Let scripts know that the annotations of a message changed, the
+    /// signal data is the summary of the annotations.
+    pub fn send_annotations_signal(&self, event_id: &EventId) {
+        let data = self.annotations_summary(event_id).to_string();
+
+        Weechat::hook_signal_send(
+            "matrix_annotations",
+            SignalData::String(data.into()),
+        );
+    }
+
+    /// Redact our reactions to the given message.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the message the reactions belong to.
+    ///
+    /// * `key` - Only redact the reaction with this key, all our reactions
+    /// to the message are redacted if no key is given.
+    pub async fn redact_reactions(
+        &self,
+        event_id: &EventId,
+        key: Option<&str>,
+    ) {
+        let own_reactions: Vec<OwnedEventId> = self
+            .reactions
+            .borrow()
+            .get(event_id)
+            .map(|reactions| {
+                reactions
+                    .iter()
+                    .filter(|r| *r.sender == *self.own_user_id)
+                    .filter(|r| key.map_or(true, |k| r.key == k))
+                    .map(|r| r.event_id.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if own_reactions.is_empty() {
+            self.print_error("You didn't react to the message");
+            return;
+        }
+
+        if self.refuse_read_only()
+            || self.refuse_action(
+                RoomAction::Message("m.room.redaction"),
+                "remove the reaction",
+            )
+        {
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        for reaction in own_reactions {
+            if let Err(e) = connection
+                .redact_event(self.room().clone(), reaction, None)
+                .await
+            {
+                self.print_error(&format!(
+                    "Error removing the reaction: {}",
+                    self.describe_error(
+                        &e,
+                        RoomAction::Message("m.room.redaction")
+                    )
+                ));
+            }
+        }
+    }
+
+    /// Modify the last line of the given event.
+    ///
+    /// Returns false if the event isn't printed in the buffer.
+    fn modify_event_line<F>(&self, event_id: &EventId, func: F) -> bool
+    where
+        F: Fn(&str, &[Cow<str>]) -> String,
+    {
+        let event_id_tag = Cow::from(event_id.to_tag());
+
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return false;
+        };
+
+        let line = buffer.lines().rfind(|l| l.tags().contains(&event_id_tag));
+
+        if let Some(line) = line {
+            line.set_message(&func(&line.message(), &line.tags()));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Find the users, and if the whole room, the given message mentions.
+    fn mentions_of(&self, content: &RoomMessageEventContent) -> Mentions {
+        let body = content.msgtype.body();
+
+        let mut user_ids = self.members.mentioned_users(body);
+        user_ids.retain(|u| u.as_str() != self.own_user_id.as_str());
+        user_ids.sort();
+
+        Mentions {
+            user_ids,
+            room: contains_word(body, "@room"),
+        }
+    }
+
+    /// Send the given text as a message, messages that are too large for a
+    /// single event are handled according to `input.oversized_messages`.
+    pub async fn send_text(&self, text: &str) {
+        let content = self.text_content(text);
+
+        if MatrixRoom::content_size(&content) > self.max_content_size() {
+            self.send_oversized_message(text.to_owned()).await;
+        } else {
+            self.send_message(content).await;
+        }
+    }
+
+    /// Apply the transformations of the input.transformations option to the
+    /// given input.
+    fn transform_input(&self, input: &str) -> String {
+        let config = self.config.borrow();
+        let input_section = config.input();
+
+        transform::pipeline(
+            &input_section.transformations(),
+            &input_section.rewrites(),
+        )
+        .apply(input)
+    }
+
+    /// Send the given text as a code block, optionally highlighted as the
+    /// given language.
+    pub async fn send_code(&self, language: Option<&str>, code: &str) {
+        let body =
+            format!("```{}\n{}\n```", language.unwrap_or_default(), code);
+        let class = language
+            .map(|l| format!(" class=\"language-{}\"", escape_html(l)))
+            .unwrap_or_default();
+        let formatted =
+            format!("<pre><code{}>{}</code></pre>", class, escape_html(code));
+
+        let content = RoomMessageEventContent::new(MessageType::Text(
+            TextMessageEventContent::html(body, formatted),
+        ));
+
+        self.send_message(content).await;
+    }
+
+    /// Create the content of a text message from the given user input.
+    ///
+    /// The input will be parsed as markdown if markdown input is enabled.
+    fn text_content(&self, input: &str) -> RoomMessageEventContent {
+        if self.config.borrow().input().markdown_input() {
+            RoomMessageEventContent::new(MessageType::Text(
+                TextMessageEventContent::markdown(input),
+            ))
+        } else {
+            RoomMessageEventContent::new(MessageType::Text(
+                TextMessageEventContent::plain(input),
+            ))
+        }
+    }
+
+    /// Create the content of a reply to the given event.
+    ///
+    /// The quote will be used as the reply fallback, it can be either the
+    /// whole body of the replied to event or just a fragment of it.
+    fn reply_content(
+        &self,
+        event_id: &EventId,
+        sender: &UserId,
+        quote: &str,
+        message: &str,
+    ) -> RoomMessageEventContent {
+        let mut quote_lines = quote.lines();
+
+        let mut body = format!(
+            "> <{}> {}",
+            sender,
+            quote_lines.next().unwrap_or_default()
+        );
+
+        for line in quote_lines {
+            body.push_str(&format!("\n> {}", line));
+        }
+
+        body.push_str("\n\n");
+        body.push_str(message);
+
+        let message_html = if self.config.borrow().input().markdown_input() {
+            TextMessageEventContent::markdown(message)
+                .formatted
+                .map(|f| f.body)
+        } else {
+            None
+        }
+        .unwrap_or_else(|| escape_html(message).replace('\n', "<br>"));
+
+        let html = format!(
+            "<mx-reply><blockquote>\
+             <a href=\"https://matrix.to/#/{room_id}/{event_id}\">In reply to</a> \
+             <a href=\"https://matrix.to/#/{sender}\">{sender}</a><br>{quote}\
+             </blockquote></mx-reply>{message}",
+            room_id = self.room_id(),
+            event_id = event_id,
+            sender = sender,
+            quote = escape_html(quote).replace('\n', "<br>"),
+            message = message_html,
+        );
+
+        let mut content = RoomMessageEventContent::new(MessageType::Text(
+            TextMessageEventContent::html(body, html),
+        ));
+
+        content.relates_to = Some(Relation::Reply {
+            in_reply_to: InReplyTo::new(event_id.to_owned()),
+        });
+
+        content
+    }
+
+    /// Send a reply to the given event.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event we're replying to.
+    ///
+    /// * `quote` - The part of the replied to message that should be quoted,
+    /// if none is given the whole message is quoted.
+    ///
+    /// * `message` - The message of the reply.
+    pub async fn send_reply(
+        &self,
+        event_id: &EventId,
+        quote: Option<&str>,
+        message: &str,
+    ) {
+        let (sender, body) = if let Some(e) = self.printed_event(event_id) {
+            e
+        } else {
+            self.print_error(&format!(
+                "The event {} wasn't found in the buffer",
+                event_id
+            ));
+            return;
+        };
+
+        let content = self.reply_content(
+            event_id,
+            &sender,
+            quote.unwrap_or(&body),
+            message,
+        );
+
+        self.send_message(content).await;
+    }
+
+    /// Send a reaction to the given event.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event we're reacting to.
+    ///
+    /// * `key` - The reaction itself, usually an emoji.
+    pub async fn send_reaction(&self, event_id: &EventId, key: &str) {
+        if self.refuse_send()
+            || self.refuse_action(
+                RoomAction::Message("m.reaction"),
+                "send the reaction",
+            )
+        {
+            return;
+        }
+
+        let connection = self.connection.borrow().clone();
+
+        if let Some(c) = connection {
+            let content = ReactionEventContent::new(AnnotationRelation::new(
+                event_id.to_owned(),
+                key.to_owned(),
+            ));
+
+            match c
+                .send_message(
+                    self.room().clone(),
+                    AnyMessageLikeEventContent::Reaction(content),
+                    None,
+                    None,
+                )
+                .await
+            {
+                Ok(_) => self.track_outbound_message(),
+                Err(e) => self.print_error(&format!(
+                    "Error sending the reaction: {}",
+                    self.describe_error(&e, RoomAction::Message("m.reaction"))
+                )),
+            }
+        } else {
+            self.print_error("Error not connected");
+        }
+    }
+
+    /// Replace the content of one of our own messages.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event that should be edited.
+    ///
+    /// * `body` - The new body of the event.
+    pub async fn send_edit(&self, event_id: &EventId, body: &str) {
+        if self.refuse_action(
+            RoomAction::Message("m.room.message"),
+            "edit the message",
+        ) {
+            return;
+        }
+
+        let new_content = self.text_content(body);
+        let mut content = self.text_content(&format!("* {}", body));
+
+        content.relates_to = Some(Relation::Replacement(Replacement::new(
+            event_id.to_owned(),
+            Box::new(new_content),
+        )));
+
+        self.send_message(content).await;
+    }
+
+    /// Replace the content of one of our own messages that is printed in the
+    /// buffer.
+    pub async fn edit_message(&self, event_id: &EventId, body: &str) {
+        match self.printed_event(event_id) {
+            Some((sender, _)) if sender == *self.own_user_id => {
+                let body = self.transform_input(body);
+                self.send_edit(event_id, &body).await;
+            }
+            Some(_) => self.print_error("Only your own messages can be edited"),
+            None => self.print_error(&format!(
+                "The message {} isn't shown in the buffer",
+                event_id
+            )),
+        }
+    }
+
+    /// Edit our last message by applying an IRC style correction, e.g.
+    /// `s/teh/the/`, to it.
+    async fn correct_last_message(&self, substitution: &Substitution) {
+        let last = self.last_own_event_id().and_then(|event_id| {
+            let (_, body) = self.printed_event(&event_id)?;
+            Some((event_id, body))
+        });
+
+        let (event_id, body) = if let Some(l) = last {
+            l
+        } else {
+            self.print_error("No message that could be edited was found");
+            return;
+        };
+
+        if let Some(body) = substitution.apply(&body) {
+            self.send_edit(&event_id, &body).await;
+        } else {
+            self.print_error(&format!(
+                "Your last message doesn't contain \"{}\"",
+                substitution.old
+            ));
+        }
+    }
+
+    /// Start editing our last message.
+    ///
+    /// The body of the message is put into the input bar and the next input
+    /// will be sent out as an edit of the message. Calling this while an edit
+    /// is in progress cancels the edit.
+    pub fn edit_last_message(&self) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let mut editing = self.editing.borrow_mut();
+
+        if editing.take().is_some() {
+            buffer.set_input("");
+            return;
+        }
+
+        let event_id = if let Some(e) = self.last_own_event_id() {
+            e
+        } else {
+            self.print_error("No message that could be edited was found");
+            return;
+        };
+
+        let body = self
+            .printed_event(&event_id)
+            .map(|(_, body)| body)
+            .unwrap_or_default();
+
+        buffer.set_input(&body);
+        *editing = Some(event_id);
+    }
+
+    /// Invite a user to the room.
+    ///
+    /// The room keys of the history aren't shared with the invitee, sharing
+    /// them as described in MSC3061 isn't implemented, the SDK doesn't let us
+    /// forward room keys we didn't get a key request for.
+    pub async fn invite(&self, user_id: OwnedUserId) {
+        if self.refuse_read_only() {
+            return;
+        }
+
+        let connection = self.connection.borrow().clone();
+
+        let connection = if let Some(c) = connection {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        if let Err(e) = connection
+            .invite_user(self.room().clone(), user_id.clone())
+            .await
+        {
+            self.print_error(&format!(
+                "Error inviting {} to the room: {}",
+                user_id,
+                self.describe_error(&e, RoomAction::Invite)
+            ));
+            return;
+        }
+
+        self.print_network(&format!("Invited {} to the room", user_id));
+
+        if self.is_encrypted()
+            && self.room().history_visibility() == HistoryVisibility::Shared
+        {
+            self.print_network(&format!(
+                "{} won't be able to decrypt the room history from before \
+                 they joined unless somebody shares the room keys with them",
+                user_id
+            ));
+        }
+    }
+
+    /// Get the size of the content once it's serialized to JSON.
+    fn content_size(content: &RoomMessageEventContent) -> usize {
+        serde_json::to_vec(content)
+            .map(|c| c.len())
+            .unwrap_or_default()
+    }
+
+    /// Get the maximal size of the content of a message we're willing to send
+    /// to the room.
+    ///
+    /// In encrypted rooms the content is wrapped together with the event type
+    /// and the room id, encrypted and base64 encoded, which grows it by a
+    /// third, so less content fits into an event.
+    fn max_content_size(&self) -> usize {
+        if self.is_encrypted() {
+            // The JSON around the content, the event type and the room id.
+            let wrapper = 64 + self.room_id().as_str().len();
+
+            (MAX_CONTENT_SIZE - ENCRYPTION_OVERHEAD) * 3 / 4 - wrapper
+        } else {
+            MAX_CONTENT_SIZE
+        }
+    }
+
+    /// Send a message that is too large to fit into a single event.
+    ///
+    /// Depending on the configuration the message will be refused, split into
+    /// multiple messages or uploaded as a text file.
+    async fn send_oversized_message(&self, input: String) {
+        let style = self.config.borrow().input().oversized_messages();
+
+        match style {
+            OversizedMessage::Refuse => self.print_error(&format!(
+                "The message is too large to be sent ({} bytes), split it \
+                 up or change the input.oversized_messages option",
+                input.len()
+            )),
+            OversizedMessage::Split => self.send_split_message(&input).await,
+            OversizedMessage::Upload => {
+                let connection = self.connection.borrow().clone();
+
+                if self.refuse_send() {
+                    return;
+                }
+
+                if let Some(c) = connection {
+                    // Messages the homeserver wouldn't accept as a file are
+                    // split up instead.
+                    if let Err(limit) =
+                        Self::check_upload_size(&c, input.len()).await
+                    {
+                        self.print_network(&format!(
+                            "The message is larger than the upload limit of \
+                             the homeserver ({}), sending it as multiple \
+                             messages instead",
+                            format_size(limit)
+                        ));
+                        self.send_split_message(&input).await;
+                        return;
+                    }
+
+                    match c
+                        .send_attachment(
+                            self.room().clone(),
+                            "message.txt".to_owned(),
+                            mime::TEXT_PLAIN_UTF_8,
+                            input.into_bytes(),
+                        )
+                        .await
+                    {
+                        Ok(_) => self.track_outbound_message(),
+                        Err(e) => self.print_error(&format!(
+                            "Error uploading the message as a file: {}",
+                            self.describe_error(
+                                &e,
+                                RoomAction::Message("m.room.message")
+                            )
+                        )),
+                    }
+                } else {
+                    self.print_error("Error not connected");
+                }
+            }
+        }
+    }
+
+    /// Send a message that is too large for a single event as multiple
+    /// messages.
+    async fn send_split_message(&self, input: &str) {
+        let max_size = self.max_content_size();
+
+        // The formatted body and JSON escaping can easily double the size of
+        // the content, leave enough room for them. Messages that grow even
+        // more, e.g. ones that are full of characters that need escaping, are
+        // split into smaller chunks until every chunk fits.
+        let mut chunk_size = max_size / 4;
+
+        let contents = loop {
+            let contents: Vec<RoomMessageEventContent> =
+                split_message(input, chunk_size)
+                    .iter()
+                    .map(|chunk| self.text_content(chunk))
+                    .collect();
+
+            if contents.iter().all(|c| Self::content_size(c) <= max_size) {
+                break contents;
+            }
+
+            chunk_size /= 2;
+
+            if chunk_size < MIN_CHUNK_SIZE {
+                self.print_error(
+                    "The message can't be split into messages that are \
+                     small enough to be sent",
+                );
+                return;
+            }
+        };
+
+        for content in contents {
+            self.send_message(content).await;
+        }
+    }
+
+    /// Check that the homeserver accepts an upload of the given size.
+    ///
+    /// Returns the upload limit of the homeserver as the error if the upload
+    /// is too large. Uploads are allowed if the limit can't be fetched, the
+    /// homeserver will refuse them itself if needed.
+    async fn check_upload_size(
+        connection: &Connection,
+        size: usize,
+    ) -> Result<(), u64> {
+        match connection.max_upload_size().await {
+            Ok(limit) if size as u64 > limit => Err(limit),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Error fetching the upload limit: {}", e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Check the size of a file before it gets uploaded, an error is printed
+    /// if the file is larger than the upload limit of the homeserver.
+    ///
+    /// Returns true if the file can be uploaded.
+    async fn upload_allowed(
+        &self,
+        connection: &Connection,
+        what: &str,
+        size: usize,
+    ) -> bool {
+        match Self::check_upload_size(connection, size).await {
+            Ok(()) => true,
+            Err(limit) => {
+                self.print_error(&format!(
+                    "The {} is too large to be uploaded ({}), the homeserver \
+                     accepts files up to {}",
+                    what,
+                    format_size(size as u64),
+                    format_size(limit)
+                ));
+                false
+            }
+        }
+    }
+
+    /// Upload the image that is currently in the clipboard and send it to
+    /// the room.
+    ///
+    /// The image is read from the output of the `input.paste_image_command`,
+    /// the image gets encrypted before the upload if the room is encrypted.
+    pub async fn paste_image(&self) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        if self.refuse_send() {
+            return;
+        }
+
+        let command = self.config.borrow().input().paste_image_command();
+        let mut args = command.split_whitespace().map(|a| a.to_owned());
+
+        let program = if let Some(p) = args.next() {
+            p
+        } else {
+            self.print_error("The input.paste_image_command option is empty");
+            return;
+        };
+        let args: Vec<String> = args.collect();
+
+        let output = self
+            .members
+            .runtime
+            .spawn_blocking(move || Command::new(program).args(args).output())
+            .await
+            .expect("Tokio error while running the paste command");
+
+        let data = match output {
+            Ok(o) if o.status.success() && !o.stdout.is_empty() => o.stdout,
+            Ok(_) => {
+                self.print_error("The clipboard doesn't contain an image");
+                return;
+            }
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error running the paste command \"{}\": {}",
+                    command, e
+                ));
+                return;
+            }
+        };
+
+        let (file_name, content_type) = if let Some(t) = image_type(&data) {
+            t
+        } else {
+            self.print_error("The clipboard doesn't contain a supported image");
+            return;
+        };
+
+        if !self.upload_allowed(&connection, "image", data.len()).await {
+            return;
+        }
+
+        match connection
+            .send_attachment(
+                self.room().clone(),
+                file_name.to_owned(),
+                content_type,
+                data,
+            )
+            .await
+        {
+            Ok(_) => self.track_outbound_message(),
+            Err(e) => self.print_error(&format!(
+                "Error sending the image: {}",
+                self.describe_error(&e, RoomAction::Message("m.room.message"))
+            )),
+        }
+    }
+
+    /// Send out a typing notice.
+    ///
+    /// This will send out a typing notice or reset the one in progress, if
+    /// needed. It will make sure that only one typing notice request is in
+    /// flight at a time.
+    ///
+    /// Typing notices are sent out only if we have more than 4 letters in the
+    /// input and the input isn't a command.
+    ///
+    /// Typing notices are never sent out for rooms that are part of the
+    /// `input.no_typing_rooms` list, or if a script set the
+    /// `matrix_no_typing` local variable of the buffer to `1` while it's
+    /// injecting input.
+    ///
+    /// If the input is empty the typing notice is disabled.
+    pub fn update_typing_notice(&self) {
+        let buffer_handle = self.buffer_handle();
+
+        let buffer = if let Ok(b) = buffer_handle.upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        if buffer.get_localvar("matrix_no_typing").as_deref() == Some("1") {
+            return;
+        }
+
+        let no_typing_rooms = self.config.borrow().input().no_typing_rooms();
+
+        if self.matches_room_list(&no_typing_rooms) || self.read_only() {
+            return;
+        }
+
+        let input = buffer.input();
+
+        if input.starts_with('/') && !input.starts_with("//") {
+            // Don't send typing notices for commands.
+            return;
+        }
+
+        let connection = self.connection.clone();
+        let room = self.room().clone();
+
+        let send = |typing: bool| async move {
+            let connection = connection.borrow().clone();
+
+            if let Some(connection) = connection {
+                let _ = connection.send_typing_notice(room, typing).await;
+            };
+        };
+
+        if input.len() < 4 {
+            // If we have an active typing notice and our input is short, e.g.
+            // we removed the input set the typing notice to false.
+            Weechat::spawn(send(false)).detach();
+        } else if input.len() >= 4 {
+            // If we have some valid input and no active typing notice, send
+            // one out.
+            Weechat::spawn(send(true)).detach();
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.messages_in_flight.locked()
+    }
+
+    /// Reset the pagination token after the buffer got cleared, so the room
+    /// history can be fetched again.
+    ///
+    /// The latest sync token is used so the history is fetched starting from
+    /// the newest event, the prev_batch token of the last synced timeline
+    /// chunk is used if we don't have a sync token.
+    pub fn reset_prev_batch(&self) {
+        let sync_token = self.connection.borrow().as_ref().and_then(|c| {
+            let client = c.client().clone();
+            self.members.runtime.block_on(client.sync_token())
+        });
+
+        *self.prev_batch.borrow_mut() = sync_token
+            .or_else(|| self.room.last_prev_batch())
+            .map(PrevBatch::Backwards);
+    }
+
+    /// Rebuild the buffer of the room after some of its events had to be
+    /// dropped because we couldn't keep up with the sync loop.
+    ///
+    /// The member list is restored from the store and the history is fetched
+    /// again, starting from the newest event. The local echoes of messages
+    /// that weren't confirmed by the server yet are kept.
+    pub async fn resync(&self) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            let echoes: Vec<(i64, Vec<String>, String)> = buffer
+                .lines()
+                .filter(|l| {
+                    l.tags().iter().any(|t| t.starts_with("matrix_echo_"))
+                })
+                .map(|l| {
+                    (
+                        l.date(),
+                        l.tags().iter().map(|t| t.to_string()).collect(),
+                        format!("{}\t{}", l.prefix(), l.message()),
+                    )
+                })
+                .collect();
+
+            buffer.clear();
+
+            for (date, tags, message) in &echoes {
+                let tags: Vec<&str> = tags.iter().map(|t| t.as_str()).collect();
+                buffer.print_date_tags(*date, &tags, message);
+            }
+        }
+
+        let room = self.room.clone();
+        let members = self
+            .members
+            .runtime
+            .spawn(async move { room.joined_user_ids().await })
+            .await
+            .expect("Fetching the joined user ids panicked");
+
+        match members {
+            Ok(members) => {
+                for user_id in members {
+                    self.members.restore_member(user_id).await;
+                }
+            }
+            Err(e) => self.print_error(&format!(
+                "Error restoring the member list: {}",
+                e
+            )),
+        }
+
+        self.reset_prev_batch();
+
+        self.print_network(
+            "Some events of this room were dropped since they arrived faster \
+             than they could be processed, the room history is fetched again",
+        );
+
+        self.get_messages().await;
+    }
+
+    /// Fetch a page of room history, the size of the page is controlled by
+    /// the `network.history_page_size` option.
+    pub async fn get_messages(&self) {
+        let page_size = self.config.borrow().network().history_page_size();
+        self.get_more_messages(page_size.max(1) as u32).await
+    }
+
+    /// Fetch the given number of events from the room history, using as many
+    /// requests as needed.
+    pub async fn get_more_messages(&self, count: u32) {
+        let messages_lock = self.messages_in_flight.clone();
+
+        let connection =
+            if let Some(c) = self.connection.borrow().as_ref().cloned() {
+                c
+            } else {
+                return;
+            };
+
+        let guard = if let Ok(l) = messages_lock.try_lock() {
+            l
+        } else {
+            return;
+        };
+
+        Weechat::bar_item_update("buffer_modes");
+        Weechat::bar_item_update("matrix_modes");
+
+        let mut remaining = count;
+
+        while remaining > 0 {
+            let limit = remaining.min(MAX_HISTORY_PAGE_SIZE);
+
+            match self.get_messages_page(&connection, limit).await {
+                Some(fetched) if fetched > 0 => {
+                    remaining = remaining.saturating_sub(fetched as u32)
+                }
+                _ => break,
+            }
+        }
+
+        drop(guard);
+
+        Weechat::bar_item_update("buffer_modes");
+        Weechat::bar_item_update("matrix_modes");
+    }
+
+    /// Fetch a single page of room history, returns the number of fetched
+    /// events.
+    async fn get_messages_page(
+        &self,
+        connection: &Connection,
+        limit: u32,
+    ) -> Option<usize> {
+        let prev_batch = self.prev_batch.borrow().as_ref().cloned()?;
+        let room = self.room().clone();
+
+        let r = connection
+            .room_messages(room, prev_batch, limit)
+            .await
+            .ok()?;
+
+        for event in r.chunk.iter().filter_map(|e| e.event.deserialize().ok()) {
+            self.handle_room_event(&event).await;
+        }
+
+        let mut prev_batch = self.prev_batch.borrow_mut();
+
+        if let Some(PrevBatch::Forward(t)) = prev_batch.as_ref() {
+            *prev_batch = Some(PrevBatch::Backwards(t.to_owned()));
+            self.sort_messages();
+        } else if r.chunk.is_empty() {
+            *prev_batch = None;
+        } else {
+            *prev_batch = r.end.map(PrevBatch::Backwards);
+            self.sort_messages();
+        }
+
+        self.place_fully_read_marker();
+
+        Some(r.chunk.len())
+    }
+
+    fn sort_messages(&self) {
+        struct LineCopy {
+            date: i64,
+            date_printed: i64,
+            tags: Vec<String>,
+            prefix: String,
+            message: String,
+        }
+
+        impl<'a> From<BufferLine<'a>> for LineCopy {
+            fn from(line: BufferLine) -> Self {
+                Self {
+                    date: line.date(),
+                    date_printed: line.date_printed(),
+                    message: line.message().to_string(),
+                    prefix: line.prefix().to_string(),
+                    tags: line.tags().iter().map(|t| t.to_string()).collect(),
+                }
+            }
+        }
+
+        // TODO: update the highlight once Weechat starts supporting it.
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            let mut lines: Vec<LineCopy> =
+                buffer.lines().map(|l| l.into()).collect();
+            lines.sort_by_key(|l| l.date);
+
+            for (line, new) in buffer.lines().zip(lines.drain(..)) {
+                let tags =
+                    new.tags.iter().map(|t| t.as_str()).collect::<Vec<&str>>();
+                let data = LineData {
+                    prefix: Some(&new.prefix),
+                    message: Some(&new.message),
+                    date: Some(new.date),
+                    date_printed: Some(new.date_printed),
+                    tags: Some(&tags),
+                };
+                line.update(data)
+            }
+        }
+    }
+
+    /// Replace the local echo of an event with a fully rendered one.
+    fn replace_local_echo(
+        &self,
+        transaction_id: &TransactionId,
+        buffer: &Buffer,
+        rendered: RenderedEvent,
+    ) {
+        let rendered = self.apply_render_profile(rendered);
+        let uuid_tag =
+            Cow::from(format!("matrix_echo_{}", transaction_id.to_string()));
+        let line_contains_uuid = |l: &BufferLine| l.tags().contains(&uuid_tag);
+
+        let mut lines = buffer.lines();
+        let mut current_line = lines.rfind(line_contains_uuid);
+
+        // We go in reverse order here since we also use rfind(). We got from
+        // the bottom of the buffer to the top since we're expecting these
+        // lines to be freshly printed and thus at the bottom.
+        let mut line_num = rendered.content.lines.len();
+
+        while let Some(line) = &current_line {
+            line_num -= 1;
+            let rendered_line = &rendered.content.lines[line_num];
+
+            let tags: Vec<&str> =
+                rendered_line.tags.iter().map(|t| t.as_str()).collect();
+
+            line.set_prefix(&rendered.prefix);
+            line.set_message(&rendered_line.message);
+            line.set_tags(&tags);
+            current_line = lines.next_back().filter(line_contains_uuid);
+        }
+    }
+
+    async fn handle_outgoing_message(
+        &self,
+        transaction_id: &TransactionId,
+        event_id: &EventId,
+    ) {
+        if let Some(OutgoingMessage { echo, content, .. }) =
+            self.outgoing_messages.remove(&transaction_id)
+        {
+            let event = OriginalSyncMessageLikeEvent {
+                sender: (&*self.own_user_id).to_owned(),
+                origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+                event_id: event_id.to_owned(),
+                content,
+                unsigned: Default::default(),
+            };
+
+            let event = AnySyncMessageLikeEvent::RoomMessage(
+                SyncMessageLikeEvent::Original(event),
+            );
+
+            if event.is_edit() {
+                self.handle_edits(&event).await;
+                return;
+            }
+
+            let rendered = self
+                .render_sync_message(&event)
+                .await
+                .expect("Sent out an event that we don't know how to render");
+
+            if let Ok(buffer) = self.buffer_handle().upgrade() {
+                if echo {
+                    self.replace_local_echo(&transaction_id, &buffer, rendered);
+                } else {
+                    self.print_rendered_event(rendered);
+                }
+            }
+        }
+    }
+
+    fn set_topic(&self) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            // The title bar can only display a single line, so flatten
+            // multi-line topics.
+            buffer.set_title(&flatten_lines(
+                &self.room().topic().unwrap_or_default(),
+            ));
+        }
+    }
+
+    /// Show the given topic or name in the buffer.
+    fn show_state_value(&self, change: StateChange, value: &str) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        match change {
+            StateChange::Topic => buffer.set_title(&flatten_lines(value)),
+            StateChange::Name => {
+                if self.members.name_override.borrow().is_none() {
+                    buffer.set_short_name(value);
+                }
+            }
+        }
+    }
+
+    /// Change the topic or the name of the room.
+    ///
+    /// The buffer shows the new value right away, until the change comes
+    /// back through sync.
+    pub async fn change_state(&self, change: StateChange, value: String) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        if self.refuse_read_only() {
+            return;
+        }
+
+        self.pending_state.borrow_mut().insert(
+            change,
+            PendingStateChange {
+                value: value.clone(),
+                confirmed_at: None,
+            },
+        );
+        self.show_state_value(change, &value);
+
+        let mut content = serde_json::Map::new();
+        content.insert(change.field().to_owned(), value.into());
+
+        if let Err(e) = connection
+            .send_state_event(
+                self.room().clone(),
+                change.event_type(),
+                content.into(),
+            )
+            .await
+        {
+            self.pending_state.borrow_mut().remove(&change);
+
+            match change {
+                StateChange::Topic => self.set_topic(),
+                StateChange::Name => self.update_buffer_name(),
+            }
+
+            self.print_error(&format!(
+                "Error changing the room {}: {}",
+                change.field(),
+                self.describe_error(&e, RoomAction::State(change.event_type()))
+            ));
+        }
+    }
+
+    /// Reconcile a topic or name change that came in through sync with our
+    /// own change of it.
+    ///
+    /// Changes of other users that happen while our change is pending, or
+    /// shortly after it went through, get a notice instead of silently
+    /// replacing the value. Returns true if the buffer should keep showing
+    /// the value of our pending change.
+    fn reconcile_state_change(&self, event: &AnySyncStateEvent) -> bool {
+        let change = match event {
+            AnySyncStateEvent::RoomTopic(_) => StateChange::Topic,
+            AnySyncStateEvent::RoomName(_) => StateChange::Name,
+            _ => return false,
+        };
+
+        let pending = if let Some(p) =
+            self.pending_state.borrow().get(&change).cloned()
+        {
+            p
+        } else {
+            return false;
+        };
+
+        if pending
+            .confirmed_at
+            .map_or(false, |t| t.elapsed() > STATE_CONFLICT_WINDOW)
+        {
+            self.pending_state.borrow_mut().remove(&change);
+            return false;
+        }
+
+        let value = event
+            .original_content()
+            .and_then(|c| serde_json::to_value(c).ok())
+            .and_then(|c| {
+                c.get(change.field())?.as_str().map(ToOwned::to_owned)
+            })
+            .unwrap_or_default();
+
+        if event.sender() == &*self.own_user_id && value == pending.value {
+            if let Some(p) = self.pending_state.borrow_mut().get_mut(&change) {
+                p.confirmed_at = Some(Instant::now());
+            }
+
+            return false;
+        }
+
+        if value == pending.value {
+            return false;
+        }
+
+        let pending_change = pending.confirmed_at.is_none();
+
+        self.print_network(&format!(
+            "{} changed the room {} to \"{}\" {} your change to \"{}\", {}",
+            event.sender(),
+            change.field(),
+            value,
+            if pending_change {
+                "while sending"
+            } else {
+                "right after"
+            },
+            pending.value,
+            if pending_change {
+                "your change will be applied after theirs"
+            } else {
+                "their change replaced yours"
+            }
+        ));
+
+        if !pending_change {
+            self.pending_state.borrow_mut().remove(&change);
+        }
+
+        pending_change
+    }
+
+    /// Get the HTML formatted version of the topic, as defined in MSC3765, if
+    /// the room has one.
+    fn rich_topic(&self) -> Option<String> {
+        let event = self
+            .members
+            .runtime
+            .block_on(self.room.get_state_event(StateEventType::RoomTopic, ""))
+            .ok()
+            .flatten()?;
+
+        let event = event.deserialize_as::<serde_json::Value>().ok()?;
+
+        event
+            .get("content")?
+            .get("m.topic")?
+            .as_array()?
+            .iter()
+            .find(|t| {
+                t.get("mimetype").and_then(|m| m.as_str()) == Some("text/html")
+            })
+            .and_then(|t| t.get("body")?.as_str().map(|b| b.to_owned()))
+    }
+
+    /// Print the full, possibly multi-line and formatted, topic of the room
+    /// into the buffer.
+    pub fn print_topic(&self) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let topic = self
+            .rich_topic()
+            .map(|t| render_html(&t))
+            .or_else(|| self.room().topic())
+            .filter(|t| !t.trim().is_empty());
+
+        if let Some(topic) = topic {
+            buffer.print(&format!(
+                "{}Topic for {}{}{} is:",
+                Weechat::prefix(Prefix::Network),
+                Weechat::color("chat_channel"),
+                buffer.short_name(),
+                Weechat::color("reset"),
+            ));
+
+            for line in topic.lines() {
+                buffer.print(&format!(
+                    "{}  {}",
+                    Weechat::prefix(Prefix::Network),
+                    line
+                ));
+            }
+        } else {
+            buffer.print(&format!(
+                "{}No topic set for {}{}{}",
+                Weechat::prefix(Prefix::Network),
+                Weechat::color("chat_channel"),
+                buffer.short_name(),
+                Weechat::color("reset"),
+            ));
+        }
+    }
+
+    fn set_alias(&self) {
+        if let Some(alias) = self.alias() {
+            if let Ok(b) = self.buffer_handle().upgrade() {
+                b.set_localvar("alias", alias.as_str());
+            }
+        }
+    }
+
+    fn update_buffer_name(&self) {
+        self.members.update_buffer_name();
+    }
+
+    /// Follow a change of the room name or of the canonical alias.
+    ///
+    /// The short name of the buffer gets recalculated, WeeChat announces this
+    /// with a `buffer_renamed` signal which the logger uses to switch to the
+    /// log file matching the new name. Changes that are part of the timeline
+    /// get a notice and a `matrix_room_renamed` signal, carrying a JSON
+    /// object with the buffer, the room id and the old and new names, so a
+    /// switch of log files doesn't go unnoticed.
+    ///
+    /// Renames caused by an `m.room.name` event are already announced by
+    /// `print_state_change()`, together with the sender.
+    fn handle_name_change(&self, timeline_event: bool, name_event: bool) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let old_name = buffer.short_name().to_string();
+        let old_alias = buffer.get_localvar("alias").map(|a| a.to_string());
+
+        self.set_alias();
+        self.update_buffer_name();
+
+        let new_name = buffer.short_name().to_string();
+        let new_alias = self.alias().map(|a| a.to_string());
+
+        if !timeline_event {
+            return;
+        }
+
+        let renamed = old_name != new_name;
+
+        if renamed && !name_event {
+            self.print_network(&format!(
+                "The room was renamed from {} to {}",
+                old_name, new_name
+            ));
+        }
+
+        if old_alias != new_alias {
+            if let Some(alias) = &new_alias {
+                self.print_network(&format!(
+                    "The main alias of the room is now {}",
+                    alias
+                ));
+            }
+        }
+
+        if renamed || old_alias != new_alias {
+            let data = serde_json::json!({
+                "buffer": buffer.full_name(),
+                "room_id": self.room_id(),
+                "old_name": old_name,
+                "new_name": new_name,
+                "old_alias": old_alias,
+                "new_alias": new_alias,
+            })
+            .to_string();
+
+            Weechat::hook_signal_send(
+                "matrix_room_renamed",
+                SignalData::String(data.into()),
+            );
+        }
+    }
+
+    /// Record that an edit is being applied to an event.
+    ///
+    /// Edits can arrive out of order, e.g. over federation, so the edit with
+    /// the latest timestamp wins, ties are broken using the event id of the
+    /// edit. Returns false if a newer edit of the event was already applied.
+    fn record_edit(
+        &self,
+        event_id: &EventId,
+        edit_id: &EventId,
+        timestamp: MilliSecondsSinceUnixEpoch,
+    ) -> bool {
+        let new_edit = self
+            .edits
+            .borrow_mut()
+            .entry(event_id.to_owned())
+            .or_default()
+            .insert(edit_id.to_owned());
+
+        if new_edit {
+            self.send_annotations_signal(event_id);
+        }
+
+        let mut latest_edits = self.latest_edits.borrow_mut();
+        let edit = (timestamp, edit_id.to_owned());
+
+        match latest_edits.get(event_id) {
+            Some(latest) if *latest >= edit => false,
+            _ => {
+                latest_edits.insert(event_id.to_owned(), edit);
+                true
+            }
+        }
+    }
+
+    fn replace_edit(
+        &self,
+        event_id: &EventId,
+        sender: &UserId,
+        edit: (&EventId, MilliSecondsSinceUnixEpoch),
+        event: RenderedEvent,
+    ) -> bool {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            let sender_tag = Cow::from(sender.to_tag());
+            let event_id_tag = Cow::from(event_id.to_tag());
+            let quote_tag = Cow::from("matrix_reply_quote");
+
+            // The quote of the replied to message stays in place, edits
+            // replace only the content of the reply.
+            let lines: Vec<BufferLine> = buffer
+                .lines()
+                .filter(|l| {
+                    let tags = l.tags();
+                    tags.contains(&event_id_tag) && !tags.contains(&quote_tag)
+                })
+                .collect();
+
+            if lines.is_empty() {
+                return false;
+            }
+
+            if lines
+                .get(0)
+                .map(|l| l.tags().contains(&sender_tag))
+                .unwrap_or(false)
+            {
+                let (edit_id, timestamp) = edit;
+
+                if self.record_edit(event_id, edit_id, timestamp) {
+                    self.replace_event_helper(&buffer, lines, event);
+                } else {
+                    debug!(
+                        "Ignoring edit {} of {}, a newer edit was already applied",
+                        edit_id, event_id
+                    );
+                }
+            }
+        }
+
+        true
+    }
+
+    fn replace_event_helper(
+        &self,
+        buffer: &Buffer,
+        lines: Vec<BufferLine<'_>>,
+        event: RenderedEvent,
+    ) {
+        use std::cmp::Ordering;
+        let date = lines.get(0).map(|l| l.date()).unwrap_or_default();
+
+        for (line, new) in lines.iter().zip(event.content.lines.iter()) {
+            let tags: Vec<&str> = new.tags.iter().map(|t| t.as_str()).collect();
+            let data = LineData {
+                // Our prefixes always come with a \t character, but when we
+                // replace stuff we're able to replace the prefix and the
+                // message separately, so trim the whitespace in the prefix.
+                prefix: Some(event.prefix.trim_end()),
+                message: Some(&new.message),
+                tags: Some(&tags),
+                ..Default::default()
+            };
+
+            line.update(data);
+        }
+
+        match lines.len().cmp(&event.content.lines.len()) {
+            Ordering::Greater => {
+                for line in &lines[event.content.lines.len()..] {
+                    line.set_message("");
+                }
+            }
+            Ordering::Less => {
+                for line in &event.content.lines[lines.len()..] {
+                    let message = format!("{}{}", &event.prefix, &line.message);
+                    let tags: Vec<&str> =
+                        line.tags.iter().map(|t| t.as_str()).collect();
+                    buffer.print_date_tags(date, &tags, &message)
+                }
+
+                self.sort_messages()
+            }
+            Ordering::Equal => (),
+        }
+    }
+
+    async fn handle_edits(&self, event: &AnySyncMessageLikeEvent) {
+        // TODO: remove this expect.
+        let sender =
+            self.members.get(event.sender()).await.expect(
+                "Rendering a message but the sender isn't in the nicklist",
+            );
+
+        if let Some((event_id, content)) = event.get_edit() {
+            let send_time = event.origin_server_ts();
+
+            if let Some(rendered) = self
+                .render_message_content(
+                    event_id,
+                    send_time,
+                    &sender,
+                    &AnyMessageLikeEventContent::RoomMessage(content.clone()),
+                )
+                .await
+                .map(|r| {
+                    // TODO: the tags are different if the room is a DM.
+                    if sender.user_id() == &*self.own_user_id {
+                        r.add_self_tags()
+                    } else {
+                        r.add_msg_tags()
+                    }
+                })
+            {
+                let marker = self.config.borrow().look().edited_marker();
+                let rendered = self.apply_render_profile(
+                    rendered.add_edited_tags().add_edited_marker(&marker),
+                );
+
+                if !self.replace_edit(
+                    event_id,
+                    event.sender(),
+                    (event.event_id(), send_time),
+                    rendered,
+                ) {
+                    self.queue_relation(
+                        event_id,
+                        PendingRelation::Edit(event.clone()),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Remember a relation whose target wasn't printed yet.
+    fn queue_relation(&self, target: &EventId, relation: PendingRelation) {
+        let mut pending = self.pending_relations.borrow_mut();
+
+        if pending.len() >= MAX_PENDING_RELATIONS {
+            pending.pop_front();
+        }
+
+        pending.push_back((target.to_owned(), relation));
+    }
+
+    /// Apply the edits, redactions and reactions that arrived before the
+    /// given event was printed.
+    async fn apply_pending_relations(&self, event_id: &EventId) {
+        let (edits, redactions): (Vec<_>, Vec<_>) = {
+            let mut pending = self.pending_relations.borrow_mut();
+            let is_target = |target: &OwnedEventId| **target == *event_id;
+
+            let matching = if pending.iter().any(|(t, _)| is_target(t)) {
+                let (matching, rest): (VecDeque<_>, VecDeque<_>) =
+                    pending.drain(..).partition(|(t, _)| is_target(t));
+                *pending = rest;
+                matching
+            } else {
+                VecDeque::new()
+            };
+
+            matching
+                .into_iter()
+                .map(|(_, relation)| relation)
+                .partition(|r| matches!(r, PendingRelation::Edit(_)))
+        };
+
+        // Edits replace the lines of the event, so they are applied before
+        // the redactions.
+        for relation in edits.iter().chain(redactions.iter()) {
+            match relation {
+                PendingRelation::Edit(e) => self.handle_edits(e).await,
+                PendingRelation::Redaction(r) => self.redact_event(r).await,
+            }
+        }
+
+        if self.reactions.borrow().contains_key(event_id)
+            || self.threads.borrow().contains_key(event_id)
+        {
+            self.update_annotation(event_id);
+        }
+    }
+
+    async fn handle_room_message(
+        &self,
+        event: &AnySyncMessageLikeEvent,
+        mentions: Option<&Mentions>,
+        push: Option<PushOutcome>,
+    ) {
+        // Reactions aren't printed, they are aggregated on the line of the
+        // message they react to, our own reactions and their redactions
+        // included.
+        match event {
+            AnySyncMessageLikeEvent::Reaction(
+                SyncMessageLikeEvent::Original(e),
+            ) => {
+                self.handle_reaction(e);
+                return;
+            }
+            AnySyncMessageLikeEvent::RoomRedaction(
+                SyncRoomRedactionEvent::Original(e),
+            ) if self.remove_reaction(&e.redacts) => return,
+            _ => (),
+        }
+
+        // Replies to threads go to the buffer of their thread, the room
+        // buffer only counts them on the line of the thread root.
+        if let Some(root) = Self::thread_root(event.original_content()) {
+            self.handle_thread_reply(&root, event).await;
+
+            if !self.config.borrow().look().show_thread_replies() {
+                return;
+            }
+        }
+
+        // If the event has a transaction id it's an event that we sent out
+        // ourselves, the content will be in the outgoing message queue and it
+        // may have been printed out as a local echo.
+        if let Some(id) = event.transaction_id() {
+            self.handle_outgoing_message(id, event.event_id()).await;
+            self.set_delivery_state(event.event_id(), DeliveryState::Synced);
+            return;
+        }
+
+        if let AnySyncMessageLikeEvent::RoomRedaction(r) = event {
+            self.redact_event(r).await;
+        } else if event.is_edit() {
+            self.handle_edits(event).await;
+        } else if self.is_content_ignored(event.original_content()) {
+            // The message matches one of the ignore rules, drop it.
+        } else if let Some(rendered) = self.render_sync_message(event).await {
+            let own_message = event.sender() == &*self.own_user_id;
+            let pills = Self::pills_of(event.original_content());
+            let rendered = self.color_pills(rendered, &pills).await;
+
+            let rendered = if own_message {
+                rendered
+            } else {
+                self.mark_mentions(rendered, mentions, &pills, push)
+            };
+
+            if !own_message {
+                self.print_unread_marker(rendered.message_timestamp);
+                self.notify_externally(&rendered);
+            }
+
+            self.print_rendered_event(rendered);
+            self.apply_pending_relations(event.event_id()).await;
+            self.set_last_activity(event.origin_server_ts());
+            self.members
+                .record_message(event.sender(), event.origin_server_ts());
+
+            if let Some(AnyMessageLikeEventContent::RoomMessage(c)) =
+                event.original_content()
+            {
+                self.auto_download(&c);
+            }
+
+            if !own_message {
+                self.count_unread_message();
+                self.send_read_receipt();
+            }
+
+            self.place_fully_read_marker();
+        }
+    }
+
+    /// Check if the rendered message highlights, either because it's tagged
+    /// as a mention or because it contains our nick.
+    fn is_highlight(&self, rendered: &RenderedEvent) -> bool {
+        let has_tag = |tag: &str| {
+            rendered
+                .content
+                .lines
+                .iter()
+                .any(|l| l.tags.iter().any(|t| t == tag))
+        };
+
+        if has_tag("notify_highlight") {
+            return true;
+        } else if has_tag("no_highlight") {
+            return false;
+        }
+
+        let nick = self
+            .buffer_handle()
+            .upgrade()
+            .ok()
+            .and_then(|b| b.get_localvar("nick").map(|n| n.to_string()));
+
+        nick.map_or(false, |nick| {
+            rendered
+                .content
+                .lines
+                .iter()
+                .any(|l| contains_word(&l.message, &nick))
+        })
+    }
+
+    /// Run the `notifications.notify_command` for highlights and direct
+    /// messages.
+    ///
+    /// The command isn't run while a relay client is attached, the relay
+    /// client notifies the user itself, or while the room is muted.
+    fn notify_externally(&self, rendered: &RenderedEvent) {
+        let command = self.config.borrow().notifications().notify_command();
+
+        let muted = self
+            .buffer_handle()
+            .upgrade()
+            .ok()
+            .and_then(|b| b.get_localvar("matrix_muted"))
+            .map_or(false, |m| m == "1");
+
+        if command.is_empty() || relay::is_attached() || muted {
+            return;
+        }
+
+        if !self.is_direct() && !self.is_highlight(rendered) {
+            return;
+        }
+
+        let mut args = command.split_whitespace();
+        let program = if let Some(p) = args.next() {
+            p
+        } else {
+            return;
+        };
+
+        let sender = Weechat::remove_color(&rendered.prefix).to_string();
+        let message = rendered
+            .content
+            .lines
+            .iter()
+            .map(|l| Weechat::remove_color(&l.message).to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        match Command::new(program)
+            .args(args)
+            .arg(sender)
+            .arg(message)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(mut child) => {
+                self.members.runtime.spawn_blocking(move || child.wait());
+            }
+            Err(e) => self.print_error(&format!(
+                "Error running the notify command \"{}\": {}",
+                command, e
+            )),
+        }
+    }
+
+    /// Check if the room is tagged as a low priority room.
+    fn is_low_priority(&self) -> bool {
+        self.members
+            .runtime
+            .block_on(self.room.tags())
+            .ok()
+            .flatten()
+            .map_or(false, |tags| tags.contains_key(&TagName::LowPriority))
+    }
+
+    /// Describe the error of a request that tried to do the given action in
+    /// the room, including the power level the action requires if we lack
+    /// it.
+    fn describe_error(
+        &self,
+        error: &matrix_sdk::Error,
+        action: RoomAction<'_>,
+    ) -> String {
+        errors::describe_error(
+            error,
+            action,
+            self.power_levels().as_ref(),
+            &self.own_user_id,
+        )
+    }
+
+    /// Check if our power level allows the given action, an error is printed
+    /// if it doesn't.
+    ///
+    /// This way the user gets told which power level is missing before the
+    /// server rejects the request.
+    ///
+    /// Returns true if the action isn't allowed.
+    fn refuse_action(&self, action: RoomAction<'_>, what: &str) -> bool {
+        let missing = errors::missing_power_level(
+            action,
+            self.power_levels().as_ref(),
+            &self.own_user_id,
+        );
+
+        if let Some(missing) = missing {
+            self.print_error(&format!("Can't {}: {}", what, missing));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get the power levels of the room.
+    fn power_levels(&self) -> Option<RoomPowerLevelsEventContent> {
+        let event = self
+            .members
+            .runtime
+            .block_on(
+                self.room
+                    .get_state_event(StateEventType::RoomPowerLevels, ""),
+            )
+            .ok()
+            .flatten()?
+            .deserialize()
+            .ok()?;
+
+        if let AnySyncStateEvent::RoomPowerLevels(SyncStateEvent::Original(e)) =
+            event
+        {
+            Some(e.content)
+        } else {
+            None
+        }
+    }
+
+    /// Get the user pills of the HTML formatted body of a message.
+    fn pills_of(
+        content: Option<AnyMessageLikeEventContent>,
+    ) -> Vec<(OwnedUserId, String)> {
+        let formatted = match content {
+            Some(AnyMessageLikeEventContent::RoomMessage(c)) => {
+                match c.msgtype {
+                    MessageType::Text(c) => c.formatted,
+                    MessageType::Emote(c) => c.formatted,
+                    MessageType::Notice(c) => c.formatted,
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        formatted
+            .filter(|f| f.format == MessageFormat::Html)
+            .map(|f| user_pills(&f.body))
+            .unwrap_or_default()
+    }
+
+    /// Color the user pills of a message in the nick color of the user they
+    /// link to.
+    async fn color_pills(
+        &self,
+        rendered: RenderedEvent,
+        pills: &[(OwnedUserId, String)],
+    ) -> RenderedEvent {
+        let mut colored = Vec::new();
+
+        for (user_id, text) in pills {
+            let color = match self.members.get(user_id).await {
+                Some(member) => member.color().to_owned(),
+                None => Weechat::info_get("nick_color_name", user_id.as_str())
+                    .map(|c| c.to_string())
+                    .unwrap_or_default(),
+            };
+
+            colored.push((text.clone(), Weechat::color(&color).to_string()));
+        }
+
+        rendered.color_pills(&colored)
+    }
+
+    /// Does one of the given pills mention us, either by linking to our user
+    /// id or by carrying our nick.
+    fn pills_mention_us(&self, pills: &[(OwnedUserId, String)]) -> bool {
+        let nick = self
+            .buffer_handle()
+            .upgrade()
+            .ok()
+            .and_then(|b| b.get_localvar("nick").map(|n| n.to_string()));
+
+        pills.iter().any(|(user_id, text)| {
+            *user_id == *self.own_user_id
+                || nick.as_deref() == Some(text.trim_start_matches('@'))
+        })
+    }
+
+    /// Tag messages that mention us or the whole room.
+    ///
+    /// If the push rules of the account were evaluated for the message they
+    /// decide if the message highlights and notifies. Otherwise intentional
+    /// mentions decide if the message highlights, if the message doesn't
+    /// contain any the body of the message is searched for mentions.
+    ///
+    /// Pills that link to us always highlight.
+    fn mark_mentions(
+        &self,
+        rendered: RenderedEvent,
+        mentions: Option<&Mentions>,
+        pills: &[(OwnedUserId, String)],
+        push: Option<PushOutcome>,
+    ) -> RenderedEvent {
+        let room_mention = if let Some(mentions) = mentions {
+            mentions.room
+        } else {
+            rendered
+                .content
+                .lines
+                .iter()
+                .any(|l| contains_word(&l.message, "@room"))
+        };
+
+        let rendered = if room_mention {
+            self.mark_room_mention(rendered, push.map_or(true, |p| p.highlight))
+        } else {
+            rendered
+        };
+
+        if let Some(push) = push {
+            let own_mention = self.pills_mention_us(pills)
+                || mentions
+                    .map_or(false, |m| m.mentions_user(&self.own_user_id));
+
+            let rendered = if own_mention || (push.highlight && !room_mention) {
+                rendered.mark_mention()
+            } else if push.highlight {
+                // The room mention already decided if the message highlights.
+                rendered
+            } else {
+                rendered.suppress_highlight()
+            };
+
+            return if push.notify {
+                rendered
+            } else {
+                rendered.lower_notify_level()
+            };
+        }
+
+        match mentions {
+            _ if self.pills_mention_us(pills) => rendered.mark_mention(),
+            Some(m) if m.mentions_user(&self.own_user_id) => {
+                rendered.mark_mention()
+            }
+            // Don't let WeeChat highlight the message because our name
+            // appears in the body if we weren't intentionally mentioned.
+            Some(_) if !room_mention => rendered.suppress_highlight(),
+            _ => rendered,
+        }
+    }
+
+    /// Color and tag messages that mention the whole room.
+    ///
+    /// Such messages only highlight if the push rules let them, and in low
+    /// priority rooms only if the `look.low_priority_room_mentions` option is
+    /// enabled.
+    fn mark_room_mention(
+        &self,
+        rendered: RenderedEvent,
+        push_highlight: bool,
+    ) -> RenderedEvent {
+        let (color, low_priority_mentions) = {
+            let config = self.config.borrow();
+            let look = config.look();
+
+            (look.room_mention_color(), look.low_priority_room_mentions())
+        };
+
+        let highlight = push_highlight
+            && (low_priority_mentions || !self.is_low_priority());
+
+        rendered.mark_room_mention(&Weechat::color(&color), highlight)
+    }
+
+    /// Send a message that notifies the whole room using an @room mention.
+    ///
+    /// The message is only sent if our power level allows us to notify the
+    /// whole room.
+    pub async fn send_room_mention(&self, text: &str) {
+        let power_levels = self.power_levels().unwrap_or_default();
+
+        let own_level = power_levels
+            .users
+            .get(&*self.own_user_id)
+            .cloned()
+            .unwrap_or(power_levels.users_default);
+        let needed_level = power_levels.notifications.room;
+
+        if own_level < needed_level {
+            self.print_error(&format!(
+                "You need power level {} to notify the whole room, yours is {}",
+                needed_level, own_level
+            ));
+            return;
+        }
+
+        let content = self.text_content(&format!("@room {}", text));
+        self.send_message(content).await
+    }
+
+    /// Print a marker line before the first message that arrives while the
+    /// user is away from the buffer.
+    ///
+    /// There's only ever a single marker line in the buffer, the marker of
+    /// an earlier absence gets moved to the new position.
+    fn print_unread_marker(&self, timestamp: i64) {
+        if !self.config.borrow().look().unread_marker()
+            || self.unread_messages.get() > 0
+        {
+            return;
+        }
+
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        if buffer == Weechat::current_buffer() {
+            return;
+        }
+
+        if self.move_unread_marker(&buffer, timestamp) {
+            self.sort_messages();
+        }
+    }
+
+    /// Move the unread marker line to the given timestamp, the line is
+    /// printed if there's none yet.
+    ///
+    /// Returns true if an existing line was moved, the lines of the buffer
+    /// need to be sorted in that case.
+    fn move_unread_marker(&self, buffer: &Buffer, timestamp: i64) -> bool {
+        let marker = buffer
+            .lines()
+            .find(|l| l.tags().iter().any(|t| t == UNREAD_MARKER_TAG));
+
+        if let Some(marker) = marker {
+            marker.update(LineData {
+                date: Some(timestamp),
+                date_printed: Some(timestamp),
+                ..Default::default()
+            });
+            true
+        } else {
+            buffer.print_date_tags(
+                timestamp,
+                &[UNREAD_MARKER_TAG, "no_highlight", "notify_none", "no_log"],
+                &format!(
+                    "\t{}-- unread --{}",
+                    Weechat::color("chat_delimiters"),
+                    Weechat::color("reset")
+                ),
+            );
+            false
+        }
+    }
+
+    /// Place the unread marker line after the event the fully read marker of
+    /// the account points to, this way the marker agrees with other clients
+    /// about which messages are unread.
+    ///
+    /// Nothing is done until the event is printed in the buffer, e.g. once
+    /// enough history was fetched. If no message of somebody else follows
+    /// the event everything was read and no marker line is needed.
+    fn place_fully_read_marker(&self) {
+        if self.fully_read.borrow().is_none()
+            || !self.config.borrow().look().unread_marker()
+        {
+            return;
+        }
+
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let fully_read_tag = match self.fully_read.borrow().as_ref() {
+            Some(e) => e.to_tag(),
+            None => return,
+        };
+
+        let mut read_date = None;
+        let mut first_unread = None;
+
+        for line in buffer.lines() {
+            let tags = line.tags();
+
+            if tags.iter().any(|t| t == &fully_read_tag) {
+                read_date = Some(line.date());
+                first_unread = None;
+                continue;
+            }
+
+            if read_date.is_none()
+                || first_unread.is_some()
+                || tags
+                    .iter()
+                    .any(|t| t == "self_msg" || t == "matrix_reply_quote")
+                || !tags.iter().any(|t| MESSAGE_TAGS.contains(&&**t))
+            {
+                continue;
+            }
+
+            first_unread = Some(line.date());
+        }
+
+        let read_date = if let Some(d) = read_date {
+            d
+        } else {
+            return;
+        };
+
+        self.fully_read.borrow_mut().take();
+
+        if let Some(date) = first_unread {
+            // The marker needs to sort between the read event and the first
+            // unread message.
+            self.move_unread_marker(&buffer, (date - 1).max(read_date));
+            self.sort_messages();
+        }
+    }
+
+    /// Count a new message if the room buffer isn't the current buffer.
+    fn count_unread_message(&self) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            if buffer != Weechat::current_buffer() {
+                self.unread_messages.set(self.unread_messages.get() + 1);
+                self.update_localvars();
+                Weechat::bar_item_update("matrix_activity");
+                Weechat::bar_item_update("matrix_hotrooms");
+            }
+        }
+    }
+
+    /// Remember the timestamp of the latest message in the room.
+    fn set_last_activity(&self, timestamp: MilliSecondsSinceUnixEpoch) {
+        if self.last_activity.get().map_or(true, |t| t < timestamp) {
+            self.last_activity.set(Some(timestamp));
+            Weechat::bar_item_update("matrix_hotrooms");
+        }
+    }
+
+    /// Get the timestamp of the latest message that arrived in the room
+    /// while we were connected.
+    pub fn last_activity(&self) -> Option<MilliSecondsSinceUnixEpoch> {
+        self.last_activity.get()
+    }
+
+    /// Set the list of users that are currently typing in the room.
+    ///
+    /// The nicks of the users are looked up for the matrix_typing bar item,
+    /// the number of typing users is stored in the `typing` local variable
+    /// of the buffer.
+    pub fn set_typing_users(&self, users: Vec<OwnedUserId>) {
+        *self.typing_users.borrow_mut() = users.clone();
+
+        let room = self.clone();
+
+        Weechat::spawn(async move {
+            let mut nicks = Vec::new();
+
+            for user_id in users.iter().filter(|u| **u != *room.own_user_id) {
+                nicks.push(room.nick_or_user_id(user_id).await);
+            }
+
+            // A newer typing update arrived while the nicks were looked up.
+            if *room.typing_users.borrow() != users {
+                return;
+            }
+
+            *room.typing_nicks.borrow_mut() = nicks;
+            room.update_localvars();
+            Weechat::bar_item_update("matrix_typing");
+        })
+        .detach();
+    }
+
+    /// Describe who is typing in the room for the matrix_typing bar item,
+    /// `None` if nobody is typing.
+    pub fn typing_summary(&self) -> Option<String> {
+        let nicks = self.typing_nicks.borrow();
+
+        if nicks.is_empty() {
+            return None;
+        }
+
+        let sign = self.config.borrow().look().typing_sign();
+
+        Some(if sign.is_empty() {
+            nicks.join(", ")
+        } else {
+            format!("{} {}", sign, nicks.join(", "))
+        })
+    }
+
+    /// Get the user ids of the members of the room we know of.
+    pub fn member_ids(&self) -> Vec<OwnedUserId> {
+        self.members.user_ids()
+    }
+
+    /// Get the list of users that are currently typing in the room.
+    pub fn typing_users(&self) -> Vec<OwnedUserId> {
+        self.typing_users.borrow().clone()
+    }
+
+    /// Get the scratch buffer that reply chains of this room are printed to,
+    /// the buffer is created if needed.
+    fn reply_chain_buffer(&self) -> Option<Buffer> {
+        let mut handle = self.reply_chain_buffer.borrow_mut();
+
+        if let Some(b) = handle.as_ref().and_then(|b| b.upgrade().ok()) {
+            return Some(b);
+        }
+
+        let room_buffer = self.buffer_handle().upgrade().ok()?;
+
+        let new_handle =
+            BufferBuilder::new(&format!("{}.replies", room_buffer.name()))
+                .build()
+                .ok()?;
+        let buffer = new_handle.upgrade().ok()?;
+
+        buffer.set_short_name(&format!("{}.replies", room_buffer.short_name()));
+        buffer.set_localvar("type", "private");
+
+        *handle = Some(new_handle);
+
+        Some(buffer)
+    }
+
+    /// Get the scratch buffer that the source of events of this room is
+    /// printed to, the buffer is created if needed.
+    fn source_buffer(&self) -> Option<Buffer> {
+        let mut handle = self.source_buffer.borrow_mut();
+
+        if let Some(b) = handle.as_ref().and_then(|b| b.upgrade().ok()) {
+            return Some(b);
+        }
+
+        let room_buffer = self.buffer_handle().upgrade().ok()?;
+
+        let new_handle =
+            BufferBuilder::new(&format!("{}.source", room_buffer.name()))
+                .build()
+                .ok()?;
+        let buffer = new_handle.upgrade().ok()?;
+
+        buffer.set_short_name(&format!("{}.source", room_buffer.short_name()));
+        buffer.set_localvar("type", "private");
+
+        *handle = Some(new_handle);
+
+        Some(buffer)
+    }
+
+    /// Print the JSON source of the given event into a separate buffer, this
+    /// shows the whole content of messages whose long words were truncated.
+    pub async fn print_event_source(&self, event_id: OwnedEventId) {
+        let room = self.room.clone();
+        let id = event_id.clone();
+
+        let event = match self
+            .members
+            .runtime
+            .spawn(async move { room.event(&id).await })
+            .await
+        {
+            Ok(Ok(e)) => e.event,
+            _ => {
+                self.print_error(&format!(
+                    "Error fetching the event {}",
+                    event_id
+                ));
+                return;
+            }
+        };
+
+        let source = event
+            .deserialize_as::<serde_json::Value>()
+            .ok()
+            .and_then(|e| serde_json::to_string_pretty(&e).ok())
+            .unwrap_or_else(|| event.json().get().to_owned());
+
+        let buffer = if let Some(b) = self.source_buffer() {
+            b
+        } else {
+            self.print_error("Error creating the source buffer");
+            return;
+        };
+
+        buffer.clear();
+        buffer.set_title(&format!("Source of {}", event_id));
+
+        for line in source.lines() {
+            buffer.print(line);
+        }
+
+        buffer.switch_to();
+    }
+
+    /// Open the compose buffer of the room, or switch to it if it's already
+    /// open.
+    pub fn open_compose_buffer(&self) {
+        let mut compose = self.compose_buffer.borrow_mut();
+
+        if let Some(buffer) = compose.as_ref().and_then(|c| c.buffer()) {
+            buffer.switch_to();
+            return;
+        }
+
+        let room_buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        if let Some(c) = ComposeBuffer::new(&room_buffer) {
+            if let Some(buffer) = c.buffer() {
+                buffer.switch_to();
+            }
+
+            *compose = Some(c);
+        } else {
+            self.print_error("Error creating the compose buffer");
+        }
+    }
+
+    /// Is the given buffer the compose buffer of the room.
+    pub fn is_compose_buffer(&self, buffer: &Buffer) -> bool {
+        self.compose_buffer
+            .borrow()
+            .as_ref()
+            .and_then(|c| c.buffer())
+            .map_or(false, |b| &b == buffer)
+    }
+
+    /// Search the room history for images and list them in the gallery
+    /// buffer of the room, a gallery that is already open is replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `search` - Only list images whose name contains the given text,
+    /// ignoring case.
+    pub async fn open_gallery(&self, search: Option<String>) {
+        let files = if let Some(f) = self
+            .search_files(GALLERY_MAX_IMAGES, true, search.as_deref())
+            .await
+        {
+            f
+        } else {
+            return;
+        };
+
+        if files.is_empty() {
+            self.print_network("No images were found");
+            return;
+        }
+
+        let room_buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let mut items = Vec::new();
+
+        for file in files {
+            items.push(GalleryItem {
+                sender: self.nick_or_user_id(&file.sender).await,
+                name: file.attachment.name,
+                source: file.attachment.source,
+                timestamp: file.timestamp,
+                size: file.attachment.size,
+                dimensions: file.dimensions,
+            });
+        }
+
+        let title = format!(
+            "{} images of {}{}, up/down to select, alt-o to open, alt-d to \
+             download",
+            items.len(),
+            room_buffer.short_name(),
+            search
+                .map(|s| format!(" matching \"{}\"", s))
+                .unwrap_or_default(),
+        );
+
+        if let Some(gallery) = self.gallery_buffer.borrow_mut().take() {
+            gallery.close();
+        }
+
+        match GalleryBuffer::new(&room_buffer, &title, items) {
+            Some(g) => *self.gallery_buffer.borrow_mut() = Some(g),
+            None => self.print_error("Error creating the gallery buffer"),
+        }
+    }
+
+    /// Is the given buffer the gallery buffer of the room.
+    pub fn is_gallery_buffer(&self, buffer: &Buffer) -> bool {
+        self.gallery_buffer
+            .borrow()
+            .as_ref()
+            .and_then(|g| g.buffer())
+            .map_or(false, |b| &b == buffer)
+    }
+
+    /// Move the selection of the gallery by the given number of rows.
+    pub fn move_gallery_selection(&self, offset: isize) {
+        if let Some(g) = self.gallery_buffer.borrow().as_ref() {
+            g.move_selection(offset);
+        }
+    }
+
+    /// Get the selected image of the gallery.
+    fn selected_image(&self) -> Option<GalleryItem> {
+        let image = self
+            .gallery_buffer
+            .borrow()
+            .as_ref()
+            .and_then(|g| g.selected());
+
+        if image.is_none() {
+            self.print_error(
+                "No image is selected, use /matrix gallery to list the \
+                 images of the room",
+            );
+        }
+
+        image
+    }
+
+    /// Open the selected image of the gallery using the
+    /// `input.media_opener` command.
+    pub async fn open_gallery_image(&self) {
+        if let Some(image) = self.selected_image() {
+            self.open_media(image.source, None, "image").await;
+        }
+    }
+
+    /// Download the selected image of the gallery to the given path, if the
+    /// path is a directory the image is saved under its own name in it.
+    pub async fn download_gallery_image(&self, path: PathBuf) {
+        let image = if let Some(i) = self.selected_image() {
+            i
+        } else {
+            return;
+        };
+
+        let message =
+            match self.save_media(&image.name, image.source, path).await {
+                Ok(path) => format!(
+                    "{}{}: Saved {} to {}",
+                    Weechat::prefix(Prefix::Network),
+                    PLUGIN_NAME,
+                    image.name,
+                    path.display()
+                ),
+                Err(e) => format!(
+                    "{}{}: {}",
+                    Weechat::prefix(Prefix::Error),
+                    PLUGIN_NAME,
+                    e
+                ),
+            };
+
+        if let Some(g) = self.gallery_buffer.borrow().as_ref() {
+            g.print(&message);
+        }
+    }
+
+    /// Remove the last entered piece of the composed message.
+    pub fn undo_compose(&self) {
+        if let Some(c) = self.compose_buffer.borrow().as_ref() {
+            c.undo();
+        } else {
+            self.print_error("No message is being composed");
+        }
+    }
+
+    /// Close the compose buffer of the room and return the composed message.
+    ///
+    /// Returns `None` if no message is being composed.
+    pub fn close_compose_buffer(&self) -> Option<String> {
+        let compose = self.compose_buffer.borrow_mut().take()?;
+        let draft = compose.draft();
+        compose.close();
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.switch_to();
+        }
+
+        Some(draft)
+    }
+
+    /// Fetch a message and the messages it replies to, up to
+    /// `MAX_REPLY_CHAIN_LENGTH` levels deep.
+    ///
+    /// The chain is returned in the order the messages were sent.
+    async fn reply_chain(
+        &self,
+        event_id: OwnedEventId,
+    ) -> Vec<AnyMessageLikeEvent> {
+        let mut chain = Vec::new();
+        let mut next = Some(event_id);
+
+        while let Some(event_id) = next.take() {
+            if chain.len() >= MAX_REPLY_CHAIN_LENGTH {
+                break;
+            }
+
+            let room = self.room.clone();
+            let id = event_id.clone();
+
+            let event = match self
+                .members
+                .runtime
+                .spawn(async move { room.event(&id).await })
+                .await
+            {
+                Ok(Ok(e)) => e,
+                _ => {
+                    self.print_error(&format!(
+                        "Error fetching the event {}",
+                        event_id
+                    ));
+                    break;
+                }
+            };
+
+            let event = match event.event.deserialize() {
+                Ok(AnyTimelineEvent::MessageLike(e)) => e,
+                _ => break,
+            };
+
+            if let AnyMessageLikeEvent::RoomMessage(
+                MessageLikeEvent::Original(e),
+            ) = &event
+            {
+                if let Some(Relation::Reply { in_reply_to }) =
+                    &e.content.relates_to
+                {
+                    next = Some(in_reply_to.event_id.clone());
+                }
+            }
+
+            chain.push(event);
+        }
+
+        chain.reverse();
+        chain
+    }
+
+    /// Print the chain of replies leading up to the given event into a
+    /// scratch buffer, so long discussions can be read in order.
+    pub async fn print_reply_chain(&self, event_id: OwnedEventId) {
+        let chain = self.reply_chain(event_id.clone()).await;
+
+        let buffer = if let Some(b) = self.reply_chain_buffer() {
+            b
+        } else {
+            return;
+        };
+
+        buffer.clear();
+        buffer.set_title(&format!("Reply chain of {}", event_id));
+
+        for event in chain {
+            if let Some(content) = event.original_content() {
+                self.print_message_into(
+                    &buffer,
+                    event.event_id(),
+                    event.origin_server_ts(),
+                    event.sender(),
+                    &content,
+                )
+                .await;
+            }
+        }
+
+        buffer.switch_to();
+    }
+
+    /// Render a message and print it into one of the auxiliary buffers of the
+    /// room, e.g. the reply chain or a thread buffer.
+    async fn print_message_into(
+        &self,
+        buffer: &Buffer,
+        event_id: &EventId,
+        timestamp: MilliSecondsSinceUnixEpoch,
+        sender: &UserId,
+        content: &AnyMessageLikeEventContent,
+    ) {
+        let sender = if let Some(s) = self.members.get(sender).await {
+            s
+        } else {
+            return;
+        };
+
+        if let Some(rendered) = self
+            .render_message_content(event_id, timestamp, &sender, content)
+            .await
+        {
+            let rendered = self.apply_render_profile(rendered);
+
+            for line in rendered.content.lines {
+                let tags: Vec<&str> =
+                    line.tags.iter().map(|t| t.as_str()).collect();
+
+                buffer.print_date_tags(
+                    rendered.message_timestamp,
+                    &tags,
+                    &format!("{}{}", rendered.prefix, line.message),
+                );
+            }
+        }
+    }
+
+    /// Get the root of the thread the given message is part of.
+    fn thread_root(
+        content: Option<AnyMessageLikeEventContent>,
+    ) -> Option<OwnedEventId> {
+        match content? {
+            AnyMessageLikeEventContent::RoomMessage(
+                RoomMessageEventContent {
+                    relates_to: Some(Relation::Thread(thread)),
+                    ..
+                },
+            ) => Some(thread.event_id),
+            _ => None,
+        }
+    }
+
+    /// Remember a reply to a thread and update the number of replies on the
+    /// line of the thread root.
+    ///
+    /// Replies are kept sorted by their timestamp, history that is fetched
+    /// later arrives newest first.
+    ///
+    /// Returns false if the reply was already known.
+    fn add_thread_reply(
+        &self,
+        root: &EventId,
+        event_id: &EventId,
+        timestamp: MilliSecondsSinceUnixEpoch,
+    ) -> bool {
+        {
+            let mut threads = self.threads.borrow_mut();
+            let replies = threads.entry(root.to_owned()).or_default();
+
+            if replies.iter().any(|(_, r)| **r == *event_id) {
+                return false;
+            }
+
+            let index = replies.partition_point(|(t, _)| *t <= timestamp);
+            replies.insert(index, (timestamp, event_id.to_owned()));
+        }
+
+        self.update_annotation(root);
+
+        true
+    }
+
+    /// Handle a reply to a thread that we received in a sync response, the
+    /// reply is printed in the buffer of the thread if it's open.
+    async fn handle_thread_reply(
+        &self,
+        root: &EventId,
+        event: &AnySyncMessageLikeEvent,
+    ) {
+        if !self.add_thread_reply(
+            root,
+            event.event_id(),
+            event.origin_server_ts(),
+        ) {
+            return;
+        }
+
+        if let (Some(buffer), Some(content)) =
+            (self.thread_buffer(root), event.original_content())
+        {
+            self.print_message_into(
+                &buffer,
+                event.event_id(),
+                event.origin_server_ts(),
+                event.sender(),
+                &content,
+            )
+            .await;
+        }
+    }
+
+    /// Get the buffer of the given thread, if it's open.
+    fn thread_buffer(&self, root: &EventId) -> Option<Buffer> {
+        let mut buffers = self.thread_buffers.borrow_mut();
+        let buffer = buffers.get(root)?.upgrade().ok();
+
+        if buffer.is_none() {
+            buffers.remove(root);
+        }
+
+        buffer
+    }
+
+    /// Create the buffer of the given thread, messages that are entered in
+    /// the buffer are sent as replies to the thread.
+    fn create_thread_buffer(&self, root: &EventId) -> Option<Buffer> {
+        let room_buffer = self.buffer_handle().upgrade().ok()?;
+
+        let handle = BufferBuilder::new(&format!(
+            "{}.thread.{}",
+            room_buffer.name(),
+            root
+        ))
+        .input_callback({
+            let room = self.clone();
+            let root = root.to_owned();
+
+            move |_: &Weechat, _: &Buffer, input: Cow<str>| {
+                let room = room.clone();
+                let root = root.clone();
+                let input = input.to_string();
+
+                Weechat::spawn(async move {
+                    room.send_thread_reply(&root, &input).await
+                })
+                .detach();
+
+                Ok(())
+            }
+        })
+        .build()
+        .ok()?;
+
+        let buffer = handle.upgrade().ok()?;
+
+        buffer.set_short_name(&format!("{}.thread", room_buffer.short_name()));
+        buffer.set_title(&format!("Thread of {}", root));
+        buffer.set_localvar("type", "private");
+
+        self.thread_buffers
+            .borrow_mut()
+            .insert(root.to_owned(), handle);
+
+        Some(buffer)
+    }
+
+    /// Open a buffer showing the thread the given message started, or switch
+    /// to it if it's already open.
+    ///
+    /// The message and the latest `MAX_THREAD_REPLIES` replies are fetched
+    /// from the server.
+    pub async fn open_thread(&self, root: OwnedEventId) {
+        if let Some(buffer) = self.thread_buffer(&root) {
+            buffer.switch_to();
+            return;
+        }
+
+        let connection = if let Some(c) =
+            self.connection.borrow().as_ref().cloned()
+        {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let room = self.room.clone();
+        let id = root.clone();
+
+        let root_event = match self
+            .members
+            .runtime
+            .spawn(async move { room.event(&id).await })
+            .await
+        {
+            Ok(Ok(e)) => e.event,
+            _ => {
+                self.print_error(&format!("Error fetching the event {}", root));
+                return;
+            }
+        };
+
+        let replies = match connection
+            .relating_events(
+                self.room.clone(),
+                root.clone(),
+                RelationType::Thread,
+                MAX_THREAD_REPLIES,
+            )
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error fetching the thread of {}: {}",
+                    root, e
+                ));
+                return;
+            }
+        };
+
+        let buffer = if let Some(b) = self.create_thread_buffer(&root) {
+            b
+        } else {
+            self.print_error("Error creating the thread buffer");
+            return;
+        };
+
+        for event in std::iter::once(root_event)
+            .chain(replies.into_iter().rev())
+            .filter_map(|e| e.deserialize().ok())
+        {
+            let event = if let AnyTimelineEvent::MessageLike(e) = event {
+                e
+            } else {
+                continue;
+            };
+
+            if *event.event_id() != *root {
+                self.add_thread_reply(
+                    &root,
+                    event.event_id(),
+                    event.origin_server_ts(),
+                );
+            }
+
+            if let Some(content) = event.original_content() {
+                self.print_message_into(
+                    &buffer,
+                    event.event_id(),
+                    event.origin_server_ts(),
+                    event.sender(),
+                    &content,
+                )
+                .await;
+            }
+        }
+
+        buffer.switch_to();
+    }
+
+    /// Send a message as a reply to the given thread.
+    ///
+    /// The message isn't echoed locally, it's printed in the thread buffer
+    /// once it comes back in a sync response.
+    pub async fn send_thread_reply(&self, root: &EventId, body: &str) {
+        if self.refuse_send() {
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        let latest = self
+            .threads
+            .borrow()
+            .get(root)
+            .and_then(|r| r.last().map(|(_, e)| e.clone()))
+            .unwrap_or_else(|| root.to_owned());
+
+        let mut content = self.text_content(body);
+        content.relates_to =
+            Some(Relation::Thread(Thread::plain(root.to_owned(), latest)));
+
+        match connection
+            .send_message(
+                self.room().clone(),
+                AnyMessageLikeEventContent::RoomMessage(content),
+                None,
+                None,
+            )
+            .await
+        {
+            Ok(_) => self.track_outbound_message(),
+            Err(e) => self.print_error(&format!(
+                "Error sending the reply to the thread: {}",
+                self.describe_error(&e, RoomAction::Message("m.room.message"))
+            )),
+        }
+    }
+
+    /// Get the nick of a room member, or the user id if the user isn't a
+    /// member of the room.
+    async fn nick_or_user_id(&self, user_id: &UserId) -> String {
+        self.members
+            .get(user_id)
+            .await
+            .map(|m| m.nick())
+            .unwrap_or_else(|| user_id.to_string())
+    }
+
+    /// Describe a moderation action, returns None if the event isn't one.
+    async fn describe_moderation_event(
+        &self,
+        event: &AnyTimelineEvent,
+    ) -> Option<String> {
+        let with_reason = |action: String, reason: Option<&str>| match reason {
+            Some(reason) => format!("{} (reason: {})", action, reason),
+            None => action,
+        };
+
+        match event {
+            AnyTimelineEvent::State(AnyStateEvent::RoomMember(
+                StateEvent::Original(e),
+            )) => {
+                let verb = match e.membership_change() {
+                    MembershipChange::Kicked => "kicked",
+                    MembershipChange::Banned => "banned",
+                    MembershipChange::Unbanned => "unbanned",
+                    MembershipChange::KickedAndBanned => "kicked and banned",
+                    _ => return None,
+                };
+
+                let target = UserId::parse(e.state_key.as_str()).ok()?;
+
+                let action = format!(
+                    "{} {} {}",
+                    self.nick_or_user_id(&e.sender).await,
+                    verb,
+                    self.nick_or_user_id(&target).await
+                );
+
+                Some(with_reason(action, e.content.reason.as_deref()))
+            }
+            AnyTimelineEvent::State(AnyStateEvent::RoomPowerLevels(
+                StateEvent::Original(e),
+            )) => {
+                let new = &e.content;
+                let old = e.unsigned.prev_content.as_ref();
+
+                let old_level = |user_id: &UserId| {
+                    old.map_or(new.users_default, |o| {
+                        o.users.get(user_id).cloned().unwrap_or(o.users_default)
+                    })
+                };
+
+                let mut users: Vec<&OwnedUserId> = new.users.keys().collect();
+
+                if let Some(old) = old {
+                    users.extend(old.users.keys());
+                }
+
+                users.sort();
+                users.dedup();
+
+                let mut changes = Vec::new();
+
+                for user_id in users {
+                    let before = old_level(user_id);
+                    let after = new
+                        .users
+                        .get(user_id)
+                        .cloned()
+                        .unwrap_or(new.users_default);
+
+                    if before != after {
+                        changes.push(format!(
+                            "{} {} -> {}",
+                            self.nick_or_user_id(user_id).await,
+                            before,
+                            after
+                        ));
+                    }
+                }
+
+                let changes = if changes.is_empty() {
+                    "room permissions".to_owned()
+                } else {
+                    changes.join(", ")
+                };
+
+                Some(format!(
+                    "{} changed power levels: {}",
+                    self.nick_or_user_id(&e.sender).await,
+                    changes
+                ))
+            }
+            AnyTimelineEvent::MessageLike(
+                AnyMessageLikeEvent::RoomRedaction(
+                    RoomRedactionEvent::Original(e),
+                ),
+            ) => {
+                let action = format!(
+                    "{} redacted {}",
+                    self.nick_or_user_id(&e.sender).await,
+                    e.redacts
+                );
+
+                Some(with_reason(action, e.content.reason.as_deref()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Record that one of our messages was sent, messages of encrypted rooms
+    /// count towards the rotation of the outbound group session.
+    fn track_outbound_message(&self) {
+        if self.room.is_encrypted() {
+            let settings =
+                RotationSettings::new(self.room.encryption_settings().as_ref());
+            self.outbound_session.borrow_mut().message_sent(settings);
+        }
+    }
+
+    /// Print the encryption algorithm and the rotation settings of the room,
+    /// as well as an estimate of the age and message count of the outbound
+    /// group session.
+    ///
+    /// The SDK doesn't expose its sessions, the estimate only covers the
+    /// messages this WeeChat instance sent since it was started.
+    pub fn print_encryption_info(&self) {
+        let content = if let Some(c) = self.room.encryption_settings() {
+            c
+        } else {
+            self.print_network("The room isn't encrypted");
+            return;
+        };
+
+        let settings = RotationSettings::new(Some(&content));
+
+        self.print_network(&format!("Algorithm: {}", content.algorithm));
+        self.print_network(&format!(
+            "Session rotation: after {} messages or {}",
+            settings.messages,
+            format_duration(settings.period)
+        ));
+
+        match self.outbound_session.borrow().current(settings) {
+            Some(session) => self.print_network(&format!(
+                "Outbound session (estimate): {} old, {}/{} messages sent",
+                format_duration(session.age()),
+                session.messages,
+                settings.messages
+            )),
+            None => self.print_network(
+                "Outbound session (estimate): unknown, no message was sent \
+                 with the current session since WeeChat started",
+            ),
+        }
+
+        self.print_network(
+            "The outbound session is estimated from the messages sent since \
+             WeeChat started, the SDK may have rotated it earlier",
+        );
+    }
+
+    /// Print statistics about the messages printed in the buffer, the
+    /// message counts per member, the most active hours and the number of
+    /// attachments.
+    ///
+    /// # Arguments
+    ///
+    /// * `days` - Only count the messages of the given number of past days.
+    pub fn print_stats(&self, days: Option<u64>) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        // A period that doesn't fit into a timestamp covers all the lines.
+        let since = days
+            .and_then(|d| i64::try_from(d).ok())
+            .and_then(|d| d.checked_mul(86400))
+            .map(|p| Local::now().timestamp().saturating_sub(p));
+        let quote_tag = "matrix_reply_quote";
+        let mut stats = RoomStats::default();
+
+        for line in buffer.lines() {
+            if since.map_or(false, |s| line.date() < s) {
+                continue;
+            }
+
+            let tags = line.tags();
+
+            if tags.iter().any(|t| t == quote_tag)
+                || !tags.iter().any(|t| MESSAGE_TAGS.contains(&&**t))
+            {
+                continue;
+            }
+
+            let find_tag =
+                |prefix: &str| tags.iter().find_map(|t| t.strip_prefix(prefix));
+
+            let (event_id, sender) =
+                match (find_tag("matrix_id_"), find_tag("matrix_sender_")) {
+                    (Some(e), Some(s)) => (e, s),
+                    _ => continue,
+                };
+
+            stats.add(
+                event_id,
+                sender,
+                find_tag("nick_").unwrap_or(sender),
+                Local
+                    .timestamp_opt(line.date(), 0)
+                    .single()
+                    .map(|d| d.hour()),
+                tags.iter().any(|t| t == ATTACHMENT_TAG),
+            );
+        }
+
+        let period = match days {
+            Some(1) => " of the last day".to_owned(),
+            Some(d) => format!(" of the last {} days", d),
+            None => String::new(),
+        };
+
+        if stats.messages() == 0 {
+            self.print_network(&format!(
+                "No messages{} in the buffer, use /matrix more to fetch \
+                 more of the history",
+                period
+            ));
+            return;
+        }
+
+        self.print_network(&format!(
+            "Statistics of the {} messages{} in the buffer:",
+            stats.messages(),
+            period
+        ));
+
+        for line in stats.format_table() {
+            buffer.print(&format!("  {}", line));
+        }
+    }
+
+    /// Print the latest kicks, bans, power level changes and redactions in
+    /// the room, oldest first.
+    pub async fn print_modlog(&self, count: usize) {
+        let connection = if let Some(c) =
+            self.connection.borrow().as_ref().cloned()
+        {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let client = connection.client().clone();
+        let mut from = match self
+            .members
+            .runtime
+            .spawn(async move { client.sync_token().await })
+            .await
+            .ok()
+            .flatten()
+        {
+            Some(t) => t,
+            None => return,
+        };
 
-            let mut tags = line.tags();
-            tags.push(tag);
-            let tags: Vec<&str> = tags.iter().map(|t| t.as_ref()).collect();
+        let mut entries = Vec::new();
 
-            line.set_message(&new_message);
-            line.set_tags(&tags);
+        for _ in 0..MODLOG_MAX_PAGES {
+            let messages = match connection
+                .filtered_room_messages(
+                    self.room.clone(),
+                    from,
+                    MAX_HISTORY_PAGE_SIZE,
+                    MODLOG_EVENT_TYPES,
+                )
+                .await
+            {
+                Ok(m) => m,
+                Err(e) => {
+                    self.print_error(&format!(
+                        "Error fetching the room history: {}",
+                        e
+                    ));
+                    return None;
+                }
+            };
+
+            for event in messages
+                .chunk
+                .iter()
+                .filter_map(|e| e.event.deserialize().ok())
+            {
+                if let Some(entry) =
+                    self.describe_moderation_event(&event).await
+                {
+                    entries.push((event.origin_server_ts(), entry));
+                }
+            }
+
+            match messages.end {
+                Some(end)
+                    if entries.len() < count && !messages.chunk.is_empty() =>
+                {
+                    from = end
+                }
+                _ => break,
+            }
         }
 
-        let mut lines = buffer.lines();
-        let first_line = lines.rfind(predicate);
+        entries.truncate(count);
 
-        if let Some(line) = first_line {
-            modify_line(line, tag.clone(), redact_first_line);
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
         } else {
             return;
+        };
+
+        if entries.is_empty() {
+            self.print_network("No moderation events were found");
+            return;
         }
 
-        while let Some(line) = lines.next_back().filter(predicate) {
-            modify_line(line, tag.clone(), redact_string);
+        self.print_network(&format!(
+            "The last {} moderation events in the room:",
+            entries.len()
+        ));
+
+        for (timestamp, entry) in entries.iter().rev() {
+            let date = format_timestamp(*timestamp, "%Y-%m-%d %H:%M");
+
+            buffer.print(&format!(
+                "  {}[{}]{} {}",
+                Weechat::color("chat_delimiters"),
+                date,
+                Weechat::color("reset"),
+                entry
+            ));
         }
     }
 
-    async fn render_message_content(
-        &self,
-        event_id: &EventId,
-        send_time: MilliSecondsSinceUnixEpoch,
-        sender: &WeechatRoomMember,
-        content: &AnyMessageLikeEventContent,
-    ) -> Option<RenderedEvent> {
-        use AnyMessageLikeEventContent::*;
-        use MessageType::*;
-
-        let rendered = match content {
-            RoomEncrypted(c) => {
-                c.render_with_prefix(send_time, event_id, sender, &())
-            }
-            RoomMessage(c) => match &c.msgtype {
-                Text(c) => {
-                    c.render_with_prefix(send_time, event_id, sender, &())
-                }
-                Emote(c) => {
-                    c.render_with_prefix(send_time, event_id, &sender, &sender)
-                }
-                Notice(c) => {
-                    c.render_with_prefix(send_time, event_id, &sender, &sender)
-                }
-                ServerNotice(c) => {
-                    c.render_with_prefix(send_time, event_id, &sender, &sender)
-                }
-                Location(c) => {
-                    c.render_with_prefix(send_time, event_id, &sender, &sender)
-                }
-                Audio(c) => c.render_with_prefix(
-                    send_time,
-                    event_id,
-                    &sender,
-                    &self.homeserver,
-                ),
-                Video(c) => c.render_with_prefix(
-                    send_time,
-                    event_id,
-                    &sender,
-                    &self.homeserver,
-                ),
-                File(c) => c.render_with_prefix(
-                    send_time,
-                    event_id,
-                    &sender,
-                    &self.homeserver,
-                ),
-                Image(c) => c.render_with_prefix(
-                    send_time,
-                    event_id,
-                    &sender,
-                    &self.homeserver,
-                ),
-                _ => return None,
-            },
-            _ => return None,
+    /// Print the edit history of the given message, the original content
+    /// followed by every edit of its sender, oldest first.
+    pub async fn print_edit_history(&self, event_id: &EventId) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
         };
 
-        Some(rendered)
-    }
+        let room = self.room.clone();
+        let id = event_id.to_owned();
 
-    async fn render_sync_message(
-        &self,
-        event: &AnySyncMessageLikeEvent,
-    ) -> Option<RenderedEvent> {
-        // TODO: remove this expect.
-        let sender =
-            self.members.get(event.sender()).await.expect(
-                "Rendering a message but the sender isn't in the nicklist",
-            );
+        let original = match self
+            .members
+            .runtime
+            .spawn(async move { room.event(&id).await })
+            .await
+        {
+            Ok(Ok(e)) => e.event.deserialize().ok(),
+            _ => None,
+        };
 
-        if let Some(content) = event.original_content() {
-            let send_time = event.origin_server_ts();
-            self.render_message_content(
-                event.event_id(),
-                send_time,
-                &sender,
-                &content,
+        let original = if let Some(AnyTimelineEvent::MessageLike(e)) = original
+        {
+            e
+        } else {
+            self.print_error(&format!("Error fetching the event {}", event_id));
+            return;
+        };
+
+        let edits = match connection
+            .relating_events(
+                self.room.clone(),
+                event_id.to_owned(),
+                RelationType::Replacement,
+                MAX_EDITS,
             )
             .await
-            .map(|r| {
-                // TODO: the tags are different if the room is a DM.
-                if sender.user_id() == &*self.own_user_id {
-                    r.add_self_tags()
-                } else {
-                    r.add_msg_tags()
+        {
+            Ok(e) => e,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error fetching the edits of {}: {}",
+                    event_id, e
+                ));
+                return;
+            }
+        };
+
+        // The body of a message, or the new body if the message is an edit.
+        let body =
+            |event: &AnyMessageLikeEvent| match event.original_content()? {
+                AnyMessageLikeEventContent::RoomMessage(c) => Some(
+                    c.get_edit()
+                        .map_or_else(|| c.body(), |(_, new)| new.body())
+                        .replace('\n', " "),
+                ),
+                _ => None,
+            };
+
+        // Only the sender of a message is allowed to edit it, edits of other
+        // users are ignored like they are in the buffer.
+        let mut edits: Vec<(MilliSecondsSinceUnixEpoch, String)> = edits
+            .iter()
+            .filter_map(|e| match e.deserialize().ok()? {
+                AnyTimelineEvent::MessageLike(e)
+                    if e.sender() == original.sender() =>
+                {
+                    Some((e.origin_server_ts(), body(&e)?))
                 }
+                _ => None,
             })
-        } else {
-            self.render_redacted_event(event).await
+            .collect();
+
+        if edits.is_empty() {
+            self.print_network(&format!(
+                "The message {} wasn't edited",
+                event_id
+            ));
+            return;
         }
-    }
 
-    // Add the content of the message to our outgoing message queue and print out
-    // a local echo line if local echo is enabled.
-    async fn queue_outgoing_message(
-        &self,
-        transaction_id: &TransactionId,
-        content: &RoomMessageEventContent,
-    ) {
-        if self.config.borrow().look().local_echo() {
-            if let MessageType::Text(c) = &content.msgtype {
-                let sender =
-                    self.members.get(&self.own_user_id).await.unwrap_or_else(
-                        || panic!("No own member {}", self.own_user_id),
-                    );
+        edits.sort_by(|a, b| a.0.cmp(&b.0));
 
-                let local_echo = c
-                    .render_with_prefix_for_echo(&sender, transaction_id, &())
-                    .add_self_tags();
-                self.print_rendered_event(local_echo);
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
 
-                self.outgoing_messages
-                    .add_with_echo(transaction_id.to_owned(), content.clone());
+        self.print_network(&format!(
+            "The message {} was edited {} times:",
+            event_id,
+            edits.len()
+        ));
+
+        let original_body = body(&original).unwrap_or_default();
+
+        for (i, (timestamp, body)) in
+            std::iter::once((original.origin_server_ts(), original_body))
+                .chain(edits)
+                .enumerate()
+        {
+            let date = format_timestamp(timestamp, "%Y-%m-%d %H:%M:%S");
+            let label = if i == 0 {
+                "original".to_owned()
             } else {
-                self.outgoing_messages
-                    .add(transaction_id.to_owned(), content.clone());
-            }
-        } else {
-            self.outgoing_messages
-                .add(transaction_id.to_owned(), content.clone());
+                format!("edit {}", i)
+            };
+
+            buffer.print(&format!(
+                "  {}[{}]{} {}: {}",
+                Weechat::color("chat_delimiters"),
+                date,
+                Weechat::color("reset"),
+                label,
+                body
+            ));
         }
     }
 
-    /// Send the given content to the server.
+    /// Get the file the given message contains, if it's a file, image,
+    /// video or audio message.
+    fn attachment_of(content: &RoomMessageEventContent) -> Option<Attachment> {
+        macro_rules! attachment {
+            ($content:expr) => {
+                Attachment {
+                    name: $content.body.clone(),
+                    source: $content.source.clone(),
+                    size: $content
+                        .info
+                        .as_ref()
+                        .and_then(|i| i.size)
+                        .map(u64::from),
+                    mimetype: $content
+                        .info
+                        .as_ref()
+                        .and_then(|i| i.mimetype.clone()),
+                }
+            };
+        }
+
+        match &content.msgtype {
+            MessageType::File(c) => Some(attachment!(c)),
+            MessageType::Image(c) => Some(attachment!(c)),
+            MessageType::Video(c) => Some(attachment!(c)),
+            MessageType::Audio(c) => Some(attachment!(c)),
+            _ => None,
+        }
+    }
+
+    /// Search the room history for files, images, videos and audio messages,
+    /// newest first.
     ///
-    /// # Arguments
+    /// Errors are printed and result in `None`.
     ///
-    /// * `content` - The content that should be sent to the server.
+    /// # Arguments
     ///
-    /// # Examples
+    /// * `count` - The maximal number of files that are returned.
     ///
-    /// ```
-    /// let content = MessageEventContent::Text(TextMessageEventContent {
-    ///     body: "Hello world".to_owned(),
-    ///     formatted: None,
-    ///     relates_to: None,
-    /// });
-    /// let content = AnyMessageEventContent::RoomMessage(content);
+    /// * `images_only` - Only search for images.
     ///
-    /// buffer.send_message(content).await
-    /// ```
-    pub async fn send_message(&self, content: RoomMessageEventContent) {
-        let transaction_id = TransactionId::new();
+    /// * `search` - Only return files whose name contains the given text,
+    /// ignoring case.
+    async fn search_files(
+        &self,
+        count: usize,
+        images_only: bool,
+        search: Option<&str>,
+    ) -> Option<Vec<RoomFile>> {
+        let connection = if let Some(c) =
+            self.connection.borrow().as_ref().cloned()
+        {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return None;
+        };
 
-        let connection = self.connection.borrow().clone();
+        let search = search.map(|s| s.to_lowercase());
 
-        if let Some(c) = connection {
-            self.queue_outgoing_message(&transaction_id, &content).await;
-            match c
-                .send_message(
-                    self.room().clone(),
-                    AnyMessageLikeEventContent::RoomMessage(content),
-                    Some(transaction_id.to_owned()),
+        let client = connection.client().clone();
+        let mut from = match self
+            .members
+            .runtime
+            .spawn(async move { client.sync_token().await })
+            .await
+            .ok()
+            .flatten()
+        {
+            Some(t) => t,
+            None => return None,
+        };
+
+        let mut files = Vec::new();
+
+        for _ in 0..FILES_MAX_PAGES {
+            let messages = match connection
+                .filtered_room_messages(
+                    self.room.clone(),
+                    from,
+                    MAX_HISTORY_PAGE_SIZE,
+                    FILES_EVENT_TYPES,
                 )
                 .await
             {
-                Ok(r) => {
-                    self.handle_outgoing_message(&transaction_id, &r.event_id)
-                        .await;
+                Ok(m) => m,
+                Err(e) => {
+                    self.print_error(&format!(
+                        "Error fetching the room history: {}",
+                        e
+                    ));
+                    return;
                 }
-                Err(_e) => {
-                    // TODO: print out an error, remember to modify the local
-                    // echo line if there is one.
-                    self.outgoing_messages.remove(&transaction_id);
+            };
+
+            for event in messages
+                .chunk
+                .iter()
+                .filter_map(|e| e.event.deserialize().ok())
+            {
+                if let AnyTimelineEvent::MessageLike(
+                    AnyMessageLikeEvent::RoomMessage(
+                        MessageLikeEvent::Original(e),
+                    ),
+                ) = event
+                {
+                    let dimensions = match &e.content.msgtype {
+                        MessageType::Image(c) => {
+                            c.info.as_ref().and_then(|i| {
+                                Some((i.width?.into(), i.height?.into()))
+                            })
+                        }
+                        _ if images_only => continue,
+                        _ => None,
+                    };
+
+                    let attachment = match Self::attachment_of(&e.content) {
+                        Some(a)
+                            if search.as_ref().map_or(true, |s| {
+                                a.name.to_lowercase().contains(s)
+                            }) =>
+                        {
+                            a
+                        }
+                        _ => continue,
+                    };
+
+                    files.push(RoomFile {
+                        attachment,
+                        sender: e.sender,
+                        timestamp: e.origin_server_ts,
+                        dimensions,
+                    });
                 }
             }
-        } else if let Ok(buffer) = self.buffer_handle().upgrade() {
-            buffer.print("Error not connected");
+
+            match messages.end {
+                Some(end)
+                    if files.len() < count && !messages.chunk.is_empty() =>
+                {
+                    from = end
+                }
+                _ => break,
+            }
         }
+
+        files.truncate(count);
+
+        Some(files)
     }
 
-    /// Send out a typing notice.
-    ///
-    /// This will send out a typing notice or reset the one in progress, if
-    /// needed. It will make sure that only one typing notice request is in
-    /// flight at a time.
-    ///
-    /// Typing notices are sent out only if we have more than 4 letters in the
-    /// input and the input isn't a command.
+    /// Search the room history for files, images, videos and audio messages
+    /// and print the given number of them, newest first.
     ///
-    /// If the input is empty the typing notice is disabled.
-    pub fn update_typing_notice(&self) {
-        let buffer_handle = self.buffer_handle();
+    /// The files are numbered, the numbers can be used with `/matrix
+    /// download` and `/matrix open`.
+    pub async fn print_files(&self, count: usize) {
+        let files = if let Some(f) = self.search_files(count, false, None).await
+        {
+            f
+        } else {
+            return;
+        };
+
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        if files.is_empty() {
+            self.print_network("No files were found");
+            return;
+        }
+
+        self.print_network(&format!(
+            "The last {} files in the room, use /matrix download or /matrix \
+             open with their number:",
+            files.len()
+        ));
+
+        for (number, file) in files.iter().enumerate() {
+            let date = format_timestamp(file.timestamp, "%Y-%m-%d %H:%M");
+
+            let sender = self
+                .members
+                .get(&file.sender)
+                .await
+                .map(|m| m.nick())
+                .unwrap_or_else(|| file.sender.to_string());
+
+            let size = file
+                .attachment
+                .size
+                .map(|s| format!(" ({})", format_size(s)))
+                .unwrap_or_default();
+
+            buffer.print(&format!(
+                "  {}. {}[{}]{} {}{} from {}",
+                number + 1,
+                Weechat::color("chat_delimiters"),
+                date,
+                Weechat::color("reset"),
+                file.attachment.name,
+                size,
+                sender
+            ));
+        }
+
+        *self.files.borrow_mut() = files;
+    }
+
+    /// Print the users that are banned from the room, with the moderator
+    /// that banned them and the reason, newest first.
+    pub async fn print_bans(&self) {
+        let room = self.room.clone();
+
+        let events = match self
+            .members
+            .runtime
+            .spawn(async move {
+                room.get_state_events(StateEventType::RoomMember).await
+            })
+            .await
+        {
+            Ok(Ok(e)) => e,
+            _ => {
+                self.print_error("Error fetching the members of the room");
+                return;
+            }
+        };
 
-        let buffer = if let Ok(b) = buffer_handle.upgrade() {
+        let mut bans: Vec<RoomBan> = events
+            .iter()
+            .filter_map(|e| match e.deserialize().ok()? {
+                AnySyncStateEvent::RoomMember(SyncStateEvent::Original(e))
+                    if e.content.membership == MembershipState::Ban =>
+                {
+                    Some(RoomBan {
+                        user_id: UserId::parse(e.state_key.as_str()).ok()?,
+                        moderator: e.sender,
+                        reason: e.content.reason,
+                        timestamp: e.origin_server_ts,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+
+        bans.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
             b
         } else {
             return;
         };
 
-        let input = buffer.input();
-
-        if input.starts_with('/') && !input.starts_with("//") {
-            // Don't send typing notices for commands.
+        if bans.is_empty() {
+            self.print_network("Nobody is banned from the room");
             return;
         }
 
-        let connection = self.connection.clone();
-        let room = self.room().clone();
-
-        let send = |typing: bool| async move {
-            let connection = connection.borrow().clone();
-
-            if let Some(connection) = connection {
-                let _ = connection.send_typing_notice(room, typing).await;
-            };
-        };
-
-        if input.len() < 4 {
-            // If we have an active typing notice and our input is short, e.g.
-            // we removed the input set the typing notice to false.
-            Weechat::spawn(send(false)).detach();
-        } else if input.len() >= 4 {
-            // If we have some valid input and no active typing notice, send
-            // one out.
-            Weechat::spawn(send(true)).detach();
+        self.print_network(&format!(
+            "{} users are banned from the room, use /unban with their number \
+             to lift a ban:",
+            bans.len()
+        ));
+
+        for (number, ban) in bans.iter().enumerate() {
+            let date = format_timestamp(ban.timestamp, "%Y-%m-%d %H:%M");
+
+            let reason = ban
+                .reason
+                .as_ref()
+                .filter(|r| !r.is_empty())
+                .map(|r| format!(" (reason: {})", r))
+                .unwrap_or_default();
+
+            buffer.print(&format!(
+                "  {}. {}[{}]{} {} by {}{}",
+                number + 1,
+                Weechat::color("chat_delimiters"),
+                date,
+                Weechat::color("reset"),
+                ban.user_id,
+                self.nick_or_user_id(&ban.moderator).await,
+                reason
+            ));
         }
-    }
 
-    pub fn is_busy(&self) -> bool {
-        self.messages_in_flight.locked()
+        *self.bans.borrow_mut() = bans;
     }
 
-    pub fn reset_prev_batch(&self) {
-        // TODO: we'll want to be able to scroll up again after we clear the
-        // buffer.
-        *self.prev_batch.borrow_mut() = None;
-    }
+    /// Print the bridges that were detected in the room, using the bridge
+    /// state events and the namespaces of the user ids of the members.
+    pub async fn print_bridges(&self) {
+        let room = self.room.clone();
 
-    pub async fn get_messages(&self) {
-        let messages_lock = self.messages_in_flight.clone();
+        let result = self
+            .members
+            .runtime
+            .spawn(async move {
+                let mut events = Vec::new();
+
+                for event_type in BRIDGE_EVENT_TYPES {
+                    events.extend(
+                        room.get_state_events(StateEventType::from(
+                            *event_type,
+                        ))
+                        .await?,
+                    );
+                }
 
-        let connection = self.connection.borrow().as_ref().cloned();
+                let members = room.joined_user_ids().await?;
 
-        let prev_batch =
-            if let Some(p) = self.prev_batch.borrow().as_ref().cloned() {
-                p
-            } else {
-                return;
-            };
+                Ok::<_, matrix_sdk::Error>((events, members))
+            })
+            .await;
 
-        let guard = if let Ok(l) = messages_lock.try_lock() {
-            l
-        } else {
-            return;
+        let (events, members) = match result {
+            Ok(Ok(r)) => r,
+            _ => {
+                self.print_error("Error fetching the state of the room");
+                return;
+            }
         };
 
-        Weechat::bar_item_update("buffer_modes");
-        Weechat::bar_item_update("matrix_modes");
+        let mut bridges: Vec<Bridge> = Vec::new();
 
-        if let Some(connection) = connection {
-            let room = self.room().clone();
+        // Bridges that send both event types are only listed once.
+        for bridge in events
+            .iter()
+            .filter_map(|e| e.deserialize_as::<serde_json::Value>().ok())
+            .filter_map(|e| Bridge::from_state_event(&e))
+        {
+            let duplicate = bridges.iter().any(|b| {
+                b.bot == bridge.bot
+                    && b.protocol == bridge.protocol
+                    && b.channel == bridge.channel
+            });
+
+            if !duplicate {
+                bridges.push(bridge);
+            }
+        }
 
-            if let Ok(r) = connection.room_messages(room, prev_batch).await {
-                for event in
-                    r.chunk.iter().filter_map(|e| e.event.deserialize().ok())
-                {
-                    self.handle_room_event(&event).await;
-                }
+        bridges::count_users(&mut bridges, &members);
 
-                let mut prev_batch = self.prev_batch.borrow_mut();
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
 
-                if let Some(PrevBatch::Forward(t)) = prev_batch.as_ref() {
-                    *prev_batch = Some(PrevBatch::Backwards(t.to_owned()));
-                    self.sort_messages();
-                } else if r.chunk.is_empty() {
-                    *prev_batch = None;
-                } else {
-                    *prev_batch = r.end.map(PrevBatch::Backwards);
-                    self.sort_messages();
-                }
-            }
+        if bridges.is_empty() {
+            self.print_network("No bridges were detected in the room");
+            return;
         }
 
-        drop(guard);
+        self.print_network(&format!(
+            "{} bridges were detected in the room:",
+            bridges.len()
+        ));
 
-        Weechat::bar_item_update("buffer_modes");
-        Weechat::bar_item_update("matrix_modes");
+        for (number, bridge) in bridges.iter().enumerate() {
+            buffer.print(&format!("  {}. {}", number + 1, bridge.describe()));
+        }
     }
 
-    fn sort_messages(&self) {
-        struct LineCopy {
-            date: i64,
-            date_printed: i64,
-            tags: Vec<String>,
-            prefix: String,
-            message: String,
+    /// Lift the ban of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The user id of the banned user or their number in the
+    /// last `/matrix bans` listing.
+    ///
+    /// * `reason` - The reason for lifting the ban.
+    pub async fn unban(&self, target: &str, reason: Option<String>) {
+        if self.refuse_read_only() {
+            return;
         }
 
-        impl<'a> From<BufferLine<'a>> for LineCopy {
-            fn from(line: BufferLine) -> Self {
-                Self {
-                    date: line.date(),
-                    date_printed: line.date_printed(),
-                    message: line.message().to_string(),
-                    prefix: line.prefix().to_string(),
-                    tags: line.tags().iter().map(|t| t.to_string()).collect(),
-                }
+        let user_id = if let Ok(number) = target.parse::<usize>() {
+            let user_id = number
+                .checked_sub(1)
+                .and_then(|i| self.bans.borrow().get(i).cloned())
+                .map(|b| b.user_id);
+
+            if let Some(u) = user_id {
+                u
+            } else {
+                self.print_error(&format!(
+                    "No ban with the number {}, use /matrix bans to list the \
+                     bans of the room",
+                    number
+                ));
+                return;
             }
-        }
+        } else if let Ok(u) = UserId::parse(target) {
+            u
+        } else {
+            self.print_error(&format!(
+                "The given user \"{}\" isn't a valid user ID",
+                target
+            ));
+            return;
+        };
 
-        // TODO: update the highlight once Weechat starts supporting it.
-        if let Ok(buffer) = self.buffer_handle().upgrade() {
-            let mut lines: Vec<LineCopy> =
-                buffer.lines().map(|l| l.into()).collect();
-            lines.sort_by_key(|l| l.date);
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
 
-            for (line, new) in buffer.lines().zip(lines.drain(..)) {
-                let tags =
-                    new.tags.iter().map(|t| t.as_str()).collect::<Vec<&str>>();
-                let data = LineData {
-                    prefix: Some(&new.prefix),
-                    message: Some(&new.message),
-                    date: Some(new.date),
-                    date_printed: Some(new.date_printed),
-                    tags: Some(&tags),
-                };
-                line.update(data)
-            }
+        match connection
+            .unban_user(self.room().clone(), user_id.clone(), reason)
+            .await
+        {
+            Ok(()) => self.print_network(&format!("Unbanned {}", user_id)),
+            Err(e) => self.print_error(&format!(
+                "Error unbanning {}: {}",
+                user_id,
+                self.describe_error(&e, RoomAction::Ban)
+            )),
         }
     }
 
-    /// Replace the local echo of an event with a fully rendered one.
-    fn replace_local_echo(
+    /// Get a file of the last `/matrix files` listing by its number.
+    fn listed_file(&self, number: usize) -> Option<Attachment> {
+        let file = number
+            .checked_sub(1)
+            .and_then(|i| self.files.borrow().get(i).cloned())
+            .map(|f| f.attachment);
+
+        if file.is_none() {
+            self.print_error(&format!(
+                "No file with the number {}, use /matrix files to list the \
+                 files of the room",
+                number
+            ));
+        }
+
+        file
+    }
+
+    /// Download the given media and save it to the given path, if the path
+    /// is a directory the media is saved under its own name in it.
+    ///
+    /// Returns the path the media was saved to.
+    async fn save_media(
         &self,
-        transaction_id: &TransactionId,
-        buffer: &Buffer,
-        rendered: RenderedEvent,
-    ) {
-        let uuid_tag =
-            Cow::from(format!("matrix_echo_{}", transaction_id.to_string()));
-        let line_contains_uuid = |l: &BufferLine| l.tags().contains(&uuid_tag);
+        name: &str,
+        source: MediaSource,
+        path: PathBuf,
+    ) -> Result<PathBuf, String> {
+        let data = self
+            .media_content(source)
+            .await
+            .map_err(|e| format!("Error downloading {}: {}", name, e))?;
 
-        let mut lines = buffer.lines();
-        let mut current_line = lines.rfind(line_contains_uuid);
+        let path = if path.is_dir() {
+            path.join(Self::media_file_name(name))
+        } else {
+            path
+        };
 
-        // We go in reverse order here since we also use rfind(). We got from
-        // the bottom of the buffer to the top since we're expecting these
-        // lines to be freshly printed and thus at the bottom.
-        let mut line_num = rendered.content.lines.len();
+        fs::write(&path, data).map_err(|e| {
+            format!("Error saving {} to {}: {}", name, path.display(), e)
+        })?;
 
-        while let Some(line) = &current_line {
-            line_num -= 1;
-            let rendered_line = &rendered.content.lines[line_num];
+        Ok(path)
+    }
 
-            line.set_message(&rendered_line.message);
-            current_line = lines.next_back().filter(line_contains_uuid);
+    /// Download a file of the last `/matrix files` listing to the given
+    /// path, if the path is a directory the file is saved under its own name
+    /// in it.
+    pub async fn download_file(&self, number: usize, path: PathBuf) {
+        let file = if let Some(f) = self.listed_file(number) {
+            f
+        } else {
+            return;
+        };
+
+        match self.save_media(&file.name, file.source, path).await {
+            Ok(path) => self.print_network(&format!(
+                "Saved {} to {}",
+                file.name,
+                path.display()
+            )),
+            Err(e) => self.print_error(&e),
         }
     }
 
-    async fn handle_outgoing_message(
-        &self,
-        transaction_id: &TransactionId,
-        event_id: &EventId,
-    ) {
-        if let Some((echo, content)) =
-            self.outgoing_messages.remove(&transaction_id)
+    /// Open a file of the last `/matrix files` listing using the
+    /// `input.media_opener` command.
+    pub async fn open_file(&self, number: usize) {
+        if let Some(file) = self.listed_file(number) {
+            self.open_media(file.source, file.mimetype.as_deref(), "file")
+                .await;
+        }
+    }
+
+    /// Print a table of the joined members of the room, with their presence,
+    /// typing state, and the time they were last active.
+    ///
+    /// Members that are typing or currently active are listed first.
+    pub async fn print_who(&self) {
+        let room = self.room.clone();
+
+        let members = match self
+            .members
+            .runtime
+            .spawn(async move { room.joined_members_no_sync().await })
+            .await
+            .expect("Fetching the room members from the store panicked")
         {
-            let event = OriginalSyncMessageLikeEvent {
-                sender: (&*self.own_user_id).to_owned(),
-                origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
-                event_id: event_id.to_owned(),
-                content,
-                unsigned: Default::default(),
-            };
+            Ok(m) => m,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error fetching the room members from the store: {}",
+                    e
+                ));
+                return;
+            }
+        };
 
-            let event = AnySyncMessageLikeEvent::RoomMessage(
-                SyncMessageLikeEvent::Original(event),
-            );
+        let typing_users = self.typing_users();
+        let escape = self.config.borrow().look().escape_spoofing_chars();
+
+        let mut rows: Vec<_> = members
+            .iter()
+            .map(|member| {
+                let presence = member.presence().map(|p| &p.content);
+                let typing = typing_users.iter().any(|u| u == member.user_id());
+                let active =
+                    presence.and_then(|p| p.currently_active).unwrap_or(false);
+                let last_active = presence
+                    .and_then(|p| p.last_active_ago)
+                    .map(|ago| Duration::from_millis(ago.into()));
+
+                (
+                    if escape {
+                        escape_spoofing_chars(member.name()).into_owned()
+                    } else {
+                        member.name().to_owned()
+                    },
+                    presence.map_or("unknown".to_owned(), |p| {
+                        p.presence.to_string()
+                    }),
+                    typing,
+                    active,
+                    last_active,
+                )
+            })
+            .collect();
+
+        rows.sort_by_key(|(name, _, typing, active, last_active)| {
+            (
+                !typing,
+                !active,
+                last_active.unwrap_or(Duration::MAX),
+                name.to_lowercase(),
+            )
+        });
 
-            let rendered = self
-                .render_sync_message(&event)
-                .await
-                .expect("Sent out an event that we don't know how to render");
+        let width = rows
+            .iter()
+            .map(|(name, ..)| name.graphemes(true).count())
+            .max()
+            .unwrap_or_default();
 
-            if let Ok(buffer) = self.buffer_handle().upgrade() {
-                if echo {
-                    self.replace_local_echo(&transaction_id, &buffer, rendered);
+        self.print_network(&format!(
+            "{} joined members in the room:",
+            rows.len()
+        ));
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            for (name, presence, typing, active, last_active) in rows {
+                let padding = " ".repeat(width - name.graphemes(true).count());
+
+                let last_active = if active {
+                    "now".to_owned()
                 } else {
-                    self.print_rendered_event(rendered);
-                }
+                    last_active
+                        .map(|d| format!("{} ago", format_duration(d)))
+                        .unwrap_or_else(|| "-".to_owned())
+                };
+
+                buffer.print(&format!(
+                    "  {}{}  {:<11} {:<9} {}",
+                    name,
+                    padding,
+                    presence,
+                    last_active,
+                    if typing { "typing" } else { "" },
+                ));
             }
         }
     }
 
-    fn set_topic(&self) {
-        if let Ok(buffer) = self.buffer_handle().upgrade() {
-            buffer.set_title(&self.room().topic().unwrap_or_default());
-        }
+    /// Get the number of unread messages and highlights in the room.
+    ///
+    /// The unread count is the larger of the number of messages printed since
+    /// the buffer was last visited and the count the server sent us.
+    pub fn activity(&self) -> (u64, u64) {
+        let counts = self.room.unread_notification_counts();
+
+        (
+            self.unread_messages.get().max(counts.notification_count),
+            counts.highlight_count,
+        )
     }
 
-    fn set_alias(&self) {
-        if let Some(alias) = self.alias() {
-            if let Ok(b) = self.buffer_handle().upgrade() {
-                b.set_localvar("alias", alias.as_str());
-            }
-        }
+    /// Reset the locally counted unread messages, e.g. when the buffer is
+    /// visited.
+    pub fn reset_activity(&self) {
+        self.unread_messages.set(0);
+        self.update_localvars();
+        Weechat::bar_item_update("matrix_hotrooms");
     }
 
-    fn update_buffer_name(&self) {
-        self.members.update_buffer_name();
-    }
+    /// Handle a read receipt of one of our other sessions, e.g. the room was
+    /// read on a phone.
+    ///
+    /// The messages up to the event count as read, the buffer is removed
+    /// from the hotlist if no message of somebody else came after it.
+    pub fn handle_own_read_receipt(&self, event_id: &EventId) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
 
-    fn replace_edit(
-        &self,
-        event_id: &EventId,
-        sender: &UserId,
-        event: RenderedEvent,
-    ) {
-        if let Ok(buffer) = self.buffer_handle().upgrade() {
-            let sender_tag = Cow::from(sender.to_tag());
-            let event_id_tag = Cow::from(event_id.to_tag());
+        let receipt_tag = Cow::from(event_id.to_tag());
+        let mut read = false;
+        let mut unread = HashSet::new();
 
-            let lines: Vec<BufferLine> = buffer
-                .lines()
-                .filter(|l| l.tags().contains(&event_id_tag))
-                .collect();
+        for line in buffer.lines() {
+            let tags = line.tags();
 
-            if lines
-                .get(0)
-                .map(|l| l.tags().contains(&sender_tag))
-                .unwrap_or(false)
+            if tags.contains(&receipt_tag) {
+                read = true;
+                unread.clear();
+                continue;
+            }
+
+            if tags
+                .iter()
+                .any(|t| t == "self_msg" || t == "matrix_reply_quote")
+                || !tags.iter().any(|t| MESSAGE_TAGS.contains(&&**t))
+            {
+                continue;
+            }
+
+            if let Some(e) =
+                tags.iter().find_map(|t| t.strip_prefix("matrix_id_"))
             {
-                self.replace_event_helper(&buffer, lines, event);
+                unread.insert(e.to_owned());
             }
         }
+
+        // The event isn't shown in the buffer, so we can't tell which of the
+        // printed messages were read.
+        if !read {
+            return;
+        }
+
+        self.unread_messages.set(unread.len() as u64);
+        self.update_localvars();
+
+        if unread.is_empty() {
+            let _ = buffer.run_command("/mute /buffer set hotlist -1");
+        }
+
+        Weechat::bar_item_update("matrix_activity");
+        Weechat::bar_item_update("matrix_hotrooms");
     }
 
-    fn replace_event_helper(
-        &self,
-        buffer: &Buffer,
-        lines: Vec<BufferLine<'_>>,
-        event: RenderedEvent,
-    ) {
-        use std::cmp::Ordering;
-        let date = lines.get(0).map(|l| l.date()).unwrap_or_default();
+    /// Mark the room as read up to the last event that is printed in the
+    /// buffer, this moves our read receipt as well as the fully read marker.
+    pub async fn mark_read(&self) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
 
-        for (line, new) in lines.iter().zip(event.content.lines.iter()) {
-            let tags: Vec<&str> = new.tags.iter().map(|t| t.as_str()).collect();
-            let data = LineData {
-                // Our prefixes always come with a \t character, but when we
-                // replace stuff we're able to replace the prefix and the
-                // message separately, so trim the whitespace in the prefix.
-                prefix: Some(event.prefix.trim_end()),
-                message: Some(&new.message),
-                tags: Some(&tags),
-                ..Default::default()
-            };
+        let event_id = if let Some(e) = self.last_event_id() {
+            e
+        } else {
+            return;
+        };
 
-            line.update(data);
+        // Read-only servers only clear the activity of the room locally.
+        if self.read_only() {
+            self.reset_activity();
+            Weechat::bar_item_update("matrix_activity");
+            return;
         }
 
-        match lines.len().cmp(&event.content.lines.len()) {
-            Ordering::Greater => {
-                for line in &lines[event.content.lines.len()..] {
-                    line.set_message("");
-                }
-            }
-            Ordering::Less => {
-                for line in &event.content.lines[lines.len()..] {
-                    let message = format!("{}{}", &event.prefix, &line.message);
-                    let tags: Vec<&str> =
-                        line.tags.iter().map(|t| t.as_str()).collect();
-                    buffer.print_date_tags(date, &tags, &message)
-                }
+        let private = matches!(
+            self.config.borrow().look().send_read_receipts(),
+            ReadReceipts::Private
+        );
 
-                self.sort_messages()
-            }
-            Ordering::Equal => (),
+        if let Err(e) = connection
+            .mark_read(self.room().clone(), event_id.clone(), private)
+            .await
+        {
+            self.print_error(&format!("Error marking the room as read: {}", e));
+            return;
         }
+
+        *self.last_read_receipt.borrow_mut() = Some(event_id);
+        self.reset_activity();
+        Weechat::bar_item_update("matrix_activity");
     }
 
-    async fn handle_edits(&self, event: &AnySyncMessageLikeEvent) {
-        // TODO: remove this expect.
-        let sender =
-            self.members.get(event.sender()).await.expect(
-                "Rendering a message but the sender isn't in the nicklist",
-            );
+    /// Move the fully read marker of the account to the last event that is
+    /// printed in the buffer, used when the buffer is marked as read inside
+    /// of WeeChat, this way other clients agree on what's unread.
+    ///
+    /// Nothing is sent if the marker already points to the last event.
+    pub fn mark_fully_read(&self) {
+        let connection = match self.connection.borrow().clone() {
+            Some(c) if !self.read_only() => c,
+            _ => return,
+        };
 
-        if let Some((event_id, content)) = event.get_edit() {
-            let send_time = event.origin_server_ts();
+        let event_id = match self.last_event_id() {
+            Some(e) if self.fully_read_event.borrow().as_ref() != Some(&e) => e,
+            _ => return,
+        };
 
-            if let Some(rendered) = self
-                .render_message_content(
-                    event_id,
-                    send_time,
-                    &sender,
-                    &AnyMessageLikeEventContent::RoomMessage(content.clone()),
-                )
+        *self.fully_read_event.borrow_mut() = Some(event_id.clone());
+
+        let room = self.clone();
+
+        Weechat::spawn(async move {
+            if let Err(e) = connection
+                .set_fully_read(room.room().clone(), event_id)
                 .await
-                .map(|r| {
-                    // TODO: the tags are different if the room is a DM.
-                    if sender.user_id() == &*self.own_user_id {
-                        r.add_self_tags()
-                    } else {
-                        r.add_msg_tags()
-                    }
-                })
             {
-                self.replace_edit(event_id, event.sender(), rendered);
+                room.fully_read_event.borrow_mut().take();
+                room.print_error(&format!(
+                    "Error moving the fully read marker: {}",
+                    e
+                ));
             }
-        }
+        })
+        .detach();
     }
 
-    async fn handle_room_message(&self, event: &AnySyncMessageLikeEvent) {
-        // If the event has a transaction id it's an event that we sent out
-        // ourselves, the content will be in the outgoing message queue and it
-        // may have been printed out as a local echo.
-        if let Some(id) = event.transaction_id() {
-            self.handle_outgoing_message(id, event.event_id()).await;
+    /// Mark the room as read if the `network.auto_mark_read` option is
+    /// enabled.
+    ///
+    /// Nothing is done while we're disconnected, e.g. when the buffers get
+    /// closed because the plugin is unloading.
+    pub fn auto_mark_read(&self) {
+        if !self.config.borrow().network().auto_mark_read()
+            || self.connection.borrow().is_none()
+        {
             return;
         }
 
-        if let AnySyncMessageLikeEvent::RoomRedaction(r) = event {
-            self.redact_event(r).await;
-        } else if event.is_edit() {
-            self.handle_edits(event).await;
-        } else if let Some(rendered) = self.render_sync_message(event).await {
-            self.print_rendered_event(rendered);
+        let room = self.clone();
+        Weechat::spawn(async move { room.mark_read().await }).detach();
+    }
+
+    /// Send a read receipt for the last event that is printed in the buffer
+    /// if the buffer is the current one and the `look.send_read_receipts`
+    /// option is enabled.
+    ///
+    /// Nothing is sent if the receipt wouldn't move, this way switching back
+    /// and forth between buffers doesn't spam the server.
+    pub fn send_read_receipt(&self) {
+        let private = match self.config.borrow().look().send_read_receipts() {
+            ReadReceipts::Off => return,
+            ReadReceipts::Public => false,
+            ReadReceipts::Private => true,
+        };
+
+        let connection = match self.connection.borrow().clone() {
+            Some(c) if !self.read_only() => c,
+            _ => return,
+        };
+
+        match self.buffer_handle().upgrade() {
+            Ok(b) if b == Weechat::current_buffer() => (),
+            _ => return,
         }
+
+        let event_id = match self.last_event_id() {
+            Some(e) if self.last_read_receipt.borrow().as_ref() != Some(&e) => {
+                e
+            }
+            _ => return,
+        };
+
+        *self.last_read_receipt.borrow_mut() = Some(event_id.clone());
+
+        let room = self.clone();
+
+        Weechat::spawn(async move {
+            if let Err(e) = connection
+                .mark_read(room.room().clone(), event_id, private)
+                .await
+            {
+                error!(
+                    "Error sending a read receipt for {}: {}",
+                    room.room_id(),
+                    e
+                );
+                *room.last_read_receipt.borrow_mut() = None;
+            }
+        })
+        .detach();
     }
 
     async fn render_redacted_event(
@@ -1054,10 +7474,16 @@ impl MatrixRoom {
             SyncMessageLikeEvent::Redacted(e),
         ) = event
         {
-            let redacter = e.unsigned.redacted_because.as_ref()?.sender();
-            let redacter = self.members.get(redacter).await?;
             let sender = self.members.get(&e.sender).await?;
 
+            // The redaction event should always be part of the unsigned data,
+            // but servers don't always include it, render the event with an
+            // unknown redacter in that case.
+            let redacter = match e.unsigned.redacted_because.as_ref() {
+                Some(redaction) => self.members.get(redaction.sender()).await,
+                None => None,
+            };
+
             Some(e.render_with_prefix(
                 e.origin_server_ts,
                 event.event_id(),
@@ -1077,7 +7503,77 @@ impl MatrixRoom {
     ) {
         self.members
             .handle_membership_event(event, state_event, ambiguity_change)
-            .await
+            .await;
+
+        if self.is_encrypted() {
+            self.reset_unverified_device_count();
+        }
+
+        self.update_localvars();
+
+        if let SyncStateEvent::Original(e) = event {
+            if e.state_key.as_str() != self.own_user_id.as_str() {
+                return;
+            }
+
+            if let MembershipChange::ProfileChanged {
+                displayname_change,
+                avatar_url_change,
+            } = e.membership_change()
+            {
+                self.handle_own_profile_change(
+                    displayname_change.is_some(),
+                    avatar_url_change.is_some(),
+                    !state_event,
+                )
+                .await;
+            }
+        }
+    }
+
+    /// Keep our own nick in the buffer up to date after our profile changed,
+    /// possibly from another session.
+    async fn handle_own_profile_change(
+        &self,
+        displayname_changed: bool,
+        avatar_changed: bool,
+        print_notice: bool,
+    ) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        if displayname_changed {
+            let me = if let Some(m) = self.members.get(&self.own_user_id).await
+            {
+                m
+            } else {
+                return;
+            };
+
+            let nick = me.nick();
+            buffer.set_localvar("nick", &nick);
+
+            // Local echoes that are still waiting for the server to confirm
+            // them were rendered with the old nick.
+            let prefix = me.nick_colored();
+
+            for line in buffer.lines().filter(|l| {
+                l.tags().iter().any(|t| t.starts_with("matrix_echo_"))
+            }) {
+                line.set_prefix(&prefix);
+            }
+
+            if print_notice {
+                self.print_network(&format!("You are now known as {}", nick));
+            }
+        }
+
+        if avatar_changed && print_notice {
+            self.print_network("Your avatar was changed");
+        }
     }
 
     fn set_prev_batch(&self) {
@@ -1089,12 +7585,20 @@ impl MatrixRoom {
         }
     }
 
-    pub async fn handle_sync_room_event(&self, event: AnySyncTimelineEvent) {
+    pub async fn handle_sync_room_event(
+        &self,
+        event: AnySyncTimelineEvent,
+        mentions: Option<Mentions>,
+        push: Option<PushOutcome>,
+    ) {
         self.set_prev_batch();
 
         match &event {
             AnySyncTimelineEvent::MessageLike(message) => {
-                self.handle_room_message(message).await
+                if self.is_event_type_shown(&message.event_type().to_string()) {
+                    self.handle_room_message(message, mentions.as_ref(), push)
+                        .await
+                }
             }
             AnySyncTimelineEvent::State(event) => {
                 self.handle_sync_state_event(event, false).await
@@ -1102,35 +7606,145 @@ impl MatrixRoom {
         }
     }
 
+    /// Handle an event of a type the plugin doesn't know about.
+    ///
+    /// Scripts get notified about the event, and the event gets printed if
+    /// one of the registered event hooks knows how to render it.
+    pub async fn handle_custom_event(
+        &self,
+        event: &Raw<AnySyncTimelineEvent>,
+        hooks: &EventHooks,
+    ) {
+        let value: serde_json::Value =
+            if let Ok(v) = serde_json::from_str(event.json().get()) {
+                v
+            } else {
+                return;
+            };
+
+        if value["type"] == RETENTION_EVENT_TYPE {
+            self.update_retention();
+        }
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            EventHooks::send_signal(
+                &buffer.full_name(),
+                self.room_id.as_str(),
+                &value,
+            );
+        }
+
+        let (event_id, sender, timestamp) = match (
+            event.get_field::<OwnedEventId>("event_id"),
+            event.get_field::<OwnedUserId>("sender"),
+            event.get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts"),
+        ) {
+            (Ok(Some(e)), Ok(Some(s)), Ok(Some(t))) => (e, s, t),
+            _ => return,
+        };
+
+        let sender = if let Some(s) = self.members.get(&sender).await {
+            s
+        } else {
+            return;
+        };
+
+        if let Some(custom) = hooks.render(&sender, &value) {
+            let rendered =
+                custom.render_with_prefix(timestamp, &event_id, &sender, &());
+
+            // Fallback lines are already tagged to not notify.
+            let rendered = if custom.is_fallback() {
+                rendered
+            } else if sender.user_id() == &*self.own_user_id {
+                rendered.add_self_tags()
+            } else {
+                rendered.add_msg_tags()
+            };
+
+            self.print_rendered_event(rendered);
+        }
+    }
+
     pub async fn handle_room_event(&self, event: &AnyTimelineEvent) {
         match &event {
             AnyTimelineEvent::MessageLike(event) => {
-                // TODO: Only print out historical events if they aren't edits of
-                // other events.
-                if !event.is_edit() {
+                let sync_event: AnySyncMessageLikeEvent = event.clone().into();
+
+                // Relations are remembered until the event they relate to
+                // gets printed.
+                match &sync_event {
+                    AnySyncMessageLikeEvent::Reaction(
+                        SyncMessageLikeEvent::Original(e),
+                    ) => {
+                        self.handle_reaction(e);
+                        return;
+                    }
+                    AnySyncMessageLikeEvent::RoomRedaction(r) => {
+                        if let SyncRoomRedactionEvent::Original(e) = r {
+                            if !self.remove_reaction(&e.redacts) {
+                                self.redact_event(r).await;
+                            }
+                        }
+                        return;
+                    }
+                    e if e.is_edit() => {
+                        self.handle_edits(e).await;
+                        return;
+                    }
+                    _ => (),
+                }
+
+                if let Some(root) = Self::thread_root(event.original_content())
+                {
+                    self.add_thread_reply(
+                        &root,
+                        event.event_id(),
+                        event.origin_server_ts(),
+                    );
+
+                    if !self.config.borrow().look().show_thread_replies() {
+                        return;
+                    }
+                }
+
+                if self.is_event_type_shown(&event.event_type().to_string())
+                    && !self.is_content_ignored(event.original_content())
+                {
                     let sender = self.members.get(event.sender()).await.expect(
                     "Rendering a message but the sender isn't in the nicklist",
                 );
 
-                    let content =
+                    let send_time = event.origin_server_ts();
+
+                    let rendered =
                         if let Some(content) = event.original_content() {
-                            content
+                            self.render_message_content(
+                                event.event_id(),
+                                send_time,
+                                &sender,
+                                &content,
+                            )
+                            .await
                         } else {
-                            todo!("Do we just skip redacted events here?")
+                            let event: AnySyncMessageLikeEvent =
+                                event.clone().into();
+                            self.render_redacted_event(&event).await
                         };
 
-                    let send_time = event.origin_server_ts();
+                    let pills = Self::pills_of(event.original_content());
 
-                    if let Some(rendered) = self
-                        .render_message_content(
-                            event.event_id(),
-                            send_time,
-                            &sender,
-                            &content,
-                        )
-                        .await
-                    {
-                        self.print_rendered_event(rendered);
+                    if let Some(rendered) = rendered {
+                        let rendered = self.color_pills(rendered, &pills).await;
+                        let format =
+                            self.config.borrow().look().backfill_time_format();
+                        self.print_rendered_event(rendered.add_date(&format));
+
+                        // Backfilling goes back in time, the edits,
+                        // reactions and thread replies of a message are
+                        // handled before the message, so they can only be
+                        // applied now.
+                        self.apply_pending_relations(event.event_id()).await;
                     }
                 }
             }
@@ -1143,15 +7757,217 @@ impl MatrixRoom {
         &self.room
     }
 
+    /// Print a summary line for a redacted state event that is part of the
+    /// timeline, the content of such events is gone so there isn't anything
+    /// else to render.
+    async fn print_redacted_state_event(&self, event: &AnySyncStateEvent) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let sender = self
+            .members
+            .get(event.sender())
+            .await
+            .map(|s| s.nick())
+            .unwrap_or_else(|| event.sender().to_string());
+
+        let timestamp: i64 = (event.origin_server_ts().0 / uint!(1000)).into();
+        let event_tag = event.event_id().to_tag();
+        let tags = ["matrix_redacted", "no_highlight", event_tag.as_str()];
+
+        self.members.flush_lines();
+
+        buffer.print_date_tags(
+            timestamp,
+            &tags,
+            &format!(
+                "{}{}<{}A {} state event sent by {} was redacted{}>{}",
+                Weechat::prefix(Prefix::Network),
+                Weechat::color("chat_delimiters"),
+                Weechat::color("logger.color.backlog_line"),
+                event.event_type(),
+                sender,
+                Weechat::color("chat_delimiters"),
+                Weechat::color("reset"),
+            ),
+        );
+    }
+
+    /// Print a notice for a change of the room state, e.g. a new topic or
+    /// power level.
+    async fn print_state_change(&self, event: &AnySyncStateEvent) {
+        let description = if let Some(d) = render_state_change(event) {
+            d
+        } else {
+            return;
+        };
+
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let sender = self
+            .members
+            .get(event.sender())
+            .await
+            .map(|s| s.nick())
+            .unwrap_or_else(|| event.sender().to_string());
+
+        let timestamp: i64 = (event.origin_server_ts().0 / uint!(1000)).into();
+        let event_tag = event.event_id().to_tag();
+        let tags = ["matrix_state_change", "no_highlight", event_tag.as_str()];
+
+        self.members.flush_lines();
+
+        buffer.print_date_tags(
+            timestamp,
+            &tags,
+            &format!(
+                "{}{} {}",
+                Weechat::prefix(Prefix::Network),
+                sender,
+                description
+            ),
+        );
+    }
+
+    /// Print a banner summarizing the creation event of the room.
+    ///
+    /// The creation event is only part of a sync response when we join the
+    /// room, the banner uses the creation time so it ends up at the top of
+    /// the buffer.
+    async fn print_create_banner(
+        &self,
+        event: &OriginalSyncStateEvent<RoomCreateEventContent>,
+    ) {
+        const BANNER_TAG: &str = "matrix_room_create";
+
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        if buffer
+            .lines()
+            .any(|l| l.tags().iter().any(|t| t == BANNER_TAG))
+        {
+            return;
+        }
+
+        let content = &event.content;
+
+        let creator = self
+            .members
+            .get(&content.creator)
+            .await
+            .map(|m| m.nick())
+            .unwrap_or_else(|| content.creator.to_string());
+
+        let mut banner = format!(
+            "Room created by {}, room version {}",
+            creator, content.room_version
+        );
+
+        if !content.federate {
+            banner.push_str(", federation is disabled");
+        }
+
+        if let Some(predecessor) = &content.predecessor {
+            banner.push_str(&format!(
+                ", it replaces the room {}, use /matrix predecessor to open it",
+                predecessor.room_id
+            ));
+        }
+
+        let timestamp: i64 = (event.origin_server_ts.0 / uint!(1000)).into();
+        let event_tag = event.event_id.to_tag();
+
+        buffer.print_date_tags(
+            timestamp,
+            &[
+                BANNER_TAG,
+                "no_highlight",
+                "notify_none",
+                event_tag.as_str(),
+            ],
+            &format!("{}{}", Weechat::prefix(Prefix::Network), banner),
+        );
+        self.sort_messages();
+    }
+
+    /// Get the room this room replaces, and the servers that can be used to
+    /// join it.
+    pub fn predecessor(&self) -> Option<(OwnedRoomId, Vec<OwnedServerName>)> {
+        let content = self.room().create_content()?;
+        let predecessor = content.predecessor?;
+
+        let mut servers = vec![content.creator.server_name().to_owned()];
+        let room_server = predecessor.room_id.server_name().to_owned();
+
+        if !servers.contains(&room_server) {
+            servers.push(room_server);
+        }
+
+        Some((predecessor.room_id, servers))
+    }
+
     pub async fn handle_sync_state_event(
         &self,
         event: &AnySyncStateEvent,
-        _state_event: bool,
+        state_event: bool,
     ) {
+        if !state_event && event.original_content().is_none() {
+            self.print_redacted_state_event(event).await;
+        }
+
+        if !state_event {
+            self.print_state_change(event).await;
+        }
+
+        if event.event_type().to_string() == RETENTION_EVENT_TYPE {
+            self.update_retention();
+        }
+
+        if self.reconcile_state_change(event) {
+            return;
+        }
+
         match event {
-            AnySyncStateEvent::RoomName(_) => self.update_buffer_name(),
+            AnySyncStateEvent::RoomName(_) => {
+                self.handle_name_change(!state_event, true)
+            }
+            AnySyncStateEvent::RoomCanonicalAlias(_) => {
+                self.handle_name_change(!state_event, false)
+            }
             AnySyncStateEvent::RoomTopic(_) => self.set_topic(),
-            AnySyncStateEvent::RoomCanonicalAlias(_) => self.set_alias(),
+            AnySyncStateEvent::RoomEncryption(_) => {
+                self.update_outbox_persistence();
+                self.update_localvars();
+            }
+            AnySyncStateEvent::RoomPowerLevels(SyncStateEvent::Original(e)) => {
+                self.members.handle_power_levels(
+                    &e.content,
+                    e.unsigned.prev_content.as_ref(),
+                )
+            }
+            AnySyncStateEvent::RoomMember(SyncStateEvent::Original(e))
+                if matches!(
+                    e.content.membership,
+                    MembershipState::Leave | MembershipState::Ban
+                ) =>
+            {
+                self.outbound_session.borrow_mut().discard()
+            }
+            AnySyncStateEvent::SpaceParent(_) => self.update_space_localvar(),
+            AnySyncStateEvent::RoomCreate(SyncStateEvent::Original(e)) => {
+                self.print_create_banner(e).await
+            }
             _ => (),
         }
     }
@@ -0,0 +1,102 @@
+//! Tracking of the outbound group session of encrypted rooms.
+//!
+//! Messages sent to an encrypted room are encrypted with an outbound Megolm
+//! session. The SDK creates the session with the rotation settings of the
+//! `m.room.encryption` state event of the room and replaces it once it's
+//! older than `rotation_period_ms`, once it encrypted `rotation_period_msgs`
+//! messages, or once a member left the room.
+//!
+//! The SDK doesn't expose its sessions, the session is instead tracked by
+//! applying the same rules to the messages we send. The tracked session is
+//! only accurate for the messages this WeeChat instance sent since it was
+//! started.
+
+use std::time::{Duration, SystemTime};
+
+use matrix_sdk::ruma::events::room::encryption::RoomEncryptionEventContent;
+
+/// The rotation period the SDK uses if the room doesn't specify one.
+const DEFAULT_ROTATION_PERIOD: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The number of messages after which the SDK rotates the session if the
+/// room doesn't specify one.
+const DEFAULT_ROTATION_MESSAGES: u64 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RotationSettings {
+    /// The maximal age of a session.
+    pub period: Duration,
+    /// The maximal number of messages a session encrypts.
+    pub messages: u64,
+}
+
+impl RotationSettings {
+    /// Get the rotation settings from the content of the encryption state
+    /// event of a room.
+    pub fn new(content: Option<&RoomEncryptionEventContent>) -> Self {
+        Self {
+            period: content
+                .and_then(|c| c.rotation_period_ms)
+                .map_or(DEFAULT_ROTATION_PERIOD, |p| {
+                    Duration::from_millis(p.into())
+                }),
+            messages: content
+                .and_then(|c| c.rotation_period_msgs)
+                .map_or(DEFAULT_ROTATION_MESSAGES, Into::into),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct OutboundSession {
+    /// When the session was created.
+    pub created: SystemTime,
+    /// The number of messages the session encrypted.
+    pub messages: u64,
+}
+
+impl OutboundSession {
+    /// The age of the session.
+    pub fn age(&self) -> Duration {
+        self.created.elapsed().unwrap_or_default()
+    }
+
+    fn expired(&self, settings: RotationSettings) -> bool {
+        self.messages >= settings.messages || self.age() >= settings.period
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct OutboundSessionTracker {
+    session: Option<OutboundSession>,
+}
+
+impl OutboundSessionTracker {
+    /// Record that a message was encrypted and sent, a new session is started
+    /// if the current one should have been rotated.
+    pub fn message_sent(&mut self, settings: RotationSettings) {
+        let mut session =
+            self.current(settings).unwrap_or_else(|| OutboundSession {
+                created: SystemTime::now(),
+                messages: 0,
+            });
+
+        session.messages += 1;
+        self.session = Some(session);
+    }
+
+    /// Forget the current session, the SDK discards it e.g. if a member left
+    /// the room.
+    pub fn discard(&mut self) {
+        self.session = None;
+    }
+
+    /// Get the session that will be used to encrypt the next message, `None`
+    /// if a new session will be created.
+    pub fn current(
+        &self,
+        settings: RotationSettings,
+    ) -> Option<OutboundSession> {
+        self.session.filter(|s| !s.expired(settings))
+    }
+}
@@ -0,0 +1,76 @@
+//! Per room settings of the plugin.
+//!
+//! The settings are stored in the room account data of the user, this way
+//! they are shared between all the WeeChat instances of the user and survive
+//! a wipe of the local store.
+
+use serde_json::{json, Value};
+
+/// The event type of the room account data holding the settings.
+pub const ROOM_SETTINGS_EVENT_TYPE: &str = "io.weechat.matrix.settings";
+
+/// The notify level of a room buffer, mirrors the WeeChat buffer notify
+/// levels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotifyLevel {
+    None,
+    Highlight,
+    Message,
+    All,
+}
+
+impl NotifyLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotifyLevel::None => "none",
+            NotifyLevel::Highlight => "highlight",
+            NotifyLevel::Message => "message",
+            NotifyLevel::All => "all",
+        }
+    }
+
+    pub fn parse(level: &str) -> Option<Self> {
+        match level {
+            "none" => Some(NotifyLevel::None),
+            "highlight" => Some(NotifyLevel::Highlight),
+            "message" => Some(NotifyLevel::Message),
+            "all" => Some(NotifyLevel::All),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoomSettings {
+    /// The notify level of the buffer, `None` uses the WeeChat default.
+    pub notify: Option<NotifyLevel>,
+    /// A name that is used instead of the calculated room name.
+    pub name: Option<String>,
+    /// Is the buffer hidden.
+    pub hidden: bool,
+}
+
+impl RoomSettings {
+    /// Parse the settings from the content of the room account data event.
+    ///
+    /// Unknown or invalid fields are ignored.
+    pub fn from_json(content: &Value) -> Self {
+        Self {
+            notify: content["notify"].as_str().and_then(NotifyLevel::parse),
+            name: content["name"]
+                .as_str()
+                .filter(|n| !n.is_empty())
+                .map(ToOwned::to_owned),
+            hidden: content["hidden"].as_bool().unwrap_or_default(),
+        }
+    }
+
+    /// Convert the settings to the content of the room account data event.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "notify": self.notify.map(|n| n.as_str()),
+            "name": self.name,
+            "hidden": self.hidden,
+        })
+    }
+}
@@ -0,0 +1,235 @@
+//! Statistics about the messages printed in a room buffer.
+//!
+//! The statistics are computed from the lines of the buffer, so they only
+//! cover the history that was fetched and rendered, `/matrix more` can be
+//! used to fetch more of it first.
+
+use std::collections::{HashMap, HashSet};
+
+/// The number of members and hours the statistics list.
+const TOP_COUNT: usize = 5;
+
+/// The tags of the lines that show messages.
+pub const MESSAGE_TAGS: &[&str] = &[
+    "matrix_text",
+    "matrix_emote",
+    "matrix_notice",
+    "matrix_media",
+    "matrix_location",
+];
+
+/// The tag of the lines that show attachments.
+pub const ATTACHMENT_TAG: &str = "matrix_media";
+
+#[derive(Debug, Default)]
+struct MemberStats {
+    /// The nick the member had on their latest message.
+    nick: String,
+    messages: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct RoomStats {
+    events: HashSet<String>,
+    members: HashMap<String, MemberStats>,
+    hours: [usize; 24],
+    attachments: usize,
+}
+
+impl RoomStats {
+    /// Count a message, messages that span multiple lines are only counted
+    /// once.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event of the message.
+    ///
+    /// * `sender` - The user id of the sender.
+    ///
+    /// * `nick` - The nick the sender had when the message was printed.
+    ///
+    /// * `hour` - The local hour of the day the message was sent at, if it's
+    /// known.
+    ///
+    /// * `attachment` - Is the message an attachment.
+    pub fn add(
+        &mut self,
+        event_id: &str,
+        sender: &str,
+        nick: &str,
+        hour: Option<u32>,
+        attachment: bool,
+    ) {
+        if !self.events.insert(event_id.to_owned()) {
+            return;
+        }
+
+        let member = self.members.entry(sender.to_owned()).or_default();
+        member.nick = nick.to_owned();
+        member.messages += 1;
+
+        if let Some(hour) = hour {
+            self.hours[hour as usize % 24] += 1;
+        }
+
+        if attachment {
+            self.attachments += 1;
+        }
+    }
+
+    /// The number of counted messages.
+    pub fn messages(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Format the statistics as a table, one line per row.
+    pub fn format_table(&self) -> Vec<String> {
+        let total = self.messages().max(1);
+
+        let mut members: Vec<&MemberStats> = self.members.values().collect();
+        members.sort_by(|a, b| {
+            b.messages
+                .cmp(&a.messages)
+                .then_with(|| a.nick.cmp(&b.nick))
+        });
+
+        let mut hours: Vec<(usize, usize)> = self
+            .hours
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        hours.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let others = if members.len() > TOP_COUNT {
+            let messages: usize =
+                members.iter().skip(TOP_COUNT).map(|m| m.messages).sum();
+
+            Some((format!("({} others)", members.len() - TOP_COUNT), messages))
+        } else {
+            None
+        };
+
+        let width = members
+            .iter()
+            .take(TOP_COUNT)
+            .map(|m| m.nick.chars().count())
+            .chain(others.iter().map(|(label, _)| label.len()))
+            .max()
+            .unwrap_or(0)
+            .max("Member".len());
+
+        let mut lines = vec![format!(
+            "{:<width$}  {:>8}  {:>5}",
+            "Member",
+            "Messages",
+            "Share",
+            width = width
+        )];
+
+        for member in members.iter().take(TOP_COUNT) {
+            lines.push(format!(
+                "{:<width$}  {:>8}  {:>4}%",
+                member.nick,
+                member.messages,
+                member.messages * 100 / total,
+                width = width
+            ));
+        }
+
+        if let Some((label, messages)) = others {
+            lines.push(format!(
+                "{:<width$}  {:>8}  {:>4}%",
+                label,
+                messages,
+                messages * 100 / total,
+                width = width
+            ));
+        }
+
+        let hours = hours
+            .iter()
+            .take(TOP_COUNT)
+            .map(|(hour, count)| format!("{:02}:00 ({})", hour, count))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        lines.push(format!("Most active hours: {}", hours));
+        lines.push(format!("Attachments: {}", self.attachments));
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn messages_are_counted_once() {
+        let mut stats = RoomStats::default();
+
+        stats.add("$1", "@alice:example.org", "alice", Some(10), false);
+        stats.add("$1", "@alice:example.org", "alice", Some(10), false);
+        stats.add("$2", "@bob:example.org", "bob", None, true);
+
+        assert_eq!(stats.messages(), 2);
+        assert_eq!(stats.hours[10], 1);
+        assert_eq!(stats.hours.iter().sum::<usize>(), 1);
+        assert_eq!(stats.attachments, 1);
+    }
+
+    #[test]
+    fn latest_nick_is_used() {
+        let mut stats = RoomStats::default();
+
+        stats.add("$1", "@alice:example.org", "alice", Some(1), false);
+        stats.add("$2", "@alice:example.org", "Alice", Some(1), false);
+
+        let table = stats.format_table();
+
+        assert_eq!(table[1], "Alice          2   100%");
+    }
+
+    #[test]
+    fn table_is_sorted_by_message_count() {
+        let mut stats = RoomStats::default();
+
+        stats.add("$1", "@alice:example.org", "alice", Some(9), false);
+        stats.add("$2", "@bob:example.org", "bob", Some(9), false);
+        stats.add("$3", "@bob:example.org", "bob", Some(23), true);
+        stats.add("$4", "@bob:example.org", "bob", Some(23), false);
+
+        assert_eq!(
+            stats.format_table(),
+            vec![
+                "Member  Messages  Share",
+                "bob            3    75%",
+                "alice          1    25%",
+                "Most active hours: 09:00 (2), 23:00 (2)",
+                "Attachments: 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn other_members_are_summarized() {
+        let mut stats = RoomStats::default();
+
+        for i in 0..TOP_COUNT + 2 {
+            stats.add(
+                &format!("${}", i),
+                &format!("@user{}:example.org", i),
+                &format!("user{}", i),
+                Some(0),
+                false,
+            );
+        }
+
+        let table = stats.format_table();
+
+        assert_eq!(table.len(), TOP_COUNT + 4);
+        assert_eq!(table[TOP_COUNT + 1], "(2 others)         2    28%");
+    }
+}
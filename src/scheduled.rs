@@ -0,0 +1,169 @@
+//! Scheduled messages.
+//!
+//! Messages can be scheduled to be sent at a later point in time using
+//! `/matrix send-at` or `/matrix send-in`. The messages are stored in the
+//! server directory so restarts don't lose them, messages whose time passed
+//! while WeeChat wasn't running get sent once we're connected again. The file
+//! contains the text of the messages, so only our own user can read it.
+
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use serde_json::{json, Value};
+use tracing::error;
+
+use crate::utils::write_private_file;
+
+#[derive(Clone, Debug)]
+pub struct ScheduledMessage {
+    /// The number that identifies the message in the list of scheduled
+    /// messages.
+    pub id: u64,
+    pub room_id: OwnedRoomId,
+    pub send_at: SystemTime,
+    pub text: String,
+}
+
+impl ScheduledMessage {
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "room_id": self.room_id,
+            "send_at": self
+                .send_at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            "text": self.text,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(Self {
+            id: value["id"].as_u64()?,
+            room_id: RoomId::parse(value["room_id"].as_str()?).ok()?,
+            send_at: UNIX_EPOCH
+                + Duration::from_secs(value["send_at"].as_u64()?),
+            text: value["text"].as_str()?.to_owned(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ScheduledMessages {
+    path: PathBuf,
+    messages: Vec<ScheduledMessage>,
+}
+
+impl ScheduledMessages {
+    /// Load the scheduled messages from the given file.
+    ///
+    /// A missing or invalid file results in no scheduled messages.
+    pub fn load(path: PathBuf) -> Self {
+        let value: Value = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|c| serde_json::from_str(&c).ok())
+            .unwrap_or_default();
+
+        let messages = value
+            .as_array()
+            .map(|m| m.iter().filter_map(ScheduledMessage::from_json).collect())
+            .unwrap_or_default();
+
+        Self { path, messages }
+    }
+
+    fn save(&self) {
+        let value: Vec<Value> = self
+            .messages
+            .iter()
+            .map(ScheduledMessage::to_json)
+            .collect();
+
+        let ret = write_private_file(
+            &self.path,
+            Value::from(value).to_string().as_bytes(),
+        );
+
+        if let Err(e) = ret {
+            error!("Error saving the scheduled messages: {}", e);
+        }
+    }
+
+    /// Schedule a message to be sent to the given room at the given time.
+    ///
+    /// Returns the id of the scheduled message.
+    pub fn add(
+        &mut self,
+        room_id: &RoomId,
+        send_at: SystemTime,
+        text: String,
+    ) -> u64 {
+        let id =
+            self.messages.iter().map(|m| m.id).max().unwrap_or_default() + 1;
+
+        self.messages.push(ScheduledMessage {
+            id,
+            room_id: room_id.to_owned(),
+            send_at,
+            text,
+        });
+        self.save();
+
+        id
+    }
+
+    /// Cancel the scheduled message with the given id.
+    pub fn cancel(&mut self, id: u64) -> Option<ScheduledMessage> {
+        let index = self.messages.iter().position(|m| m.id == id)?;
+        let message = self.messages.remove(index);
+        self.save();
+
+        Some(message)
+    }
+
+    /// Get all the scheduled messages, sorted by the time they'll be sent.
+    pub fn messages(&self) -> Vec<ScheduledMessage> {
+        let mut messages = self.messages.clone();
+        messages.sort_by_key(|m| m.send_at);
+
+        messages
+    }
+
+    /// Remove and return the messages which are due and can be sent.
+    ///
+    /// The given closure decides if a message can be sent right now, messages
+    /// that can't be sent stay scheduled.
+    pub fn take_due(
+        &mut self,
+        can_send: impl Fn(&ScheduledMessage) -> bool,
+    ) -> Vec<ScheduledMessage> {
+        let now = SystemTime::now();
+
+        let (due, pending): (Vec<_>, Vec<_>) = self
+            .messages
+            .drain(..)
+            .partition(|m| m.send_at <= now && can_send(m));
+
+        self.messages = pending;
+
+        if !due.is_empty() {
+            self.save();
+        }
+
+        due
+    }
+
+    /// The time that is left until each of the scheduled messages is due.
+    pub fn remaining(&self) -> Vec<Duration> {
+        let now = SystemTime::now();
+
+        self.messages
+            .iter()
+            .map(|m| m.send_at.duration_since(now).unwrap_or_default())
+            .collect()
+    }
+}
@@ -6,8 +6,8 @@
 //! server they need to be removed from the server section when the server is
 //! dropped.
 //!
-//! The server will create a tokio runtime which will spawn a task for the sync
-//! loop.
+//! The server will spawn a task for the sync loop on the tokio runtime of the
+//! plugin, the runtime is shared between all the servers.
 //!
 //! It will also spawn a task on the Weechat mainloop, this one waits for
 //! responses from the sync loop.
@@ -43,7 +43,7 @@
 //!   +--------------------------------------------------------------------+
 //!
 //!
-//! The tokio runtime and response receiver task will be alive only if the user
+//! The sync loop and response receiver task will be alive only if the user
 //! connects to the server while the room buffer input callback will print an
 //! error if the server is disconnected.
 //!
@@ -56,28 +56,39 @@
 
 use chrono::{offset::Utc, DateTime};
 use std::{
-    cell::{Ref, RefCell, RefMut},
+    cell::{Cell, Ref, RefCell, RefMut},
     cmp::Reverse,
     collections::HashMap,
+    io::Write,
     path::PathBuf,
     rc::{Rc, Weak},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant, SystemTime},
 };
 use tracing::error;
 use url::Url;
 
+use serde_json::Value;
+
 use matrix_sdk::{
     self,
+    crypto::{encrypt_room_key_export, store::RecoveryKey},
     deserialized_responses::AmbiguityChange,
-    encryption::RoomKeyImportResult,
+    encryption::{verification::SasVerification, RoomKeyImportResult},
     room::Joined,
     ruma::{
-        api::client::session::login::v3::Response as LoginResponse,
+        api::client::{
+            session::login::v3::Response as LoginResponse,
+            uiaa::{AuthType, UiaaInfo},
+        },
         events::{
             room::member::RoomMemberEventContent, AnySyncStateEvent,
             AnySyncTimelineEvent, SyncStateEvent,
         },
-        DeviceId, DeviceKeyAlgorithm, MilliSecondsSinceUnixEpoch,
-        OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId,
+        push::Ruleset,
+        DeviceId, DeviceKeyAlgorithm, EventId, MilliSecondsSinceUnixEpoch,
+        OwnedDeviceId, OwnedEventId, OwnedRoomAliasId, OwnedRoomId,
+        OwnedServerName, OwnedUserId, RoomAliasId, RoomId, UserId,
     },
     Client, Error,
 };
@@ -89,9 +100,21 @@ use weechat::{
 };
 
 use crate::{
+    auth_prompt::AuthPrompt,
     config::ServerBuffer,
-    connection::{Connection, InteractiveAuthInfo},
-    room::RoomHandle,
+    connection::{
+        ClientError, ClientErrorKind, Connection, InteractiveAuthInfo,
+    },
+    encrypted_rooms::EncryptedRooms,
+    key_backup::{decrypt_room_key, BackupProblem, KeyBackupMonitor},
+    mentions::Mentions,
+    mute::MuteTimers,
+    outbox::Outbox,
+    push_rules::{PushOutcome, RoomNotificationMode},
+    room::{NicklistThresholds, RoomHandle, RoomSettings},
+    scheduled::{ScheduledMessage, ScheduledMessages},
+    sync_log::SyncLog,
+    utils::{expand_device_name, format_size},
     ConfigHandle, Servers, PLUGIN_NAME,
 };
 
@@ -116,22 +139,51 @@ pub struct ServerSettings {
     pub autoconnect: bool,
     pub username: String,
     pub password: String,
+    /// The template of the display name of our device.
+    pub device_name: String,
     pub ssl_verify: bool,
+    pub presence: bool,
+    pub read_receipts: bool,
+    /// Don't send anything that reveals our activity to the rooms.
+    pub read_only: bool,
+    pub nicklist_thresholds: NicklistThresholds,
 }
 
 impl Default for ServerSettings {
     fn default() -> Self {
         Self {
             ssl_verify: true,
+            presence: true,
+            read_receipts: true,
+            read_only: false,
+            nicklist_thresholds: NicklistThresholds::default(),
             proxy: None,
             autoconnect: false,
             homeserver: None,
             username: "".to_owned(),
             password: "".to_owned(),
+            device_name: DEFAULT_DEVICE_NAME.to_owned(),
         }
     }
 }
 
+impl ServerSettings {
+    /// Get the display name our device should have, with the template of the
+    /// device name expanded.
+    pub fn device_name(&self, server_name: &str) -> String {
+        let hostname = hostname::get()
+            .map(|h| h.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        expand_device_name(
+            &self.device_name,
+            &hostname,
+            server_name,
+            &self.username,
+        )
+    }
+}
+
 impl ServerSettings {
     pub fn new() -> Self {
         Default::default()
@@ -142,6 +194,32 @@ pub struct LoginInfo {
     user_id: OwnedUserId,
 }
 
+/// The display name of our device if the user didn't configure one.
+const DEFAULT_DEVICE_NAME: &str = "WeeChat-Matrix-rs";
+
+/// How long a resolved room alias is kept in the cache.
+const ALIAS_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How long to wait before retrying to send scheduled messages that couldn't
+/// be sent when they were due.
+const SCHEDULED_MESSAGE_RETRY: Duration = Duration::from_secs(60);
+
+/// How long to wait before reconnecting after the sync loop stopped because
+/// of a temporary error.
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// How often the health of the server-side key backup is checked.
+const KEY_BACKUP_CHECK_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// A room alias or room id that was resolved to a room id.
+#[derive(Clone, Debug)]
+pub struct ResolvedRoom {
+    pub room_id: OwnedRoomId,
+    /// Servers that can be used to join the room.
+    pub servers: Vec<OwnedServerName>,
+    resolved_at: Instant,
+}
+
 #[derive(Clone)]
 pub struct MatrixServer {
     inner: Rc<InnerServer>,
@@ -173,6 +251,27 @@ pub struct InnerServer {
     login_state: Rc<RefCell<Option<LoginInfo>>>,
     connection: Rc<RefCell<Option<Connection>>>,
     server_buffer: Rc<RefCell<Option<BufferHandle>>>,
+    outbox: Outbox,
+    mutes: Rc<RefCell<MuteTimers>>,
+    encrypted_rooms: Rc<RefCell<EncryptedRooms>>,
+    scheduled: Rc<RefCell<ScheduledMessages>>,
+    key_backup: Rc<RefCell<KeyBackupMonitor>>,
+    /// The interactive verifications of our own devices that wait for the
+    /// user to compare the emojis.
+    verifications: Rc<RefCell<HashMap<OwnedDeviceId, SasVerification>>>,
+    sync_log: SyncLog,
+    alias_cache: Rc<RefCell<HashMap<OwnedRoomAliasId, ResolvedRoom>>>,
+    /// The push rules of the account, once they were fetched.
+    push_rules: Rc<RefCell<Option<Ruleset>>>,
+    auth_prompt: Rc<RefCell<Option<BufferHandle>>>,
+    /// Did the server soft logout our session, the next connection needs to
+    /// log in again with the same device.
+    soft_logout: Rc<Cell<bool>>,
+    /// Bumped to cancel a scheduled reconnect, the reconnect only happens if
+    /// the generation didn't change while it waited.
+    reconnect_generation: Rc<Cell<u64>>,
+    /// Is a reconnect scheduled.
+    reconnect_pending: Rc<Cell<bool>>,
 }
 
 impl MatrixServer {
@@ -195,13 +294,221 @@ impl MatrixServer {
             login_state: Rc::new(RefCell::new(None)),
             connection: Rc::new(RefCell::new(None)),
             server_buffer: Rc::new(RefCell::new(None)),
+            outbox: Outbox::default(),
+            mutes: Rc::new(RefCell::new(MuteTimers::default())),
+            encrypted_rooms: Rc::new(RefCell::new(EncryptedRooms::default())),
+            scheduled: Rc::new(RefCell::new(ScheduledMessages::default())),
+            key_backup: Rc::new(RefCell::new(KeyBackupMonitor::default())),
+            verifications: Rc::new(RefCell::new(HashMap::new())),
+            sync_log: SyncLog::new(Self::server_path(name).join("sync.log")),
+            alias_cache: Rc::new(RefCell::new(HashMap::new())),
+            push_rules: Rc::new(RefCell::new(None)),
+            auth_prompt: Rc::new(RefCell::new(None)),
+            soft_logout: Rc::new(Cell::new(false)),
+            reconnect_generation: Rc::new(Cell::new(0)),
+            reconnect_pending: Rc::new(Cell::new(false)),
         };
 
         let server = server.into();
 
         MatrixServer::create_server_conf(&server_name, server_section, &server);
 
-        MatrixServer { inner: server }
+        let server = MatrixServer { inner: server };
+
+        let mutes =
+            MuteTimers::load(server.get_server_path().join("mutes.json"));
+
+        for remaining in mutes.remaining() {
+            server.schedule_mute_expiry(remaining);
+        }
+
+        *server.mutes.borrow_mut() = mutes;
+
+        *server.encrypted_rooms.borrow_mut() = EncryptedRooms::load(
+            server.get_server_path().join("encrypted_rooms.json"),
+        );
+
+        let scheduled = ScheduledMessages::load(
+            server.get_server_path().join("scheduled.json"),
+        );
+
+        for remaining in scheduled.remaining() {
+            server.schedule_message_check(remaining);
+        }
+
+        *server.scheduled.borrow_mut() = scheduled;
+
+        *server.key_backup.borrow_mut() = KeyBackupMonitor::load(
+            server.get_server_path().join("key_backup.json"),
+        );
+        server.schedule_key_backup_check();
+
+        server
+    }
+
+    /// Check the health of the key backup periodically while we're
+    /// connected.
+    fn schedule_key_backup_check(&self) {
+        let server = self.clone_weak();
+        let runtime = self.servers.runtime().to_owned();
+
+        Weechat::spawn(async move {
+            let _ = runtime
+                .spawn(tokio::time::sleep(KEY_BACKUP_CHECK_INTERVAL))
+                .await;
+
+            if let Some(server) = server.upgrade() {
+                let server = MatrixServer { inner: server };
+                server.schedule_key_backup_check();
+
+                if server.connected() {
+                    server.check_key_backup().await;
+                }
+            }
+        })
+        .detach();
+    }
+
+    /// Send out the scheduled messages that are due once the given duration
+    /// passes.
+    fn schedule_message_check(&self, duration: Duration) {
+        let server = self.clone_weak();
+        let runtime = self.servers.runtime().to_owned();
+
+        Weechat::spawn(async move {
+            let _ = runtime.spawn(tokio::time::sleep(duration)).await;
+
+            if let Some(server) = server.upgrade() {
+                MatrixServer { inner: server }.send_due_messages();
+            }
+        })
+        .detach();
+    }
+
+    /// Send out the scheduled messages that are due.
+    ///
+    /// Messages that are due but can't be sent yet, because we aren't
+    /// connected or the room isn't there yet, are retried later on.
+    fn send_due_messages(&self) {
+        let connected = self.connected();
+
+        let due = {
+            let rooms = self.rooms.borrow();
+            self.scheduled
+                .borrow_mut()
+                .take_due(|m| connected && rooms.contains_key(&m.room_id))
+        };
+
+        for message in due {
+            let room = self.rooms.borrow().get(&message.room_id).cloned();
+
+            if let Some(room) = room {
+                Weechat::spawn(
+                    async move { room.send_text(&message.text).await },
+                )
+                .detach();
+            }
+        }
+
+        let overdue = self
+            .scheduled
+            .borrow()
+            .remaining()
+            .contains(&Duration::ZERO);
+
+        if overdue {
+            self.schedule_message_check(SCHEDULED_MESSAGE_RETRY);
+        }
+    }
+
+    /// Schedule a message to be sent to the given room at the given time.
+    ///
+    /// Returns the id of the scheduled message.
+    pub fn schedule_message(
+        &self,
+        room: &RoomHandle,
+        send_at: SystemTime,
+        text: String,
+    ) -> u64 {
+        let id = self
+            .scheduled
+            .borrow_mut()
+            .add(room.room_id(), send_at, text);
+
+        self.schedule_message_check(
+            send_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+        );
+
+        id
+    }
+
+    /// Check the mute timers once the given duration passes and unmute the
+    /// buffers whose timer expired.
+    fn schedule_mute_expiry(&self, duration: Duration) {
+        let server = self.clone_weak();
+        let runtime = self.servers.runtime().to_owned();
+
+        Weechat::spawn(async move {
+            let _ = runtime
+                .spawn(tokio::time::sleep(duration + Duration::from_secs(1)))
+                .await;
+
+            if let Some(server) = server.upgrade() {
+                if server.mutes.borrow_mut().expire() {
+                    server.apply_mutes();
+                }
+            }
+        })
+        .detach();
+    }
+
+    /// Mute a room for the given duration, or unmute it if no duration is
+    /// given.
+    pub fn mute_room(
+        &self,
+        room: &RoomHandle,
+        duration: Option<Duration>,
+    ) -> Result<(), String> {
+        self.mutes
+            .borrow_mut()
+            .mute_room(room.room_id(), duration)?;
+
+        if let Some(duration) = duration {
+            self.schedule_mute_expiry(duration);
+        }
+
+        self.apply_mutes();
+
+        Ok(())
+    }
+
+    /// Mute all the rooms of the server for the given duration, or unmute
+    /// them if no duration is given.
+    pub fn mute_server(
+        &self,
+        duration: Option<Duration>,
+    ) -> Result<(), String> {
+        self.mutes.borrow_mut().mute_server(duration)?;
+
+        if let Some(duration) = duration {
+            self.schedule_mute_expiry(duration);
+        }
+
+        self.apply_mutes();
+
+        Ok(())
+    }
+
+    /// The directory where the data of the server with the given name is
+    /// stored.
+    pub fn server_path(server_name: &str) -> PathBuf {
+        let mut path = Weechat::home_dir();
+        path.push("matrix-rust");
+        path.push(server_name);
+
+        path
     }
 
     pub fn clone_weak(&self) -> Weak<InnerServer> {
@@ -209,6 +516,8 @@ impl MatrixServer {
     }
 
     pub fn connect(&self) -> Result<(), ServerError> {
+        self.cancel_reconnect();
+
         if self.connected() {
             self.print_error(&format!(
                 "Already connected to {}{}{}",
@@ -221,7 +530,8 @@ impl MatrixServer {
         }
 
         let client = self.get_or_create_client()?;
-        let connection = Connection::new(&self, &client);
+        let connection =
+            Connection::new(&self, &client, self.servers.runtime().to_owned());
         self.set_connection(connection);
 
         self.print_network(&format!(
@@ -392,6 +702,41 @@ impl MatrixServer {
             .expect("Can't create password option");
 
         let server = server_copy;
+        let server_copy = server.clone();
+
+        let device_name =
+            StringOptionSettings::new(format!("{}.device_name", server_name))
+                .description(
+                    "The display name of our device, {hostname}, {server} \
+                     and {user} are replaced by the host name of the machine, \
+                     the name of the server and the username, the device is \
+                     renamed if this changes",
+                )
+                .default_value(DEFAULT_DEVICE_NAME)
+                .set_change_callback(move |_, option| {
+                    let server_ref = server.upgrade().expect(
+                        "Server got deleted while server config is alive",
+                    );
+
+                    server_ref.settings.borrow_mut().device_name =
+                        option.value().to_string();
+
+                    if server_ref.connected() {
+                        let server = MatrixServer { inner: server_ref };
+
+                        Weechat::spawn(async move {
+                            server.check_device_name().await
+                        })
+                        .detach();
+                    }
+                });
+
+        server_section
+            .new_string_option(device_name)
+            .expect("Can't create device name option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
 
         let ssl_verify =
             BooleanOptionSettings::new(format!("{}.ssl_verify", server_name))
@@ -409,6 +754,140 @@ impl MatrixServer {
         server_section
             .new_boolean_option(ssl_verify)
             .expect("Can't create autoconnect option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let presence =
+            BooleanOptionSettings::new(format!("{}.presence", server_name))
+                .description(
+                    "Receive presence updates from the server, disable this \
+                     to save bandwidth or for privacy reasons",
+                )
+                .default_value(true)
+                .set_change_callback(move |_, option| {
+                    let value = option.value();
+
+                    let server_ref = server.upgrade().expect(
+                        "Server got deleted while server config is alive",
+                    );
+
+                    server_ref.settings.borrow_mut().presence = value;
+                });
+
+        server_section
+            .new_boolean_option(presence)
+            .expect("Can't create presence option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let read_receipts = BooleanOptionSettings::new(format!(
+            "{}.read_receipts",
+            server_name
+        ))
+        .description(
+            "Receive read receipts from the server, disable this to save \
+             bandwidth or for privacy reasons",
+        )
+        .default_value(true)
+        .set_change_callback(move |_, option| {
+            let value = option.value();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            server_ref.settings.borrow_mut().read_receipts = value;
+        });
+
+        server_section
+            .new_boolean_option(read_receipts)
+            .expect("Can't create read receipts option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let read_only =
+            BooleanOptionSettings::new(format!("{}.read_only", server_name))
+                .description(
+                    "Spectator mode, rooms are still synced and rendered but \
+                     no messages, reactions, invites, joins, device changes, \
+                     read receipts or typing notices are sent and the account \
+                     syncs as offline, the presence changes on the next \
+                     connect",
+                )
+                .default_value(false)
+                .set_change_callback(move |_, option| {
+                    let value = option.value();
+
+                    let server_ref = server.upgrade().expect(
+                        "Server got deleted while server config is alive",
+                    );
+
+                    server_ref.settings.borrow_mut().read_only = value;
+                });
+
+        server_section
+            .new_boolean_option(read_only)
+            .expect("Can't create read only option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let nicklist_groups = StringOptionSettings::new(format!(
+            "{}.nicklist_groups",
+            server_name
+        ))
+        .description(
+            "A comma separated list of the power levels members need to be \
+             listed as ops, half-ops and voiced members in the nicklist, in \
+             descending order",
+        )
+        .default_value("100,50,1")
+        .set_check_callback(|_, _, value| {
+            NicklistThresholds::parse(&value).is_some()
+        })
+        .set_change_callback(move |_, option| {
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            server_ref.settings.borrow_mut().nicklist_thresholds =
+                NicklistThresholds::parse(&option.value()).unwrap_or_default();
+
+            for room in server_ref.rooms() {
+                room.refresh_nicklist();
+            }
+        });
+
+        server_section
+            .new_string_option(nicklist_groups)
+            .expect("Can't create nicklist groups option");
+
+        let server = server_copy;
+
+        let sync_log =
+            BooleanOptionSettings::new(format!("{}.sync_log", server_name))
+                .description(
+                    "Append the raw sync responses, with access tokens \
+                     scrubbed, to the sync.log file in the server directory, \
+                     this is meant for debugging, the content of decrypted \
+                     events is redacted but the messages of unencrypted rooms \
+                     and the room state are written to disk in plain text",
+                )
+                .default_value(false)
+                .set_change_callback(move |_, option| {
+                    let server_ref = server.upgrade().expect(
+                        "Server got deleted while server config is alive",
+                    );
+
+                    server_ref.sync_log.set_enabled(option.value());
+                });
+
+        server_section
+            .new_boolean_option(sync_log)
+            .expect("Can't create sync log option");
     }
 }
 
@@ -426,10 +905,16 @@ impl Drop for MatrixServer {
 
             for option_name in &[
                 "autoconnect",
+                "device_name",
                 "homeserver",
+                "nicklist_groups",
                 "password",
+                "presence",
                 "proxy",
+                "read_only",
+                "read_receipts",
                 "ssl_verify",
+                "sync_log",
                 "username",
             ] {
                 let option_name =
@@ -451,6 +936,47 @@ impl InnerServer {
         self.rooms.borrow().values().cloned().collect()
     }
 
+    /// Get the ids of all the rooms we're joined to, together with the names
+    /// the rooms can be searched by.
+    ///
+    /// This includes rooms that don't have a buffer yet.
+    pub fn joined_room_names(&self) -> Vec<(OwnedRoomId, Vec<String>)> {
+        if self.login_state.borrow().is_none() {
+            return Vec::new();
+        }
+
+        let client = self.client.borrow();
+        let client = if let Some(c) = client.as_ref() {
+            c
+        } else {
+            return Vec::new();
+        };
+
+        let rooms = self.rooms.borrow();
+
+        client
+            .joined_rooms()
+            .into_iter()
+            .map(|room| {
+                let room_id = room.room_id().to_owned();
+
+                let buffer_name = rooms
+                    .get(&room_id)
+                    .and_then(|r| r.buffer_handle().upgrade().ok())
+                    .map(|b| b.short_name().to_string());
+
+                let names = buffer_name
+                    .into_iter()
+                    .chain(room.name())
+                    .chain(room.canonical_alias().map(|a| a.to_string()))
+                    .chain(std::iter::once(room_id.to_string()))
+                    .collect();
+
+                (room_id, names)
+            })
+            .collect()
+    }
+
     pub(crate) fn get_or_create_room(&self, room_id: &RoomId) -> RoomHandle {
         if !self.rooms.borrow().contains_key(room_id) {
             let homeserver = self
@@ -480,11 +1006,22 @@ impl InnerServer {
                 self.servers.runtime().to_owned(),
                 &self.connection,
                 self.config.inner.clone(),
+                self.settings.clone(),
                 room,
                 homeserver,
                 room_id,
                 &login_state.user_id,
+                self.sync_log.clone(),
             );
+            self.apply_mute(&buffer);
+
+            if let Some(rules) = self.push_rules.borrow().as_ref() {
+                buffer.set_notification_mode(RoomNotificationMode::from_rules(
+                    rules, room_id,
+                ));
+            }
+
+            self.check_room_encryption(&buffer);
             self.rooms.borrow_mut().insert(room_id.to_owned(), buffer);
         }
 
@@ -495,12 +1032,8 @@ impl InnerServer {
         self.config.clone()
     }
 
-    pub fn user_name(&self) -> String {
-        self.settings.borrow().username.clone()
-    }
-
-    pub fn password(&self) -> String {
-        self.settings.borrow().password.clone()
+    pub fn settings(&self) -> ServerSettings {
+        self.settings.borrow().clone()
     }
 
     pub async fn restore_room(&self, room: Joined) {
@@ -524,6 +1057,8 @@ impl InnerServer {
             Ok(buffer) => {
                 let room_id = buffer.room_id().to_owned();
 
+                self.apply_mute(&buffer);
+                self.check_room_encryption(&buffer);
                 self.rooms.borrow_mut().insert(room_id, buffer);
             }
             Err(e) => self.print_error(&format!(
@@ -668,6 +1203,70 @@ impl InnerServer {
         self.print_with_prefix(&Weechat::prefix(Prefix::Error), message);
     }
 
+    /// Is the given room muted, either by itself or because the whole server
+    /// is muted.
+    pub fn is_room_muted(&self, room_id: &RoomId) -> bool {
+        self.mutes.borrow().is_room_muted(room_id)
+    }
+
+    /// Update the notify level of a room buffer to match the mute timers.
+    ///
+    /// The notify level the buffer had before it got muted is remembered and
+    /// restored once the buffer gets unmuted.
+    fn apply_mute(&self, room: &RoomHandle) {
+        if self.is_room_muted(room.room_id()) {
+            if !room.is_muted() {
+                self.mutes
+                    .borrow_mut()
+                    .save_level(room.room_id(), room.buffer_notify_level());
+                room.set_muted(true, None);
+            }
+        } else {
+            // The timer might have expired while we weren't running, in that
+            // case the buffer isn't marked as muted but the level still needs
+            // to be restored.
+            let level = self.mutes.borrow_mut().take_level(room.room_id());
+
+            if room.is_muted() || level.is_some() {
+                room.set_muted(false, level.as_deref());
+            }
+        }
+    }
+
+    /// Update the notify level of all our room buffers to match the mute
+    /// timers.
+    fn apply_mutes(&self) {
+        for room in self.rooms() {
+            self.apply_mute(&room);
+        }
+
+        Weechat::bar_item_update("buffer_modes");
+    }
+
+    /// Get the messages that are scheduled to be sent to the rooms of the
+    /// server.
+    pub fn scheduled_messages(&self) -> Vec<ScheduledMessage> {
+        self.scheduled.borrow().messages()
+    }
+
+    /// Cancel the scheduled message with the given id.
+    pub fn cancel_scheduled_message(
+        &self,
+        id: u64,
+    ) -> Option<ScheduledMessage> {
+        self.scheduled.borrow_mut().cancel(id)
+    }
+
+    /// Get the outbox of the server.
+    pub fn outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+
+    /// Refresh the outbox buffer of the server and switch to it.
+    pub fn show_outbox(&self) {
+        self.outbox.show(&self.server_name, &self.rooms());
+    }
+
     /// Is the server connected.
     pub fn connected(&self) -> bool {
         self.connection.borrow().is_some()
@@ -694,44 +1293,453 @@ impl InnerServer {
         }
     }
 
+    pub async fn receive_members(
+        &self,
+        room_id: OwnedRoomId,
+        members: Vec<(
+            SyncStateEvent<RoomMemberEventContent>,
+            Option<AmbiguityChange>,
+        )>,
+    ) {
+        let room = self.rooms.borrow().get(&room_id).cloned();
+
+        if let Some(room) = room {
+            for (member, ambiguity_change) in members {
+                room.handle_membership_event(
+                    &member,
+                    true,
+                    ambiguity_change.as_ref(),
+                )
+                .await;
+            }
+        } else {
+            error!("Room with id {} not found.", room_id);
+        }
+    }
+
+    pub fn receive_room_settings(
+        &self,
+        room_id: &RoomId,
+        settings: RoomSettings,
+    ) {
+        let room = self.get_or_create_room(room_id);
+        room.apply_settings(settings);
+    }
+
+    /// Resync the given rooms after some of their events had to be dropped.
+    pub async fn resync_rooms(&self, room_ids: Vec<OwnedRoomId>) {
+        for room_id in room_ids {
+            let room = self.rooms.borrow().get(&room_id).cloned();
+
+            if let Some(room) = room {
+                room.resync().await;
+            }
+        }
+    }
+
     pub async fn receive_joined_state_event(
         &self,
         room_id: &RoomId,
         event: AnySyncStateEvent,
     ) {
         let room = self.get_or_create_room(room_id);
-        room.handle_sync_state_event(&event, true).await
+        room.handle_sync_state_event(&event, true).await;
+        self.check_room_encryption(&room);
+    }
+
+    /// Remember the rooms that have encryption enabled, rooms whose state
+    /// claims that encryption got disabled stay encrypted.
+    fn check_room_encryption(&self, room: &RoomHandle) {
+        if room.room().is_encrypted() {
+            self.encrypted_rooms.borrow_mut().add(room.room_id());
+        } else if self.encrypted_rooms.borrow().contains(room.room_id()) {
+            room.set_encryption_downgraded();
+        }
     }
 
     pub async fn receive_joined_timeline_event(
         &self,
         room_id: &RoomId,
         event: AnySyncTimelineEvent,
+        mentions: Option<Mentions>,
+        push: Option<PushOutcome>,
     ) {
         let room = self.get_or_create_room(room_id);
-        room.handle_sync_room_event(event).await
+        room.handle_sync_room_event(event, mentions, push).await
     }
 
-    pub fn receive_login(&self, response: LoginResponse) {
-        let login_state = LoginInfo {
-            user_id: response.user_id,
-        };
+    pub async fn receive_custom_event(
+        &self,
+        room_id: &RoomId,
+        event: Raw<AnySyncTimelineEvent>,
+    ) {
+        let room = self.get_or_create_room(room_id);
+        room.handle_custom_event(&event, self.servers.event_hooks())
+            .await
+    }
 
-        *self.login_state.borrow_mut() = Some(login_state);
+    /// Store the push rules of the account and update the notification mode
+    /// of the rooms.
+    pub fn receive_push_rules(&self, rules: Ruleset) {
+        for (room_id, room) in self.rooms.borrow().iter() {
+            room.set_notification_mode(RoomNotificationMode::from_rules(
+                &rules, room_id,
+            ));
+        }
+
+        *self.push_rules.borrow_mut() = Some(rules);
     }
 
-    fn create_server_dir(&self) -> std::io::Result<()> {
-        let path = self.get_server_path();
-        std::fs::create_dir_all(path)
+    pub fn receive_typing_users(
+        &self,
+        room_id: &RoomId,
+        users: Vec<OwnedUserId>,
+    ) {
+        let room = self.get_or_create_room(room_id);
+        room.set_typing_users(users);
+    }
+
+    /// Switch to the buffer of the given room, the room is joined first if
+    /// we aren't a member of it.
+    pub async fn open_room(
+        &self,
+        room_id: OwnedRoomId,
+        servers: Vec<OwnedServerName>,
+    ) {
+        if self.joined_room_names().iter().any(|(r, _)| *r == room_id) {
+            let room = self.get_or_create_room(&room_id);
+
+            if let Ok(buffer) = room.buffer_handle().upgrade() {
+                buffer.switch_to();
+            }
+
+            return;
+        }
+
+        if self.settings.borrow().read_only {
+            self.print_error(
+                "Refusing to join the room, the server is in read-only mode",
+            );
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to join rooms");
+            return;
+        };
+
+        self.print_network(&format!("Joining {}", room_id));
+
+        if let Err(e) = connection.join_room(room_id.clone(), servers).await {
+            self.print_error(&format!("Error joining {}: {}", room_id, e));
+        }
+    }
+
+    pub fn receive_read_receipts(
+        &self,
+        room_id: &RoomId,
+        event_ids: &[OwnedEventId],
+    ) {
+        let room = self.rooms.borrow().get(room_id).cloned();
+
+        if let Some(room) = room {
+            room.handle_read_receipts(event_ids);
+        }
+    }
+
+    pub fn receive_own_read_receipt(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) {
+        let room = self.rooms.borrow().get(room_id).cloned();
+
+        if let Some(room) = room {
+            room.handle_own_read_receipt(event_id);
+        }
+    }
+
+    pub fn receive_login(&self, response: LoginResponse) {
+        let login_state = LoginInfo {
+            user_id: response.user_id,
+        };
+
+        *self.login_state.borrow_mut() = Some(login_state);
+    }
+
+    /// Handle an error that stopped the sync loop.
+    ///
+    /// The connection is dropped, temporary errors are retried after a while,
+    /// for the others the user is told how to continue.
+    pub fn receive_error(&self, error: ClientError) {
+        self.print_error(&error.to_string());
+
+        // The error is received by a task of the connection, dropping the
+        // connection cancels the task so drop it from a separate one.
+        let connection = self.connection.clone();
+        Weechat::spawn(async move {
+            connection.borrow_mut().take();
+        })
+        .detach();
+
+        match error.kind {
+            ClientErrorKind::BadCredentials => {
+                if let Some(server) = self.servers.get(&self.server_name) {
+                    Weechat::spawn(async move {
+                        server.retry_login().await;
+                    })
+                    .detach();
+                }
+            }
+            ClientErrorKind::SoftLogout => {
+                self.print_network(
+                    "The server logged out the session, logging in again \
+                     with the same device",
+                );
+                self.soft_logout.set(true);
+                self.schedule_reconnect(Duration::from_secs(0));
+            }
+            ClientErrorKind::UnknownToken => self.print_error(&format!(
+                "The session was logged out, use /matrix connect {} to log \
+                 in again",
+                self.server_name
+            )),
+            _ if error.retryable => {
+                self.print_network(&format!(
+                    "Reconnecting in {} seconds",
+                    RECONNECT_DELAY.as_secs()
+                ));
+                self.schedule_reconnect(RECONNECT_DELAY);
+            }
+            _ => self.print_error(&format!(
+                "Use /matrix connect {} to connect again",
+                self.server_name
+            )),
+        }
+    }
+
+    /// Reconnect to the server once the given delay passes, unless the user
+    /// connected, disconnected or deleted the server in the meantime.
+    fn schedule_reconnect(&self, delay: Duration) {
+        let servers = self.servers.clone();
+        let server_name = self.server_name.clone();
+        let runtime = self.servers.runtime().to_owned();
+
+        let generation = self.reconnect_generation.get() + 1;
+        let current_generation = self.reconnect_generation.clone();
+        let pending = self.reconnect_pending.clone();
+
+        current_generation.set(generation);
+        pending.set(true);
+
+        Weechat::spawn(async move {
+            let _ = runtime.spawn(tokio::time::sleep(delay)).await;
+
+            if current_generation.get() != generation {
+                return;
+            }
+
+            pending.set(false);
+
+            if let Some(server) = servers.get(&server_name) {
+                if !server.connected() {
+                    if let Err(e) = server.connect() {
+                        server.print_error(&format!(
+                            "Error reconnecting: {:?}",
+                            e
+                        ));
+                    }
+                }
+            }
+        })
+        .detach();
+    }
+
+    /// Cancel the scheduled reconnect, returns true if one was scheduled.
+    pub fn cancel_reconnect(&self) -> bool {
+        self.reconnect_generation
+            .set(self.reconnect_generation.get() + 1);
+        self.reconnect_pending.replace(false)
+    }
+
+    /// Ask the user for a new password after the server rejected ours and log
+    /// in again with it.
+    ///
+    /// The password is only kept in memory, the password option stays as it
+    /// is.
+    async fn retry_login(&self) {
+        let username = self.settings.borrow().username.clone();
+
+        let password = self
+            .prompt(
+                &format!(
+                    "Enter the password of {} for {}",
+                    username, self.server_name
+                ),
+                "The server rejected the password, enter the correct one \
+                 below or close this buffer to give up",
+            )
+            .await;
+
+        let password = if let Some(p) = password.filter(|p| !p.is_empty()) {
+            p
+        } else {
+            self.print_error(&format!(
+                "Fix the password option and use /matrix connect {} to log \
+                 in again",
+                self.server_name
+            ));
+            return;
+        };
+
+        self.settings.borrow_mut().password = password;
+
+        if let Some(server) = self.servers.get(&self.server_name) {
+            if let Err(e) = server.connect() {
+                self.print_error(&format!("Error reconnecting: {:?}", e));
+            }
+        }
+    }
+
+    /// Open a prompt buffer and wait for the user to answer it.
+    ///
+    /// A prompt that is still open is replaced by the new one. Returns `None`
+    /// if the user closed the buffer instead of answering.
+    async fn prompt(&self, title: &str, message: &str) -> Option<String> {
+        let old_prompt = self.auth_prompt.borrow_mut().take();
+
+        if let Some(buffer) = old_prompt.and_then(|b| b.upgrade().ok()) {
+            buffer.close();
+        }
+
+        let prompt = if let Some(p) = AuthPrompt::new(&self.server_name, title)
+        {
+            p
+        } else {
+            self.print_error("Can't open the authentication prompt");
+            return None;
+        };
+
+        prompt.print(message);
+        prompt.print("The entered text won't be printed");
+        *self.auth_prompt.borrow_mut() = Some(prompt.buffer_handle());
+
+        prompt.answer().await
+    }
+
+    /// Answer an user-interactive authentication request of the server.
+    ///
+    /// The next stage of the first flow we can follow is completed, the
+    /// configured password is tried first, if the server rejects it or
+    /// doesn't accept passwords the user is prompted.
+    ///
+    /// # Arguments
+    ///
+    /// * `info` - The authentication info the server returned.
+    ///
+    /// * `tried_password` - Was the configured password already tried, it
+    /// gets set once it was.
+    ///
+    /// Returns `None` if the user gave up.
+    async fn interactive_auth(
+        &self,
+        info: &UiaaInfo,
+        tried_password: &mut bool,
+    ) -> Option<InteractiveAuthInfo> {
+        let stage = info
+            .flows
+            .iter()
+            .find(|f| f.stages.starts_with(&info.completed))
+            .and_then(|f| f.stages.get(info.completed.len()))
+            .cloned()?;
+
+        let (username, password, homeserver) = {
+            let settings = self.settings.borrow();
+            (
+                settings.username.clone(),
+                settings.password.clone(),
+                settings.homeserver.clone(),
+            )
+        };
+
+        if stage == AuthType::Password {
+            let password = if !*tried_password && !password.is_empty() {
+                *tried_password = true;
+                password
+            } else {
+                let reason = info
+                    .auth_error
+                    .as_ref()
+                    .map(|e| format!(" ({})", e.message))
+                    .unwrap_or_default();
+
+                self.prompt(
+                    &format!(
+                        "Enter the password of {} for {}",
+                        username, self.server_name
+                    ),
+                    &format!(
+                        "The server asks for your password to continue{}, \
+                         enter it below or close this buffer to give up",
+                        reason
+                    ),
+                )
+                .await?
+            };
+
+            Some(InteractiveAuthInfo::Password {
+                user: username,
+                password,
+                session: info.session.clone(),
+            })
+        } else {
+            // Stages we don't know, like captchas or terms of service, are
+            // completed in the browser using the fallback page of the server.
+            let session = info.session.clone()?;
+            let url = homeserver?
+                .join(&format!(
+                    "_matrix/client/v3/auth/{}/fallback/web?session={}",
+                    stage, session
+                ))
+                .ok()?;
+
+            self.prompt(
+                &format!(
+                    "Complete the {} step for {}",
+                    stage, self.server_name
+                ),
+                &format!(
+                    "Open {} in your browser to complete the {} step, press \
+                     enter here once done or close this buffer to give up",
+                    url, stage
+                ),
+            )
+            .await?;
+
+            Some(InteractiveAuthInfo::Fallback { session })
+        }
+    }
+
+    fn create_server_dir(&self) -> std::io::Result<()> {
+        let path = self.get_server_path();
+        std::fs::create_dir_all(path)
     }
 
     pub fn get_server_path(&self) -> PathBuf {
-        let mut path = Weechat::home_dir();
-        let server_name: &str = &self.server_name;
-        path.push("matrix-rust");
-        path.push(server_name);
+        MatrixServer::server_path(&self.server_name)
+    }
 
-        path
+    /// Get the raw sync log of the server.
+    pub fn sync_log(&self) -> &SyncLog {
+        &self.sync_log
+    }
+
+    /// Check if the server soft logged out our session since the last
+    /// connection, the flag is reset.
+    pub fn take_soft_logout(&self) -> bool {
+        self.soft_logout.replace(false)
     }
 
     pub fn connection(&self) -> Option<Connection> {
@@ -769,6 +1777,10 @@ impl InnerServer {
             client_builder = client_builder.disable_ssl_verification();
         }
 
+        // Refresh expired access tokens instead of failing the requests, if
+        // the server gave us a refresh token.
+        client_builder = client_builder.handle_refresh_tokens();
+
         let client: Client = self
             .servers
             .runtime()
@@ -782,6 +1794,13 @@ impl InnerServer {
     }
 
     pub async fn delete_devices(&self, devices: Vec<OwnedDeviceId>) {
+        if self.settings.borrow().read_only {
+            self.print_error(
+                "Refusing to delete devices, the server is in read-only mode",
+            );
+            return;
+        }
+
         let formatted = devices
             .iter()
             .map(|d| d.to_string())
@@ -803,30 +1822,28 @@ impl InnerServer {
         };
 
         if let Some(c) = self.connection() {
-            match c.delete_devices(devices.clone(), None).await {
-                Ok(_) => print_success(),
-                Err(e) => {
-                    if let Some(info) = e.uiaa_response() {
-                        let auth_info = {
-                            let settings = self.settings.borrow();
-                            InteractiveAuthInfo {
-                                user: settings.username.clone(),
-                                password: settings.password.clone(),
-                                session: info.session.clone(),
-                            }
-                        };
-
-                        if let Err(e) = c
-                            .delete_devices(devices.clone(), Some(auth_info))
-                            .await
-                        {
-                            print_fail(e);
-                        } else {
-                            print_success();
-                        }
-                    } else {
-                        print_fail(e)
+            let mut auth_info = None;
+            let mut tried_password = false;
+
+            loop {
+                let e = match c.delete_devices(devices.clone(), auth_info).await
+                {
+                    Ok(_) => {
+                        print_success();
+                        return;
                     }
+                    Err(e) => e,
+                };
+
+                auth_info = if let Some(info) = e.uiaa_response() {
+                    self.interactive_auth(info, &mut tried_password).await
+                } else {
+                    None
+                };
+
+                if auth_info.is_none() {
+                    print_fail(e);
+                    return;
                 }
             }
         };
@@ -855,13 +1872,18 @@ impl InnerServer {
     }
 
     pub async fn import_keys(&self, file: PathBuf, passphrase: String) {
+        self.print_network(&format!(
+            "Importing E2EE keys from {}, this may take a while..",
+            file.display()
+        ));
+
+        self.import_key_export(file, passphrase).await;
+    }
+
+    async fn import_key_export(&self, file: PathBuf, passphrase: String) {
         let client = self.get_client().unwrap();
 
         if let Some(c) = self.connection() {
-            self.print_network(&format!(
-                "Importing E2EE keys from {}, this may take a while..",
-                file.display()
-            ));
             let import = async move {
                 client
                     .encryption()
@@ -1135,6 +2157,185 @@ impl InnerServer {
         )
     }
 
+    /// Resolve a room alias, or a room id, to a room id and a list of servers
+    /// that can be used to join the room.
+    ///
+    /// Aliases of rooms we are in are resolved locally, other aliases are
+    /// resolved by the homeserver and the result is cached for a while.
+    pub async fn resolve_room(
+        &self,
+        room: &str,
+    ) -> Result<ResolvedRoom, String> {
+        if let Ok(room_id) = RoomId::parse(room) {
+            return Ok(ResolvedRoom {
+                servers: vec![room_id.server_name().to_owned()],
+                room_id,
+                resolved_at: Instant::now(),
+            });
+        }
+
+        let alias = RoomAliasId::parse(room)
+            .map_err(|_| format!("Invalid room alias or id {}", room))?;
+
+        let joined_room = self
+            .rooms()
+            .into_iter()
+            .find(|r| r.alias().as_ref() == Some(&alias));
+
+        if let Some(r) = joined_room {
+            return Ok(ResolvedRoom {
+                room_id: r.room_id().to_owned(),
+                servers: vec![alias.server_name().to_owned()],
+                resolved_at: Instant::now(),
+            });
+        }
+
+        let cached = self
+            .alias_cache
+            .borrow()
+            .get(&alias)
+            .filter(|r| r.resolved_at.elapsed() < ALIAS_CACHE_TTL)
+            .cloned();
+
+        if let Some(resolved) = cached {
+            return Ok(resolved);
+        }
+
+        let connection = self.connection().ok_or_else(|| {
+            "You must be connected to resolve room aliases".to_owned()
+        })?;
+
+        let response = connection
+            .resolve_room_alias(alias.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let resolved = ResolvedRoom {
+            room_id: response.room_id,
+            servers: response.servers,
+            resolved_at: Instant::now(),
+        };
+
+        self.alias_cache
+            .borrow_mut()
+            .insert(alias, resolved.clone());
+
+        Ok(resolved)
+    }
+
+    /// Measure and print the round trip time to the homeserver.
+    pub async fn ping(&self) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let homeserver = self
+            .settings
+            .borrow()
+            .homeserver
+            .as_ref()
+            .and_then(|h| h.host_str().map(|h| h.to_owned()))
+            .unwrap_or_else(|| "the homeserver".to_owned());
+
+        match connection.ping().await {
+            Ok(rtt) => self.print_network(&format!(
+                "Pong from {}: {} ms",
+                homeserver,
+                rtt.as_millis()
+            )),
+            Err(e) => self
+                .print_error(&format!("Error pinging {}: {}", homeserver, e)),
+        }
+    }
+
+    /// Print information about the homeserver of the server and the
+    /// session we're logged in with.
+    pub async fn print_server_info(&self) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let homeserver = self
+            .settings
+            .borrow()
+            .homeserver
+            .as_ref()
+            .map(|h| h.to_string())
+            .unwrap_or_else(|| "unknown".to_owned());
+
+        let client = connection.client();
+        let user_id = client
+            .user_id()
+            .map(|u| u.to_string())
+            .unwrap_or_else(|| "unknown".to_owned());
+        let device_id = client
+            .device_id()
+            .map(|d| d.to_string())
+            .unwrap_or_else(|| "unknown".to_owned());
+
+        let upload_size = match connection.max_upload_size().await {
+            Ok(size) => format_size(size),
+            Err(e) => format!("unknown ({})", e),
+        };
+
+        self.print_network(&format!(
+            "Server {}{}{}:\n  \
+             Homeserver: {}\n  \
+             User: {}\n  \
+             Device: {}\n  \
+             Maximal upload size: {}",
+            Weechat::color("chat_server"),
+            self.name(),
+            Weechat::color("reset"),
+            homeserver,
+            user_id,
+            device_id,
+            upload_size,
+        ));
+    }
+
+    /// Print the state of the connection and statistics about the channel
+    /// between the sync loop and the response receiver.
+    pub fn print_status(&self) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_network(&format!(
+                "Server {}{}{} is not connected",
+                Weechat::color("chat_server"),
+                self.name(),
+                Weechat::color("reset")
+            ));
+            return;
+        };
+
+        let stats = connection.stats();
+        let load = |v: &AtomicUsize| v.load(Ordering::Relaxed);
+
+        self.print_network(&format!(
+            "Status of server {}{}{}:\n  \
+             Rooms: {}\n  \
+             Most queued sync messages: {}\n  \
+             Coalesced member events: {}\n  \
+             Dropped sync messages: {}\n  \
+             Resynced rooms: {}",
+            Weechat::color("chat_server"),
+            self.name(),
+            Weechat::color("reset"),
+            self.rooms.borrow().len(),
+            load(&stats.max_queued),
+            load(&stats.coalesced_member_events),
+            load(&stats.dropped_messages),
+            load(&stats.resynced_rooms),
+        ));
+    }
+
     pub async fn devices(&self, user_id: Option<OwnedUserId>) {
         let connection = if let Some(c) = self.connection() {
             c
@@ -1158,29 +2359,625 @@ impl InnerServer {
         }
     }
 
-    pub fn autoconnect(&self) -> bool {
-        self.settings.borrow().autoconnect
-    }
-
-    pub fn is_connection_secure(&self) -> bool {
-        let settings = self.current_settings.borrow();
+    /// Find a joined room that is a direct chat with only the given user.
+    fn find_direct_room(&self, user_id: &UserId) -> Option<OwnedRoomId> {
+        let client = self.get_client()?;
 
-        settings.ssl_verify
-            && settings
-                .homeserver
-                .as_ref()
-                .map(|u| u.scheme() == "https")
-                .unwrap_or(false)
+        client
+            .joined_rooms()
+            .into_iter()
+            .find(|r| {
+                let targets = r.direct_targets();
+                targets.len() == 1 && targets.contains(user_id)
+            })
+            .map(|r| r.room_id().to_owned())
     }
 
-    pub fn disconnect(&self) {
-        if !self.connected() {
+    /// Switch to the direct chat with the given user, a new direct chat is
+    /// created if there isn't one yet.
+    pub async fn query(&self, user_id: OwnedUserId) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        if let Some(room_id) = self.find_direct_room(&user_id) {
+            let room = self.get_or_create_room(&room_id);
+
+            if let Ok(buffer) = room.buffer_handle().upgrade() {
+                buffer.switch_to();
+            }
+
+            return;
+        }
+
+        if self.settings.borrow().read_only {
+            self.print_error(
+                "Refusing to create a direct chat, the server is in read-only \
+                 mode",
+            );
+            return;
+        }
+
+        match connection.create_direct_room(user_id.clone()).await {
+            Ok(room_id) => self.print_network(&format!(
+                "Created the direct chat {} with {}, its buffer opens once \
+                 the server sends us the room",
+                room_id, user_id
+            )),
+            Err(e) => self.print_error(&format!(
+                "Error creating a direct chat with {}: {}",
+                user_id, e
+            )),
+        }
+    }
+
+    /// Set the human readable name of one of our devices.
+    pub async fn set_device_name(
+        &self,
+        device_id: OwnedDeviceId,
+        name: String,
+    ) {
+        if self.settings.borrow().read_only {
+            self.print_error(
+                "Refusing to rename the device, the server is in read-only \
+                 mode",
+            );
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        match connection
+            .rename_device(device_id.clone(), name.clone())
+            .await
+        {
+            Ok(_) => self.print_network(&format!(
+                "Renamed the device {} to {}",
+                device_id, name
+            )),
+            Err(e) => self.print_error(&format!(
+                "Error renaming the device {}: {}",
+                device_id, e
+            )),
+        }
+    }
+
+    /// Start the interactive verification of one of our own devices.
+    ///
+    /// The emojis of the verification are printed once the other device
+    /// accepted the request, the user confirms that they match using
+    /// [`MatrixServer::confirm_device_verification()`].
+    pub async fn verify_device(&self, device_id: OwnedDeviceId) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        self.print_network(&format!(
+            "Requested the verification of the device {}, accept the request \
+             on that device",
+            device_id
+        ));
+
+        let sas = match connection
+            .request_device_verification(device_id.clone())
+            .await
+        {
+            Ok(Some(sas)) => sas,
+            Ok(None) => {
+                self.print_error(&format!(
+                    "The verification of the device {} was cancelled or timed \
+                     out",
+                    device_id
+                ));
+                return;
+            }
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error verifying the device {}: {}",
+                    device_id, e
+                ));
+                return;
+            }
+        };
+
+        let emojis = sas
+            .emoji()
+            .map(|emojis| {
+                emojis
+                    .iter()
+                    .map(|e| format!("{} ({})", e.symbol, e.description))
+                    .collect::<Vec<_>>()
+                    .join("  ")
+            })
+            .unwrap_or_default();
+
+        self.verifications
+            .borrow_mut()
+            .insert(device_id.clone(), sas);
+
+        self.print_network(&format!(
+            "Compare the emojis with the ones the device {} shows: {}",
+            device_id, emojis
+        ));
+        self.print_network(&format!(
+            "Use {bold}/devices verify {device} confirm{reset} if they match \
+             or {bold}/devices verify {device} cancel{reset} if they don't",
+            bold = Weechat::color("bold"),
+            reset = Weechat::color("reset"),
+            device = device_id,
+        ));
+    }
+
+    /// Confirm or cancel the interactive verification of the given device,
+    /// depending on whether the emojis matched.
+    pub async fn confirm_device_verification(
+        &self,
+        device_id: OwnedDeviceId,
+        matches: bool,
+    ) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let sas = if let Some(sas) =
+            self.verifications.borrow_mut().remove(&device_id)
+        {
+            sas
+        } else {
             self.print_error(&format!(
-                "Not connected to {}{}{}",
-                Weechat::color("chat_server"),
-                self.name(),
+                "There is no verification of the device {} waiting for \
+                 confirmation",
+                device_id
+            ));
+            return;
+        };
+
+        match connection.finish_verification(sas, matches).await {
+            Ok(true) => self.print_network(&format!(
+                "Successfully verified the device {}",
+                device_id
+            )),
+            Ok(false) if !matches => self.print_network(&format!(
+                "Cancelled the verification of the device {}",
+                device_id
+            )),
+            Ok(false) => self.print_error(&format!(
+                "The verification of the device {} was cancelled or timed out",
+                device_id
+            )),
+            Err(e) => self.print_error(&format!(
+                "Error verifying the device {}: {}",
+                device_id, e
+            )),
+        }
+    }
+
+    /// Restore the room keys that are stored in the server-side key backup.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The recovery key of the backup, the base58 encoded
+    /// key other clients show when the backup is created.
+    pub async fn restore_key_backup(&self, recovery_key: String) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let recovery_key = match RecoveryKey::from_base58(&recovery_key) {
+            Ok(k) => k,
+            Err(e) => {
+                self.print_error(&format!("Invalid recovery key: {}", e));
+                return;
+            }
+        };
+
+        let version = match connection.key_backup().await {
+            Ok(Some(backup)) => backup.version,
+            Ok(None) => {
+                self.print_error("There is no key backup on the server");
+                return;
+            }
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error fetching the key backup info: {:?}",
+                    e
+                ));
+                return;
+            }
+        };
+
+        self.print_network(&format!(
+            "Restoring E2EE keys from the key backup (version {}), this may \
+             take a while..",
+            version
+        ));
+
+        let response = match connection.backed_up_room_keys(version).await {
+            Ok(r) => r,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error fetching the keys of the key backup: {:?}",
+                    e
+                ));
+                return;
+            }
+        };
+
+        let mut failed = 0;
+        let mut keys = Vec::new();
+
+        for (room_id, backup) in &response.rooms {
+            for (session_id, key_data) in &backup.sessions {
+                let key = serde_json::from_str(key_data.json().get())
+                    .ok()
+                    .and_then(|data| {
+                        decrypt_room_key(
+                            &recovery_key,
+                            room_id,
+                            session_id,
+                            &data,
+                        )
+                    });
+
+                match key {
+                    Some(key) => keys.push(key),
+                    None => failed += 1,
+                }
+            }
+        }
+
+        if keys.is_empty() && failed > 0 {
+            self.print_error(
+                "None of the keys in the key backup could be decrypted, the \
+                 recovery key doesn't belong to the backup",
+            );
+            return;
+        } else if failed > 0 {
+            self.print_error(&format!(
+                "{} keys of the key backup couldn't be decrypted",
+                failed
+            ));
+        }
+
+        // The SDK only imports keys from key exports, the decrypted keys are
+        // passed to it as a temporary export.
+        let passphrase = uuid::Uuid::new_v4().to_string();
+
+        let export = encrypt_room_key_export(&keys, &passphrase, 500)
+            .map_err(|e| e.to_string())
+            .and_then(|export| {
+                let mut file = tempfile::NamedTempFile::new()
+                    .map_err(|e| e.to_string())?;
+                file.write_all(export.as_bytes())
+                    .map_err(|e| e.to_string())?;
+                Ok(file)
+            });
+
+        match export {
+            Ok(file) => {
+                self.import_key_export(file.path().to_owned(), passphrase)
+                    .await
+            }
+            Err(e) => self.print_error(&format!(
+                "Error importing the keys of the key backup: {}",
+                e
+            )),
+        }
+    }
+
+    /// Rename our device if its name doesn't match the configured device
+    /// name, e.g. because the option changed since we logged in.
+    ///
+    /// Device names are visible to everyone we share a room with, so nothing
+    /// is renamed in read-only mode.
+    async fn check_device_name(&self) {
+        if self.settings.borrow().read_only {
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            return;
+        };
+
+        let device_id = if let Some(d) =
+            connection.client().device_id().map(ToOwned::to_owned)
+        {
+            d
+        } else {
+            return;
+        };
+
+        let name = self.settings.borrow().device_name(&self.server_name);
+
+        let current_name = match connection.devices().await {
+            Ok(r) => r
+                .devices
+                .into_iter()
+                .find(|d| d.device_id == device_id)
+                .and_then(|d| d.display_name),
+            Err(e) => {
+                error!("Error fetching our devices: {}", e);
+                return;
+            }
+        };
+
+        if current_name.as_deref() != Some(name.as_str()) {
+            self.set_device_name(device_id, name).await;
+        }
+    }
+
+    /// The device lists of some users changed, the number of unverified
+    /// devices of our encrypted rooms needs to be recomputed.
+    pub fn device_lists_changed(&self) {
+        for room in self.rooms().iter().filter(|r| r.is_encrypted()) {
+            room.reset_unverified_device_count();
+        }
+    }
+
+    /// The first sync of a connection finished.
+    pub async fn initial_sync_done(&self, first_login: bool) {
+        if first_login {
+            self.review_devices().await;
+        }
+
+        self.check_device_name().await;
+        self.check_key_backup().await;
+    }
+
+    /// The problem the last check of the key backup found, if any.
+    pub fn key_backup_problem(&self) -> Option<BackupProblem> {
+        self.key_backup.borrow().problem().cloned()
+    }
+
+    /// Check that the key backup exists, that we trust it and that keys are
+    /// still being added to it, a warning is printed if that isn't the case.
+    pub async fn check_key_backup(&self) {
+        if !self.config.borrow().network().key_backup_monitoring() {
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            return;
+        };
+
+        let backup = match connection.key_backup().await {
+            Ok(b) => b,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error fetching the key backup info: {:?}",
+                    e
+                ));
+                return;
+            }
+        };
+
+        let (backup, trusted) = if let Some(backup) = backup {
+            let trusted = self
+                .is_key_backup_trusted(
+                    &connection,
+                    backup.algorithm.json().get(),
+                )
+                .await;
+
+            (Some((backup.version, backup.count.into())), trusted)
+        } else {
+            (None, false)
+        };
+
+        let problem = self.key_backup.borrow_mut().update(backup, trusted);
+
+        if let Some(problem) = problem {
+            self.print_error(&format!(
+                "{}, use {}/matrix keys export{} on one of your devices to \
+                 keep a copy of your room keys",
+                problem.description(),
+                Weechat::color("bold"),
                 Weechat::color("reset")
             ));
+        }
+
+        Weechat::bar_item_update("buffer_modes");
+    }
+
+    /// Is the key backup with the given backup algorithm signed by our own
+    /// device or one of our verified devices.
+    ///
+    /// The SDK doesn't expose the verification of the backup signatures,
+    /// only the presence of a signature from a trusted device is checked.
+    async fn is_key_backup_trusted(
+        &self,
+        connection: &Connection,
+        algorithm: &str,
+    ) -> bool {
+        let client = connection.client();
+
+        let (user_id, own_device_id) =
+            match (client.user_id(), client.device_id()) {
+                (Some(u), Some(d)) => (u, d),
+                _ => return false,
+            };
+
+        let algorithm: Value =
+            serde_json::from_str(algorithm).unwrap_or_default();
+
+        let signing_devices: Vec<String> = algorithm["auth_data"]["signatures"]
+            [user_id.as_str()]
+        .as_object()
+        .map(|signatures| {
+            signatures
+                .keys()
+                .filter_map(|k| k.strip_prefix("ed25519:"))
+                .map(ToOwned::to_owned)
+                .collect()
+        })
+        .unwrap_or_default();
+
+        for device_id in signing_devices {
+            if device_id == own_device_id.as_str() {
+                return true;
+            }
+
+            let device = client
+                .encryption()
+                .get_device(user_id, device_id.as_str().into())
+                .await;
+
+            if let Ok(Some(device)) = device {
+                if device.is_verified() {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Walk the user through the trust state of their devices after WeeChat
+    /// logged in with a new device.
+    ///
+    /// Messages in encrypted rooms can only be read once this device received
+    /// the room keys, the user is offered to verify this device with one of
+    /// their other devices and to restore the keys from the key backup.
+    async fn review_devices(&self) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            return;
+        };
+
+        self.print_network(&format!(
+            "This is the first login of WeeChat on server {}{}{}, please \
+             review the trust state of your devices",
+            Weechat::color("chat_server"),
+            self.name(),
+            Weechat::color("reset")
+        ));
+
+        if let Err(e) = self.list_own_devices(connection.clone()).await {
+            self.print_error(&format!("Error fetching devices {:?}", e));
+            return;
+        }
+
+        let client = connection.client();
+        let own_device_id = client.device_id();
+
+        let other_devices = match client.user_id() {
+            Some(user_id) => {
+                match client.encryption().get_user_devices(user_id).await {
+                    Ok(devices) => devices
+                        .devices()
+                        .filter(|d| Some(d.device_id()) != own_device_id)
+                        .count(),
+                    Err(e) => {
+                        self.print_error(&format!(
+                            "Error fetching devices {:?}",
+                            e
+                        ));
+                        return;
+                    }
+                }
+            }
+            None => return,
+        };
+
+        let has_backup = matches!(connection.key_backup().await, Ok(Some(_)));
+
+        let bold = Weechat::color("bold");
+        let reset = Weechat::color("reset");
+
+        if other_devices > 0 {
+            self.print_network(&format!(
+                "Verify WeeChat with one of the {} devices above using \
+                 {}/devices verify <device-id>{}, verified devices share the \
+                 keys of encrypted rooms with each other",
+                other_devices, bold, reset
+            ));
+        }
+
+        if has_backup {
+            self.print_network(&format!(
+                "To read the history of your encrypted rooms restore the \
+                 E2EE keys from the key backup on the server using \
+                 {}/matrix keys restore <recovery-key>{}",
+                bold, reset
+            ));
+        } else if other_devices == 0 {
+            self.print_network(
+                "WeeChat is your only device with encryption support and \
+                 there is no key backup on the server, messages that were \
+                 sent to encrypted rooms before this login can't be \
+                 decrypted",
+            );
+        }
+
+        self.print_network(&format!(
+            "If you have an export of your E2EE keys restore it using \
+             {}/matrix keys import <file> <passphrase>{}",
+            bold, reset
+        ));
+
+        self.print_network(&format!(
+            "Use {}/matrix devices{} to review your devices again",
+            Weechat::color("bold"),
+            Weechat::color("reset")
+        ));
+    }
+
+    pub fn autoconnect(&self) -> bool {
+        self.settings.borrow().autoconnect
+    }
+
+    pub fn is_connection_secure(&self) -> bool {
+        let settings = self.current_settings.borrow();
+
+        settings.ssl_verify
+            && settings
+                .homeserver
+                .as_ref()
+                .map(|u| u.scheme() == "https")
+                .unwrap_or(false)
+    }
+
+    pub fn disconnect(&self) {
+        let cancelled = self.cancel_reconnect();
+
+        if !self.connected() {
+            if cancelled {
+                self.print_network(&format!(
+                    "Cancelled reconnecting to {}{}{}",
+                    Weechat::color("chat_server"),
+                    self.name(),
+                    Weechat::color("reset")
+                ));
+            } else {
+                self.print_error(&format!(
+                    "Not connected to {}{}{}",
+                    Weechat::color("chat_server"),
+                    self.name(),
+                    Weechat::color("reset")
+                ));
+            }
 
             return;
         }
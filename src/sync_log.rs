@@ -0,0 +1,204 @@
+//! Raw sync logging.
+//!
+//! Sync responses can be appended to a log file in the server directory, one
+//! JSON object per line, so hard to reproduce rendering bugs can be replayed
+//! later on. Access tokens are scrubbed from the logged events.
+//!
+//! Events of encrypted rooms arrive decrypted, their content is redacted
+//! before it's logged so the messages of encrypted rooms don't end up on disk
+//! in plain text. Only the content of decrypted events is redacted, the
+//! messages of unencrypted rooms and the state events are logged as they are.
+//! The exception are rooms whose retention policy the
+//! `look.retention_compliance` option asks us to respect, the content of all
+//! their messages is redacted.
+//!
+//! The log file is rotated once it grows too large, only a single rotated log
+//! file is kept around.
+
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use matrix_sdk::{
+    deserialized_responses::SyncResponse,
+    ruma::{serde::Raw, MilliSecondsSinceUnixEpoch, OwnedRoomId, RoomId},
+};
+use serde_json::{json, Map, Value};
+use tracing::error;
+
+/// The size at which the log file gets rotated, in bytes.
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct SyncLog {
+    enabled: Arc<AtomicBool>,
+    path: PathBuf,
+    /// The rooms whose message content is never logged.
+    redacted_rooms: Arc<Mutex<HashSet<OwnedRoomId>>>,
+}
+
+impl SyncLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            enabled: Arc::new(AtomicBool::new(false)),
+            path,
+            redacted_rooms: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst)
+    }
+
+    /// Set if the content of all the messages of the given room should be
+    /// redacted, not only the ones of decrypted events.
+    pub fn set_room_redacted(&self, room_id: &RoomId, redacted: bool) {
+        let mut rooms = self
+            .redacted_rooms
+            .lock()
+            .expect("Sync log room lock was poisoned");
+
+        if redacted {
+            rooms.insert(room_id.to_owned());
+        } else {
+            rooms.remove(room_id);
+        }
+    }
+
+    fn raw_to_value<T>(raw: &Raw<T>) -> Value {
+        serde_json::from_str(raw.json().get()).unwrap_or_default()
+    }
+
+    fn raw_events<T>(events: &[Raw<T>]) -> Value {
+        events.iter().map(Self::raw_to_value).collect()
+    }
+
+    /// Replace the content of a decrypted event, except for the message type
+    /// and the relation, which are needed to know how the event is rendered.
+    fn redact_decrypted_content(value: &mut Value) {
+        if let Some(content) =
+            value.get_mut("content").and_then(Value::as_object_mut)
+        {
+            for (key, value) in content.iter_mut() {
+                if key != "msgtype" && key != "m.relates_to" {
+                    *value = Value::String("<redacted>".to_owned());
+                }
+            }
+        }
+    }
+
+    /// Replace the values of all the access tokens in the given JSON value.
+    fn scrub_access_tokens(value: &mut Value) {
+        match value {
+            Value::Object(object) => {
+                for (key, value) in object.iter_mut() {
+                    if key == "access_token" {
+                        *value = Value::String("<scrubbed>".to_owned());
+                    } else {
+                        Self::scrub_access_tokens(value);
+                    }
+                }
+            }
+            Value::Array(array) => {
+                array.iter_mut().for_each(Self::scrub_access_tokens)
+            }
+            _ => (),
+        }
+    }
+
+    fn rotate(&self) -> std::io::Result<()> {
+        let size = std::fs::metadata(&self.path).map(|m| m.len());
+
+        if size.map_or(false, |s| s > MAX_LOG_SIZE) {
+            std::fs::rename(&self.path, self.path.with_extension("log.1"))?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, entry: &Value) -> std::io::Result<()> {
+        self.rotate()?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        writeln!(file, "{}", entry)
+    }
+
+    /// Append the given sync response to the log file, if logging is enabled.
+    pub fn log(&self, response: &SyncResponse) {
+        if !self.enabled() {
+            return;
+        }
+
+        let redacted_rooms = self
+            .redacted_rooms
+            .lock()
+            .expect("Sync log room lock was poisoned")
+            .clone();
+
+        let rooms: Map<String, Value> = response
+            .rooms
+            .join
+            .iter()
+            .map(|(room_id, room)| {
+                let timeline: Vec<Value> = room
+                    .timeline
+                    .events
+                    .iter()
+                    .map(|e| {
+                        let mut event = Self::raw_to_value(&e.event);
+
+                        if e.encryption_info.is_some()
+                            || redacted_rooms.contains(room_id)
+                        {
+                            Self::redact_decrypted_content(&mut event);
+                        }
+
+                        event
+                    })
+                    .collect();
+
+                let room = json!({
+                    "state": Self::raw_events(&room.state.events),
+                    "timeline": timeline,
+                    "ephemeral": Self::raw_events(&room.ephemeral.events),
+                    "limited": room.timeline.limited,
+                    "prev_batch": room.timeline.prev_batch,
+                });
+
+                (room_id.to_string(), room)
+            })
+            .collect();
+
+        let mut entry = json!({
+            "received": MilliSecondsSinceUnixEpoch::now(),
+            "next_batch": response.next_batch,
+            "rooms": { "join": rooms },
+            "presence": Self::raw_events(&response.presence.events),
+        });
+
+        Self::scrub_access_tokens(&mut entry);
+
+        if let Err(e) = self.write(&entry) {
+            error!("Error writing the sync log {}: {}", self.path.display(), e);
+        }
+    }
+}
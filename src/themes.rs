@@ -0,0 +1,203 @@
+//! Renderer themes.
+//!
+//! A theme is a named set of values for the look options that control how
+//! rooms are rendered: the signs of the status bar items, the colors, the
+//! markers of our messages and the time format of backfilled messages. Two
+//! themes are built in, the current values of the options can be saved as
+//! additional themes using `/matrix theme save <name>`.
+//!
+//! The saved themes are stored in the plugin directory.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use serde_json::Value;
+use weechat::Weechat;
+
+/// The look options that are part of a theme.
+pub const THEME_OPTIONS: &[&str] = &[
+    "encrypted_room_sign",
+    "encryption_warning_sign",
+    "key_backup_warning_sign",
+    "public_room_sign",
+    "busy_sign",
+    "typing_sign",
+    "muted_sign",
+    "retention_sign",
+    "room_mention_color",
+    "local_echo_color",
+    "own_prefix_padding",
+    "sent_marker",
+    "synced_marker",
+    "read_marker",
+    "edited_marker",
+    "redaction_style",
+    "backfill_time_format",
+    "render_profile",
+    "unread_marker",
+];
+
+/// Plain ASCII signs and no markers, close to how the IRC plugin looks.
+const CLASSIC: &[(&str, &str)] = &[
+    ("encrypted_room_sign", "E"),
+    ("encryption_warning_sign", "!"),
+    ("key_backup_warning_sign", "K"),
+    ("public_room_sign", "P"),
+    ("busy_sign", "~"),
+    ("typing_sign", "typing:"),
+    ("muted_sign", "M"),
+    ("retention_sign", "R"),
+    ("room_mention_color", "lightred"),
+    ("local_echo_color", ""),
+    ("own_prefix_padding", "0"),
+    ("sent_marker", ""),
+    ("synced_marker", ""),
+    ("read_marker", ""),
+    ("edited_marker", "(edited)"),
+    ("redaction_style", "bracketed"),
+    ("backfill_time_format", "%Y-%m-%d %H:%M"),
+    ("render_profile", "default"),
+    ("unread_marker", "off"),
+];
+
+/// Emoji signs and delivery markers, the default values of the options.
+const MODERN: &[(&str, &str)] = &[
+    ("encrypted_room_sign", "🔒"),
+    ("encryption_warning_sign", "❗"),
+    ("key_backup_warning_sign", "🔑"),
+    ("public_room_sign", "🌍"),
+    ("busy_sign", "⏳"),
+    ("typing_sign", "✏"),
+    ("muted_sign", "🔕"),
+    ("retention_sign", "⌛"),
+    ("room_mention_color", "lightred"),
+    ("local_echo_color", "darkgray"),
+    ("own_prefix_padding", "0"),
+    ("sent_marker", "✓"),
+    ("synced_marker", "✓✓"),
+    ("read_marker", "👁"),
+    ("edited_marker", "(edited)"),
+    ("redaction_style", "strike-through"),
+    ("backfill_time_format", "%Y-%m-%d %H:%M"),
+    ("render_profile", "default"),
+    ("unread_marker", "on"),
+];
+
+/// The names of the built-in themes.
+pub const BUILTIN_THEMES: &[&str] = &["classic", "modern"];
+
+/// Names that are taken by the actions of the theme command.
+const RESERVED_NAMES: &[&str] = &["list", "save", "delete"];
+
+/// The values of the theme options, keyed by the option name.
+pub type ThemeValues = BTreeMap<String, String>;
+
+#[derive(Debug, Default)]
+pub struct Themes {
+    path: PathBuf,
+    saved: BTreeMap<String, ThemeValues>,
+}
+
+impl Themes {
+    /// Load the saved themes.
+    ///
+    /// A missing or invalid file results in no saved themes.
+    pub fn load() -> Self {
+        let mut path = Weechat::home_dir();
+        path.push("matrix-rust");
+        path.push("themes.json");
+
+        let value: Value = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|c| serde_json::from_str(&c).ok())
+            .unwrap_or_default();
+
+        let saved = value
+            .as_object()
+            .map(|themes| {
+                themes
+                    .iter()
+                    .filter_map(|(name, values)| {
+                        let values = values
+                            .as_object()?
+                            .iter()
+                            .filter(|(o, _)| {
+                                THEME_OPTIONS.contains(&o.as_str())
+                            })
+                            .filter_map(|(o, v)| {
+                                Some((o.clone(), v.as_str()?.to_owned()))
+                            })
+                            .collect();
+
+                        Some((name.clone(), values))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self { path, saved }
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let value = serde_json::to_string(&self.saved)?;
+
+        self.path.parent().map_or(Ok(()), std::fs::create_dir_all)?;
+        std::fs::write(&self.path, value)
+    }
+
+    /// The names of all the themes, the built-in ones first.
+    pub fn names(&self) -> Vec<String> {
+        BUILTIN_THEMES
+            .iter()
+            .map(|n| n.to_string())
+            .chain(self.saved.keys().cloned())
+            .collect()
+    }
+
+    /// Get the option values of the theme with the given name.
+    pub fn get(&self, name: &str) -> Option<ThemeValues> {
+        let builtin = match name {
+            "classic" => CLASSIC,
+            "modern" => MODERN,
+            _ => return self.saved.get(name).cloned(),
+        };
+
+        Some(
+            builtin
+                .iter()
+                .map(|(o, v)| (o.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    /// Save the given option values as a theme, an existing saved theme with
+    /// the same name is replaced.
+    pub fn insert(
+        &mut self,
+        name: &str,
+        values: ThemeValues,
+    ) -> Result<(), String> {
+        if BUILTIN_THEMES.contains(&name) {
+            return Err(format!("{} is a built-in theme", name));
+        } else if RESERVED_NAMES.contains(&name) {
+            return Err(format!("{} can't be used as a theme name", name));
+        }
+
+        self.saved.insert(name.to_owned(), values);
+        self.save()
+            .map_err(|e| format!("Error saving the themes: {}", e))
+    }
+
+    /// Delete the saved theme with the given name.
+    pub fn remove(&mut self, name: &str) -> Result<(), String> {
+        if BUILTIN_THEMES.contains(&name) {
+            return Err(format!("{} is a built-in theme", name));
+        }
+
+        if self.saved.remove(name).is_none() {
+            return Err(format!("No theme named {}", name));
+        }
+
+        self.save()
+            .map_err(|e| format!("Error saving the themes: {}", e))
+    }
+}
@@ -0,0 +1,183 @@
+//! Transformations of outgoing messages.
+//!
+//! The text of a message can be transformed before it's sent, the
+//! `input.transformations` option lists the steps that are applied, in
+//! order. The available steps are:
+//!
+//! * `emoji` - Replace shortcodes, e.g. `:+1:`, with their emoji.
+//! * `linkify` - Wrap bare URLs in angle brackets, markdown turns those into
+//!   links.
+//! * `rewrite` - Apply the rewrites of the `input.rewrites` option, a
+//!   semicolon separated list of `<regex> <replacement>` entries.
+
+use std::{cell::RefCell, rc::Rc};
+
+use regex::Regex;
+use weechat::{Prefix, Weechat};
+
+use crate::{emoji::replace_shortcodes, utils::linkify};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    Emoji,
+    Linkify,
+    Rewrite,
+}
+
+impl Step {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "emoji" => Some(Step::Emoji),
+            "linkify" => Some(Step::Linkify),
+            "rewrite" => Some(Step::Rewrite),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Step::Emoji => "emoji",
+            Step::Linkify => "linkify",
+            Step::Rewrite => "rewrite",
+        }
+    }
+}
+
+struct Rewrite {
+    regex: Regex,
+    replacement: String,
+}
+
+impl Rewrite {
+    fn parse(entry: &str) -> Option<Result<Self, regex::Error>> {
+        let mut parts = entry.trim().splitn(2, ' ');
+        let regex = parts.next()?.trim();
+        let replacement = parts.next().unwrap_or_default().trim();
+
+        if regex.is_empty() {
+            return None;
+        }
+
+        Some(Regex::new(regex).map(|regex| Self {
+            regex,
+            replacement: replacement.to_owned(),
+        }))
+    }
+}
+
+#[derive(Default)]
+pub struct Pipeline {
+    steps: Vec<Step>,
+    rewrites: Vec<Rewrite>,
+}
+
+impl Pipeline {
+    /// Parse a pipeline from the values of the `input.transformations` and
+    /// `input.rewrites` options.
+    ///
+    /// Unknown steps and rewrites with an invalid regex are skipped, an
+    /// error is printed for each of them.
+    fn parse(steps: &str, rewrites: &str) -> Self {
+        let print_error = |message: String| {
+            Weechat::print(&format!(
+                "{}: {}",
+                Weechat::prefix(Prefix::Error),
+                message
+            ))
+        };
+
+        let steps = steps
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .filter_map(|name| {
+                let step = Step::parse(name);
+
+                if step.is_none() {
+                    print_error(format!(
+                        "Unknown step in input.transformations: {}",
+                        name
+                    ));
+                }
+
+                step
+            })
+            .collect();
+
+        let rewrites = rewrites
+            .split(';')
+            .filter_map(Rewrite::parse)
+            .filter_map(|rewrite| {
+                rewrite
+                    .map_err(|e| {
+                        print_error(format!(
+                            "Invalid regex in input.rewrites: {}",
+                            e
+                        ))
+                    })
+                    .ok()
+            })
+            .collect();
+
+        Self { steps, rewrites }
+    }
+
+    fn apply_step(&self, step: Step, text: &str) -> String {
+        match step {
+            Step::Emoji => replace_shortcodes(text),
+            Step::Linkify => linkify(text),
+            Step::Rewrite => {
+                self.rewrites.iter().fold(text.to_owned(), |text, rewrite| {
+                    rewrite
+                        .regex
+                        .replace_all(&text, rewrite.replacement.as_str())
+                        .into_owned()
+                })
+            }
+        }
+    }
+
+    /// Apply all the steps of the pipeline to the given text.
+    pub fn apply(&self, text: &str) -> String {
+        self.steps
+            .iter()
+            .fold(text.to_owned(), |text, step| self.apply_step(*step, &text))
+    }
+
+    /// Apply all the steps of the pipeline to the given text, returning the
+    /// name of every step with the text it produced.
+    pub fn preview(&self, text: &str) -> Vec<(&'static str, String)> {
+        let mut text = text.to_owned();
+
+        self.steps
+            .iter()
+            .map(|step| {
+                text = self.apply_step(*step, &text);
+                (step.name(), text.clone())
+            })
+            .collect()
+    }
+}
+
+thread_local! {
+    /// The option values the pipeline was last parsed from, with the parsed
+    /// pipeline, so the regexes only get compiled when the options change.
+    static PIPELINE: RefCell<((String, String), Rc<Pipeline>)> =
+        RefCell::new(Default::default());
+}
+
+/// Get the pipeline of the given `input.transformations` and
+/// `input.rewrites` option values.
+pub fn pipeline(steps: &str, rewrites: &str) -> Rc<Pipeline> {
+    PIPELINE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let options = (steps.to_owned(), rewrites.to_owned());
+
+        if cache.0 != options {
+            let pipeline = Rc::new(Pipeline::parse(steps, rewrites));
+            *cache = (options, pipeline);
+        }
+
+        cache.1.clone()
+    })
+}
@@ -1,9 +1,22 @@
+use std::{
+    borrow::Cow,
+    convert::TryInto,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+
+use chrono::{Local, TimeZone};
+use mime::Mime;
+use unicode_segmentation::UnicodeSegmentation;
+
 use matrix_sdk::ruma::{
     events::{
         room::message::{Relation, RoomMessageEventContent},
         AnyMessageLikeEvent, AnySyncMessageLikeEvent,
     },
-    EventId, UserId,
+    uint, EventId, MilliSecondsSinceUnixEpoch, UserId,
 };
 
 pub trait ToTag {
@@ -80,3 +93,1172 @@ impl Edit for AnyMessageLikeEvent {
         }
     }
 }
+
+/// Wrap the bare URLs in the given text in angle brackets, markdown turns
+/// those into links.
+///
+/// Punctuation at the end of a URL is considered to be part of the sentence
+/// and is left outside of the brackets.
+pub fn linkify(text: &str) -> String {
+    let mut linkified = String::with_capacity(text.len());
+
+    for word in text.split_inclusive(char::is_whitespace) {
+        let trimmed = word.trim_end();
+        let url = trimmed.trim_end_matches(|c| ".,;:!?)".contains(c));
+
+        let is_url = ["https://", "http://"]
+            .iter()
+            .any(|s| url.starts_with(s) && url.len() > s.len());
+
+        if is_url {
+            linkified.push('<');
+            linkified.push_str(url);
+            linkified.push('>');
+            linkified.push_str(&word[url.len()..]);
+        } else {
+            linkified.push_str(word);
+        }
+    }
+
+    linkified
+}
+
+/// An IRC style correction of the last message, e.g. `s/teh/the/`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Substitution {
+    pub old: String,
+    pub new: String,
+    /// Replace all occurrences instead of only the first one.
+    pub global: bool,
+}
+
+impl Substitution {
+    /// Parse a correction.
+    ///
+    /// Slashes inside of the old and new text are escaped with a backslash,
+    /// the trailing slash is optional and may be followed by a `g` to replace
+    /// all occurrences.
+    pub fn parse(input: &str) -> Option<Self> {
+        let rest = input.strip_prefix("s/")?;
+
+        if rest.contains('\n') {
+            return None;
+        }
+
+        let mut parts = Vec::new();
+        let mut part = String::new();
+        let mut chars = rest.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('/') => part.push('/'),
+                    Some(c) => {
+                        part.push('\\');
+                        part.push(c);
+                    }
+                    None => part.push('\\'),
+                },
+                '/' => parts.push(std::mem::take(&mut part)),
+                c => part.push(c),
+            }
+        }
+
+        parts.push(part);
+
+        let global = match parts.get(2).map(String::as_str) {
+            None | Some("") => false,
+            Some("g") => true,
+            _ => return None,
+        };
+
+        if parts.len() > 3 || parts.len() < 2 || parts[0].is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            old: parts.remove(0),
+            new: parts.remove(0),
+            global,
+        })
+    }
+
+    /// Apply the correction to the given text.
+    ///
+    /// Returns `None` if the text doesn't contain the text that should be
+    /// replaced.
+    pub fn apply(&self, text: &str) -> Option<String> {
+        if !text.contains(&self.old) {
+            None
+        } else if self.global {
+            Some(text.replace(&self.old, &self.new))
+        } else {
+            Some(text.replacen(&self.old, &self.new, 1))
+        }
+    }
+}
+
+/// Split the reply fallback off the body of a reply.
+///
+/// The fallback quotes the replied to message in lines starting with `> `,
+/// the first of them names the sender, e.g. `> <@alice:example.org> Hi`.
+///
+/// Returns the sender, the quoted lines and the actual reply, or `None` if the
+/// body doesn't start with a fallback.
+pub fn split_reply_fallback(body: &str) -> Option<(&str, Vec<&str>, &str)> {
+    let mut sender = None;
+    let mut quote = Vec::new();
+    let mut offset = 0;
+
+    for line in body.split_inclusive('\n') {
+        let text = line.trim_end_matches(|c| c == '\r' || c == '\n');
+
+        if let Some(quoted) = text.strip_prefix('>') {
+            let quoted = quoted.strip_prefix(' ').unwrap_or(quoted);
+
+            if sender.is_none() {
+                // Emotes are quoted as `> * <@alice:example.org> waves`.
+                let quoted = quoted.strip_prefix("* ").unwrap_or(quoted);
+                let quoted = quoted.strip_prefix('<')?;
+                let end = quoted.find('>')?;
+
+                sender = Some(&quoted[..end]);
+                quote.push(quoted[end + 1..].trim_start());
+            } else {
+                quote.push(quoted);
+            }
+
+            offset += line.len();
+        } else {
+            if text.is_empty() {
+                offset += line.len();
+            }
+
+            break;
+        }
+    }
+
+    sender.map(|sender| (sender, quote, &body[offset..]))
+}
+
+/// Remove the `<mx-reply>` element, which holds the reply fallback, from the
+/// formatted body of a reply.
+pub fn strip_mx_reply(html: &str) -> Cow<'_, str> {
+    const END: &str = "</mx-reply>";
+
+    match (html.find("<mx-reply>"), html.find(END)) {
+        (Some(start), Some(end)) if start < end => {
+            format!("{}{}", &html[..start], &html[end + END.len()..]).into()
+        }
+        _ => html.into(),
+    }
+}
+
+/// Escape the characters that have a special meaning in HTML.
+/// Write a file that only our own user can read, its parent directories are
+/// created if needed.
+///
+/// Used for files that contain the content of our messages.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+
+    // The mode only applies to new files, files that were written before
+    // might be readable by others.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+
+    file.write_all(contents)
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Replace the HTML entities that `escape_html()` produces, and a couple of
+/// other common ones, with the characters they represent.
+pub fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Flatten a possibly multi-line text into a single line.
+///
+/// Empty lines are removed and the remaining lines are joined with a space.
+pub fn flatten_lines(text: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Split a message into chunks that are at most `max_len` bytes long.
+///
+/// The message is preferably split on line boundaries, lines that are longer
+/// than `max_len` on their own are split on grapheme boundaries.
+pub fn split_message(message: &str, max_len: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for line in message.split_inclusive('\n') {
+        if !current.is_empty() && current.len() + line.len() > max_len {
+            chunks.push(std::mem::take(&mut current));
+        }
+
+        if line.len() > max_len {
+            for grapheme in line.graphemes(true) {
+                if !current.is_empty()
+                    && current.len() + grapheme.len() > max_len
+                {
+                    chunks.push(std::mem::take(&mut current));
+                }
+
+                current.push_str(grapheme);
+            }
+        } else {
+            current.push_str(line);
+        }
+    }
+
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+        .into_iter()
+        .map(|c| c.trim_end_matches('\n').to_owned())
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+/// Is the given character one that can be used to spoof nicks or confuse the
+/// terminal, e.g. bidirectional overrides or zero-width characters.
+///
+/// The zero-width joiner isn't included since it's used in emoji sequences.
+fn is_spoofing_char(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200b}'
+            | '\u{200c}'
+            | '\u{200e}'
+            | '\u{200f}'
+            | '\u{202a}'..='\u{202e}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{2069}'
+            | '\u{feff}'
+    )
+}
+
+/// Escape characters that can be used to spoof nicks or confuse the terminal
+/// by replacing them with their visible code point, e.g. `<U+202E>`.
+pub fn escape_spoofing_chars(text: &str) -> Cow<'_, str> {
+    if !text.chars().any(is_spoofing_char) {
+        return Cow::Borrowed(text);
+    }
+
+    text.chars()
+        .map(|c| {
+            if is_spoofing_char(c) {
+                format!("<U+{:04X}>", c as u32)
+            } else {
+                c.to_string()
+            }
+        })
+        .collect::<String>()
+        .into()
+}
+
+/// The separator long words are broken up with by `limit_word_length()`.
+///
+/// The zero-width space marks the break, so it can be removed again when the
+/// text of a message is read back from the buffer.
+pub const WORD_BREAK: &str = "\u{200b} ";
+
+/// Break up or truncate the words of the text that are longer than the given
+/// number of characters, e.g. base64 blobs or minified JSON.
+///
+/// Long words are broken up using `WORD_BREAK` so the terminal can wrap them,
+/// truncated words end with an ellipsis. URLs and words containing control
+/// characters, e.g. WeeChat color codes, are left alone.
+///
+/// Returns the text and whether a word was truncated.
+pub fn limit_word_length(
+    text: &str,
+    max: usize,
+    truncate: bool,
+) -> (Cow<'_, str>, bool) {
+    let too_long = |word: &str| {
+        word.chars().nth(max).is_some()
+            && !word.contains("://")
+            && !word.chars().any(char::is_control)
+    };
+
+    if max == 0 || !text.split(' ').any(too_long) {
+        return (Cow::Borrowed(text), false);
+    }
+
+    let mut truncated = false;
+
+    let text = text
+        .split(' ')
+        .map(|word| {
+            if !too_long(word) {
+                return word.to_owned();
+            }
+
+            let chars: Vec<char> = word.chars().collect();
+
+            if truncate {
+                truncated = true;
+                format!("{}…", chars[..max].iter().collect::<String>())
+            } else {
+                chars
+                    .chunks(max)
+                    .map(|c| c.iter().collect::<String>())
+                    .collect::<Vec<String>>()
+                    .join(WORD_BREAK)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    (Cow::Owned(text), truncated)
+}
+
+/// The longest duration `parse_duration()` accepts, a year.
+pub const MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Parse a human readable duration, e.g. `90s`, `30m`, `2h`, or `1d`.
+///
+/// A number without a unit is interpreted as minutes. Durations longer than
+/// `MAX_DURATION` are refused.
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration {}", duration);
+
+    let trimmed = duration.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| trimmed.len());
+
+    let (number, unit) = trimmed.split_at(split);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+
+    let seconds = match unit {
+        "s" => 1,
+        "" | "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+
+    number
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .filter(|d| *d <= MAX_DURATION)
+        .ok_or_else(|| {
+            format!(
+                "Duration {} is too long, the maximum is {}",
+                duration,
+                format_duration(MAX_DURATION)
+            )
+        })
+}
+
+/// Format a duration in the same style that `parse_duration()` accepts,
+/// using the largest unit that fits.
+pub fn format_duration(duration: Duration) -> String {
+    match duration.as_secs() {
+        s if s < 60 => format!("{}s", s),
+        s if s < 60 * 60 => format!("{}m", s / 60),
+        s if s < 24 * 60 * 60 => format!("{}h", s / (60 * 60)),
+        s => format!("{}d", s / (24 * 60 * 60)),
+    }
+}
+
+/// Format the timestamp of an event as a local date using the given strftime
+/// format.
+///
+/// Timestamps come from the server and can be out of the range of dates we
+/// can represent, those are formatted as `unknown date`.
+pub fn format_timestamp(
+    timestamp: MilliSecondsSinceUnixEpoch,
+    format: &str,
+) -> String {
+    let seconds: i64 = (timestamp.0 / uint!(1000)).into();
+
+    Local
+        .timestamp_opt(seconds, 0)
+        .single()
+        .map(|d| d.format(format).to_string())
+        .unwrap_or_else(|| "unknown date".to_owned())
+}
+
+/// Format a number of bytes using the largest binary unit that fits, with
+/// one decimal.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Get the event type patterns of a comma separated list of
+/// `<room>=<event-type>` rules which apply to a room.
+///
+/// The given closure decides whether the room part of a rule matches the
+/// room.
+pub fn event_type_patterns(
+    rules: &str,
+    room_matches: impl Fn(&str) -> bool,
+) -> Vec<&str> {
+    rules
+        .split(',')
+        .filter_map(|rule| {
+            let mut parts = rule.trim().splitn(2, '=');
+            let room = parts.next()?.trim();
+            let event_type = parts.next()?.trim();
+
+            if !event_type.is_empty() && room_matches(room) {
+                Some(event_type)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Find the template of a command alias in a semicolon separated list of
+/// `<room> /<alias> <template>` entries.
+///
+/// The given closure decides whether the room part of an entry matches the
+/// room, the first matching entry wins.
+pub fn command_alias_template<'a>(
+    aliases: &'a str,
+    alias: &str,
+    room_matches: impl Fn(&str) -> bool,
+) -> Option<&'a str> {
+    aliases.split(';').find_map(|entry| {
+        let mut parts = entry.trim().splitn(3, ' ');
+        let room = parts.next()?;
+        let name = parts.next()?;
+        let template = parts.next()?.trim();
+
+        if name == alias && !template.is_empty() && room_matches(room) {
+            Some(template)
+        } else {
+            None
+        }
+    })
+}
+
+/// Find the value of the first entry in a semicolon separated list of
+/// `<room> <value>` entries whose room part matches a room.
+///
+/// The given closure decides whether the room part of an entry matches the
+/// room.
+pub fn room_rule<'a>(
+    rules: &'a str,
+    room_matches: impl Fn(&str) -> bool,
+) -> Option<&'a str> {
+    rules.split(';').find_map(|entry| {
+        let mut parts = entry.trim().splitn(2, ' ');
+        let room = parts.next()?;
+        let value = parts.next()?.trim();
+
+        if !value.is_empty() && room_matches(room) {
+            Some(value)
+        } else {
+            None
+        }
+    })
+}
+
+/// Expand the template of a command alias, `$1` to `$9` are replaced by the
+/// arguments of the alias and `$*` by all of them.
+pub fn expand_alias_template(template: &str, args: &[&str]) -> String {
+    let mut expanded = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.peek().copied()) {
+            ('$', Some('*')) => {
+                chars.next();
+                expanded.push_str(&args.join(" "));
+            }
+            ('$', Some(d @ '1'..='9')) => {
+                chars.next();
+                let index = d as usize - '1' as usize;
+                expanded.push_str(args.get(index).copied().unwrap_or_default());
+            }
+            _ => expanded.push(c),
+        }
+    }
+
+    expanded
+}
+
+/// Split a target that is qualified with the name of one of our servers,
+/// e.g. `work:@alice:example.org`, into the server name and the target.
+///
+/// Targets are only considered qualified if the part before the first colon
+/// is the name of a server, this way user ids and room aliases, which
+/// contain colons themselves, are left alone.
+pub fn split_server_qualifier<'a, F>(
+    target: &'a str,
+    is_server: F,
+) -> (Option<&'a str>, &'a str)
+where
+    F: Fn(&str) -> bool,
+{
+    match target.split_once(':') {
+        Some((server, rest)) if !rest.is_empty() && is_server(server) => {
+            (Some(server), rest)
+        }
+        _ => (None, target),
+    }
+}
+
+/// Expand the template of the device name, `{hostname}`, `{server}` and
+/// `{user}` are replaced by the host name of the machine, the name of the
+/// server and the username.
+pub fn expand_device_name(
+    template: &str,
+    hostname: &str,
+    server_name: &str,
+    username: &str,
+) -> String {
+    template
+        .replace("{hostname}", hostname)
+        .replace("{server}", server_name)
+        .replace("{user}", username)
+}
+
+/// Check if an event type matches a pattern, patterns ending with a `*` match
+/// all the event types with the same prefix.
+pub fn event_type_matches(pattern: &str, event_type: &str) -> bool {
+    if let Some(prefix) = pattern.strip_suffix('*') {
+        event_type.starts_with(prefix)
+    } else {
+        pattern == event_type
+    }
+}
+
+/// Find the occurrences of the given word in the text, the word needs to be
+/// surrounded by non-alphanumeric characters or the edges of the text.
+fn word_indices<'a>(
+    text: &'a str,
+    word: &'a str,
+) -> impl Iterator<Item = usize> + 'a {
+    text.match_indices(word)
+        .map(|(index, _)| index)
+        .filter(move |index| {
+            let before = text[..*index].chars().next_back();
+            let after = text[index + word.len()..].chars().next();
+
+            !word.is_empty()
+                && !before.map_or(false, |c| c.is_alphanumeric())
+                && !after.map_or(false, |c| c.is_alphanumeric())
+        })
+}
+
+/// Check if the text contains the given word, the word needs to be
+/// surrounded by non-alphanumeric characters or the edges of the text.
+pub fn contains_word(text: &str, word: &str) -> bool {
+    word_indices(text, word).next().is_some()
+}
+
+/// Replace the occurrences of the given word in the text, occurrences that
+/// are part of a longer word are kept.
+pub fn replace_word(text: &str, word: &str, replacement: &str) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut last = 0;
+
+    for index in word_indices(text, word) {
+        replaced.push_str(&text[last..index]);
+        replaced.push_str(replacement);
+        last = index + word.len();
+    }
+
+    replaced.push_str(&text[last..]);
+
+    replaced
+}
+
+/// The file extensions of the media types we open with the media opener.
+const MEDIA_EXTENSIONS: &[(&str, &str)] = &[
+    ("audio/aac", "aac"),
+    ("audio/flac", "flac"),
+    ("audio/mp4", "m4a"),
+    ("audio/mpeg", "mp3"),
+    ("audio/ogg", "ogg"),
+    ("audio/wav", "wav"),
+    ("audio/webm", "weba"),
+    ("audio/x-wav", "wav"),
+    ("image/bmp", "bmp"),
+    ("image/gif", "gif"),
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/webp", "webp"),
+    ("text/plain", "txt"),
+    ("video/mp4", "mp4"),
+    ("video/webm", "webm"),
+    ("application/pdf", "pdf"),
+];
+
+/// Get the file extension for the given media type.
+///
+/// Only the media types of a fixed list are known, the names of the files
+/// other users send can't be trusted to pick an extension, the media opener
+/// might run anything with an unexpected extension.
+pub fn media_extension(mimetype: &str) -> Option<&'static str> {
+    let essence = mimetype.split(';').next().unwrap_or_default().trim();
+
+    MEDIA_EXTENSIONS
+        .iter()
+        .find(|(m, _)| m.eq_ignore_ascii_case(essence))
+        .map(|(_, extension)| *extension)
+}
+
+/// Detect the type of an image from its magic bytes.
+///
+/// Returns a file name for the image and its media type, or `None` if the
+/// data isn't an image in one of the supported formats.
+pub fn image_type(data: &[u8]) -> Option<(&'static str, Mime)> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(("image.png", mime::IMAGE_PNG))
+    } else if data.starts_with(b"\xff\xd8\xff") {
+        Some(("image.jpg", mime::IMAGE_JPEG))
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some(("image.gif", mime::IMAGE_GIF))
+    } else if data.starts_with(b"BM") {
+        Some(("image.bmp", mime::IMAGE_BMP))
+    } else if data.len() >= 12
+        && &data[..4] == b"RIFF"
+        && &data[8..12] == b"WEBP"
+    {
+        Some(("image.webp", "image/webp".parse().ok()?))
+    } else {
+        None
+    }
+}
+
+/// The number of samples the waveform of a voice message has.
+const WAVEFORM_SAMPLES: usize = 100;
+
+/// The largest value of a waveform sample, as defined by MSC1767.
+const WAVEFORM_MAX: u64 = 1024;
+
+/// The duration and the waveform of a voice message.
+#[derive(Debug, PartialEq)]
+pub struct VoiceInfo {
+    pub duration: Duration,
+    pub waveform: Vec<u16>,
+}
+
+/// Read the duration and the waveform of an Ogg Opus file.
+///
+/// Getting a real waveform would require decoding the audio, the sizes of the
+/// Ogg pages are used instead, for variable bitrate Opus they follow the
+/// loudness of the recording closely enough. Returns `None` if the data isn't
+/// an Ogg Opus file.
+pub fn ogg_opus_info(data: &[u8]) -> Option<VoiceInfo> {
+    let mut pages = Vec::new();
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        if rest.len() < 27 || &rest[..4] != b"OggS" {
+            return None;
+        }
+
+        let granule = u64::from_le_bytes(rest[6..14].try_into().ok()?);
+        let segments = rest[26] as usize;
+        let body_size: usize = rest
+            .get(27..27 + segments)?
+            .iter()
+            .map(|&s| s as usize)
+            .sum();
+        let page_size = 27 + segments + body_size;
+
+        pages.push((granule, rest.get(27 + segments..page_size)?));
+        rest = &rest[page_size..];
+    }
+
+    let (_, head) = pages.first()?;
+
+    if !head.starts_with(b"OpusHead") {
+        return None;
+    }
+
+    let pre_skip = u16::from_le_bytes(head.get(10..12)?.try_into().ok()?);
+
+    // Pages that don't finish a packet have a granule position of -1.
+    let samples = pages
+        .iter()
+        .map(|(granule, _)| *granule)
+        .filter(|&granule| granule != u64::MAX)
+        .max()?
+        .saturating_sub(pre_skip.into());
+
+    // The first two pages contain the Opus headers.
+    let sizes: Vec<u64> =
+        pages.iter().skip(2).map(|(_, b)| b.len() as u64).collect();
+    let loudest = sizes.iter().copied().max().unwrap_or_default().max(1);
+
+    let waveform = if sizes.is_empty() {
+        Vec::new()
+    } else {
+        (0..WAVEFORM_SAMPLES)
+            .map(|i| sizes[i * sizes.len() / WAVEFORM_SAMPLES])
+            .map(|size| (size * WAVEFORM_MAX / loudest) as u16)
+            .collect()
+    };
+
+    Some(VoiceInfo {
+        // Opus always uses a 48 kHz granule position.
+        duration: Duration::from_millis(samples.saturating_mul(1000) / 48_000),
+        waveform,
+    })
+}
+
+/// Score how well a pattern fuzzily matches a candidate, higher is better.
+///
+/// The characters of the pattern need to appear in the candidate in order,
+/// case insensitively. Consecutive characters and characters at the start of
+/// a word score higher, while longer candidates score lower. Returns `None` if
+/// the pattern doesn't match at all.
+pub fn fuzzy_score(pattern: &str, candidate: &str) -> Option<i64> {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let candidate: Vec<char> = candidate.to_lowercase().chars().collect();
+
+    let mut score = 0;
+    let mut position = 0;
+    let mut previous_match: Option<usize> = None;
+
+    for c in pattern {
+        let index = position
+            + candidate[position..].iter().position(|&other| other == c)?;
+
+        score += 1;
+
+        if previous_match.map_or(false, |p| p + 1 == index) {
+            score += 5;
+        }
+
+        if index == 0 || !candidate[index - 1].is_alphanumeric() {
+            score += 3;
+        }
+
+        previous_match = Some(index);
+        position = index + 1;
+    }
+
+    Some(score * 100 - candidate.len() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_message_on_lines() {
+        let message = "first line\nsecond line\nthird line";
+        let chunks = split_message(message, 24);
+
+        assert_eq!(chunks, vec!["first line\nsecond line", "third line"]);
+    }
+
+    #[test]
+    fn test_split_message_long_line() {
+        let message = "a".repeat(10);
+        let chunks = split_message(&message, 4);
+
+        assert_eq!(chunks, vec!["aaaa", "aaaa", "aa"]);
+    }
+
+    #[test]
+    fn test_html_escaping() {
+        let text = "<b>\"Fish\" & chips</b>";
+
+        assert_eq!(unescape_html(&escape_html(text)), text);
+    }
+
+    #[test]
+    fn test_flatten_lines() {
+        assert_eq!(
+            flatten_lines("Welcome!\n\n  Rules:\nBe nice "),
+            "Welcome! Rules: Be nice"
+        );
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("5 weeks").is_err());
+        assert!(parse_duration("366d").is_err());
+        assert!(parse_duration("200000000000000d").is_err());
+    }
+
+    #[test]
+    fn test_escape_spoofing_chars() {
+        assert_eq!(escape_spoofing_chars("alice"), "alice");
+        assert_eq!(
+            escape_spoofing_chars("admin\u{202e}nimda"),
+            "admin<U+202E>nimda"
+        );
+        assert_eq!(escape_spoofing_chars("a\u{200b}b"), "a<U+200B>b");
+        // Zero-width joiners in emoji sequences are left alone.
+        assert_eq!(escape_spoofing_chars("👩\u{200d}💻"), "👩\u{200d}💻");
+    }
+
+    #[test]
+    fn test_limit_word_length() {
+        assert_eq!(
+            limit_word_length("tiny word", 4, false),
+            (Cow::Borrowed("tiny word"), false)
+        );
+        assert_eq!(
+            limit_word_length("key: aGVsbG8gd29ybGQ", 4, false).0,
+            "key: aGVs\u{200b} bG8g\u{200b} d29y\u{200b} bGQ"
+        );
+        assert_eq!(
+            limit_word_length("key: aGVsbG8gd29ybGQ", 4, true),
+            (Cow::Owned("key: aGVs…".to_owned()), true)
+        );
+        assert_eq!(
+            limit_word_length("https://example.org/a", 4, true).0,
+            "https://example.org/a"
+        );
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(7200)), "2h");
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+
+    #[test]
+    fn test_media_extension() {
+        assert_eq!(media_extension("audio/ogg"), Some("ogg"));
+        assert_eq!(media_extension("audio/ogg; codecs=opus"), Some("ogg"));
+        assert_eq!(media_extension("IMAGE/PNG"), Some("png"));
+        assert_eq!(media_extension("application/x-desktop"), None);
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        use matrix_sdk::ruma::UInt;
+
+        let timestamp = MilliSecondsSinceUnixEpoch(UInt::MAX);
+        assert_eq!(format_timestamp(timestamp, "%Y"), "unknown date");
+
+        let timestamp = MilliSecondsSinceUnixEpoch(UInt::new(0).unwrap());
+        assert!(format_timestamp(timestamp, "%Y").starts_with("19"));
+    }
+
+    #[test]
+    fn test_fuzzy_score() {
+        assert_eq!(fuzzy_score("xyz", "#rust"), None);
+        assert_eq!(fuzzy_score("tsur", "#rust"), None);
+        assert!(fuzzy_score("RST", "#rust").is_some());
+
+        // Consecutive matches beat scattered ones.
+        assert!(fuzzy_score("rust", "#rust") > fuzzy_score("rust", "#r-u-s-t"));
+        // Matches at word starts beat matches inside of words.
+        assert!(
+            fuzzy_score("wm", "#weechat-matrix")
+                > fuzzy_score("wm", "#swimming")
+        );
+        // Shorter candidates win ties.
+        assert!(fuzzy_score("rust", "#rust") > fuzzy_score("rust", "#rust-de"));
+    }
+
+    #[test]
+    fn test_contains_word() {
+        assert!(contains_word("alice: hello", "alice"));
+        assert!(contains_word("hi @room!", "@room"));
+        assert!(!contains_word("malice aforethought", "alice"));
+        assert!(!contains_word("@roommate", "@room"));
+        assert!(!contains_word("anything", ""));
+    }
+
+    #[test]
+    fn test_replace_word() {
+        assert_eq!(replace_word("alice: hi alice", "alice", "A"), "A: hi A");
+        assert_eq!(
+            replace_word("malice and alice", "alice", "A"),
+            "malice and A"
+        );
+        assert_eq!(replace_word("@roommate", "@room", "A"), "@roommate");
+        assert_eq!(
+            replace_word("foo@roomy @room", "@room", "A"),
+            "foo@roomy A"
+        );
+        assert_eq!(replace_word("anything", "", "A"), "anything");
+    }
+
+    #[test]
+    fn test_image_type() {
+        let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR";
+
+        assert_eq!(image_type(png), Some(("image.png", mime::IMAGE_PNG)));
+        assert_eq!(
+            image_type(b"RIFF\x00\x00\x00\x00WEBPVP8 ").map(|t| t.0),
+            Some("image.webp")
+        );
+        assert_eq!(image_type(b"Hello world"), None);
+    }
+
+    fn ogg_page(granule: u64, body: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\x00\x00".to_vec();
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&[0; 12]);
+        page.push(1);
+        page.push(body.len() as u8);
+        page.extend_from_slice(body);
+        page
+    }
+
+    #[test]
+    fn test_ogg_opus_info() {
+        let mut head = b"OpusHead\x01\x01".to_vec();
+        head.extend_from_slice(&312u16.to_le_bytes());
+
+        let mut data = ogg_page(0, &head);
+        data.extend(ogg_page(0, b"OpusTags"));
+        data.extend(ogg_page(48_000 + 312, &[0; 100]));
+        data.extend(ogg_page(96_000 + 312, &[0; 50]));
+
+        let info = ogg_opus_info(&data).unwrap();
+
+        assert_eq!(info.duration, Duration::from_secs(2));
+        assert_eq!(info.waveform.len(), WAVEFORM_SAMPLES);
+        assert_eq!(info.waveform[0], 1024);
+        assert_eq!(info.waveform[WAVEFORM_SAMPLES - 1], 512);
+
+        assert_eq!(ogg_opus_info(b"Hello world"), None);
+        assert_eq!(ogg_opus_info(&ogg_page(0, b"OggVorbis")), None);
+    }
+
+    #[test]
+    fn test_command_aliases() {
+        let aliases = "#ops:example.org /deploy Deploying $1 to $2; \
+                       * /shrug ¯\\_(ツ)_/¯ $*";
+        let ops = |r: &str| r == "*" || r == "#ops:example.org";
+        let other = |r: &str| r == "*";
+
+        assert_eq!(
+            command_alias_template(aliases, "/deploy", ops),
+            Some("Deploying $1 to $2")
+        );
+        assert_eq!(command_alias_template(aliases, "/deploy", other), None);
+        assert!(command_alias_template(aliases, "/shrug", other).is_some());
+
+        assert_eq!(
+            expand_alias_template("Deploying $1 to $2", &["api", "prod"]),
+            "Deploying api to prod"
+        );
+        assert_eq!(
+            expand_alias_template("$* costs $0 or $9", &["tea", "time"]),
+            "tea time costs $0 or "
+        );
+    }
+
+    #[test]
+    fn test_split_reply_fallback() {
+        let body = "> <@alice:example.org> Hello\n> there\n\nHi alice";
+
+        assert_eq!(
+            split_reply_fallback(body),
+            Some(("@alice:example.org", vec!["Hello", "there"], "Hi alice"))
+        );
+        assert_eq!(
+            split_reply_fallback("> * <@bob:example.org> waves\n\n:)"),
+            Some(("@bob:example.org", vec!["waves"], ":)"))
+        );
+        assert_eq!(split_reply_fallback("> just a quote\n\ntext"), None);
+        assert_eq!(split_reply_fallback("No fallback"), None);
+    }
+
+    #[test]
+    fn test_strip_mx_reply() {
+        let html = "<mx-reply><blockquote>Hello</blockquote></mx-reply>\
+                    <b>Hi</b> alice";
+
+        assert_eq!(strip_mx_reply(html), "<b>Hi</b> alice");
+        assert_eq!(strip_mx_reply("<b>No fallback</b>"), "<b>No fallback</b>");
+    }
+
+    #[test]
+    fn test_substitution() {
+        let s = Substitution::parse("s/teh/the/").unwrap();
+        assert_eq!(
+            s.apply("teh cat and teh dog").unwrap(),
+            "the cat and teh dog"
+        );
+
+        let s = Substitution::parse("s/teh/the/g").unwrap();
+        assert_eq!(
+            s.apply("teh cat and teh dog").unwrap(),
+            "the cat and the dog"
+        );
+
+        let s = Substitution::parse("s/a\\/b/c").unwrap();
+        assert_eq!(s.old, "a/b");
+        assert_eq!(s.new, "c");
+        assert_eq!(s.apply("xyz"), None);
+
+        assert_eq!(Substitution::parse("s/teh"), None);
+        assert_eq!(Substitution::parse("s//the/"), None);
+        assert_eq!(Substitution::parse("s/a/b/c/"), None);
+        assert_eq!(Substitution::parse("see s/a/b/"), None);
+    }
+
+    #[test]
+    fn test_linkify() {
+        assert_eq!(
+            linkify("See https://example.org/a?b=c."),
+            "See <https://example.org/a?b=c>."
+        );
+        assert_eq!(
+            linkify("(http://example.org)\n<https://matrix.org> http://"),
+            "(http://example.org)\n<https://matrix.org> http://"
+        );
+        assert_eq!(
+            linkify("a  http://example.org b"),
+            "a  <http://example.org> b"
+        );
+    }
+
+    #[test]
+    fn test_split_server_qualifier() {
+        let is_server = |s: &str| s == "work" || s == "home";
+
+        assert_eq!(
+            split_server_qualifier("work:@alice:example.org", is_server),
+            (Some("work"), "@alice:example.org")
+        );
+        assert_eq!(
+            split_server_qualifier("@alice:example.org", is_server),
+            (None, "@alice:example.org")
+        );
+        assert_eq!(
+            split_server_qualifier("#home:example.org", is_server),
+            (None, "#home:example.org")
+        );
+        assert_eq!(split_server_qualifier("home:", is_server), (None, "home:"));
+    }
+
+    #[test]
+    fn test_expand_device_name() {
+        assert_eq!(
+            expand_device_name(
+                "WeeChat on {hostname} ({user}@{server})",
+                "laptop",
+                "matrix.org",
+                "alice"
+            ),
+            "WeeChat on laptop (alice@matrix.org)"
+        );
+        assert_eq!(
+            expand_device_name("WeeChat-Matrix-rs", "laptop", "a", "b"),
+            "WeeChat-Matrix-rs"
+        );
+    }
+
+    #[test]
+    fn test_room_rule() {
+        let rules = "#dev:example.org en; !abc:example.org; * en,de";
+        let dev = |r: &str| r == "*" || r == "#dev:example.org";
+        let other = |r: &str| r == "*" || r == "!abc:example.org";
+
+        assert_eq!(room_rule(rules, dev), Some("en"));
+        assert_eq!(room_rule(rules, other), Some("en,de"));
+        assert_eq!(room_rule("", other), None);
+    }
+
+    #[test]
+    fn test_event_type_rules() {
+        let rules = "*=m.sticker, #news:example.org=m.reaction,invalid,\
+                     #other:example.org=m.room.*";
+        let patterns = event_type_patterns(rules, |r| {
+            r == "*" || r == "#news:example.org"
+        });
+
+        assert_eq!(patterns, vec!["m.sticker", "m.reaction"]);
+
+        assert!(event_type_matches("m.room.*", "m.room.message"));
+        assert!(event_type_matches("m.reaction", "m.reaction"));
+        assert!(!event_type_matches("m.room.*", "m.reaction"));
+        assert!(!event_type_matches("m.room", "m.room.message"));
+    }
+
+    #[test]
+    fn test_split_message_short() {
+        assert_eq!(split_message("hello", 100), vec!["hello"]);
+    }
+
+    #[test]
+    fn test_write_private_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox").join("room.json");
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "old content").unwrap();
+
+        write_private_file(&path, b"new").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}